@@ -0,0 +1,119 @@
+//! Keys of git-annex's SHA256 and SHA256E backends
+//!
+//! Keys look like `SHA256E-s1234--<hexdigest>.jpg`, so states hashed with
+//! SHA-256 can be converted to and from git-annex without reading files.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::Path;
+
+/// Longest extension kept in SHA256E keys, git-annex's default
+const MAX_EXTENSION_LEN: usize = 4;
+/// Number of extensions kept in SHA256E keys, like `.tar.gz`
+const MAX_EXTENSIONS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sha256,
+    /// Like SHA256, with the extension of the file appended to the key
+    Sha256E,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Sha256 => "SHA256",
+            Backend::Sha256E => "SHA256E",
+        })
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "SHA256" => Ok(Backend::Sha256),
+            "SHA256E" => Ok(Backend::Sha256E),
+            _ => Err(format!("expected `SHA256` or `SHA256E`, got `{}`", s)),
+        }
+    }
+}
+
+/// Extension git-annex would add to the key of a file, including the dot
+fn key_extension(path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some((_, extensions)) = file_name.split_once('.') else {
+        return String::new();
+    };
+    let mut extensions = extensions
+        .rsplit('.')
+        .take_while(|e| {
+            !e.is_empty()
+                && e.len() <= MAX_EXTENSION_LEN
+                && e.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+        .take(MAX_EXTENSIONS)
+        .collect::<Vec<_>>();
+    extensions.reverse();
+    extensions.iter().map(|e| format!(".{}", e)).collect()
+}
+
+/// Key of a file with the given contents
+pub fn key(backend: Backend, path: &Path, digest: &[u8; 32], len: u64) -> String {
+    let extension = match backend {
+        Backend::Sha256 => String::new(),
+        Backend::Sha256E => key_extension(path),
+    };
+    format!("{}-s{}--{}{}", backend, len, hex::encode(digest), extension)
+}
+
+/// Parses a key into its digest and size
+///
+/// Only keys of the SHA256 and SHA256E backends with a size can be
+/// converted, chunked keys are rejected.
+pub fn parse_key(key: &str) -> Result<([u8; 32], u64)> {
+    let invalid = || format!("unsupported git-annex key: '{}'", key);
+    let (fields, name) = key.split_once("--").with_context(invalid)?;
+    let mut fields = fields.split('-');
+    fields
+        .next()
+        .unwrap_or_default()
+        .parse::<Backend>()
+        .map_err(anyhow::Error::msg)
+        .with_context(invalid)?;
+    let mut len = None;
+    for field in fields {
+        match field.split_at_checked(1) {
+            Some(("s", size)) => len = Some(size.parse().with_context(invalid)?),
+            Some(("m", _)) => {}
+            _ => return Err(anyhow::Error::msg(invalid())),
+        }
+    }
+    let mut digest = [0_u8; 32];
+    hex::decode_to_slice(name.get(..64).with_context(invalid)?, &mut digest)
+        .with_context(invalid)?;
+    Ok((digest, len.with_context(invalid)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_round_trip() -> Result<()> {
+        let digest = [0xab; 32];
+        let key = key(Backend::Sha256E, Path::new("a/b.tar.gz"), &digest, 12);
+        assert_eq!(key, format!("SHA256E-s12--{}.tar.gz", "ab".repeat(32)));
+        assert_eq!(parse_key(&key)?, (digest, 12));
+        assert_eq!(
+            super::key(Backend::Sha256, Path::new("photo.jpeg"), &digest, 1),
+            format!("SHA256-s1--{}", "ab".repeat(32))
+        );
+        assert_eq!(key_extension(Path::new("notes.final.markdown")), "");
+        assert_eq!(key_extension(Path::new("Makefile")), "");
+        assert!(parse_key(&format!("SHA256E-s12-S4-C1--{}", "ab".repeat(32))).is_err());
+        assert!(parse_key(&format!("MD5E-s12--{}", "ab".repeat(16))).is_err());
+        Ok(())
+    }
+}
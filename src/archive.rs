@@ -0,0 +1,3909 @@
+use crate::config::Config;
+use crate::container::{
+    changed_members, hash_members, is_container, member_path, read_members, write_members, Members,
+};
+use crate::deleted::{self, Tombstone};
+use crate::device_pools::{
+    self, device, filesystem_uuid, DeviceId, DevicePools, DeviceQueues, DeviceThreads,
+};
+use crate::directory_counts::{DirectoryCounts, ShrunkDirectory};
+use crate::file_check::{
+    hash_file, long_path, CheckTier, FileCheckResult, FileCheckResultModified, FileToCheck,
+    ReadError, ReadOptions, TierStats, DEFAULT_BUF_SIZE,
+};
+use crate::file_info::{set_immutable, Attributes, FileInfo};
+use crate::hash::HashAlgorithm;
+use crate::index::{digest_counts_path, DigestCounts};
+use crate::memory::MemoryBudget;
+use crate::observer::{NoopObserver, Observer};
+use crate::partial::{check_host, partial_state_path};
+use crate::plan::{write_plan, Plan, PlannedRead, ReadReason};
+use crate::priority;
+use crate::review::{read_changes, write_changes, Decision, Verdicts};
+use crate::roots::{map_prefix, PrefixMap, Root, Roots};
+use crate::spill::{merge_by_path, UnmodifiedFiles};
+use crate::state::{
+    check_new_label, check_new_state_path, count_entries, latest_state_path, named_run_path,
+    new_run_id, pinned_state, read_header, read_state, read_state_at, resolve_state, rotate_states,
+    run_path, write_errors, write_state, write_state_at, write_stats, NameFormat, StateHeader,
+};
+use crate::stats::{ExtensionGroup, Stats, StatsCollector};
+use crate::throughput::{ProgressTracker, Throughput};
+use crate::walk::{inode, list_file, list_files, Excludes, Links, WalkOrder};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::DirEntry;
+
+/// Number of files queued for reading per thread before listing files pauses
+const MAX_QUEUED_FILES_PER_THREAD: usize = 16;
+
+/// Fraction of the archive an update marks as missing without asking first
+///
+/// More than this is usually an unmounted disk or the wrong directory.
+const MAX_MISSING_FRACTION: f64 = 0.1;
+
+/// How much mtimes may differ on network filesystems by default
+///
+/// SMB servers backed by FAT only store them with 2 second precision,
+/// NFS clients can cache them rounded.
+const NETWORK_MTIME_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// How often a failed read is retried on network filesystems by default
+const NETWORK_READ_RETRIES: u32 = 2;
+
+/// Order in which files are read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadOrder {
+    /// Read files in the order they are found, by path unless with [`WalkOrder::Fast`]
+    #[default]
+    Path,
+    /// Read files ordered by their inode number
+    Inode,
+    /// Read the smallest files first, so most files are checked early in a run
+    SmallestFirst,
+}
+
+impl FromStr for ReadOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(ReadOrder::Path),
+            "inode" => Ok(ReadOrder::Inode),
+            "smallest-first" => Ok(ReadOrder::SmallestFirst),
+            _ => Err(format!(
+                "expected `path`, `inode` or `smallest-first`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with files that grew while they were read, like logs or downloads
+///
+/// Their digest is likely of a half-written file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GrowingFiles {
+    /// Record what was read and list them in the report
+    #[default]
+    Record,
+    /// Keep them as they were in the state, new ones aren't recorded
+    Skip,
+    /// Read them once more after all other files, skip them if they grew again
+    RetryLater,
+}
+
+impl FromStr for GrowingFiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(GrowingFiles::Record),
+            "skip" => Ok(GrowingFiles::Skip),
+            "retry-later" => Ok(GrowingFiles::RetryLater),
+            _ => Err(format!(
+                "expected `record`, `skip` or `retry-later`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// State verify compares against unless one is given
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Baseline {
+    /// The state pinned with [`crate::state::pin_state`], the latest one if none is
+    #[default]
+    Golden,
+    /// The latest state, even if another one is pinned
+    Latest,
+}
+
+impl FromStr for Baseline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "golden" => Ok(Baseline::Golden),
+            "latest" => Ok(Baseline::Latest),
+            _ => Err(format!("expected `golden` or `latest`, got `{}`", s)),
+        }
+    }
+}
+
+/// Where the contents of a missing file have to be found for it to count as moved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupeScope {
+    /// In the same directory, so only renamed files count as moved
+    SameDirectory,
+    /// Below the same top-level directory
+    SameTopLevel,
+    /// Anywhere in the directory
+    #[default]
+    Anywhere,
+}
+
+impl DedupeScope {
+    /// Part of the path files have to share to be in the same scope
+    fn scope<'a>(&self, rel_path: &'a Path) -> &'a Path {
+        match self {
+            DedupeScope::SameDirectory => rel_path.parent().unwrap_or(Path::new("")),
+            DedupeScope::SameTopLevel => match rel_path.components().next() {
+                Some(top) if rel_path.parent() != Some(Path::new("")) => Path::new(top.as_os_str()),
+                _ => Path::new(""),
+            },
+            DedupeScope::Anywhere => Path::new(""),
+        }
+    }
+}
+
+impl FromStr for DedupeScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "same-dir" => Ok(DedupeScope::SameDirectory),
+            "same-top-level" => Ok(DedupeScope::SameTopLevel),
+            "anywhere" => Ok(DedupeScope::Anywhere),
+            _ => Err(format!(
+                "expected `same-dir`, `same-top-level` or `anywhere`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// Options for listing and reading files, shared by update and verify
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveOptions {
+    /// Number of threads for reading files, detected from the storage type if None
+    pub threads: Option<usize>,
+    /// Number of threads for specific devices
+    pub device_threads: Vec<DeviceThreads>,
+    /// Bypass the page cache when reading files
+    pub direct_io: bool,
+    /// Read with idle I/O priority and low CPU priority
+    pub nice: bool,
+    /// Limit memory usage to roughly this many bytes
+    pub max_memory: Option<u64>,
+    pub read_order: ReadOrder,
+    pub excludes: Excludes,
+    /// Extensions to report together in the stats
+    pub extension_groups: Vec<ExtensionGroup>,
+    /// Number of files that took the longest to read to report in the stats
+    pub slowest_files: usize,
+    /// Also hash the members of zip files and tarballs, see [`crate::container`]
+    pub container_members: bool,
+    /// What to do with symlinks and junctions
+    pub links: Links,
+    /// Order the entries of directories are listed in
+    ///
+    /// Results and states are sorted by path either way, only the order
+    /// files are read in with [`ReadOrder::Path`] changes.
+    pub walk_order: WalkOrder,
+    /// Skip files locked by other programs without failing verify
+    ///
+    /// They're listed on their own instead of with the files that couldn't
+    /// be read for other reasons, and aren't written to the .errors file.
+    pub skip_locked: bool,
+    /// How much mtimes may differ for a file to count as unchanged
+    ///
+    /// Detected from the filesystem if None: 2 seconds on network
+    /// filesystems, none on local ones.
+    pub mtime_tolerance: Option<Duration>,
+    /// How often a failed read is retried, waiting a second longer every time
+    ///
+    /// Detected from the filesystem if None: twice on network filesystems,
+    /// never on local ones. Missing files and missing permissions aren't
+    /// retried.
+    pub read_retries: Option<u32>,
+    /// Further directories, their files are recorded below the prefix of each
+    ///
+    /// Added to those of the profile, a prefix given here replaces the one
+    /// there.
+    pub roots: Vec<Root>,
+    /// Write the files a run is going to read to this file before reading them
+    ///
+    /// All files are listed before the first one is read then, even with
+    /// [`ReadOrder::Path`]. See [`crate::plan::write_plan`] for the format.
+    pub plan: Option<PathBuf>,
+    /// Stop once the plan is written, the report only has the plan then
+    pub plan_only: bool,
+    /// What to do with files that were larger once read than when they were listed
+    pub growing_files: GrowingFiles,
+}
+
+/// Options for [`Archive::update`]
+#[derive(Clone, Debug, Default)]
+pub struct UpdateOptions {
+    /// Read all files, even if their modification time and size are unchanged
+    pub read_all_files: bool,
+    /// Algorithm for a new state, existing states keep theirs
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// Volume the directory is on, files on other volumes are kept as they are
+    pub volume: Option<String>,
+    /// Write the new state without asking, even if a large part of the archive is missing
+    pub force: bool,
+    /// Keep files that weren't found as they are instead of marking them as missing
+    pub append: bool,
+    /// Record missing and modified files even if their contents are found elsewhere
+    ///
+    /// By default, a missing file with its contents still in the directory
+    /// is considered moved and dropped, the same goes for the previous
+    /// contents of a modified file.
+    pub keep_duplicates: bool,
+    /// Where the contents of a missing file have to be found for it to count as moved
+    pub dedupe_scope: DedupeScope,
+    /// Keep missing files in the state until they have been missing for this long
+    ///
+    /// They're only recorded in the .missing file the first time. Files an
+    /// earlier update kept are still kept without it, they're not recorded
+    /// as missing a second time.
+    pub forget_missing_after: Option<Duration>,
+    /// Write a state without anything specific to this run, see [`StateHeader::canonical`]
+    pub canonical: bool,
+    /// Only list files below these paths relative to the directory, all if empty
+    ///
+    /// Files of the state outside of them are kept as they are.
+    pub paths: Vec<PathBuf>,
+    /// Only check these files, paths relative to the directory, instead of listing any
+    ///
+    /// For lists of changed files from another tool, like `find -newer`.
+    /// Files of the state not among them are kept as they are, files
+    /// among them that are gone are missing. Can't be combined with paths.
+    pub files: Option<Vec<PathBuf>>,
+    /// Set the immutable flag of all files seen, like `chattr +i`, only on Linux
+    ///
+    /// Files kept as they are, like those on other volumes, aren't touched.
+    pub set_immutable: bool,
+    /// Compare to this state instead of the latest one
+    ///
+    /// Relative paths are relative to the state directory, so the name of
+    /// a state file is enough, as is its label.
+    pub previous_state: Option<PathBuf>,
+    /// Name of the new state in the state directory, has to end in .state
+    ///
+    /// Named after the current time if None. States not named after the
+    /// time they were written are sorted by name to find the latest one,
+    /// so names have to sort in the order states are written.
+    pub state_file: Option<PathBuf>,
+    /// Label of the new state, unique among the states, see [`resolve_state`]
+    pub label: Option<String>,
+    /// Template to name the new state after instead of the one of the profile
+    pub state_name_format: Option<NameFormat>,
+    /// Write a partial state of this host instead of a state, see [`crate::partial`]
+    ///
+    /// Only the files below paths are written, to the partial directory,
+    /// for [`crate::partial::merge`] to take into the next state. Other
+    /// hosts can write partial states to the same state directory at the
+    /// same time.
+    pub partial: Option<String>,
+}
+
+/// Options for [`Archive::verify`]
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    /// Allow files present in the archive state to be missing
+    pub ignore_missing: bool,
+    /// Only check files are in the archive, not their paths
+    pub only_presence: bool,
+    /// Volume the directory is on, files on other volumes aren't checked
+    pub volume: Option<String>,
+    /// Fail instead of warning if the directory is on a different filesystem than the state
+    pub strict_volume: bool,
+    /// Report this many unmodified files as modified, to test what happens then
+    ///
+    /// Only the digest compared to the state is changed, the files are
+    /// left untouched.
+    pub simulate_corruption: usize,
+    /// Read modified files a second time, bypassing the page cache if possible
+    ///
+    /// Files are only reported as modified if both reads give the same
+    /// digest, so a transient read error isn't mistaken for corruption.
+    /// Reads that agree with neither each other nor the state are reported
+    /// as read errors.
+    pub double_read: bool,
+    /// Verify against this state instead of the latest or golden one
+    ///
+    /// Relative paths are relative to the state directory, so the name of
+    /// a state file is enough, as is its label. Takes precedence over against.
+    pub state_file: Option<PathBuf>,
+    /// Which state to verify against if no state_file is given
+    pub against: Baseline,
+    /// Verify against this state file on its own, without a state directory
+    ///
+    /// For copies of an archive handed on with just a state file. Nothing
+    /// in the state directory is read or written then: there's no profile,
+    /// no .errors file is written and the spill files of max_memory go to
+    /// the temporary directory. Takes precedence over state_file.
+    pub manifest: Option<PathBuf>,
+    /// Where directories of the profile are found now, for a clone mounted elsewhere
+    ///
+    /// Applies to the roots of the profile. The directory of the archive
+    /// is the one given to [`Archive::new`], map it with [`map_prefix`]
+    /// if it's taken from the profile.
+    pub map_prefix: Vec<PrefixMap>,
+    /// Match files to the state regardless of case, for a copy on a case-insensitive filesystem
+    ///
+    /// Paths found are only compared ignoring case if no path of the state
+    /// matches exactly. Files matched this way are reported with the path
+    /// found.
+    pub case_insensitive_paths: bool,
+    /// List all files and report what their metadata gives away before reading any
+    ///
+    /// Missing files and files with a different size or mtime are passed
+    /// to [`Observer::on_metadata_pass`] within seconds, instead of turning
+    /// up somewhere among terabytes of reads.
+    pub metadata_first: bool,
+    /// Cheap checks to run before reading files completely, in this order
+    ///
+    /// Files passing all of them are unmodified. Files failing one are read
+    /// completely, so modified files are reported with their digest. All
+    /// files are read completely if there are none.
+    pub tiers: Vec<CheckTier>,
+    /// Only check these files, paths relative to the directory, instead of listing any
+    ///
+    /// Files of the state not among them are neither checked nor missing.
+    pub files: Option<Vec<PathBuf>>,
+}
+
+/// What a verify found out by listing files, before reading any, see [`VerifyOptions::metadata_first`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataPass {
+    /// Files of the state not found, sorted by path like the lists below
+    pub missing: Vec<PathBuf>,
+    /// Files found that aren't in the state
+    pub new: Vec<PathBuf>,
+    /// Files with a different size or mtime than in the state
+    pub changed: Vec<PathBuf>,
+}
+
+/// A file whose Windows attributes changed since the latest state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeChange {
+    pub path: PathBuf,
+    pub previous: Attributes,
+    pub current: Attributes,
+}
+
+/// Outcome of [`Archive::update`]
+#[derive(Clone, Debug, Default)]
+pub struct UpdateReport {
+    /// ID of the run, also written to the state and stats
+    pub run_id: String,
+    /// Path of the state file written
+    pub state_path: PathBuf,
+    pub stats: Stats,
+    pub duration: Duration,
+    /// Files that weren't in the previous state
+    pub new: Vec<PathBuf>,
+    /// Files with contents different from the previous state
+    pub modified: Vec<PathBuf>,
+    /// Files in the previous state that are gone and weren't found elsewhere
+    pub missing: Vec<PathBuf>,
+    /// Modified files that are empty now, more often data loss than a change
+    ///
+    /// Files whose change was accepted in a review aren't among them.
+    pub truncated: Vec<PathBuf>,
+    /// Modified files whose change was accepted in a review, see [`crate::review`]
+    pub accepted: Vec<PathBuf>,
+    /// Files flagged as corrupted in a review and still corrupted, kept as they were in the previous state
+    pub flagged_corrupted: Vec<PathBuf>,
+    /// Number of files kept as they are because they're on other volumes, by volume
+    pub other_volumes: BTreeMap<String, u64>,
+    /// Number of files not found but kept as they are, only with append
+    pub kept_not_found: u64,
+    /// Number of missing files kept in the state, see forget_missing_after
+    pub still_missing: u64,
+    /// Number of missing files no longer kept, only with forget_missing_after
+    pub forgotten: u64,
+    /// Number of files kept as they are because they're outside of the paths or not among the files
+    pub kept_outside_paths: u64,
+    /// Files with different attributes than in the previous state, only on Windows and Linux
+    pub attribute_changes: Vec<AttributeChange>,
+    /// Number of files made immutable, only with set_immutable
+    pub made_immutable: u64,
+    /// Number of files that couldn't be made immutable, only with set_immutable
+    pub not_made_immutable: u64,
+    /// Old states removed to keep the number of states from the profile
+    pub removed_states: Vec<PathBuf>,
+    /// Files that couldn't be read, kept as they were in the previous state
+    pub read_errors: Vec<ReadError>,
+    /// The .errors file listing them, only if there are any
+    pub errors_path: Option<PathBuf>,
+    /// Files skipped because they're locked, kept as they were, only with skip_locked
+    pub locked: Vec<PathBuf>,
+    /// Files that grew while they were read, recorded or skipped depending on growing_files
+    pub growing: Vec<PathBuf>,
+    /// Directories that lost a large part of their files, see [`DirectoryCounts::shrunk`]
+    pub shrunk_directories: Vec<ShrunkDirectory>,
+    /// The plan written, only with plan
+    ///
+    /// With plan_only, nothing else was done and everything else is empty.
+    pub plan: Option<Plan>,
+}
+
+impl UpdateReport {
+    /// Number of files whose previous contents aren't present anymore
+    pub fn newly_missing(&self) -> u64 {
+        (self.modified.len() + self.missing.len()) as u64
+    }
+}
+
+/// Outcome of [`Archive::verify`]
+///
+/// Which lists are filled depends on the [`VerifyOptions`], files only
+/// end up in them if they make the verification fail.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// ID of the run, shown in the first message
+    pub run_id: String,
+    pub stats: Stats,
+    pub duration: Duration,
+    /// Files with contents different from the archive at their path
+    pub modified: Vec<PathBuf>,
+    /// What the modified files were in the archive and are now, in the same order
+    pub changes: Vec<FileCheckResultModified>,
+    /// Modified files whose change was accepted in a review, they don't make the verification fail
+    pub accepted: Vec<PathBuf>,
+    /// The .changes file listing the modified files as found, see [`Archive::accept_changes`]
+    pub changes_path: Option<PathBuf>,
+    /// Files in the archive not found at their path
+    pub missing: Vec<PathBuf>,
+    /// When the missing files were last seen, only without ignore_missing
+    pub last_seen: BTreeMap<PathBuf, SystemTime>,
+    /// Files found which are not in the archive
+    pub not_in_archive: Vec<PathBuf>,
+    /// Number of files in the archive not found anywhere, only with only_presence
+    pub archive_files_not_found: u64,
+    /// Members of modified containers that changed, only with container_members
+    pub modified_members: Vec<PathBuf>,
+    /// Missing files an earlier update kept in the state, with the time they went missing
+    ///
+    /// They were recorded as missing then, see
+    /// [`UpdateOptions::forget_missing_after`], and don't make the
+    /// verification fail.
+    pub still_missing: BTreeMap<PathBuf, SystemTime>,
+    /// Missing files stored on other volumes, by volume
+    pub missing_on_volumes: BTreeMap<String, Vec<PathBuf>>,
+    /// Number of files not checked because they're on other volumes, by volume
+    pub other_volumes: BTreeMap<String, u64>,
+    /// Files with different attributes than in the archive, only on Windows and Linux
+    ///
+    /// Changed attributes don't make the verification fail, the contents
+    /// are still the same.
+    pub attribute_changes: Vec<AttributeChange>,
+    /// Number of unmodified files reported as modified, only with simulate_corruption
+    pub simulated_corruptions: usize,
+    /// Files that couldn't be read, they make the verification fail
+    pub read_errors: Vec<ReadError>,
+    /// The .errors file listing them, only if there are any
+    pub errors_path: Option<PathBuf>,
+    /// Files skipped because they're locked, they don't make the verification fail
+    pub locked: Vec<PathBuf>,
+    /// Files that grew while they were read, checked or skipped depending on growing_files
+    pub growing: Vec<PathBuf>,
+    /// Files passing and failing each of the cheap tiers, in the order they're run
+    pub tier_stats: Vec<TierStats>,
+    /// Directories that lost a large part of their files, see [`DirectoryCounts::shrunk`]
+    pub shrunk_directories: Vec<ShrunkDirectory>,
+    /// What listing the files found before reading them, only with metadata_first
+    pub metadata_pass: Option<MetadataPass>,
+    /// The plan written, only with plan
+    ///
+    /// With plan_only, nothing else was done and everything else is empty.
+    pub plan: Option<Plan>,
+}
+
+impl VerifyReport {
+    /// Whether the files match the archive
+    pub fn is_ok(&self) -> bool {
+        self.modified.is_empty()
+            && self.missing.is_empty()
+            && self.not_in_archive.is_empty()
+            && self.archive_files_not_found == 0
+            && self.read_errors.is_empty()
+    }
+
+    /// Number of files that make the verification fail
+    pub fn failures(&self) -> u64 {
+        (self.modified.len()
+            + self.missing.len()
+            + self.not_in_archive.len()
+            + self.read_errors.len()) as u64
+            + self.archive_files_not_found
+    }
+
+    /// Whether the failures are few enough to be accepted
+    ///
+    /// Every limit given has to be met. Without any, no failures are
+    /// accepted. The percentage is of all files checked.
+    pub fn failures_below(&self, max_failures: Option<u64>, max_percent: Option<f64>) -> bool {
+        if max_failures.is_none() && max_percent.is_none() {
+            return self.is_ok();
+        }
+        let failures = self.failures();
+        let percent = match self.stats.files_checked {
+            0 => 0.0,
+            checked => failures as f64 * 100.0 / checked as f64,
+        };
+        max_failures.is_none_or(|max| failures <= max)
+            && max_percent.is_none_or(|max| percent <= max)
+    }
+}
+
+/// Digests of a state from the .digests file next to it, if it's there and matches
+///
+/// States written by older versions and manifests copied without it have none.
+fn indexed_digests(state_path: &Path, header: &StateHeader) -> Result<Option<DigestCounts>> {
+    let path = digest_counts_path(state_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let (counts_header, counts) = DigestCounts::read(&path)?;
+    Ok((counts_header.hash_algorithm == header.hash_algorithm).then_some(counts))
+}
+
+/// Key to look up a path regardless of case
+fn lowercase(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Fails with a readable error if a state given by the caller doesn't exist
+fn existing_state(state_path: PathBuf) -> Result<PathBuf> {
+    if !state_path.is_file() {
+        return Err(anyhow::Error::msg(format!(
+            "state {:?} not found",
+            state_path
+        )));
+    }
+    Ok(state_path)
+}
+
+/// A directory tree and the state directory keeping track of it
+pub struct Archive {
+    state_dir: PathBuf,
+    directory: PathBuf,
+    options: ArchiveOptions,
+    observer: Arc<dyn Observer>,
+}
+
+/// Which files of the directory a run checks
+#[derive(Clone, Copy)]
+enum Selection<'a> {
+    /// All files below these paths, all files if it's empty
+    Below(&'a [PathBuf]),
+    /// Just these files, nothing is listed
+    Only(&'a [PathBuf]),
+}
+
+/// How far checking the files got
+enum Checked {
+    Files(Box<CheckedFiles>),
+    /// Stopped once the plan was written, with plan_only
+    PlanOnly(Plan),
+}
+
+/// Everything known after all files were checked
+struct CheckedFiles {
+    header: StateHeader,
+    /// All results except unmodified files, sorted by path
+    checked_files: Vec<FileCheckResult>,
+    unmodified: UnmodifiedFiles,
+    /// Number of files on other volumes, by volume
+    other_volumes: BTreeMap<String, u64>,
+    /// Number of files outside of the paths listed
+    outside_paths: u64,
+    /// Files with different attributes than in the latest state
+    attribute_changes: Vec<AttributeChange>,
+    /// Files that couldn't be read, sorted by path
+    read_errors: Vec<ReadError>,
+    /// Files skipped because they're locked, sorted by path, only with skip_locked
+    locked: Vec<PathBuf>,
+    /// Files that grew while they were read, sorted by path
+    growing: Vec<PathBuf>,
+    /// Files passing and failing each of the cheap tiers, only with tiers
+    tier_stats: Vec<TierStats>,
+    /// Where the files are stored
+    roots: Arc<Roots>,
+    /// Number of unmodified files reported as modified
+    simulated_corruptions: usize,
+    /// Number of files in the latest state
+    previous_files: usize,
+    /// Number of files per directory in the latest state
+    directory_counts: DirectoryCounts,
+    /// Discrepancies found before reading, only with metadata_first
+    metadata_pass: Option<MetadataPass>,
+    /// The plan written, only with plan
+    plan: Option<Plan>,
+    stats: StatsCollector,
+    start: Instant,
+}
+
+impl Archive {
+    /// Keeps track of directory in state_dir
+    ///
+    /// On Windows the directory is made absolute and prefixed with `\\?\`,
+    /// so files nested deeper than 260 characters can be read.
+    pub fn new(
+        state_dir: impl Into<PathBuf>,
+        directory: impl Into<PathBuf>,
+        options: ArchiveOptions,
+    ) -> Self {
+        Archive {
+            state_dir: state_dir.into(),
+            directory: long_path(&directory.into()),
+            options,
+            observer: Arc::new(NoopObserver),
+        }
+    }
+
+    /// Reports progress to observer instead of discarding it
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Profile of the state directory, the defaults if it has none
+    fn config(&self) -> Result<Config> {
+        Ok(Config::read(&self.state_dir)?.unwrap_or_default())
+    }
+
+    /// The directory along with the roots of the options and the profile
+    fn roots(&self, config: &Config) -> Roots {
+        let from_config = config.roots.iter().filter(|root| {
+            !self
+                .options
+                .roots
+                .iter()
+                .any(|given| given.prefix == root.prefix)
+        });
+        let roots = self
+            .options
+            .roots
+            .iter()
+            .chain(from_config)
+            .map(|root| Root {
+                prefix: root.prefix.clone(),
+                directory: long_path(&root.directory),
+            })
+            .collect::<Vec<_>>();
+        Roots::new(&self.directory, &roots)
+    }
+
+    /// Fails if the profile belongs to a different directory
+    fn check_directory(&self, config: &Config) -> Result<()> {
+        if let Some(expected) = &config.directory {
+            let directory = self
+                .directory
+                .canonicalize()
+                .with_context(|| format!("Unable to find {:?}", self.directory))?;
+            if directory != *expected {
+                return Err(anyhow::Error::msg(format!(
+                    "{:?} belongs to the archive in {:?}, refusing to update it with {:?}",
+                    self.state_dir, expected, directory
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes files deleted on purpose from the state and logs them
+    ///
+    /// Files listed in the latest state but missing from the directory
+    /// are recorded in the deleted log and a new state without them is
+    /// written. Only files below one of paths are accepted, unless paths
+    /// is empty. Returns the files accepted as deleted.
+    ///
+    /// Files stored on a volume are only accepted if it's the one the
+    /// directory is on: volume, or else the only one the filesystem of the
+    /// directory was recorded for. Those of other volumes can't be found
+    /// there, they're kept.
+    pub fn accept_missing(
+        &self,
+        paths: &[PathBuf],
+        volume: Option<&str>,
+    ) -> Result<Vec<Tombstone>> {
+        let config = self.config()?;
+        self.check_directory(&config)?;
+        let roots = self.roots(&config);
+        if latest_state_path(&self.state_dir)?.is_none() {
+            return Err(anyhow::Error::msg(format!(
+                "no state found in {:?}",
+                self.state_dir
+            )));
+        }
+        let (mut header, files) = read_state(&self.state_dir)?;
+        header.run_id = Some(new_run_id());
+        header.label = None;
+        let volume = volume.map(str::to_owned).or_else(|| {
+            let uuid = filesystem_uuid(&self.directory)?;
+            let mut on_filesystem = header
+                .filesystems
+                .iter()
+                .filter(|(name, recorded)| !name.is_empty() && **recorded == uuid);
+            match (on_filesystem.next(), on_filesystem.next()) {
+                (Some((name, _)), None) => Some(name.clone()),
+                _ => None,
+            }
+        });
+        let mut kept = Vec::with_capacity(files.len());
+        let mut deleted = Vec::new();
+        for (rel_path, fi) in files {
+            if fi.volume.is_some() && fi.volume != volume {
+                kept.push(fi);
+                continue;
+            }
+            let selected = paths.is_empty() || paths.iter().any(|p| rel_path.starts_with(p));
+            let missing = match roots.full_path(&rel_path).symlink_metadata() {
+                Ok(_) => false,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => true,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Unable to check {:?}", rel_path))
+                }
+            };
+            if selected && missing {
+                deleted.push(fi);
+            } else {
+                kept.push(fi);
+            }
+        }
+        if deleted.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        kept.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        let state_path = write_state(
+            &self.state_dir,
+            &header,
+            kept.into_iter()
+                .map(|fi| Ok(FileCheckResult::Unmodifed(fi))),
+        )?;
+        let deleted_in = state_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        deleted.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        let tombstones = deleted
+            .into_iter()
+            .map(|fi| Tombstone {
+                rel_path: fi.rel_path,
+                digest: fi.digest,
+                len: fi.len,
+                hash_algorithm: header.hash_algorithm,
+                deleted_in: deleted_in.clone(),
+            })
+            .collect::<Vec<_>>();
+        deleted::append(&self.state_dir, &tombstones)?;
+        Ok(tombstones)
+    }
+
+    /// Records the contents a verify run found for modified files as the ones of the archive
+    ///
+    /// Writes a new state with the files as they were found by the run, or
+    /// the latest run finding modified files if run is None. Only files
+    /// matching one of patterns are accepted, unless patterns is empty.
+    /// Nothing is read from the directory. Refuses if a state was written
+    /// since the run, the changes may be outdated. Returns the ID of the
+    /// run and the files accepted.
+    pub fn accept_changes(
+        &self,
+        run: Option<&str>,
+        patterns: &[Regex],
+    ) -> Result<(String, Vec<PathBuf>)> {
+        let changes = read_changes(&self.state_dir, run)?;
+        let latest = latest_state_path(&self.state_dir)?;
+        let latest_name = latest
+            .as_deref()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned());
+        if latest_name.as_deref() != Some(changes.verified.as_str()) {
+            return Err(anyhow::Error::msg(format!(
+                "run {} verified against {}, but the latest state is {}, verify again (with --against latest if a golden state is pinned)",
+                changes.run_id,
+                changes.verified,
+                latest_name.as_deref().unwrap_or("none")
+            )));
+        }
+        let (mut header, mut files) = read_state(&self.state_dir)?;
+        if header.hash_algorithm != changes.hash_algorithm {
+            return Err(anyhow::Error::msg(format!(
+                "{:?} uses {}, but the state uses {}",
+                changes.path, changes.hash_algorithm, header.hash_algorithm
+            )));
+        }
+        let mut modified = Vec::new();
+        for current in changes.files {
+            let selected = patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|pattern| pattern.is_match(&current.rel_path.to_string_lossy()));
+            if !selected {
+                continue;
+            }
+            // Gone from the state since, like with accept-missing
+            if let Some(previous) = files.remove(&current.rel_path) {
+                modified.push(FileCheckResultModified { previous, current });
+            }
+        }
+        if modified.is_empty() {
+            return Ok((changes.run_id, Vec::new()));
+        }
+
+        header.run_id = Some(new_run_id());
+        header.label = None;
+        let accepted = modified
+            .iter()
+            .map(|fi_mod| fi_mod.current.rel_path.clone())
+            .collect();
+        let mut results = files
+            .into_values()
+            .map(FileCheckResult::Unmodifed)
+            .chain(
+                modified
+                    .into_iter()
+                    .map(|modified| FileCheckResult::Modified(Box::new(modified))),
+            )
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| a.rel_path().cmp(b.rel_path()));
+        write_state(&self.state_dir, &header, results.into_iter().map(Ok))?;
+        Ok((changes.run_id, accepted))
+    }
+
+    /// Creates the ID of a new run and tells the observer about it
+    fn start_run(&self) -> String {
+        let run_id = new_run_id();
+        self.observer.on_message(&format!("run {}", run_id));
+        run_id
+    }
+
+    /// Checks all files and writes a new state
+    ///
+    /// Refuses to update a directory other than the one in the profile of
+    /// the state directory.
+    pub fn update(&self, options: &UpdateOptions) -> Result<UpdateReport> {
+        let config = self.config()?;
+        self.check_directory(&config)?;
+        let run_id = self.start_run();
+        if let Some(state_file) = &options.state_file {
+            check_new_state_path(&self.state_dir.join(state_file))?;
+        }
+        if let (Some(_), Some(_)) = (&options.state_file, &options.state_name_format) {
+            return Err(anyhow::Error::msg(
+                "a state can't be given both a name and a name format",
+            ));
+        }
+        if let Some(label) = &options.label {
+            check_new_label(&self.state_dir, label)?;
+        }
+        if let Some(host) = &options.partial {
+            check_host(host)?;
+            if options.files.is_some()
+                || options.state_file.is_some()
+                || options.state_name_format.is_some()
+                || options.label.is_some()
+                || options.canonical
+            {
+                return Err(anyhow::Error::msg(
+                    "a partial state has all files below the paths, it can't be given files to check, a name or a label or be canonical",
+                ));
+            }
+        }
+        let previous_state = match &options.previous_state {
+            Some(previous_state) => Some(resolve_state(
+                &self.state_dir,
+                &previous_state.to_string_lossy(),
+            )?),
+            None => latest_state_path(&self.state_dir)?,
+        };
+        // Whole seconds, as that's all that's kept of spilled files
+        let run_start = SystemTime::UNIX_EPOCH
+            + Duration::from_secs(
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs(),
+            );
+        let CheckedFiles {
+            mut header,
+            checked_files,
+            unmodified,
+            other_volumes,
+            outside_paths,
+            attribute_changes,
+            read_errors,
+            locked,
+            growing,
+            roots,
+            previous_files,
+            directory_counts,
+            plan,
+            stats,
+            start,
+            ..
+        } = match self.check_files(
+            options.read_all_files,
+            options.hash_algorithm.or(config.hash_algorithm),
+            previous_state.clone(),
+            options.volume.as_deref(),
+            match (&options.files, options.paths.is_empty()) {
+                (Some(_), false) => {
+                    return Err(anyhow::Error::msg(
+                        "files to check can't be given along with paths",
+                    ))
+                }
+                (Some(files), true) => Selection::Only(files),
+                (None, _) => Selection::Below(&options.paths),
+            },
+            None,
+        )? {
+            Checked::Files(checked) => *checked,
+            Checked::PlanOnly(plan) => {
+                return Ok(UpdateReport {
+                    run_id,
+                    plan: Some(plan),
+                    ..Default::default()
+                })
+            }
+        };
+        header.canonical = options.canonical;
+        header.run_id = Some(run_id.clone());
+        // The header is the one of the previous state
+        header.label = options.label.clone();
+        if options.partial.is_some() {
+            header.host = options.partial.clone();
+            header.covers = options.paths.clone();
+        }
+        if let Some(uuid) = filesystem_uuid(&self.directory) {
+            let volume = options.volume.clone().unwrap_or_default();
+            if let Some(previous) = header.filesystems.insert(volume, uuid.clone()) {
+                if previous != uuid {
+                    self.observer.on_message(&format!(
+                        "{:?} is on filesystem {}, it was on {} during the last update",
+                        self.directory, uuid, previous
+                    ));
+                }
+            }
+        }
+
+        let present_digests = checked_files
+            .iter()
+            .filter_map(|f| match &f {
+                FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => Some(fi.digest),
+                FileCheckResult::Modified(fi_mod) => Some(fi_mod.current.digest),
+                _ => None,
+            })
+            .chain(unmodified.digests().copied())
+            .collect::<HashSet<_>>();
+
+        // Locations of the digests of missing files and previous contents,
+        // only needed if they have to be found in the same scope
+        let present_in_scope = match options.dedupe_scope {
+            DedupeScope::Anywhere => HashSet::new(),
+            scope => {
+                let wanted = checked_files
+                    .iter()
+                    .filter_map(|f| match f {
+                        FileCheckResult::Missing(fi) => Some(fi.digest),
+                        FileCheckResult::Modified(fi_mod) => Some(fi_mod.previous.digest),
+                        _ => None,
+                    })
+                    .collect::<HashSet<_>>();
+                checked_files
+                    .iter()
+                    .filter_map(|f| match f {
+                        FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => {
+                            Some((fi.rel_path.clone(), fi.digest))
+                        }
+                        FileCheckResult::Modified(fi_mod) => {
+                            Some((fi_mod.current.rel_path.clone(), fi_mod.current.digest))
+                        }
+                        FileCheckResult::Missing(_) => None,
+                    })
+                    .filter(|(_, digest)| wanted.contains(digest))
+                    .chain(unmodified.with_digests(&wanted)?)
+                    .map(|(rel_path, digest)| (scope.scope(&rel_path).to_path_buf(), digest))
+                    .collect::<HashSet<_>>()
+            }
+        };
+        let found_elsewhere = |fi: &FileInfo| {
+            // Empty files all share one digest, they're only ever matched by path
+            !options.keep_duplicates
+                && fi.len > 0
+                && match options.dedupe_scope {
+                    DedupeScope::Anywhere => present_digests.contains(&fi.digest),
+                    scope => present_in_scope
+                        .contains(&(scope.scope(&fi.rel_path).to_path_buf(), fi.digest)),
+                }
+        };
+
+        let verdicts = Verdicts::read(&self.state_dir)?;
+        let mut flagged_corrupted = Vec::new();
+        let mut duplicates_removed: u64 = 0;
+        let mut kept_not_found = 0;
+        let checked_files_deduped = checked_files
+            .into_iter()
+            .filter_map(|f| match f {
+                // Recording the damaged contents would lose what a restore is checked against
+                FileCheckResult::Modified(mod_fi)
+                    if verdicts.decision(&mod_fi) == Some(Decision::Corrupted) =>
+                {
+                    flagged_corrupted.push(mod_fi.current.rel_path);
+                    Some(FileCheckResult::Unmodifed(mod_fi.previous))
+                }
+                // The directory is only a staging area, files not found stay in the state
+                FileCheckResult::Missing(fi) if options.append => {
+                    kept_not_found += 1;
+                    Some(FileCheckResult::Unmodifed(fi))
+                }
+                FileCheckResult::Missing(fi) => {
+                    if found_elsewhere(&fi) {
+                        duplicates_removed += 1;
+                        None
+                    } else {
+                        Some(FileCheckResult::Missing(fi))
+                    }
+                }
+                FileCheckResult::Modified(mod_fi) => {
+                    if found_elsewhere(&mod_fi.previous) {
+                        duplicates_removed += 1;
+                        // Previous version was a duplicate, consider this to be new
+                        Some(FileCheckResult::New(mod_fi.current))
+                    } else {
+                        Some(FileCheckResult::Modified(mod_fi))
+                    }
+                }
+                other => Some(other),
+            })
+            .collect::<Vec<_>>();
+        stats.duplicates_removed(duplicates_removed);
+        let (checked_files_deduped, still_missing, forgotten) = keep_missing(
+            checked_files_deduped,
+            options.forget_missing_after,
+            SystemTime::now(),
+        );
+
+        let mut new = Vec::new();
+        let mut modified = Vec::new();
+        let mut missing = Vec::new();
+        let mut truncated = Vec::new();
+        let mut accepted = Vec::new();
+        for file in &checked_files_deduped {
+            match file {
+                FileCheckResult::New(_) => new.push(file.rel_path().to_path_buf()),
+                FileCheckResult::Modified(mod_fi) => {
+                    if verdicts.decision(mod_fi) == Some(Decision::Accepted) {
+                        accepted.push(file.rel_path().to_path_buf());
+                    } else if mod_fi.current.len == 0 {
+                        truncated.push(file.rel_path().to_path_buf());
+                    }
+                    modified.push(file.rel_path().to_path_buf())
+                }
+                FileCheckResult::Missing(_) => missing.push(file.rel_path().to_path_buf()),
+                _ => {}
+            }
+        }
+
+        self.observer
+            .on_summary(&stats.get_results(), start.elapsed());
+        if !options.force && missing.len() as f64 > previous_files as f64 * MAX_MISSING_FRACTION {
+            let question = format!(
+                "{} of {} files in the archive are missing, is {:?} the right directory?",
+                missing.len(),
+                previous_files,
+                self.directory
+            );
+            if !self.observer.confirm(&question) {
+                return Err(anyhow::Error::msg(format!(
+                    "{} of {} files in the archive would be marked as missing, refusing to write a new state without --force",
+                    missing.len(),
+                    previous_files
+                )));
+            }
+        }
+        let write_start = Instant::now();
+        // Reuses the allocation, the set is as large as needed already
+        let mut unique_digests = present_digests;
+        unique_digests.clear();
+        let (mut logical_size, mut unique_size) = (0, 0);
+        // Containers found and whether they are unchanged
+        let mut containers = Vec::new();
+        let (mut made_immutable, mut not_made_immutable) = (0, 0);
+        let state_path = match (&options.state_file, &options.partial) {
+            (_, Some(host)) => partial_state_path(&self.state_dir, host)?,
+            (Some(state_file), None) => self.state_dir.join(state_file),
+            (None, None) => named_run_path(
+                &self.state_dir,
+                "state",
+                options.state_name_format.as_ref(),
+                options.label.as_deref(),
+            )?,
+        };
+        write_state_at(
+            &state_path,
+            &header,
+            merge_by_path(checked_files_deduped.into_iter(), unmodified.into_sorted()?)
+                // Other hosts may cover the files kept outside of the paths
+                .filter(|f| match (&options.partial, f) {
+                    (Some(_), Ok(f)) => {
+                        options.paths.is_empty()
+                            || options.paths.iter().any(|p| f.rel_path().starts_with(p))
+                    }
+                    _ => true,
+                })
+                .map(|mut f| {
+                    let fi = match &mut f {
+                        Ok(FileCheckResult::New(fi)) | Ok(FileCheckResult::Unmodifed(fi)) => fi,
+                        Ok(FileCheckResult::Modified(fi_mod)) => &mut fi_mod.current,
+                        _ => return f,
+                    };
+                    if fi.last_seen >= run_start {
+                        fi.host = options.partial.clone();
+                    }
+                    if options.set_immutable {
+                        match self.make_immutable(fi, run_start, &roots) {
+                            Some(Ok(())) => made_immutable += 1,
+                            Some(Err(err)) => {
+                                // Usually all of them fail for the same reason
+                                if not_made_immutable == 0 {
+                                    self.observer.on_message(&format!(
+                                        "unable to make {:?} immutable: {}",
+                                        fi.rel_path, err
+                                    ));
+                                }
+                                not_made_immutable += 1;
+                            }
+                            None => {}
+                        }
+                    }
+                    f
+                })
+                .inspect(|f| {
+                    let (fi, unchanged) = match f {
+                        Ok(FileCheckResult::New(fi)) => (fi, false),
+                        Ok(FileCheckResult::Unmodifed(fi)) => (fi, true),
+                        Ok(FileCheckResult::Modified(fi_mod)) => (&fi_mod.current, false),
+                        _ => return,
+                    };
+                    if fi.missing_since.is_some() {
+                        return;
+                    }
+                    if self.options.container_members && is_container(&fi.rel_path) {
+                        containers.push((fi.rel_path.clone(), unchanged));
+                    }
+                    logical_size += fi.len;
+                    if unique_digests.insert(fi.digest) {
+                        unique_size += fi.len;
+                    }
+                }),
+        )?;
+        stats.content_sizes(logical_size, unique_size);
+        stats.update_timings(|t| t.write_state = write_start.elapsed());
+        if self.options.container_members {
+            let previous = match &previous_state {
+                Some(previous_state) => read_members(previous_state)?,
+                None => Members::new(),
+            };
+            write_members(
+                &state_path,
+                &self.container_members(containers, previous, &header, &roots),
+            )?;
+        }
+        let errors_path = match read_errors.is_empty() {
+            true => None,
+            false => {
+                let path = state_path.with_extension("errors");
+                write_errors(&path, &run_id, &read_errors)?;
+                Some(path)
+            }
+        };
+        let duration = start.elapsed();
+        // Last, it marks partial states as complete, see partial::partial_states
+        write_stats(&state_path, &stats.get_results(), duration, Some(&run_id))?;
+        // Hosts writing partial states only ever create files of their own
+        let removed_states = match (config.keep_states, &options.partial) {
+            (Some(keep), None) => rotate_states(&self.state_dir, keep)?,
+            _ => Vec::new(),
+        };
+
+        let shrunk_directories = directory_counts.shrunk(&missing);
+        Ok(UpdateReport {
+            run_id,
+            state_path,
+            stats: stats.get_results(),
+            duration,
+            new,
+            modified,
+            missing,
+            truncated,
+            accepted,
+            flagged_corrupted,
+            other_volumes,
+            kept_not_found,
+            still_missing,
+            forgotten,
+            kept_outside_paths: outside_paths,
+            attribute_changes,
+            made_immutable,
+            not_made_immutable,
+            removed_states,
+            read_errors,
+            errors_path,
+            locked,
+            growing,
+            shrunk_directories,
+            plan,
+        })
+    }
+
+    /// Sets the immutable flag of a file seen since run_start and records it
+    ///
+    /// None if the file wasn't seen in this run, is immutable already or isn't
+    /// a regular file.
+    fn make_immutable(
+        &self,
+        fi: &mut FileInfo,
+        run_start: SystemTime,
+        roots: &Roots,
+    ) -> Option<Result<()>> {
+        let attributes = fi.attributes.unwrap_or_default();
+        if fi.last_seen < run_start || fi.missing_since.is_some() || attributes.immutable {
+            return None;
+        }
+        let path = roots.full_path(&fi.rel_path);
+        if !path.symlink_metadata().is_ok_and(|m| m.is_file()) {
+            return None;
+        }
+        Some(set_immutable(&path).map_err(anyhow::Error::new).map(|()| {
+            fi.attributes = Some(Attributes {
+                immutable: true,
+                ..attributes
+            });
+        }))
+    }
+
+    /// Checks all files against the latest state without changing it
+    pub fn verify(&self, options: &VerifyOptions) -> Result<VerifyReport> {
+        let run_id = self.start_run();
+        self.check_filesystem(options)?;
+        let state_path = self.verified_state_path(Some(options))?;
+        if options.manifest.is_none() && options.state_file.is_none() {
+            let latest = latest_state_path(&self.state_dir)?;
+            if let (Some(state_path), true) = (&state_path, state_path != latest) {
+                self.observer.on_message(&format!(
+                    "verifying against the golden state {:?}, newer states are left out unless --against latest is given",
+                    state_path
+                ));
+            }
+        }
+        let CheckedFiles {
+            header,
+            checked_files,
+            unmodified,
+            other_volumes,
+            attribute_changes,
+            read_errors,
+            locked,
+            growing,
+            tier_stats,
+            roots,
+            simulated_corruptions,
+            directory_counts,
+            metadata_pass,
+            plan,
+            stats,
+            start,
+            ..
+        } = match self.check_files(
+            true,
+            None,
+            state_path.clone(),
+            options.volume.as_deref(),
+            options
+                .files
+                .as_deref()
+                .map_or(Selection::Below(&[]), Selection::Only),
+            Some(options),
+        )? {
+            Checked::Files(checked) => *checked,
+            Checked::PlanOnly(plan) => {
+                return Ok(VerifyReport {
+                    run_id,
+                    plan: Some(plan),
+                    ..Default::default()
+                })
+            }
+        };
+
+        let indexed = match (&state_path, options.only_presence) {
+            (Some(state_path), true) => indexed_digests(state_path, &header)?,
+            _ => None,
+        };
+        let archive_digests = match indexed {
+            Some(counts) => counts,
+            None => DigestCounts::of(
+                checked_files
+                    .iter()
+                    .filter_map(|f| match &f {
+                        FileCheckResult::Unmodifed(fi) | FileCheckResult::Missing(fi) => {
+                            Some(fi.digest)
+                        }
+                        FileCheckResult::Modified(fi_mod) => Some(fi_mod.previous.digest),
+                        FileCheckResult::New(_) => None,
+                    })
+                    .chain(unmodified.digests().copied()),
+            ),
+        };
+
+        self.observer
+            .on_summary(&stats.get_results(), start.elapsed());
+        let mut report = VerifyReport {
+            run_id,
+            stats: Stats::default(),
+            duration: Duration::default(),
+            modified: Vec::new(),
+            changes: Vec::new(),
+            accepted: Vec::new(),
+            changes_path: None,
+            missing: Vec::new(),
+            last_seen: BTreeMap::new(),
+            not_in_archive: Vec::new(),
+            archive_files_not_found: 0,
+            modified_members: Vec::new(),
+            still_missing: BTreeMap::new(),
+            missing_on_volumes: BTreeMap::new(),
+            other_volumes,
+            attribute_changes,
+            simulated_corruptions,
+            errors_path: None,
+            read_errors,
+            locked,
+            growing,
+            tier_stats,
+            shrunk_directories: Vec::new(),
+            metadata_pass,
+            plan,
+        };
+        if !report.read_errors.is_empty() && options.manifest.is_none() {
+            let path = run_path(&self.state_dir, "errors")?;
+            write_errors(&path, &report.run_id, &report.read_errors)?;
+            report.errors_path = Some(path);
+        }
+        let paths = |filter: &dyn Fn(&FileCheckResult) -> bool| {
+            checked_files
+                .iter()
+                .filter(|f| filter(f))
+                .map(|f| f.rel_path().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+        let is_new = |f: &FileCheckResult| matches!(f, FileCheckResult::New(_));
+        let is_modified = |f: &FileCheckResult| matches!(f, FileCheckResult::Modified(_));
+        let is_not_in_archive = |f: &FileCheckResult| match f {
+            FileCheckResult::New(fi) => !archive_digests.contains(&fi.digest),
+            FileCheckResult::Modified(fi_mod) => !archive_digests.contains(&fi_mod.current.digest),
+            FileCheckResult::Unmodifed(_) | FileCheckResult::Missing(_) => false,
+        };
+
+        match (options.ignore_missing, options.only_presence) {
+            (true, true) => {
+                // ensure all files found are present in the archive
+                report.not_in_archive = paths(&is_not_in_archive);
+            }
+            (true, false) => {
+                // ensure the files found match the ones in the archive at that path
+                report.modified = paths(&is_modified);
+                report.not_in_archive = paths(&is_new);
+            }
+            (false, true) => {
+                // ensure all files in the archive are found somewhere
+                report.not_in_archive = paths(&is_not_in_archive);
+                let mut missing_digests =
+                    archive_digests.digests().copied().collect::<HashSet<_>>();
+                for digest in unmodified.digests() {
+                    missing_digests.remove(digest);
+                }
+                for file in &checked_files {
+                    match file {
+                        FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => {
+                            missing_digests.remove(&fi.digest);
+                        }
+                        FileCheckResult::Modified(fi_mod) => {
+                            missing_digests.remove(&fi_mod.current.digest);
+                        }
+                        _ => {}
+                    }
+                }
+                report.archive_files_not_found = missing_digests.len() as u64;
+            }
+            (false, false) => {
+                // ensure all files in the archive are found at their path
+                report.missing = paths(
+                    &|f| matches!(f, FileCheckResult::Missing(fi) if fi.missing_since.is_none()),
+                );
+                for file in &checked_files {
+                    match file {
+                        FileCheckResult::Missing(FileInfo {
+                            rel_path,
+                            missing_since: Some(since),
+                            ..
+                        }) => {
+                            report.still_missing.insert(rel_path.clone(), *since);
+                            continue;
+                        }
+                        FileCheckResult::Missing(fi) => {
+                            report.last_seen.insert(fi.rel_path.clone(), fi.last_seen);
+                        }
+                        _ => {}
+                    }
+                    if let FileCheckResult::Missing(FileInfo {
+                        rel_path,
+                        volume: Some(volume),
+                        ..
+                    }) = file
+                    {
+                        if options.volume.as_ref() != Some(volume) {
+                            report
+                                .missing_on_volumes
+                                .entry(volume.clone())
+                                .or_default()
+                                .push(rel_path.clone());
+                        }
+                    }
+                }
+                report.modified = paths(&is_modified);
+                report.not_in_archive = paths(&is_new);
+            }
+        }
+
+        // Only filled if modified files make the verification fail
+        if !report.modified.is_empty() {
+            let verdicts = match options.manifest {
+                Some(_) => Verdicts::default(),
+                None => Verdicts::read(&self.state_dir)?,
+            };
+            let (accepted, changes): (Vec<_>, Vec<_>) = checked_files
+                .iter()
+                .filter_map(|f| match f {
+                    FileCheckResult::Modified(fi_mod) => Some(fi_mod.as_ref().clone()),
+                    _ => None,
+                })
+                .partition(|fi_mod| verdicts.decision(fi_mod) == Some(Decision::Accepted));
+            report.accepted = accepted.into_iter().map(|fi| fi.current.rel_path).collect();
+            report.modified = changes
+                .iter()
+                .map(|fi| fi.current.rel_path.clone())
+                .collect();
+            report.changes = changes;
+        }
+        // Simulated modifications have made up digests, they mustn't be accepted
+        if let (Some(state_path), None, 0, false) = (
+            &state_path,
+            &options.manifest,
+            options.simulate_corruption,
+            report.changes.is_empty(),
+        ) {
+            report.changes_path = Some(write_changes(
+                &self.state_dir,
+                header.hash_algorithm,
+                &report.run_id,
+                state_path,
+                &report.changes,
+            )?);
+        }
+
+        if self.options.container_members {
+            report.modified_members =
+                self.modified_members(&report.modified, &header, options, &roots)?;
+        }
+
+        report.shrunk_directories = directory_counts.shrunk(&report.missing);
+        report.stats = stats.get_results();
+        report.duration = start.elapsed();
+        Ok(report)
+    }
+
+    /// State checked against, the one given in the verify options or the latest
+    fn verified_state_path(&self, options: Option<&VerifyOptions>) -> Result<Option<PathBuf>> {
+        if let Some(manifest) = options.and_then(|o| o.manifest.as_ref()) {
+            return existing_state(manifest.clone()).map(Some);
+        }
+        if let Some(state_file) = options.and_then(|o| o.state_file.as_ref()) {
+            return resolve_state(&self.state_dir, &state_file.to_string_lossy()).map(Some);
+        }
+        match options.map_or(Baseline::default(), |o| o.against) {
+            Baseline::Golden => match pinned_state(&self.state_dir)? {
+                Some(pinned) => Ok(Some(pinned)),
+                None => latest_state_path(&self.state_dir),
+            },
+            Baseline::Latest => latest_state_path(&self.state_dir),
+        }
+    }
+
+    /// Warns or fails if the directory isn't on the filesystem recorded in the state
+    ///
+    /// States written before filesystems were recorded aren't checked.
+    fn check_filesystem(&self, options: &VerifyOptions) -> Result<()> {
+        let Some(state_path) = self.verified_state_path(Some(options))? else {
+            return Ok(());
+        };
+        let header = read_header(&state_path)?;
+        let volume = options.volume.as_deref().unwrap_or_default();
+        let Some(expected) = header.filesystems.get(volume) else {
+            return Ok(());
+        };
+        let message = match filesystem_uuid(&self.directory) {
+            Some(uuid) if uuid == *expected => return Ok(()),
+            Some(uuid) => format!(
+                "{:?} is on filesystem {}, the state was written for filesystem {}",
+                self.directory, uuid, expected
+            ),
+            None => format!(
+                "unable to determine the filesystem of {:?}, the state was written for filesystem {}",
+                self.directory, expected
+            ),
+        };
+        if options.strict_volume {
+            return Err(anyhow::Error::msg(message));
+        }
+        self.observer.on_message(&message);
+        Ok(())
+    }
+
+    /// Hashes the members of containers, reusing those of the previous state if possible
+    ///
+    /// Containers that can't be read are reported to the observer and left out.
+    fn container_members(
+        &self,
+        containers: Vec<(PathBuf, bool)>,
+        mut previous: Members,
+        header: &StateHeader,
+        roots: &Roots,
+    ) -> Members {
+        let (reused, to_hash): (Vec<_>, Vec<_>) = containers
+            .into_iter()
+            .partition(|(path, unchanged)| *unchanged && previous.contains_key(path));
+        let mut members = reused
+            .into_iter()
+            .map(|(path, _)| {
+                let members = previous.remove(&path).unwrap();
+                (path, members)
+            })
+            .collect::<Members>();
+        let hashed = to_hash
+            .into_par_iter()
+            .filter_map(|(path, _)| {
+                match hash_members(&roots.full_path(&path), header.hash_algorithm) {
+                    Ok(members) => Some((path, members)),
+                    Err(err) => {
+                        self.observer.on_message(&format!("{:#}", err));
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        members.extend(hashed);
+        members
+    }
+
+    /// Members of modified containers that differ from the state verified against
+    fn modified_members(
+        &self,
+        modified: &[PathBuf],
+        header: &StateHeader,
+        options: &VerifyOptions,
+        roots: &Roots,
+    ) -> Result<Vec<PathBuf>> {
+        let previous = match self.verified_state_path(Some(options))? {
+            Some(state_path) => read_members(&state_path)?,
+            None => return Ok(Vec::new()),
+        };
+        let mut changed = Vec::new();
+        for path in modified {
+            let Some(previous) = previous.get(path) else {
+                continue;
+            };
+            match hash_members(&roots.full_path(path), header.hash_algorithm) {
+                Ok(current) => changed.extend(
+                    changed_members(previous, &current)
+                        .iter()
+                        .map(|name| member_path(path, name)),
+                ),
+                Err(err) => self.observer.on_message(&format!("{:#}", err)),
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Like [`Archive::verify`], but returns every file checked as soon as it's read
+    ///
+    /// Replaces the observer set with [`Archive::with_observer`].
+    pub fn verify_iter(self) -> VerifyIter {
+        let (sender, results) = sync_channel(MAX_QUEUED_FILES_PER_THREAD);
+        let observer = Arc::new(IterObserver {
+            sender,
+            stopped: AtomicBool::new(false),
+        });
+        let archive = self.with_observer(observer.clone());
+        std::thread::spawn(move || {
+            match archive.verified_state_path(None).and_then(|state_path| {
+                archive.check_files(
+                    true,
+                    None,
+                    state_path,
+                    None,
+                    Selection::Below(&[]),
+                    Some(&VerifyOptions::default()),
+                )
+            }) {
+                Ok(Checked::Files(checked)) => {
+                    for read_error in checked.read_errors {
+                        observer.send(Err(anyhow::Error::new(read_error)));
+                    }
+                    for file in checked.checked_files {
+                        if let FileCheckResult::Missing(_) = file {
+                            observer.send(Ok(file));
+                        }
+                    }
+                }
+                Ok(Checked::PlanOnly(_)) => {}
+                Err(err) if observer.should_stop() => drop(err),
+                Err(err) => observer.send(Err(err)),
+            }
+        });
+        VerifyIter { results }
+    }
+
+    /// Lists and reads files, comparing them to the state at state_path
+    ///
+    /// With a volume, the files found are recorded as stored on it and
+    /// files of other volumes are kept as unmodified instead of missing.
+    /// The same goes for files the selection leaves out. Verify
+    /// passes its options for the checks only it does, only update keeps
+    /// the unmodified files and not just their digests.
+    fn check_files(
+        &self,
+        read_all_files: bool,
+        hash_algorithm: Option<HashAlgorithm>,
+        state_path: Option<PathBuf>,
+        volume: Option<&str>,
+        selection: Selection,
+        verify_options: Option<&VerifyOptions>,
+    ) -> Result<Checked> {
+        let opts = &self.options;
+        let (paths, files) = match selection {
+            Selection::Below(paths) => (paths, None),
+            Selection::Only(files) => (&[][..], Some(files)),
+        };
+        if let Some(path) = paths.iter().chain(files.into_iter().flatten()).find(|p| {
+            p.as_os_str().is_empty()
+                || p.components().any(|c| {
+                    !matches!(
+                        c,
+                        std::path::Component::Normal(_) | std::path::Component::CurDir
+                    )
+                })
+        }) {
+            return Err(anyhow::Error::msg(format!(
+                "{:?} isn't a path relative to the directory",
+                path
+            )));
+        }
+        // Rebuilt from their components, as `/` isn't a separator in the
+        // long paths of Windows. Only a leading `.`, like in `./a` from find,
+        // is kept as a component, it's dropped as well.
+        let normalized = |path: &PathBuf| {
+            path.components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .collect::<PathBuf>()
+        };
+        // Nested paths would be listed twice, only the outermost ones are kept
+        let mut paths = paths.iter().map(normalized).collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup_by(|nested, outer| nested.starts_with(outer));
+        let files = files.map(|files| {
+            let mut files = files.iter().map(normalized).collect::<Vec<_>>();
+            files.sort();
+            files.dedup();
+            files
+        });
+        let mut excludes = opts.excludes.clone();
+        let manifest = verify_options.is_some_and(|o| o.manifest.is_some());
+        let mut config = match manifest {
+            true => Config::default(),
+            false => self.config()?,
+        };
+        if let Some(options) = verify_options {
+            for root in &mut config.roots {
+                root.directory = map_prefix(&options.map_prefix, &root.directory);
+            }
+        }
+        let roots = Arc::new(self.roots(&config));
+        let config_excludes = config.excludes;
+        excludes
+            .exclude_directory
+            .extend(config_excludes.exclude_directory);
+        excludes.exclude_file.extend(config_excludes.exclude_file);
+        excludes.exclude_path.extend(config_excludes.exclude_path);
+        excludes
+            .exclude_content_type
+            .extend(config_excludes.exclude_content_type);
+        excludes.skip_hidden |= config_excludes.skip_hidden;
+        if opts.nice {
+            // Listing files is I/O too, so the calling thread is lowered as well
+            priority::lower_current_thread().context("Unable to lower priority for --nice")?;
+            self.observer
+                .on_message("running with low I/O and CPU priority");
+        }
+        let base_path = self.directory.as_path();
+        let network_filesystem = device_pools::network_filesystem(base_path);
+        let mtime_tolerance = opts.mtime_tolerance.unwrap_or(match network_filesystem {
+            Some(_) => NETWORK_MTIME_TOLERANCE,
+            None => Duration::ZERO,
+        });
+        let read_retries = opts.read_retries.unwrap_or(match network_filesystem {
+            Some(_) => NETWORK_READ_RETRIES,
+            None => 0,
+        });
+        if let Some(filesystem) = network_filesystem {
+            self.observer.on_message(&format!(
+                "warning: {:?} is on a network filesystem ({}), mtimes may be off and reads fail \
+                 when the connection drops. Allowing mtimes to differ by {:?} and retrying failed \
+                 reads {} time(s), see --mtime-tolerance and --read-retries",
+                base_path, filesystem, mtime_tolerance, read_retries
+            ));
+        }
+        let threads = match opts.threads {
+            Some(threads) => threads,
+            // Links and servers are shared, so files are read one at a time
+            None if network_filesystem.is_some() => 1,
+            None => {
+                let rotational = device_pools::storage_is_rotational(base_path);
+                self.observer.on_message(&match rotational {
+                    Some(true) => format!("{:?} is on rotational storage", base_path),
+                    Some(false) => format!("{:?} is on non-rotational storage", base_path),
+                    None => format!("unable to determine the storage type of {:?}", base_path),
+                });
+                device_pools::default_threads(rotational)
+            }
+        };
+        let mut pools = DevicePools::new(threads, &opts.device_threads, opts.nice)?;
+        for dt in &opts.device_threads {
+            self.observer.on_message(&format!(
+                "using {} thread(s) for the device containing {:?}",
+                dt.threads, dt.path
+            ));
+        }
+        if opts.device_threads.is_empty() {
+            self.observer
+                .on_message(&format!("using {threads} thread(s)"));
+        } else {
+            self.observer
+                .on_message(&format!("using {threads} thread(s) for other devices"));
+        }
+        let per_device = pools.is_per_device();
+        let budget = opts.max_memory.map(|total| MemoryBudget { total });
+
+        let start_load_old_state = Instant::now();
+        match &state_path {
+            None => self
+                .observer
+                .on_message(&format!("no previous state found in {:?}", self.state_dir)),
+            Some(path) if manifest || verify_options.is_some_and(|o| o.state_file.is_some()) => {
+                self.observer
+                    .on_message(&format!("verifying against {:?}", path))
+            }
+            Some(_) => {}
+        }
+        if let (Some(budget), Some(path)) = (budget, &state_path) {
+            budget.check_state_files(count_entries(path)?)?;
+        }
+        let (mut header, mut old_states_by_filename) = read_state_at(state_path.as_deref())?;
+        let previous_files = old_states_by_filename.len();
+        let directory_counts =
+            DirectoryCounts::of(old_states_by_filename.keys().map(PathBuf::as_path));
+        // Lowercase paths of the state, only to look up paths not found as they are
+        let state_paths_by_lowercase =
+            match verify_options.is_some_and(|o| o.case_insensitive_paths) {
+                true => old_states_by_filename
+                    .keys()
+                    .map(|path| (lowercase(path), path.clone()))
+                    .collect(),
+                false => HashMap::new(),
+            };
+        let load_state = start_load_old_state.elapsed();
+        self.observer.on_message(&format!(
+            "loaded previous states of {} files in {:.1?} from {}",
+            old_states_by_filename.len(),
+            load_state,
+            self.state_dir.display()
+        ));
+
+        let start = Instant::now();
+        let stats = StatsCollector::new()
+            .with_extension_groups(&opts.extension_groups)
+            .with_slowest_files(opts.slowest_files);
+        stats.update_timings(|t| t.load_state = load_state);
+        let mut files_checked = 0;
+        if let Some(hash_algorithm) = hash_algorithm {
+            if old_states_by_filename.is_empty() {
+                header.hash_algorithm = hash_algorithm;
+            } else if header.hash_algorithm != hash_algorithm {
+                return Err(anyhow::Error::msg(format!(
+                    "the previous state uses {}, changing the hash algorithm of an existing state is not supported",
+                    header.hash_algorithm
+                )));
+            }
+        }
+        self.observer.on_message(&format!(
+            "hashing with {} ({})",
+            header.hash_algorithm,
+            header.hash_algorithm.implementation()
+        ));
+        let read_options = ReadOptions {
+            direct_io: opts.direct_io,
+            hash_algorithm: header.hash_algorithm,
+            buffer_size: budget.map_or(DEFAULT_BUF_SIZE, |b| b.buffer_size(pools.max_threads())),
+        };
+        let queued_files_per_thread = budget.map_or(MAX_QUEUED_FILES_PER_THREAD, |b| {
+            b.queued_files_per_thread(pools.max_threads(), MAX_QUEUED_FILES_PER_THREAD)
+        });
+
+        let mut checked_files = Vec::new();
+        let spill_dir = std::env::temp_dir();
+        // Verify only needs the digests of unmodified files
+        let mut unmodified = UnmodifiedFiles::new(
+            budget.map(|_| match manifest {
+                true => spill_dir.as_path(),
+                false => self.state_dir.as_path(),
+            }),
+            budget.map_or(usize::MAX, |b| b.max_files_in_memory()),
+            verify_options.is_none(),
+        );
+
+        // The number of files queued for reading is limited. Without this,
+        // listing files would outpace reading them and pending work piles up
+        // in memory. Each device only gets as many as its threads keep busy,
+        // the rest waits in its own queue, listing only waits once all queues
+        // together are full.
+        let (check_files_sender, check_files_recv) = mpsc::channel();
+        let mut queues = DeviceQueues::default();
+        let max_queued = pools.max_threads() * queued_files_per_thread;
+
+        // Time spent waiting for queued files to be read
+        let mut wait_for_reads = Duration::ZERO;
+        let simulate_corruption = verify_options.map_or(0, |o| o.simulate_corruption);
+        let corruptions_left = Arc::new(AtomicUsize::new(simulate_corruption));
+        let double_read = verify_options.is_some_and(|o| o.double_read);
+        // Files are only read once all of them are listed then
+        let metadata_first = verify_options.is_some_and(|o| o.metadata_first);
+        let read_errors = Arc::new(Mutex::new(Vec::new()));
+        let attribute_changes = Arc::new(Mutex::new(Vec::new()));
+        let skip_locked = self.options.skip_locked;
+        let growing_files = self.options.growing_files;
+        let growing = Arc::new(Mutex::new(Vec::new()));
+        // Files that grew while they were read, along with what's kept of them
+        let retry_later = Arc::new(Mutex::new(Vec::new()));
+        let tiers: Arc<[CheckTier]> = verify_options
+            .map_or(Vec::new(), |o| o.tiers.clone())
+            .into();
+        let tier_stats = Arc::new(Mutex::new(
+            tiers
+                .iter()
+                .map(|&tier| TierStats {
+                    tier,
+                    passed: 0,
+                    escalated: 0,
+                })
+                .collect::<Vec<_>>(),
+        ));
+        // Earlier runs give an idea how long reading takes before this one can tell
+        let earlier = match manifest {
+            true => Ok(Throughput::default()),
+            false => Throughput::read(&self.state_dir),
+        };
+        let progress = Arc::new(ProgressTracker::new(earlier.unwrap_or_else(|err| {
+            self.observer
+                .on_message(&format!("{:#}, estimating from scratch", err));
+            Throughput::default()
+        })));
+        // Queues the next file, `None` waits for all queued files to be read
+        let mut dispatch = |next: Option<(DeviceId, FileToCheck)>,
+                            checked_files: &mut Vec<FileCheckResult>,
+                            unmodified: &mut UnmodifiedFiles|
+         -> Result<()> {
+            let draining = next.is_none();
+            if let Some((device, file)) = next {
+                queues.push(device, file);
+            }
+            let wait_start = Instant::now();
+            loop {
+                while let Some((device, file)) =
+                    queues.start(|device| pools.threads(device) * queued_files_per_thread)
+                {
+                    let sender = check_files_sender.clone();
+                    let stats = stats.clone();
+                    let roots = roots.clone();
+                    let observer = self.observer.clone();
+                    let volume = volume.map(str::to_owned);
+                    let corruptions_left = corruptions_left.clone();
+                    let read_errors = read_errors.clone();
+                    let attribute_changes = attribute_changes.clone();
+                    let growing = growing.clone();
+                    let retry_later = retry_later.clone();
+                    let tiers = tiers.clone();
+                    let tier_stats = tier_stats.clone();
+                    let progress = progress.clone();
+                    let listed_len = file.metadata().len();
+                    // The pools may share one device id for all files
+                    let file_device = device_pools::device(file.metadata());
+                    pools.get(device)?.spawn_fifo(move || {
+                        let rel_path = file.rel_path().to_owned();
+                        observer.on_file_start(&rel_path);
+                        let started = Instant::now();
+                        progress.started(file_device, started);
+                        // Along with the number of bytes read for it
+                        let (bytes_read, result) = 'read: {
+                            // Kept as it was if it can't be read, it's unknown whether it changed
+                            let kept = match &file {
+                                FileToCheck::NeedsChecking { previous, .. } => {
+                                    Some(previous.clone())
+                                }
+                                FileToCheck::New { .. } => None,
+                            };
+                            // Reading consumes the file, a copy is needed to read it again
+                            let retry_file = (read_retries > 0).then(|| file.clone());
+                            let later_file =
+                                (growing_files == GrowingFiles::RetryLater).then(|| file.clone());
+                            if let (Some(previous), false) = (&kept, tiers.is_empty()) {
+                                let failed = file.first_failed_tier(&tiers, &roots, &read_options);
+                                // On an error it's read completely, which runs into the error
+                                // again and counts it as unreadable, not by any of the tiers
+                                if let Ok(failed) = failed {
+                                    for counts in tier_stats.lock().unwrap().iter_mut() {
+                                        if Some(counts.tier) == failed {
+                                            counts.escalated += 1;
+                                            break;
+                                        }
+                                        counts.passed += 1;
+                                    }
+                                }
+                                if let Ok(None) = failed {
+                                    let mut file_info = previous.clone();
+                                    file_info.last_seen = SystemTime::now();
+                                    // Not read, so looked up like for unmodified files
+                                    let attributes =
+                                        Attributes::at(&roots.full_path(&file_info.rel_path));
+                                    attribute_changes
+                                        .lock()
+                                        .unwrap()
+                                        .extend(attribute_change(&file_info, attributes));
+                                    file_info.attributes = attributes.or(file_info.attributes);
+                                    stats.file_passed_tiers(&file_info);
+                                    stats.worker_busy(device, started.elapsed());
+                                    let result = FileCheckResult::Unmodifed(file_info);
+                                    observer.on_result(&result);
+                                    break 'read (0, Ok(Some(result)));
+                                }
+                            }
+                            let mut result = file.check(&roots, &read_options);
+                            for retry in 1..=read_retries {
+                                let transient = result.as_ref().err().is_some_and(|err| {
+                                    ReadError::new(&rel_path, err)
+                                        .is_some_and(|e| e.may_be_transient())
+                                });
+                                let (true, Some(file), Err(err)) =
+                                    (transient, &retry_file, &result)
+                                else {
+                                    break;
+                                };
+                                observer.on_message(&format!("retrying in {retry}s: {:#}", err));
+                                std::thread::sleep(Duration::from_secs(retry.into()));
+                                result = file.clone().check(&roots, &read_options);
+                            }
+                            let read_attributes = match &result {
+                                Ok(FileCheckResult::Unmodifed(fi)) => fi.attributes,
+                                Ok(FileCheckResult::Modified(fi_mod)) => fi_mod.current.attributes,
+                                _ => None,
+                            };
+                            if let Some(previous) = &kept {
+                                attribute_changes
+                                    .lock()
+                                    .unwrap()
+                                    .extend(attribute_change(previous, read_attributes));
+                            }
+                            if let Err(err) = &result {
+                                if let Some(read_error) = ReadError::new(&rel_path, err) {
+                                    if skip_locked && read_error.is_locked() {
+                                        stats.file_locked();
+                                        observer.on_message(&format!(
+                                            "skipped, locked by another program: {}",
+                                            read_error.message
+                                        ));
+                                    } else {
+                                        stats.file_unreadable();
+                                        observer.on_message(&format!(
+                                            "skipped: {}",
+                                            read_error.message
+                                        ));
+                                    }
+                                    read_errors.lock().unwrap().push(read_error);
+                                    stats.worker_busy(device, started.elapsed());
+                                    let kept = kept.map(FileCheckResult::Unmodifed);
+                                    break 'read (0, Ok(kept));
+                                }
+                            }
+                            if result.as_ref().is_ok_and(|r| grew(listed_len, r)) {
+                                observer.on_message(&format!(
+                                    "{:?} grew while it was read, it's likely still being written",
+                                    rel_path
+                                ));
+                                let kept = kept.map(FileCheckResult::Unmodifed);
+                                match (growing_files, later_file) {
+                                    (GrowingFiles::Record, _) => {
+                                        growing.lock().unwrap().push(rel_path.clone())
+                                    }
+                                    (GrowingFiles::RetryLater, Some(file)) => {
+                                        retry_later.lock().unwrap().push((file, kept));
+                                        stats.worker_busy(device, started.elapsed());
+                                        // Nothing is kept until it's read again
+                                        break 'read (listed_len, Ok(None));
+                                    }
+                                    _ => {
+                                        growing.lock().unwrap().push(rel_path);
+                                        stats.worker_busy(device, started.elapsed());
+                                        break 'read (listed_len, Ok(kept));
+                                    }
+                                }
+                            }
+                            if let (Ok(check_result), Some(volume)) = (&mut result, &volume) {
+                                check_result.set_volume(volume);
+                            }
+                            if double_read {
+                                if let Ok(FileCheckResult::Modified(fi_mod)) = result {
+                                    // Kept as it was, the reads confirmed nothing
+                                    let kept = FileCheckResult::Unmodifed(fi_mod.previous.clone());
+                                    match read_again(&roots, fi_mod, &read_options, &*observer) {
+                                        Ok(again) => result = Ok(again),
+                                        Err(read_error) => {
+                                            stats.file_unreadable();
+                                            observer.on_message(&format!(
+                                                "skipped: {}",
+                                                read_error.message
+                                            ));
+                                            read_errors.lock().unwrap().push(read_error);
+                                            stats.worker_busy(device, started.elapsed());
+                                            break 'read (listed_len, Ok(Some(kept)));
+                                        }
+                                    }
+                                }
+                            }
+                            let simulated = match &result {
+                                Ok(FileCheckResult::Unmodifed(fi))
+                                    if corruptions_left
+                                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                                            n.checked_sub(1)
+                                        })
+                                        .is_ok() =>
+                                {
+                                    Some(fi.clone())
+                                }
+                                _ => None,
+                            };
+                            if let Some(previous) = simulated {
+                                observer.on_message(&format!(
+                                    "SIMULATED CORRUPTION of {:?}, the file itself is fine",
+                                    previous.rel_path
+                                ));
+                                let mut current = previous.clone();
+                                current.digest[0] ^= 1;
+                                result = Ok(FileCheckResult::Modified(Box::new(
+                                    FileCheckResultModified { previous, current },
+                                )));
+                            }
+                            stats.worker_busy(device, started.elapsed());
+                            if let Ok(check_result) = &result {
+                                count_read(&stats, check_result, started.elapsed());
+                                observer.on_result(check_result);
+                            }
+                            let bytes_read = if result.is_ok() { listed_len } else { 0 };
+                            (bytes_read, result.map(Some))
+                        };
+                        observer.on_progress(&progress.finished(
+                            file_device,
+                            listed_len,
+                            bytes_read,
+                        ));
+                        // The receiver is gone if the run was aborted due to an error
+                        sender.send((device, result)).ok();
+                    });
+                }
+                let full = match draining {
+                    true => !queues.is_empty(),
+                    false => queues.len() >= max_queued,
+                };
+                if !full {
+                    break;
+                }
+                let (done_device, result) = check_files_recv.recv().unwrap();
+                queues.finished(done_device);
+                push_result(result?, checked_files, unmodified)?;
+            }
+            // Waiting for the last reads is counted by the caller
+            if !draining {
+                wait_for_reads += wait_start.elapsed();
+            }
+            Ok(())
+        };
+
+        let mut files_to_read = Vec::new();
+        let walk_start = Instant::now();
+        let starts = match &files {
+            // Each one listed on its own, from the root it's in
+            Some(files) => files
+                .iter()
+                .map(|rel_path| Root {
+                    prefix: rel_path.clone(),
+                    directory: roots.full_path(rel_path),
+                })
+                .collect(),
+            None => roots.walk_starts(&paths),
+        };
+        for (start, file_result) in starts.iter().flat_map(|start| {
+            let listed: Box<dyn Iterator<Item = Result<DirEntry>>> = match files {
+                Some(_) => Box::new(list_file(
+                    &start.directory,
+                    &start.prefix,
+                    &excludes,
+                    opts.links,
+                )),
+                None => Box::new(list_files(
+                    &start.directory,
+                    &excludes,
+                    opts.links,
+                    opts.walk_order,
+                )),
+            };
+            listed.map(move |file| (start, file))
+        }) {
+            if self.observer.should_stop() {
+                return Err(anyhow::Error::msg("stopped by observer"));
+            }
+            let file = file_result?;
+            let rel_path = start.rel_path(file.path());
+            // Hidden by a root mounted below the one listed
+            if !roots.owns(start, &rel_path) {
+                continue;
+            }
+            files_checked += 1;
+
+            let metadata = file
+                .metadata()
+                .with_context(|| format!("Unable to get metadata for {:?}", file.path()))?;
+            let sort_key = match opts.read_order {
+                ReadOrder::SmallestFirst => metadata.len(),
+                ReadOrder::Inode => inode(&file),
+                // Only sorted to write the plan first, they stay in path order
+                ReadOrder::Path => files_to_read.len() as u64,
+            };
+            let file_device = if per_device { device(&metadata) } else { 0 };
+            let previous = old_states_by_filename.remove(&rel_path).or_else(|| {
+                let state_path = state_paths_by_lowercase.get(&lowercase(&rel_path))?;
+                // Read at the path found, the one of the state may not open
+                let mut fi = old_states_by_filename.remove(state_path)?;
+                fi.rel_path = rel_path.clone();
+                Some(fi)
+            });
+            let (file_to_check, reason) = match previous {
+                None => (
+                    FileToCheck::New {
+                        path: file.into_path(),
+                        rel_path,
+                        metadata,
+                    },
+                    ReadReason::New,
+                ),
+                Some(fi) => match fi.needs_reading(&metadata, mtime_tolerance) {
+                    Ok(needs_reading) if (needs_reading || read_all_files) => (
+                        FileToCheck::NeedsChecking {
+                            previous: fi,
+                            metadata,
+                        },
+                        match needs_reading {
+                            true => ReadReason::MetadataChanged,
+                            false => ReadReason::Reverify,
+                        },
+                    ),
+                    Ok(_) => {
+                        stats.file_unchanged(&fi);
+
+                        // Attribute changes don't touch the mtime, files that
+                        // aren't read are looked at here. The others get the
+                        // attributes of the handle they're read through.
+                        let attributes = Attributes::at(file.path());
+                        attribute_changes
+                            .lock()
+                            .unwrap()
+                            .extend(attribute_change(&fi, attributes));
+                        let mut new_fi = fi;
+                        new_fi.attributes = attributes.or(new_fi.attributes);
+                        new_fi.last_seen = SystemTime::now();
+                        new_fi.missing_since = None;
+                        if let Some(volume) = volume {
+                            new_fi.volume = Some(volume.to_owned());
+                        }
+                        unmodified.push(new_fi)?;
+                        continue;
+                    }
+                    Err(err) => {
+                        return Err(err.context(format!(
+                            "Failed to check if file needs to be read: {:?}",
+                            file.path()
+                        )))
+                    }
+                },
+            };
+
+            progress.queue(
+                device_pools::device(file_to_check.metadata()),
+                || roots.full_path(file_to_check.rel_path()),
+                file_to_check.metadata().len(),
+            );
+            match opts.read_order {
+                ReadOrder::Path if opts.plan.is_none() && !metadata_first => dispatch(
+                    Some((file_device, file_to_check)),
+                    &mut checked_files,
+                    &mut unmodified,
+                )?,
+                _ => files_to_read.push((sort_key, file_device, file_to_check, reason)),
+            }
+        }
+
+        let walk = walk_start.elapsed();
+        progress.listed();
+
+        // Files on other volumes can't be in this directory and files outside
+        // of the paths or not among the files weren't listed, they're kept as
+        // they are
+        let listed = files
+            .as_ref()
+            .map(|files| files.iter().collect::<HashSet<_>>());
+        let mut other_volumes = BTreeMap::new();
+        let mut outside_paths = 0;
+        let mut missing = Vec::new();
+        for fi in old_states_by_filename.into_values() {
+            match (&fi.volume, volume) {
+                (Some(stored_on), Some(volume)) if stored_on != volume => {
+                    *other_volumes.entry(stored_on.clone()).or_default() += 1;
+                    unmodified.push(fi)?;
+                }
+                _ if !paths.is_empty() && !paths.iter().any(|p| fi.rel_path.starts_with(p)) => {
+                    outside_paths += 1;
+                    unmodified.push(fi)?;
+                }
+                _ if listed
+                    .as_ref()
+                    .is_some_and(|listed| !listed.contains(&fi.rel_path)) =>
+                {
+                    outside_paths += 1;
+                    unmodified.push(fi)?;
+                }
+                _ => missing.push(fi),
+            }
+        }
+        stats.files_checked(files_checked + missing.len() as u64);
+
+        // Inode numbers roughly follow the physical layout on most filesystems,
+        // so reading in this order avoids a lot of seeking on spinning disks.
+        // The sort is stable, files of the same size stay in path order.
+        files_to_read.sort_by_key(|(sort_key, _, _, _)| *sort_key);
+        let metadata_pass = metadata_first.then(|| {
+            let with_reason = |wanted: ReadReason| {
+                let mut paths = files_to_read
+                    .iter()
+                    .filter(|(_, _, _, reason)| *reason == wanted)
+                    .map(|(_, _, file, _)| file.rel_path().to_owned())
+                    .collect::<Vec<_>>();
+                paths.sort();
+                paths
+            };
+            let mut missing = missing
+                .iter()
+                .map(|fi| fi.rel_path.clone())
+                .collect::<Vec<_>>();
+            missing.sort();
+            MetadataPass {
+                missing,
+                new: with_reason(ReadReason::New),
+                changed: with_reason(ReadReason::MetadataChanged),
+            }
+        });
+        if let Some(pass) = &metadata_pass {
+            self.observer.on_metadata_pass(pass);
+        }
+        let mut plan = None;
+        if let Some(plan_path) = &opts.plan {
+            let reads = files_to_read
+                .iter()
+                .map(|(_, _, file, reason)| PlannedRead {
+                    rel_path: file.rel_path().to_owned(),
+                    len: file.metadata().len(),
+                    reason: *reason,
+                })
+                .collect::<Vec<_>>();
+            let written = write_plan(plan_path, &reads)?;
+            if opts.plan_only {
+                return Ok(Checked::PlanOnly(written));
+            }
+            self.observer.on_message(&format!(
+                "plan of {} files ({} bytes) written to {:?}",
+                written.files, written.bytes, written.path
+            ));
+            plan = Some(written);
+        }
+        for (_, file_device, file_to_check, _) in files_to_read {
+            if self.observer.should_stop() {
+                return Err(anyhow::Error::msg("stopped by observer"));
+            }
+            dispatch(
+                Some((file_device, file_to_check)),
+                &mut checked_files,
+                &mut unmodified,
+            )?;
+        }
+
+        let wait_start = Instant::now();
+        dispatch(None, &mut checked_files, &mut unmodified)?;
+        // Files passing a tier are barely read, they'd make the devices look slow
+        if !manifest && tiers.is_empty() {
+            let written = progress
+                .measured()
+                .map_or(Ok(()), |throughput| throughput.write(&self.state_dir));
+            if let Err(err) = written {
+                self.observer
+                    .on_message(&format!("{:#}, the next estimate starts from scratch", err));
+            }
+        }
+
+        // Read one after another, there are usually only a few of them
+        let retry_later = std::mem::take(&mut *retry_later.lock().unwrap());
+        for (file, kept) in retry_later {
+            let rel_path = file.rel_path().to_owned();
+            let started = Instant::now();
+            let result = file
+                .listed_again(&roots)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| {
+                    self.observer.on_file_start(&rel_path);
+                    let listed_len = file.metadata().len();
+                    Ok((listed_len, file.check(&roots, &read_options)?))
+                });
+            let result = match result {
+                Ok((listed_len, check_result)) if grew(listed_len, &check_result) => {
+                    self.observer.on_message(&format!(
+                        "skipped {:?}, it grew again while it was read a second time",
+                        rel_path
+                    ));
+                    growing.lock().unwrap().push(rel_path);
+                    kept
+                }
+                Ok((_, mut check_result)) => {
+                    if let Some(volume) = volume {
+                        check_result.set_volume(volume);
+                    }
+                    count_read(&stats, &check_result, started.elapsed());
+                    self.observer.on_result(&check_result);
+                    Some(check_result)
+                }
+                Err(err) => {
+                    let read_error = ReadError::new(&rel_path, &err).ok_or(err)?;
+                    stats.file_unreadable();
+                    self.observer
+                        .on_message(&format!("skipped: {}", read_error.message));
+                    read_errors.lock().unwrap().push(read_error);
+                    kept
+                }
+            };
+            push_result(result, &mut checked_files, &mut unmodified)?;
+        }
+        stats.update_timings(|t| {
+            // Reads were only waited for while listing files in path order
+            t.walk = match opts.read_order {
+                ReadOrder::Path => walk.saturating_sub(wait_for_reads),
+                ReadOrder::Inode | ReadOrder::SmallestFirst => walk,
+            };
+            t.wait_for_reads = wait_for_reads + wait_start.elapsed();
+            t.check = start.elapsed();
+        });
+
+        let tier_stats = std::mem::take(&mut *tier_stats.lock().unwrap());
+        let mut growing = std::mem::take(&mut *growing.lock().unwrap());
+        growing.sort();
+        let mut read_errors = std::mem::take(&mut *read_errors.lock().unwrap());
+        read_errors.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        let (locked, read_errors): (Vec<_>, Vec<_>) = read_errors
+            .into_iter()
+            .partition(|e| skip_locked && e.is_locked());
+        let locked = locked.into_iter().map(|e| e.rel_path).collect();
+        let mut attribute_changes = std::mem::take(&mut *attribute_changes.lock().unwrap());
+        attribute_changes.sort_by(|a, b| a.path.cmp(&b.path));
+        stats.files_not_found(missing.len() as u64);
+
+        checked_files.extend(missing.into_iter().map(FileCheckResult::Missing));
+
+        checked_files.sort_by(|a, b| a.rel_path().cmp(b.rel_path()));
+
+        Ok(Checked::Files(Box::new(CheckedFiles {
+            header,
+            checked_files,
+            unmodified,
+            other_volumes,
+            outside_paths,
+            attribute_changes,
+            read_errors,
+            locked,
+            growing,
+            tier_stats,
+            roots,
+            simulated_corruptions: simulate_corruption - corruptions_left.load(Ordering::Relaxed),
+            previous_files,
+            directory_counts,
+            metadata_pass,
+            plan,
+            stats,
+            start,
+        })))
+    }
+}
+
+/// Sends every file read and the missing files to a VerifyIter
+struct IterObserver {
+    sender: SyncSender<Result<FileCheckResult>>,
+    stopped: AtomicBool,
+}
+
+impl IterObserver {
+    fn send(&self, result: Result<FileCheckResult>) {
+        if self.sender.send(result).is_err() {
+            // The iterator was dropped
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Observer for IterObserver {
+    fn on_result(&self, result: &FileCheckResult) {
+        self.send(Ok(result.clone()));
+    }
+
+    fn should_stop(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+/// Results of a verification, in the order files finish reading
+///
+/// Files are listed and read in the background. Files missing from the
+/// directory are only known once all files are listed, so they come
+/// last, after the files that couldn't be read. Those are errors holding
+/// a [`ReadError`] with the path, the iteration goes on after them, any
+/// other error ends it. Dropping the iterator stops the verification.
+pub struct VerifyIter {
+    results: Receiver<Result<FileCheckResult>>,
+}
+
+impl Iterator for VerifyIter {
+    type Item = Result<FileCheckResult>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.results.recv().ok()
+    }
+}
+
+/// Verifies the files in directory against the latest state in state_dir
+///
+/// Shorthand for [`Archive::verify_iter`] with the default options.
+pub fn verify_iter(state_dir: impl Into<PathBuf>, directory: impl Into<PathBuf>) -> VerifyIter {
+    Archive::new(state_dir, directory, ArchiveOptions::default()).verify_iter()
+}
+
+/// Keeps missing files in the state until they've been missing for longer than grace
+///
+/// Files missing for the first time are recorded as missing and kept, files
+/// missing since an earlier update are only kept. Without a grace, files
+/// missing for the first time are only recorded, while the ones an earlier
+/// update kept stay as they are. Returns the new results and the number of
+/// files still kept and forgotten.
+fn keep_missing(
+    checked_files: Vec<FileCheckResult>,
+    grace: Option<Duration>,
+    now: SystemTime,
+) -> (Vec<FileCheckResult>, u64, u64) {
+    let (mut still_missing, mut forgotten) = (0, 0);
+    let mut results = Vec::with_capacity(checked_files.len());
+    for file in checked_files {
+        let FileCheckResult::Missing(fi) = file else {
+            results.push(file);
+            continue;
+        };
+        match (fi.missing_since, grace) {
+            (None, None) => results.push(FileCheckResult::Missing(fi)),
+            (None, Some(_)) => {
+                let mut kept = fi.clone();
+                kept.missing_since = Some(now);
+                results.push(FileCheckResult::Missing(fi));
+                results.push(FileCheckResult::Unmodifed(kept));
+                still_missing += 1;
+            }
+            (Some(since), Some(grace)) if since + grace <= now => forgotten += 1,
+            (Some(_), _) => {
+                results.push(FileCheckResult::Unmodifed(fi));
+                still_missing += 1;
+            }
+        }
+    }
+    (results, still_missing, forgotten)
+}
+
+/// Reads a modified file a second time, see [`VerifyOptions::double_read`]
+///
+/// Files matching the state on the second read are unmodified. Reads that
+/// disagree with each other and the state confirm neither, the file can't
+/// be read reliably. They're a read error, the state keeps what it had.
+fn read_again(
+    roots: &Roots,
+    modified: Box<FileCheckResultModified>,
+    read_options: &ReadOptions,
+    observer: &dyn Observer,
+) -> Result<FileCheckResult, ReadError> {
+    let rel_path = &modified.current.rel_path;
+    let path = roots.full_path(rel_path);
+    let direct = ReadOptions {
+        direct_io: true,
+        ..*read_options
+    };
+    // Not every filesystem supports direct I/O, tmpfs for example
+    let second = std::fs::metadata(&path).and_then(|metadata| {
+        hash_file(rel_path, &path, &metadata, &direct)
+            .or_else(|_| hash_file(rel_path, &path, &metadata, read_options))
+    });
+    match second {
+        Ok(mut second) if second.digest == modified.previous.digest => {
+            observer.on_message(&format!(
+                "{:?} matched the state when read a second time, the first read was likely a transient error",
+                modified.current.rel_path
+            ));
+            second.volume = modified.current.volume;
+            Ok(FileCheckResult::Unmodifed(second))
+        }
+        Ok(second) if second.digest != modified.current.digest => Err(ReadError {
+            rel_path: rel_path.clone(),
+            kind: std::io::ErrorKind::InvalidData,
+            errno: None,
+            message: format!(
+                "{:?} gave different contents on every read, the disk or memory may be failing",
+                path
+            ),
+        }),
+        Ok(_) => Ok(FileCheckResult::Modified(modified)),
+        Err(err) => {
+            observer.on_message(&format!("unable to read {:?} a second time: {}", path, err));
+            Ok(FileCheckResult::Modified(modified))
+        }
+    }
+}
+
+/// Whether more was read of a file than it had when it was listed, it's likely still being written
+fn grew(listed_len: u64, result: &FileCheckResult) -> bool {
+    match result {
+        FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => fi.len > listed_len,
+        FileCheckResult::Modified(fi_mod) => fi_mod.current.len > listed_len,
+        FileCheckResult::Missing(_) => false,
+    }
+}
+
+/// Adds a file read to the stats
+fn count_read(stats: &StatsCollector, result: &FileCheckResult, duration: Duration) {
+    match result {
+        FileCheckResult::New(file_info) => {
+            stats.file_read_new(file_info);
+            stats.file_read_duration(file_info, duration);
+        }
+        FileCheckResult::Unmodifed(file_info) => {
+            stats.file_read_unmodifed(file_info);
+            stats.file_read_duration(file_info, duration);
+        }
+        FileCheckResult::Modified(file_infos) => {
+            stats.file_read_modified(&file_infos.current);
+            stats.file_read_duration(&file_infos.current, duration);
+        }
+        FileCheckResult::Missing(_) => {
+            stats.file_not_found();
+        }
+    }
+}
+
+/// Keeps unmodified files separately, they're most of the results and may be spilled to disk
+///
+/// None is a file that couldn't be read and wasn't in the state, there's
+/// nothing to keep of it.
+/// How the attributes found for a file differ from the ones it has in the state, if they do
+fn attribute_change(fi: &FileInfo, current: Option<Attributes>) -> Option<AttributeChange> {
+    match (fi.attributes, current) {
+        (Some(previous), Some(current)) if previous != current => Some(AttributeChange {
+            path: fi.rel_path.clone(),
+            previous,
+            current,
+        }),
+        _ => None,
+    }
+}
+
+fn push_result(
+    result: Option<FileCheckResult>,
+    checked_files: &mut Vec<FileCheckResult>,
+    unmodified: &mut UnmodifiedFiles,
+) -> Result<()> {
+    match result {
+        None => Ok(()),
+        Some(FileCheckResult::Unmodifed(fi)) => unmodified.push(fi),
+        Some(other) => {
+            checked_files.push(other);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::review::Verdict;
+    use crate::test_dir::TestDir;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingObserver {
+        started: AtomicUsize,
+        results: AtomicUsize,
+        summaries: AtomicUsize,
+    }
+
+    impl Observer for CountingObserver {
+        fn on_file_start(&self, _rel_path: &Path) {
+            self.started.fetch_add(1, Ordering::Relaxed);
+        }
+        fn on_result(&self, _result: &FileCheckResult) {
+            self.results.fetch_add(1, Ordering::Relaxed);
+        }
+        fn on_summary(&self, _stats: &Stats, _duration: Duration) {
+            self.summaries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn update_and_verify() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("update");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for i in 0..10 {
+            std::fs::write(
+                files_dir.join(format!("file {}", i)),
+                format!("contents {}", i),
+            )?;
+        }
+
+        let observer = Arc::new(CountingObserver::default());
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default())
+            .with_observer(observer.clone());
+        let report = archive.update(&UpdateOptions::default())?;
+        assert_eq!(report.stats.files_new, 10);
+        assert!(report.state_path.exists());
+        assert_eq!(observer.started.load(Ordering::Relaxed), 10);
+        assert_eq!(observer.results.load(Ordering::Relaxed), 10);
+        assert_eq!(observer.summaries.load(Ordering::Relaxed), 1);
+
+        assert!(archive.verify(&VerifyOptions::default())?.is_ok());
+
+        std::fs::write(files_dir.join("file 3"), "modified")?;
+        std::fs::remove_file(files_dir.join("file 5"))?;
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert!(!report.is_ok());
+        assert_eq!(report.modified, vec![PathBuf::from("file 3")]);
+        assert_eq!(report.missing, vec![PathBuf::from("file 5")]);
+
+        let mut results = verify_iter(&state_dir, &files_dir)
+            .map(|result| {
+                result.map(|file| match file {
+                    FileCheckResult::Modified(_) => format!("M {}", file.rel_path().display()),
+                    FileCheckResult::Missing(_) => format!("- {}", file.rel_path().display()),
+                    _ => format!("  {}", file.rel_path().display()),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Only the missing file is guaranteed to be last
+        assert_eq!(results.pop(), Some("- file 5".to_owned()));
+        results.sort();
+        assert_eq!(results.len(), 9);
+        assert_eq!(results[0], "  file 0");
+        assert_eq!(results[8], "M file 3");
+
+        // Stopping early must not hang
+        assert!(verify_iter(&state_dir, &files_dir).next().is_some());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn verify_iter_read_errors() -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TestDir::new("verify-iter-errors");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["a", "b", "c"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        Archive::new(&state_dir, &files_dir, ArchiveOptions::default())
+            .update(&UpdateOptions::default())?;
+        std::fs::remove_file(files_dir.join("c"))?;
+
+        let unreadable = files_dir.join("b");
+        std::fs::set_permissions(&unreadable, std::fs::Permissions::from_mode(0o000))?;
+        if std::fs::File::open(&unreadable).is_ok() {
+            // Root reads it anyway
+            return Ok(());
+        }
+        let mut results = verify_iter(&state_dir, &files_dir).collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert!(results.pop().unwrap()?.rel_path() == Path::new("c"));
+        let errors = results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .map(|err| err.downcast_ref::<ReadError>().unwrap().rel_path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(errors, [PathBuf::from("b")]);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_files_matched_by_path() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("empty");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for (name, contents) in [("a", ""), ("b", ""), ("c", "contents")] {
+            std::fs::write(files_dir.join(name), contents)?;
+        }
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+
+        std::fs::remove_file(files_dir.join("a"))?;
+        std::fs::write(files_dir.join("c"), "")?;
+        // A third of the archive is missing, so the state is only written with force
+        assert!(archive.update(&UpdateOptions::default()).is_err());
+        let report = archive.update(&UpdateOptions {
+            force: true,
+            ..Default::default()
+        })?;
+        // Another empty file doesn't count as a copy of the missing one
+        assert_eq!(report.missing, vec![PathBuf::from("a")]);
+        assert_eq!(report.stats.files_duplicate_removed, 0);
+        assert_eq!(report.truncated, vec![PathBuf::from("c")]);
+        Ok(())
+    }
+
+    #[test]
+    fn volumes_updated_separately() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("volumes");
+        let state_dir = dir.join("state");
+        for (disk, name) in [("one", "a"), ("one", "b"), ("two", "c")] {
+            std::fs::create_dir_all(dir.join(disk))?;
+            std::fs::write(dir.join(disk).join(name), name)?;
+        }
+        std::fs::create_dir_all(&state_dir)?;
+        let on_volume = |volume: &str| UpdateOptions {
+            volume: Some(volume.to_owned()),
+            ..Default::default()
+        };
+        let one = Archive::new(&state_dir, dir.join("one"), ArchiveOptions::default());
+        let two = Archive::new(&state_dir, dir.join("two"), ArchiveOptions::default());
+        one.update(&on_volume("one"))?;
+        let report = two.update(&on_volume("two"))?;
+        assert!(report.missing.is_empty());
+        assert_eq!(
+            report.other_volumes,
+            BTreeMap::from([("one".to_owned(), 2)])
+        );
+
+        let report = two.verify(&VerifyOptions {
+            volume: Some("two".to_owned()),
+            ..Default::default()
+        })?;
+        assert!(report.is_ok());
+        assert_eq!(
+            report.other_volumes,
+            BTreeMap::from([("one".to_owned(), 2)])
+        );
+        let report = two.verify(&VerifyOptions::default())?;
+        assert_eq!(
+            report.missing_on_volumes,
+            BTreeMap::from([(
+                "one".to_owned(),
+                vec![PathBuf::from("a"), PathBuf::from("b")]
+            )])
+        );
+
+        // Only c can be found in two, a and b are on a disk not plugged in
+        std::fs::remove_file(dir.join("two/c"))?;
+        assert!(two.accept_missing(&[], None)?.is_empty());
+        let accepted = two.accept_missing(&[], Some("two"))?;
+        assert_eq!(
+            accepted.iter().map(|t| &t.rel_path).collect::<Vec<_>>(),
+            vec![Path::new("c")]
+        );
+        assert_eq!(read_state(&state_dir)?.1.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn partial_update_keeps_other_files() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("update-partial");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(files_dir.join("photos/2024"))?;
+        std::fs::create_dir_all(files_dir.join("docs"))?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["photos/a", "photos/2024/b", "docs/c"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+
+        std::fs::remove_file(files_dir.join("docs/c"))?;
+        std::fs::write(files_dir.join("photos/2024/d"), "d")?;
+        let partial = |path: &str| UpdateOptions {
+            paths: vec![PathBuf::from(path)],
+            ..Default::default()
+        };
+        assert!(archive.update(&partial("../files")).is_err());
+        let report = archive.update(&partial("photos/2024/"))?;
+        assert!(report.missing.is_empty());
+        assert_eq!(report.kept_outside_paths, 2);
+        assert_eq!(report.new, vec![PathBuf::from("photos/2024/d")]);
+        let (_, files) = read_state(&state_dir)?;
+        assert_eq!(files.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn only_files_given_checked() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("files-from");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(files_dir.join("docs"))?;
+        std::fs::create_dir_all(files_dir.join(".cache"))?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["a", "b", "docs/c"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        let archive = Archive::new(
+            &state_dir,
+            &files_dir,
+            ArchiveOptions {
+                excludes: Excludes {
+                    skip_hidden: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        archive.update(&UpdateOptions::default())?;
+
+        // Changed and gone files the other tool knows of, b changed unnoticed
+        std::fs::write(files_dir.join("b"), "changed")?;
+        std::fs::write(files_dir.join("docs/d"), "d")?;
+        std::fs::write(files_dir.join(".cache/e"), "e")?;
+        std::fs::remove_file(files_dir.join("docs/c"))?;
+        let files = ["./docs/d", "docs/c", ".cache/e", "docs"]
+            .map(PathBuf::from)
+            .to_vec();
+        let report = archive.verify(&VerifyOptions {
+            files: Some(files.clone()),
+            ..Default::default()
+        })?;
+        assert_eq!(report.missing, vec![PathBuf::from("docs/c")]);
+        assert_eq!(report.not_in_archive, vec![PathBuf::from("docs/d")]);
+        assert!(report.modified.is_empty());
+        // One of three files missing would stop the update otherwise
+        let report = archive.update(&UpdateOptions {
+            files: Some(files),
+            force: true,
+            ..Default::default()
+        })?;
+        assert_eq!(report.missing, vec![PathBuf::from("docs/c")]);
+        assert_eq!(report.kept_outside_paths, 2);
+        assert_eq!(report.stats.files_new, 1);
+        let (_, files) = read_state(&state_dir)?;
+        let mut paths = files.into_keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, ["a", "b", "docs/d"].map(PathBuf::from));
+        assert_eq!(report.new, vec![PathBuf::from("docs/d")]);
+        assert!(archive
+            .update(&UpdateOptions {
+                files: Some(vec![PathBuf::from("../a")]),
+                ..Default::default()
+            })
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deeply_nested_files() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("deep");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        // Longer than the 260 characters Windows allows without a prefix
+        let nested = (0..12).map(|i| format!("{:030}", i)).collect::<PathBuf>();
+        std::fs::create_dir_all(files_dir.join(&nested))?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join(&nested).join("file"), "contents")?;
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        let report = archive.update(&UpdateOptions::default())?;
+        assert_eq!(report.stats.files_new, 1);
+        assert!(archive.verify(&VerifyOptions::default())?.is_ok());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_policies() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("links");
+        let files_dir = dir.join("files");
+        std::fs::create_dir_all(files_dir.join("sub"))?;
+        std::fs::write(files_dir.join("a"), "contents")?;
+        std::os::unix::fs::symlink("a", files_dir.join("link"))?;
+        std::os::unix::fs::symlink("..", files_dir.join("sub/loop"))?;
+        let update = |links: Links| -> Result<UpdateReport> {
+            let state_dir = dir.join(format!("state-{:?}", links));
+            std::fs::create_dir_all(&state_dir)?;
+            let options = ArchiveOptions {
+                links,
+                ..Default::default()
+            };
+            Archive::new(&state_dir, &files_dir, options).update(&UpdateOptions::default())
+        };
+        assert_eq!(update(Links::Skip)?.stats.files_new, 1);
+        // Only the link back up is left out
+        assert_eq!(update(Links::Follow)?.stats.files_new, 2);
+        assert_eq!(update(Links::Record)?.stats.files_new, 3);
+
+        std::fs::remove_file(files_dir.join("link"))?;
+        std::os::unix::fs::symlink("sub", files_dir.join("link"))?;
+        assert_eq!(update(Links::Record)?.modified, vec![PathBuf::from("link")]);
+        Ok(())
+    }
+
+    #[test]
+    fn reviewed_changes_accepted_or_kept() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("update-review");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["a", "b", "c"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+
+        std::fs::write(files_dir.join("a"), "edited")?;
+        std::fs::write(files_dir.join("b"), "rotten")?;
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert_eq!(
+            report.modified,
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        let verdicts = report
+            .changes
+            .iter()
+            .zip([Decision::Accepted, Decision::Corrupted])
+            .map(|(change, decision)| Verdict::of(change, decision, &report.run_id))
+            .collect::<Vec<_>>();
+        crate::review::append(&state_dir, &verdicts)?;
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert_eq!(report.accepted, vec![PathBuf::from("a")]);
+        assert_eq!(report.modified, vec![PathBuf::from("b")]);
+
+        let report = archive.update(&UpdateOptions::default())?;
+        assert_eq!(report.modified, vec![PathBuf::from("a")]);
+        assert_eq!(report.accepted, vec![PathBuf::from("a")]);
+        assert_eq!(report.flagged_corrupted, vec![PathBuf::from("b")]);
+        // The digest before the damage is kept, so restoring the file fixes it
+        std::fs::write(files_dir.join("b"), "b")?;
+        assert!(archive.verify(&VerifyOptions::default())?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn changes_accepted_without_update() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("accept");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(files_dir.join("catalogs"))?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["catalogs/a", "catalogs/b", "c"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+        assert!(archive.accept_changes(None, &[]).is_err());
+
+        for name in ["catalogs/a", "c"] {
+            std::fs::write(files_dir.join(name), "changed")?;
+        }
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert!(report.changes_path.is_some());
+        let patterns = [crate::review::path_pattern("catalogs/**")?];
+        let (run_id, accepted) = archive.accept_changes(Some(&report.run_id), &patterns)?;
+        assert_eq!(run_id, report.run_id);
+        assert_eq!(accepted, vec![PathBuf::from("catalogs/a")]);
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert_eq!(report.modified, vec![PathBuf::from("c")]);
+        // The state accepted into is newer than the first run
+        assert!(archive.accept_changes(Some(&run_id), &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_against_golden_state() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("golden");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("a"), "a")?;
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        let golden = archive.update(&UpdateOptions::default())?.state_path;
+        crate::state::pin_state(&state_dir, Some(&golden))?;
+
+        std::fs::write(files_dir.join("a"), "changed")?;
+        archive.update(&UpdateOptions::default())?;
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert_eq!(report.modified, vec![PathBuf::from("a")]);
+        assert!(archive
+            .verify(&VerifyOptions {
+                against: Baseline::Latest,
+                ..Default::default()
+            })?
+            .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn states_referred_to_by_label() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("label");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("a"), "a")?;
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        let labeled = UpdateOptions {
+            label: Some("pre-migration".to_owned()),
+            ..Default::default()
+        };
+        let state_path = archive.update(&labeled)?.state_path;
+
+        std::fs::write(files_dir.join("a"), "changed")?;
+        // Labels stay with one state
+        assert!(archive.update(&labeled).is_err());
+        archive.update(&UpdateOptions::default())?;
+        assert_eq!(
+            crate::state::resolve_state(&state_dir, "pre-migration")?,
+            state_path
+        );
+        let report = archive.verify(&VerifyOptions {
+            state_file: Some(PathBuf::from("pre-migration")),
+            ..Default::default()
+        })?;
+        assert_eq!(report.modified, vec![PathBuf::from("a")]);
+        Ok(())
+    }
+
+    #[test]
+    fn simulated_corruption() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("simulate");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["a", "b", "c"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+        let report = archive.verify(&VerifyOptions {
+            simulate_corruption: 2,
+            ..Default::default()
+        })?;
+        assert_eq!(report.modified.len(), 2);
+        assert_eq!(report.simulated_corruptions, 2);
+        assert!(!report.is_ok());
+        assert!(!report.failures_below(None, None));
+        assert!(report.failures_below(Some(2), None));
+        assert!(!report.failures_below(Some(1), None));
+        // 2 of 3 files
+        assert!(!report.failures_below(Some(2), Some(50.0)));
+        assert!(report.failures_below(None, Some(70.0)));
+        assert!(archive.verify(&VerifyOptions::default())?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn second_reads_decide() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("double-read");
+        let path = dir.join("a");
+        std::fs::write(&path, "contents")?;
+        let read_options = ReadOptions::default();
+        let fi = hash_file(Path::new("a"), &path, &path.metadata()?, &read_options)?;
+        let mut misread = fi.clone();
+        misread.digest[0] ^= 1;
+
+        // The first read went wrong, the file matches the state
+        let first_read_wrong = FileCheckResultModified {
+            previous: fi.clone(),
+            current: misread.clone(),
+        };
+        let roots = Roots::new(&dir, &[]);
+        let result = read_again(
+            &roots,
+            Box::new(first_read_wrong),
+            &read_options,
+            &NoopObserver,
+        );
+        assert!(matches!(result, Ok(FileCheckResult::Unmodifed(_))));
+        // Both reads agree the file differs from the state
+        let modified = FileCheckResultModified {
+            previous: misread.clone(),
+            current: fi.clone(),
+        };
+        let result = read_again(&roots, Box::new(modified), &read_options, &NoopObserver);
+        assert!(matches!(result, Ok(FileCheckResult::Modified(_))));
+        // The second read agrees with neither the state nor the first one
+        let mut misread_again = misread.clone();
+        misread_again.digest[1] ^= 1;
+        let unconfirmed = FileCheckResultModified {
+            previous: misread,
+            current: misread_again,
+        };
+        let result = read_again(&roots, Box::new(unconfirmed), &read_options, &NoopObserver);
+        assert_eq!(
+            result.map_err(|e| (e.rel_path, e.kind)).err(),
+            Some((PathBuf::from("a"), std::io::ErrorKind::InvalidData))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_against_older_state() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("state-file");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("a"), "a")?;
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        let baseline = archive.update(&UpdateOptions::default())?.state_path;
+        std::fs::write(files_dir.join("a"), "changed")?;
+        archive.update(&UpdateOptions::default())?;
+
+        assert!(archive.verify(&VerifyOptions::default())?.is_ok());
+        let report = archive.verify(&VerifyOptions {
+            state_file: baseline.file_name().map(PathBuf::from),
+            ..Default::default()
+        })?;
+        assert_eq!(report.modified, vec![PathBuf::from("a")]);
+        assert!(archive
+            .verify(&VerifyOptions {
+                state_file: Some(PathBuf::from("19700101 000000.state")),
+                ..Default::default()
+            })
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_with_manifest_only() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("manifest");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        let copy_dir = dir.join("copy");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::create_dir_all(&copy_dir)?;
+        std::fs::write(files_dir.join("a"), "a")?;
+        std::fs::write(files_dir.join("b"), "b")?;
+        let state_path = Archive::new(&state_dir, &files_dir, ArchiveOptions::default())
+            .update(&UpdateOptions::default())?
+            .state_path;
+        let manifest = copy_dir.join("archive.state");
+        std::fs::copy(&state_path, &manifest)?;
+        std::fs::write(files_dir.join("b"), "B")?;
+
+        // The state directory doesn't exist, nothing is looked up there
+        let archive = Archive::new(dir.join("nowhere"), &files_dir, ArchiveOptions::default());
+        let report = archive.verify(&VerifyOptions {
+            manifest: Some(manifest.clone()),
+            ..Default::default()
+        })?;
+        assert_eq!(report.modified, vec![PathBuf::from("b")]);
+        assert!(!dir.join("nowhere").exists());
+        assert_eq!(std::fs::read_dir(&copy_dir)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn content_types_excluded() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("content-type");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("notes"), "notes")?;
+        std::fs::write(files_dir.join("download"), [0; 10000])?;
+        std::fs::write(files_dir.join("empty"), "")?;
+        std::fs::write(files_dir.join("document"), "%PDF-1.7")?;
+        let archive = Archive::new(
+            &state_dir,
+            &files_dir,
+            ArchiveOptions {
+                excludes: Excludes {
+                    exclude_content_type: vec!["zeros".parse()?, "magic:25504446".parse()?],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let report = archive.update(&UpdateOptions::default())?;
+        assert_eq!(report.stats.files_new, 2);
+        let (_, files) = read_state(&state_dir)?;
+        assert!(files.contains_key(Path::new("notes")));
+        assert!(files.contains_key(Path::new("empty")));
+        Ok(())
+    }
+
+    #[test]
+    fn hidden_files_skipped() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("hidden");
+        // Hidden itself, which doesn't keep its files from being listed
+        let files_dir = dir.join(".files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(files_dir.join(".git"))?;
+        std::fs::create_dir_all(files_dir.join("photos"))?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join(".git/HEAD"), "ref")?;
+        std::fs::write(files_dir.join("photos/.DS_Store"), "junk")?;
+        std::fs::write(files_dir.join("photos/a.jpg"), "a")?;
+        let archive = Archive::new(
+            &state_dir,
+            &files_dir,
+            ArchiveOptions {
+                excludes: Excludes {
+                    skip_hidden: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        archive.update(&UpdateOptions::default())?;
+        let (_, files) = read_state(&state_dir)?;
+        assert_eq!(
+            files.into_keys().collect::<Vec<_>>(),
+            vec![PathBuf::from("photos/a.jpg")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn smallest_files_read_first() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("smallest-first");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("a"), [1; 3000])?;
+        std::fs::write(files_dir.join("b"), "b")?;
+        std::fs::write(files_dir.join("c"), [1; 20])?;
+        let archive = Archive::new(
+            &state_dir,
+            &files_dir,
+            ArchiveOptions {
+                threads: Some(1),
+                read_order: ReadOrder::SmallestFirst,
+                ..Default::default()
+            },
+        );
+        let read = archive
+            .verify_iter()
+            .map(|result| Ok(result?.rel_path().to_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(read, ["b", "c", "a"].map(PathBuf::from));
+        Ok(())
+    }
+
+    #[test]
+    fn state_names_chosen_by_caller() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("update-state-names");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("a"), "a")?;
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        let report = archive.update(&UpdateOptions {
+            state_file: Some(PathBuf::from("snapshot-2.state")),
+            ..Default::default()
+        })?;
+        assert_eq!(report.state_path, state_dir.join("snapshot-2.state"));
+
+        // Sorts before the first one, so it has to be named as the previous state
+        std::fs::write(files_dir.join("a"), "changed")?;
+        let report = archive.update(&UpdateOptions {
+            previous_state: Some(PathBuf::from("snapshot-2.state")),
+            state_file: Some(PathBuf::from("snapshot-1.state")),
+            ..Default::default()
+        })?;
+        assert_eq!(report.modified, vec![PathBuf::from("a")]);
+        assert!(state_dir.join("snapshot-1.modified").exists());
+
+        assert!(archive
+            .update(&UpdateOptions {
+                state_file: Some(PathBuf::from("snapshot-2.state")),
+                ..Default::default()
+            })
+            .is_err());
+        assert!(archive
+            .update(&UpdateOptions {
+                state_file: Some(PathBuf::from("snapshot-3.txt")),
+                ..Default::default()
+            })
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn root_mounted_below_prefix() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("roots");
+        let files_dir = dir.join("files");
+        let scans_dir = dir.join("scans");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(files_dir.join("scans"))?;
+        std::fs::create_dir_all(&scans_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("a"), "a")?;
+        // Hidden by the root, like a file below a mount point
+        std::fs::write(files_dir.join("scans/hidden"), "hidden")?;
+        std::fs::write(scans_dir.join("b"), "b")?;
+        let archive = Archive::new(
+            &state_dir,
+            &files_dir,
+            ArchiveOptions {
+                roots: vec![format!("scans={}", scans_dir.display()).parse()?],
+                ..Default::default()
+            },
+        );
+        archive.update(&UpdateOptions::default())?;
+        let (_, files) = read_state(&state_dir)?;
+        let mut paths = files.into_keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, vec![PathBuf::from("a"), PathBuf::from("scans/b")]);
+
+        std::fs::write(scans_dir.join("b"), "B")?;
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert_eq!(report.modified, vec![PathBuf::from("scans/b")]);
+        let report = archive.update(&UpdateOptions {
+            paths: vec![PathBuf::from("scans")],
+            ..Default::default()
+        })?;
+        assert_eq!(report.modified, vec![PathBuf::from("scans/b")]);
+        assert_eq!(report.kept_outside_paths, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn paths_matched_ignoring_case() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("case");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(files_dir.join("Photos"))?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("Photos/IMG_1.JPG"), "1")?;
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+        // Like a restore onto a filesystem that changed the case
+        std::fs::rename(files_dir.join("Photos"), files_dir.join("photos"))?;
+
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert_eq!(report.missing, vec![PathBuf::from("Photos/IMG_1.JPG")]);
+        assert_eq!(
+            report.not_in_archive,
+            vec![PathBuf::from("photos/IMG_1.JPG")]
+        );
+        let report = archive.verify(&VerifyOptions {
+            case_insensitive_paths: true,
+            ..Default::default()
+        })?;
+        assert!(report.is_ok());
+        assert_eq!(report.stats.files_checked, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_written_before_reading() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("plan-run");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::write(files_dir.join("changed"), "a")?;
+        std::fs::write(files_dir.join("same"), "a")?;
+        Archive::new(&state_dir, &files_dir, ArchiveOptions::default())
+            .update(&UpdateOptions::default())?;
+        std::fs::write(files_dir.join("changed"), "longer")?;
+        std::fs::write(files_dir.join("new"), "new")?;
+
+        let plan_path = dir.join("plan");
+        let archive = Archive::new(
+            &state_dir,
+            &files_dir,
+            ArchiveOptions {
+                plan: Some(plan_path.clone()),
+                plan_only: true,
+                ..Default::default()
+            },
+        );
+        let plan = archive.update(&UpdateOptions::default())?.plan.unwrap();
+        assert_eq!((plan.files, plan.bytes), (2, 9));
+        assert_eq!(
+            std::fs::read_to_string(&plan_path)?,
+            "# 2 files, 9 bytes to read\nchanged # metadata changed, 6 bytes\nnew # new, 3 bytes\n"
+        );
+        assert_eq!(std::fs::read_dir(&state_dir)?.count(), 5);
+
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert_eq!(report.plan.unwrap().files, 3);
+        assert_eq!(report.stats.files_checked, 0);
+        assert!(std::fs::read_to_string(&plan_path)?.contains("same # reverify, 1 bytes"));
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct PassObserver {
+        events: Mutex<Vec<&'static str>>,
+    }
+
+    impl Observer for PassObserver {
+        fn on_file_start(&self, _rel_path: &Path) {
+            self.events.lock().unwrap().push("read");
+        }
+        fn on_metadata_pass(&self, _pass: &MetadataPass) {
+            self.events.lock().unwrap().push("pass");
+        }
+    }
+
+    #[test]
+    fn metadata_pass_before_reads() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("metadata-first");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["same", "grown", "gone"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        Archive::new(&state_dir, &files_dir, ArchiveOptions::default())
+            .update(&UpdateOptions::default())?;
+        std::fs::write(files_dir.join("grown"), "grown a lot")?;
+        std::fs::remove_file(files_dir.join("gone"))?;
+        std::fs::write(files_dir.join("added"), "added")?;
+
+        let observer = Arc::new(PassObserver::default());
+        let report = Archive::new(&state_dir, &files_dir, ArchiveOptions::default())
+            .with_observer(observer.clone())
+            .verify(&VerifyOptions {
+                metadata_first: true,
+                ..Default::default()
+            })?;
+        assert_eq!(
+            report.metadata_pass,
+            Some(MetadataPass {
+                missing: vec![PathBuf::from("gone")],
+                new: vec![PathBuf::from("added")],
+                changed: vec![PathBuf::from("grown")],
+            })
+        );
+        assert_eq!(report.modified, vec![PathBuf::from("grown")]);
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            ["pass", "read", "read", "read"]
+        );
+        Ok(())
+    }
+
+    /// Appends to a file whenever it's about to be read, like a program still writing it
+    struct AppendingObserver {
+        path: PathBuf,
+        appends_left: AtomicUsize,
+    }
+
+    impl Observer for AppendingObserver {
+        fn on_file_start(&self, rel_path: &Path) {
+            let appends_left =
+                self.appends_left
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+            if rel_path == Path::new("log") && appends_left.is_ok() {
+                let mut contents = std::fs::read(&self.path).unwrap();
+                contents.extend_from_slice(b" more");
+                std::fs::write(&self.path, contents).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn growing_files() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("growing");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        let log = files_dir.join("log");
+        std::fs::write(&log, "log")?;
+        let run = |growing_files, appends| {
+            Archive::new(
+                &state_dir,
+                &files_dir,
+                ArchiveOptions {
+                    growing_files,
+                    ..Default::default()
+                },
+            )
+            .with_observer(Arc::new(AppendingObserver {
+                path: log.clone(),
+                appends_left: AtomicUsize::new(appends),
+            }))
+            .update(&UpdateOptions::default())
+        };
+        let recorded_len = || -> Result<u64, Box<dyn std::error::Error>> {
+            Ok(read_state(&state_dir)?.1[Path::new("log")].len)
+        };
+
+        // Read again once it stopped growing
+        let report = run(GrowingFiles::RetryLater, 1)?;
+        assert!(report.growing.is_empty());
+        assert_eq!(recorded_len()?, 8);
+
+        // Skipped if it keeps growing, the state keeps what it had
+        std::fs::write(&log, "log rotated")?;
+        let report = run(GrowingFiles::RetryLater, 2)?;
+        assert_eq!(report.growing, vec![PathBuf::from("log")]);
+        assert_eq!(recorded_len()?, 8);
+
+        let report = run(GrowingFiles::Record, 1)?;
+        assert_eq!(report.growing, vec![PathBuf::from("log")]);
+        assert_eq!(recorded_len()?, 26);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn attribute_changes_of_read_and_unread_files() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::file_info::clear_immutable;
+
+        let dir = TestDir::new("attribute-changes");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["read", "unread"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+
+        // Only the mtime of one of them changes, so only that one is read
+        let read = files_dir.join("read");
+        std::fs::File::options()
+            .write(true)
+            .open(&read)?
+            .set_modified(SystemTime::UNIX_EPOCH)?;
+        let immutable = ["read", "unread"].map(|name| set_immutable(&files_dir.join(name)));
+        let report = archive.update(&UpdateOptions::default());
+        for name in ["read", "unread"] {
+            let _ = clear_immutable(&files_dir.join(name));
+        }
+        if immutable.iter().any(|set| set.is_err()) {
+            // Needs CAP_LINUX_IMMUTABLE and a filesystem with the flag
+            return Ok(());
+        }
+        let report = report?;
+        assert_eq!(report.stats.files_read, 1);
+        assert_eq!(
+            report
+                .attribute_changes
+                .iter()
+                .map(|change| (change.path.to_str().unwrap(), change.current.immutable))
+                .collect::<Vec<_>>(),
+            [("read", true), ("unread", true)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tiers_settle_files_before_reading() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("update-quick");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        let video = files_dir.join("video");
+        let len = 3 * crate::file_check::QUICK_LEN;
+        std::fs::write(&video, vec![1; len])?;
+        std::fs::write(files_dir.join("small"), "small")?;
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+
+        // Damaged in the middle, without changing size or mtime
+        let mtime = video.metadata()?.modified()?;
+        let mut contents = vec![1; len];
+        contents[len / 2] = 0;
+        std::fs::write(&video, &contents)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&video)?
+            .set_modified(mtime)?;
+        let quick = VerifyOptions {
+            tiers: vec![CheckTier::Size, CheckTier::Quick],
+            ..Default::default()
+        };
+        let report = archive.verify(&quick)?;
+        assert!(report.is_ok());
+        // The small file has no quick digest and is read completely
+        assert_eq!(
+            report.tier_stats,
+            vec![
+                TierStats {
+                    tier: CheckTier::Size,
+                    passed: 2,
+                    escalated: 0,
+                },
+                TierStats {
+                    tier: CheckTier::Quick,
+                    passed: 1,
+                    escalated: 1,
+                },
+            ]
+        );
+        assert_eq!(
+            (report.stats.files_read, report.stats.files_unchanged),
+            (1, 2)
+        );
+        assert!(!archive.verify(&VerifyOptions::default())?.is_ok());
+
+        contents[len - 1] = 0;
+        std::fs::write(&video, &contents)?;
+        assert_eq!(
+            archive.verify(&quick)?.modified,
+            vec![PathBuf::from("video")]
+        );
+
+        // Not counted by any tier, but as unreadable
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            archive.update(&UpdateOptions::default())?;
+            std::fs::set_permissions(&video, std::fs::Permissions::from_mode(0o000))?;
+            if std::fs::File::open(&video).is_ok() {
+                // Root reads it anyway
+                return Ok(());
+            }
+            let report = archive.verify(&quick)?;
+            assert_eq!(report.read_errors.len(), 1);
+            assert_eq!(
+                report
+                    .tier_stats
+                    .iter()
+                    .map(|t| (t.passed, t.escalated))
+                    .collect::<Vec<_>>(),
+                [(1, 0), (0, 1)]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn moved_files_only_recorded_if_kept() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("moved");
+        let files_dir = dir.join("files");
+        std::fs::create_dir_all(&files_dir)?;
+        for i in 0..10 {
+            std::fs::write(files_dir.join(i.to_string()), i.to_string())?;
+        }
+        let archives = ["dropped", "kept"].map(|name| {
+            let state_dir = dir.join(name);
+            std::fs::create_dir_all(&state_dir).unwrap();
+            Archive::new(state_dir, &files_dir, ArchiveOptions::default())
+        });
+        for archive in &archives {
+            archive.update(&UpdateOptions::default())?;
+        }
+
+        std::fs::rename(files_dir.join("0"), files_dir.join("moved"))?;
+        let report = archives[0].update(&UpdateOptions::default())?;
+        assert!(report.missing.is_empty());
+        let report = archives[1].update(&UpdateOptions {
+            keep_duplicates: true,
+            ..Default::default()
+        })?;
+        assert_eq!(report.missing, vec![PathBuf::from("0")]);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_files_kept_for_a_while() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let missing = |path: &str, missing_since: Option<SystemTime>| {
+            FileCheckResult::Missing(FileInfo {
+                missing_since,
+                ..FileInfo::new(path, [0; 32], 1)
+            })
+        };
+        let files = vec![
+            missing("new", None),
+            missing("old", Some(now - day * 200)),
+            missing("recent", Some(now - day)),
+        ];
+        let (results, still_missing, forgotten) = keep_missing(files.clone(), Some(day * 180), now);
+        assert_eq!((still_missing, forgotten), (2, 1));
+        let results = results
+            .iter()
+            .map(|f| match f {
+                FileCheckResult::Missing(fi) => format!("- {}", fi.rel_path.display()),
+                FileCheckResult::Unmodifed(fi) => {
+                    assert!(fi.missing_since.is_some());
+                    format!("  {}", fi.rel_path.display())
+                }
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(results, ["- new", "  new", "  recent"]);
+
+        // Without a grace, the ones already kept aren't missing again
+        let (results, still_missing, forgotten) = keep_missing(files, None, now);
+        assert_eq!((still_missing, forgotten), (2, 0));
+        let results = results
+            .iter()
+            .map(|f| match f {
+                FileCheckResult::Missing(fi) => format!("- {}", fi.rel_path.display()),
+                _ => format!("  {}", f.rel_path().display()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(results, ["- new", "  old", "  recent"]);
+    }
+
+    #[test]
+    fn kept_missing_files_not_missing_again() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("kept-missing");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for name in ["a", "b"] {
+            std::fs::write(files_dir.join(name), name)?;
+        }
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        archive.update(&UpdateOptions::default())?;
+        std::fs::remove_file(files_dir.join("b"))?;
+        let report = archive.update(&UpdateOptions {
+            forget_missing_after: Some(Duration::from_secs(24 * 60 * 60)),
+            force: true,
+            ..Default::default()
+        })?;
+        assert_eq!((report.missing.len(), report.still_missing), (1, 1));
+
+        // Kept as it is without the flag as well
+        let report = archive.update(&UpdateOptions::default())?;
+        assert_eq!((report.missing.len(), report.still_missing), (0, 1));
+        let report = archive.verify(&VerifyOptions::default())?;
+        assert!(report.is_ok());
+        assert!(report.missing.is_empty());
+        assert_eq!(
+            report.still_missing.keys().collect::<Vec<_>>(),
+            [Path::new("b")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_states_are_identical() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("canonical");
+        let files_dir = dir.join("files");
+        std::fs::create_dir_all(files_dir.join("sub"))?;
+        for i in 0..20 {
+            std::fs::write(files_dir.join(format!("sub/{}", i)), i.to_string())?;
+            std::fs::write(files_dir.join(i.to_string()), "same")?;
+        }
+        let mut written = Vec::new();
+        // Neither the order files are read in nor the one they're found in matters
+        for (threads, walk_order) in [(1, WalkOrder::Sorted), (4, WalkOrder::Fast)] {
+            let state_dir = dir.join(format!("state-{}", threads));
+            std::fs::create_dir_all(&state_dir)?;
+            let options = ArchiveOptions {
+                threads: Some(threads),
+                walk_order,
+                ..Default::default()
+            };
+            let report = Archive::new(&state_dir, &files_dir, options).update(&UpdateOptions {
+                canonical: true,
+                ..Default::default()
+            })?;
+            written.push(std::fs::read(report.state_path)?);
+        }
+        assert_eq!(written[0], written[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_scopes() {
+        let path = Path::new("originals/2023/a.jpg");
+        assert_eq!(
+            DedupeScope::SameDirectory.scope(path),
+            Path::new("originals/2023")
+        );
+        assert_eq!(
+            DedupeScope::SameTopLevel.scope(path),
+            Path::new("originals")
+        );
+        assert_eq!(DedupeScope::Anywhere.scope(path), Path::new(""));
+        assert_eq!(
+            DedupeScope::SameTopLevel.scope(Path::new("a.jpg")),
+            Path::new("")
+        );
+        assert_eq!(
+            "same-top-level".parse::<DedupeScope>(),
+            Ok(DedupeScope::SameTopLevel)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::history::history;
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Number of directories listed as largest growth contributors
+const TOP_CONTRIBUTORS: usize = 10;
+
+/// Number of files and bytes per directory
+type Totals = HashMap<PathBuf, (i64, i64)>;
+
+/// Sums up files and bytes per directory, up to depth components deep
+fn totals_by_directory(files: &[FileInfo], depth: usize) -> Totals {
+    let mut totals = Totals::new();
+    for fi in files {
+        let mut components = fi.rel_path.components();
+        // Files directly in the archive count towards the archive itself
+        components.next_back();
+        let directory: PathBuf = components.take(depth).collect();
+        let total = totals.entry(directory).or_default();
+        total.0 += 1;
+        total.1 += fi.len as i64;
+    }
+    totals
+}
+
+fn gib(bytes: i64) -> f64 {
+    bytes as f64 / 1024.0 / 1024.0 / 1024.0
+}
+
+/// Prints the number of files and bytes of every run and what grew the most
+///
+/// With csv, only the numbers per run are printed as CSV for further use.
+pub fn run(state_dir: &Path, csv: bool, depth: usize) -> Result<()> {
+    let mut first: Option<(String, Totals)> = None;
+    let mut latest: Option<(String, Vec<FileInfo>)> = None;
+    let (mut previous_files, mut previous_bytes) = (0, 0);
+
+    if csv {
+        println!("run,files,bytes,files_delta,bytes_delta");
+    }
+    for entry in history(state_dir)? {
+        let entry = entry?;
+        let files = entry.state.files.len() as i64;
+        let bytes = entry.state.files.iter().map(|fi| fi.len as i64).sum();
+        if csv {
+            println!(
+                "{},{},{},{},{}",
+                entry.name,
+                files,
+                bytes,
+                files - previous_files,
+                bytes - previous_bytes
+            );
+        } else {
+            println!(
+                "{}: {} files ({:+}), {:.1} GiB ({:+.1} GiB)",
+                entry.name,
+                files,
+                files - previous_files,
+                gib(bytes),
+                gib(bytes - previous_bytes)
+            );
+        }
+        (previous_files, previous_bytes) = (files, bytes);
+        if first.is_none() {
+            first = Some((
+                entry.name.clone(),
+                totals_by_directory(&entry.state.files, depth),
+            ));
+        }
+        latest = Some((entry.name, entry.state.files));
+    }
+
+    let (Some((first_name, first_totals)), Some((latest_name, latest_files))) = (first, latest)
+    else {
+        println!("no states found in {:?}", state_dir);
+        return Ok(());
+    };
+    if csv || first_name == latest_name {
+        return Ok(());
+    }
+    let mut growth = totals_by_directory(&latest_files, depth);
+    for (directory, (files, bytes)) in first_totals {
+        let total = growth.entry(directory).or_default();
+        total.0 -= files;
+        total.1 -= bytes;
+    }
+    let mut growth = growth
+        .into_iter()
+        .filter(|(_, total)| *total != (0, 0))
+        .collect::<Vec<_>>();
+    growth.sort_by_key(|(directory, (_, bytes))| (std::cmp::Reverse(*bytes), directory.clone()));
+
+    println!("largest growth from {} to {}:", first_name, latest_name);
+    for (directory, (files, bytes)) in growth.iter().take(TOP_CONTRIBUTORS) {
+        println!(
+            "└ {}: {:+.1} GiB ({:+} files)",
+            if directory.as_os_str().is_empty() {
+                ".".into()
+            } else {
+                directory.to_string_lossy()
+            },
+            gib(*bytes),
+            files
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_by_directory_depth() {
+        let files = ["a.jpg", "2023/b.jpg", "2023/03/c.jpg", "2024/d.jpg"]
+            .map(|path| FileInfo::new(path, [0; 32], 10));
+        let totals = totals_by_directory(&files, 1);
+        assert_eq!(totals[Path::new("")], (1, 10));
+        assert_eq!(totals[Path::new("2023")], (2, 20));
+        assert_eq!(totals[Path::new("2024")], (1, 10));
+        assert_eq!(
+            totals_by_directory(&files, 2)[Path::new("2023/03")],
+            (1, 10)
+        );
+    }
+}
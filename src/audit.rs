@@ -0,0 +1,99 @@
+use rusty_archive::deleted;
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::history::{diff, history};
+
+use crate::time_format::TimeFormat;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Name of a state followed by its time, like `20230315T132501Z (94 days ago, 1678886701)`
+fn described(name: &str, time_format: TimeFormat, now: SystemTime) -> String {
+    match time_format.format_state_name(name, now) {
+        Some(time) => format!("{} ({})", name, time),
+        None => name.to_owned(),
+    }
+}
+
+/// Prints the history of the archive, one line per run
+///
+/// With paths, the runs every path was first and last listed in are
+/// printed as well.
+pub fn run(state_dir: &Path, paths: bool, time_format: TimeFormat) -> Result<()> {
+    let now = SystemTime::now();
+    let mut names = Vec::new();
+    // Indexes into names of the first and last run listing a path
+    let mut appearances: BTreeMap<PathBuf, (usize, usize)> = BTreeMap::new();
+    let mut previous: Vec<FileInfo> = Vec::new();
+    let mut previous_bytes = 0;
+
+    for entry in history(state_dir)? {
+        let entry = entry?;
+        let files = entry.state.files;
+        let bytes: u64 = files.iter().map(|fi| fi.len).sum();
+        let diff = diff(&previous, &files);
+        let label = match &entry.state.header.label {
+            Some(label) => format!(" '{}'", label),
+            None => String::new(),
+        };
+        println!(
+            "{}{}: {} files ({:.1} GiB, {:+.1} GiB), {} added, {} modified, {} missing",
+            described(&entry.name, time_format, now),
+            label,
+            files.len(),
+            bytes as f64 / 1024.0 / 1024.0 / 1024.0,
+            (bytes as f64 - previous_bytes as f64) / 1024.0 / 1024.0 / 1024.0,
+            diff.added.len(),
+            diff.modified.len(),
+            diff.missing.len(),
+        );
+
+        if paths {
+            let run = names.len();
+            for fi in &files {
+                appearances
+                    .entry(fi.rel_path.clone())
+                    .or_insert((run, run))
+                    .1 = run;
+            }
+        }
+        names.push(entry.name);
+        previous = files;
+        previous_bytes = bytes;
+    }
+    if names.is_empty() {
+        println!("no states found in {:?}", state_dir);
+        return Ok(());
+    }
+    println!(
+        "{} run(s) from {} to {}",
+        names.len(),
+        names[0],
+        names[names.len() - 1]
+    );
+
+    let tombstones = deleted::read(state_dir)?;
+    if !tombstones.is_empty() {
+        println!(
+            "{} file(s) were accepted as deleted, see {:?}",
+            tombstones.len(),
+            state_dir.join(deleted::DELETED_LOG)
+        );
+    }
+
+    let latest = names.len() - 1;
+    for (path, (first, last)) in &appearances {
+        println!(
+            "{}: first listed {}, {}",
+            path.to_string_lossy(),
+            described(&names[*first], time_format, now),
+            if *last == latest {
+                "still listed".to_owned()
+            } else {
+                format!("last listed {}", described(&names[*last], time_format, now))
+            }
+        );
+    }
+    Ok(())
+}
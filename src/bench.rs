@@ -0,0 +1,176 @@
+use crate::cli::CommandlineOptions;
+use rusty_archive::file_check::{hash_file, ReadOptions};
+use rusty_archive::walk::list_files;
+use rusty_archive::HashAlgorithm;
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::path::Path;
+use std::time::Instant;
+
+/// Amount of data hashed in memory per algorithm
+const IN_MEMORY_SIZE: usize = 256 * 1024 * 1024;
+
+/// Numbers of threads to try when reading files
+const THREAD_COUNTS: [usize; 6] = [1, 2, 4, 8, 16, 32];
+
+/// Fraction of the best throughput a lower thread count needs to reach to be recommended
+const GOOD_ENOUGH: f64 = 0.9;
+
+fn mib_per_s(bytes: u64, start: Instant) -> f64 {
+    bytes as f64 / 1024.0 / 1024.0 / start.elapsed().as_secs_f64()
+}
+
+/// Shuffles items in a reproducible way, so samples are spread over the whole directory
+fn shuffle<T>(items: &mut [T]) {
+    // xorshift64, good enough for picking sample files
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+pub fn run(
+    opts: &CommandlineOptions,
+    hash_algorithm: HashAlgorithm,
+    sample_size: u64,
+    directory: Option<&Path>,
+) -> Result<()> {
+    let data = (0..IN_MEMORY_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+    println!("hashing {} MiB in memory:", IN_MEMORY_SIZE / 1024 / 1024);
+    for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        let start = Instant::now();
+        let mut hasher = algorithm.hasher();
+        hasher.update(&data);
+        hasher.finalize();
+        println!(
+            "└ {} ({}): {:.0} MiB/s",
+            algorithm,
+            algorithm.implementation(),
+            mib_per_s(data.len() as u64, start)
+        );
+
+        if algorithm.is_parallel() {
+            let start = Instant::now();
+            let mut hasher = algorithm.hasher();
+            hasher.update_parallel(&data);
+            hasher.finalize();
+            println!(
+                "└ {} ({}, {} threads): {:.0} MiB/s",
+                algorithm,
+                algorithm.implementation(),
+                rayon::current_num_threads(),
+                mib_per_s(data.len() as u64, start)
+            );
+        }
+    }
+    drop(data);
+
+    let directory = match directory {
+        Some(directory) => directory,
+        None => {
+            println!("pass a DIRECTORY to measure reading files and get a recommended --threads");
+            return Ok(());
+        }
+    };
+
+    let archive_options = opts.archive_options();
+    let mut files = list_files(
+        directory,
+        &archive_options.excludes,
+        archive_options.links,
+        archive_options.walk_order,
+    )
+    .map(|f| {
+        let f = f?;
+        let metadata = f
+            .metadata()
+            .with_context(|| format!("Unable to get metadata for {:?}", f.path()))?;
+        Ok((f.into_path(), metadata))
+    })
+    .collect::<Result<Vec<_>>>()?;
+    shuffle(&mut files);
+    let mut files = files.into_iter();
+
+    let read_options = ReadOptions {
+        direct_io: opts.direct_io,
+        hash_algorithm,
+        ..Default::default()
+    };
+    let sample_bytes = sample_size * 1024 * 1024;
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get()) * 2;
+
+    println!(
+        "reading {} MiB of files from {:?} per number of threads:",
+        sample_size, directory
+    );
+    if !opts.direct_io {
+        println!("└ files read recently may be cached, pass --direct-io to avoid this");
+    }
+    let mut results = Vec::new();
+    for threads in THREAD_COUNTS.into_iter().filter(|t| *t <= max_threads) {
+        let mut sample = Vec::new();
+        let mut bytes = 0;
+        for file in files.by_ref() {
+            bytes += file.1.len();
+            sample.push(file);
+            if bytes >= sample_bytes {
+                break;
+            }
+        }
+        if sample.is_empty() {
+            println!(
+                "└ not enough files to try more than {} thread(s)",
+                threads / 2
+            );
+            break;
+        }
+
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+        let start = Instant::now();
+        let bytes_read = pool.install(|| {
+            sample
+                .par_iter()
+                .map(|(path, metadata)| {
+                    hash_file(path, path, metadata, &read_options)
+                        .map(|fi| fi.len)
+                        .with_context(|| format!("Failed to read {:?}", path))
+                })
+                .try_reduce(|| 0, |a, b| Ok(a + b))
+        })?;
+        let throughput = mib_per_s(bytes_read, start);
+        println!(
+            "└ {} thread(s): {:.0} MiB/s ({} files, {:.1} GiB)",
+            threads,
+            throughput,
+            sample.len(),
+            bytes_read as f64 / 1024.0 / 1024.0 / 1024.0
+        );
+        results.push((threads, throughput));
+    }
+
+    let best = results.iter().map(|(_, t)| *t).fold(0.0, f64::max);
+    if let Some((threads, _)) = results.iter().find(|(_, t)| *t >= best * GOOD_ENOUGH) {
+        println!("recommended: --threads {}", threads);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffle_keeps_all_items() {
+        let mut items = (0..100).collect::<Vec<_>>();
+        shuffle(&mut items);
+        assert_ne!(items, (0..100).collect::<Vec<_>>());
+        items.sort();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+    }
+}
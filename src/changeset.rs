@@ -0,0 +1,312 @@
+use crate::file_check::{FileCheckResult, FileCheckResultModified};
+use crate::file_info::FileInfo;
+use crate::hash::HashAlgorithm;
+use crate::history::diff;
+use crate::state::{new_run_id, read_state, write_state, StateHeader};
+
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What changed between two states, to bring the state of another copy up to date
+///
+/// Copies in other places only exchange this instead of whole states.
+/// Stored one entry per line after a header naming the states, as the
+/// state line of the file prefixed with what happened to it:
+///
+/// ```text
+/// # hash: sha256
+/// # from: 20240101 031500.state
+/// # to: 20240301 031500.state
+/// + <state line of a new file>
+/// ~ <digest before> <state line of a changed file>
+/// - <state line of a removed file, as the older state has it>
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changeset {
+    pub hash_algorithm: HashAlgorithm,
+    /// File name of the older state
+    pub from: String,
+    /// File name of the newer state
+    pub to: String,
+    pub added: Vec<FileInfo>,
+    /// The digest before and the newer entry
+    pub changed: Vec<([u8; 32], FileInfo)>,
+    pub removed: Vec<FileInfo>,
+}
+
+fn parse_digest(hexdigest: &str) -> Option<[u8; 32]> {
+    let mut digest = [0_u8; 32];
+    hex::decode_to_slice(hexdigest, &mut digest).ok()?;
+    Some(digest)
+}
+
+impl Changeset {
+    /// Compares two lists of files sorted by path, from and to are the states they're from
+    pub fn between(
+        hash_algorithm: HashAlgorithm,
+        from: &Path,
+        older: &[FileInfo],
+        to: &Path,
+        newer: &[FileInfo],
+    ) -> Changeset {
+        let diff = diff(older, newer);
+        let name = |path: &Path| {
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        };
+        Changeset {
+            hash_algorithm,
+            from: name(from),
+            to: name(to),
+            added: diff.added.into_iter().cloned().collect(),
+            changed: diff
+                .modified
+                .into_iter()
+                .map(|(o, n)| (o.digest, n.clone()))
+                .collect(),
+            removed: diff.missing.into_iter().cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
+        writeln!(to, "# hash: {}", self.hash_algorithm)?;
+        writeln!(to, "# from: {}", self.from)?;
+        writeln!(to, "# to: {}", self.to)?;
+        for fi in &self.added {
+            write!(to, "+ ")?;
+            fi.write(to)?;
+        }
+        for (was, fi) in &self.changed {
+            write!(to, "~ {} ", hex::encode(was))?;
+            fi.write(to)?;
+        }
+        for fi in &self.removed {
+            write!(to, "- ")?;
+            fi.write(to)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse(contents: &str) -> Result<Changeset> {
+        let mut header = StateHeader::default();
+        let (mut from, mut to) = (None, None);
+        let (mut added, mut changed, mut removed) = (Vec::new(), Vec::new(), Vec::new());
+        for line in contents.lines() {
+            let invalid = || format!("invalid changeset line: '{}'", line);
+            if let Some(name) = line.strip_prefix("# from: ") {
+                from = Some(name.to_owned());
+            } else if let Some(name) = line.strip_prefix("# to: ") {
+                to = Some(name.to_owned());
+            } else if !header.parse_line(line)? {
+                match line.split_at_checked(2) {
+                    Some(("+ ", entry)) => added.push(FileInfo::parse(entry)?),
+                    Some(("~ ", entry)) => {
+                        let (was, entry) = entry.split_once(' ').with_context(invalid)?;
+                        changed.push((
+                            parse_digest(was).with_context(invalid)?,
+                            FileInfo::parse(entry)?,
+                        ));
+                    }
+                    Some(("- ", entry)) => removed.push(FileInfo::parse(entry)?),
+                    _ => return Err(anyhow::Error::msg(invalid())),
+                }
+            }
+        }
+        let missing = |what| format!("the changeset doesn't name the {} state", what);
+        Ok(Changeset {
+            hash_algorithm: header.hash_algorithm,
+            from: from.with_context(|| missing("older"))?,
+            to: to.with_context(|| missing("newer"))?,
+            added,
+            changed,
+            removed,
+        })
+    }
+}
+
+/// What applying a changeset did
+#[derive(Debug, Default, PartialEq)]
+pub struct Applied {
+    /// The state written, None if the state had all changes already
+    pub state_path: Option<PathBuf>,
+    pub added: u64,
+    pub changed: u64,
+    pub removed: u64,
+    /// Entries the state had already, like when a changeset is applied twice
+    pub already: u64,
+}
+
+/// Writes a new state of the state directory with the changes of a changeset
+///
+/// Every file changed or removed has to have the digest the changeset
+/// found before, and every new file has to be new, otherwise the copies
+/// drifted apart and nothing is written. The entries are taken over as
+/// they are, the next update of the copy reads the files again if their
+/// modification times differ.
+pub fn apply(state_dir: &Path, changeset: &Changeset) -> Result<Applied> {
+    let (mut header, mut files) = read_state(state_dir)?;
+    if header.hash_algorithm != changeset.hash_algorithm {
+        return Err(anyhow::Error::msg(format!(
+            "the changeset uses {}, but the state uses {}",
+            changeset.hash_algorithm, header.hash_algorithm
+        )));
+    }
+    let mut applied = Applied::default();
+    let mut conflicts = Vec::new();
+    let mut results = Vec::new();
+    for fi in &changeset.added {
+        match files.get(&fi.rel_path) {
+            Some(current) if current.digest == fi.digest => applied.already += 1,
+            Some(_) => conflicts.push(format!(
+                "{:?} is new, but the state has it already",
+                fi.rel_path
+            )),
+            None => {
+                applied.added += 1;
+                results.push(FileCheckResult::New(fi.clone()));
+            }
+        }
+    }
+    for (was, fi) in &changeset.changed {
+        match files.remove(&fi.rel_path) {
+            Some(current) if current.digest == *was => {
+                applied.changed += 1;
+                results.push(FileCheckResult::Modified(Box::new(
+                    FileCheckResultModified {
+                        previous: current,
+                        current: fi.clone(),
+                    },
+                )));
+            }
+            Some(current) if current.digest == fi.digest => {
+                applied.already += 1;
+                files.insert(current.rel_path.clone(), current);
+            }
+            Some(current) => {
+                conflicts.push(format!(
+                    "{:?} changed, but from other contents",
+                    fi.rel_path
+                ));
+                files.insert(current.rel_path.clone(), current);
+            }
+            None => conflicts.push(format!(
+                "{:?} changed, but the state doesn't have it",
+                fi.rel_path
+            )),
+        }
+    }
+    for fi in &changeset.removed {
+        match files.remove(&fi.rel_path) {
+            Some(current) if current.digest == fi.digest => {
+                applied.removed += 1;
+                results.push(FileCheckResult::Missing(current));
+            }
+            Some(current) => {
+                conflicts.push(format!(
+                    "{:?} was removed, but had other contents",
+                    fi.rel_path
+                ));
+                files.insert(current.rel_path.clone(), current);
+            }
+            None => applied.already += 1,
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "the state doesn't match the state the changeset starts from ({}), nothing applied:\n{}",
+            changeset.from,
+            conflicts.join("\n")
+        )));
+    }
+    if results.is_empty() {
+        return Ok(applied);
+    }
+
+    header.run_id = Some(new_run_id());
+    header.label = None;
+    // Files changed or removed were taken out of files already
+    let mut results = files
+        .into_values()
+        .map(FileCheckResult::Unmodifed)
+        .chain(results)
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| a.rel_path().cmp(b.rel_path()));
+    applied.state_path = Some(write_state(
+        state_dir,
+        &header,
+        results.into_iter().map(Ok),
+    )?);
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn changeset_round_trip_and_apply() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("changeset");
+        let older = vec![
+            FileInfo::new("a", [1; 32], 1),
+            FileInfo::new("b", [2; 32], 1),
+            FileInfo::new("c 2", [3; 32], 1),
+        ];
+        let newer = vec![
+            FileInfo::new("b", [4; 32], 1),
+            FileInfo::new("c 2", [3; 32], 1),
+            FileInfo::new("d", [5; 32], 1),
+        ];
+        let changeset = Changeset::between(
+            HashAlgorithm::Sha256,
+            Path::new("/state/20240101 031500.state"),
+            &older,
+            Path::new("20240301 031500.state"),
+            &newer,
+        );
+        assert_eq!(changeset.from, "20240101 031500.state");
+        let mut written = Vec::new();
+        changeset.write(&mut written)?;
+        assert_eq!(Changeset::parse(std::str::from_utf8(&written)?)?, changeset);
+        assert!(Changeset::parse("# hash: sha256\n? a\n").is_err());
+
+        // The copy has the older state, in a state of its own
+        let header = StateHeader::default();
+        write_state(
+            &dir,
+            &header,
+            older.iter().cloned().map(FileCheckResult::New).map(Ok),
+        )?;
+        let applied = apply(&dir, &changeset)?;
+        assert_eq!(
+            (
+                applied.added,
+                applied.changed,
+                applied.removed,
+                applied.already
+            ),
+            (1, 1, 1, 0)
+        );
+        let (_, files) = read_state(&dir)?;
+        let mut files = files.into_values().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        assert_eq!(files, newer);
+        // Applied already
+        let applied = apply(&dir, &changeset)?;
+        assert_eq!((applied.state_path, applied.already), (None, 3));
+
+        // The copy drifted apart
+        let mut other = changeset.clone();
+        other.changed[0].0 = [9; 32];
+        other.changed[0].1.digest = [8; 32];
+        assert!(apply(&dir, &other).is_err());
+        Ok(())
+    }
+}
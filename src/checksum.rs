@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Path of the checksum sidecar of a state, .modified or .missing file
+///
+/// The sidecar uses the format of `sha256sum`, so it can be checked
+/// with `sha256sum -c` as well.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = OsString::from(path.as_os_str());
+    sidecar.push(".sha256");
+    PathBuf::from(sidecar)
+}
+
+pub fn write_sidecar(path: &Path, digest: &[u8; 32]) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut f = File::options()
+        .write(true)
+        .create_new(true)
+        .open(sidecar_path(path))?;
+    writeln!(f, "{}  {}", hex::encode(digest), file_name)
+}
+
+/// Compares the digest of a file to its sidecar
+///
+/// Files written by older versions don't have a sidecar, those are
+/// accepted as is.
+pub fn verify_sidecar(path: &Path, digest: &[u8; 32]) -> Result<()> {
+    let sidecar = sidecar_path(path);
+    let contents = match std::fs::read_to_string(&sidecar) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Unable to read checksum {:?}", sidecar))
+        }
+    };
+    let expected = contents
+        .split_whitespace()
+        .next()
+        .with_context(|| format!("invalid checksum file {:?}", sidecar))?;
+    if !expected.eq_ignore_ascii_case(&hex::encode(digest)) {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} doesn't match its checksum in {:?}, it's most likely corrupted",
+            path, sidecar
+        )));
+    }
+    Ok(())
+}
+
+/// Hashes everything written through it
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the digest of everything written
+    pub fn finalize(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the digest of everything read
+    pub fn finalize(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn sidecar_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("checksum");
+        let path = dir.join("state");
+        let mut writer = HashingWriter::new(File::create(&path)?);
+        writer.write_all(b"some state\n")?;
+        write_sidecar(&path, &writer.finalize())?;
+
+        let mut reader = HashingReader::new(File::open(&path)?);
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        verify_sidecar(&path, &reader.finalize())?;
+
+        std::fs::write(&path, "some stale\n")?;
+        let digest: [u8; 32] = Sha256::digest(std::fs::read(&path)?).into();
+        assert!(verify_sidecar(&path, &digest).is_err());
+
+        Ok(())
+    }
+}
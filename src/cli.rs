@@ -1,28 +1,304 @@
+use crate::export_changes::BackupTool;
+use crate::state_diff::DiffFormat;
+use crate::time_format::TimeFormat;
+
 use bpaf::Bpaf;
 use regex::Regex;
+use rusty_archive::annex::Backend;
+use rusty_archive::device_pools::DeviceThreads;
+use rusty_archive::file_check::CheckTier;
+use rusty_archive::roots::{PrefixMap, Root};
+use rusty_archive::state::{NameFormat, StateNames};
+use rusty_archive::stats::ExtensionGroup;
+use rusty_archive::walk::{ContentType, Excludes, Links, WalkOrder};
+use rusty_archive::{
+    ArchiveOptions, Baseline, DedupeScope, GrowingFiles, HashAlgorithm, ReadOrder,
+};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 fn regex(arg: String) -> Result<Regex, regex::Error> {
     Regex::new(&arg)
 }
 
-#[derive(Clone, Debug, Bpaf)]
-/// Hash files in a directory tree
-#[bpaf(options)]
-pub struct Update {}
+fn pattern(arg: String) -> anyhow::Result<Regex> {
+    rusty_archive::review::path_pattern(&arg)
+}
+
+/// Parses sizes like `512M` or `2G`, using binary units
+fn size(arg: String) -> Result<u64, String> {
+    let arg_upper = arg.to_ascii_uppercase();
+    let number = arg_upper.trim_end_matches(['B', 'I']);
+    let (number, multiplier) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1 << 10),
+        Some('M') => (&number[..number.len() - 1], 1 << 20),
+        Some('G') => (&number[..number.len() - 1], 1 << 30),
+        Some('T') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("expected a size like 512M or 2G, got `{}`", arg))
+}
+
+/// Parses durations like `12h`, `180d` or `8w`
+fn duration(arg: String) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration like 12h, 180d or 8w, got `{}`", arg);
+    let (number, unit) = arg.split_at(arg.len().saturating_sub(1));
+    let seconds = match unit {
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    number
+        .parse::<u64>()
+        .map(|n| Duration::from_secs(n * seconds))
+        .map_err(|_| invalid())
+}
+
+/// Parses tiers like `size,quick,full`, full is what happens anyway and may come last
+fn tiers(arg: String) -> Result<Vec<CheckTier>, String> {
+    let mut names = arg.split(',').collect::<Vec<_>>();
+    if names.last() == Some(&"full") {
+        names.pop();
+    }
+    names.into_iter().map(str::parse).collect()
+}
+
+/// Archives are stored in subdirectories named after them
+fn archive_name(arg: String) -> Result<String, String> {
+    if arg.is_empty() || arg.starts_with('.') || arg.contains(['/', '\\']) {
+        return Err(format!(
+            "expected a name without path separators and not starting with a dot, got `{}`",
+            arg
+        ));
+    }
+    Ok(arg)
+}
+
+/// Volume names are stored as a field of the state, so they can't contain spaces
+fn volume_name(arg: String) -> Result<String, String> {
+    if arg.is_empty() || arg.contains(char::is_whitespace) {
+        return Err(format!("expected a name without whitespace, got `{}`", arg));
+    }
+    Ok(arg)
+}
+
+fn extension_group(arg: String) -> Result<ExtensionGroup, String> {
+    let (name, extensions) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=EXT,EXT, got `{}`", arg))?;
+    Ok(ExtensionGroup {
+        name: name.to_owned(),
+        extensions: extensions
+            .split(',')
+            .map(|extension| extension.trim_start_matches('.').to_owned())
+            .collect(),
+    })
+}
+
+fn device_threads(arg: String) -> Result<DeviceThreads, String> {
+    let (path, threads) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATH=THREADS, got `{}`", arg))?;
+    let threads = threads
+        .parse()
+        .map_err(|err| format!("invalid number of threads `{}`: {}", threads, err))?;
+    Ok(DeviceThreads {
+        path: PathBuf::from(path),
+        threads,
+    })
+}
 
 #[derive(Clone, Debug, Bpaf)]
 pub enum Cmd {
+    /// Create a state directory with a profile for an archive
+    ///
+    /// The profile stores the directory of the archive, the hash algorithm
+    /// and the excludes given. Updates of other directories are refused
+    /// and DIRECTORY can be left out for update and verify.
+    #[bpaf(command)]
+    Init {
+        /// algorithm to hash files with: sha256 or blake3 [default: sha256]
+        #[bpaf(argument("ALGORITHM"))]
+        hash: Option<HashAlgorithm>,
+
+        /// keep the latest N states on update, older ones only one per month [default: keep all]
+        ///
+        /// Only states and the files derived from them are removed, the
+        /// .modified and .missing files of every run are kept.
+        #[bpaf(argument("N"))]
+        keep_states: Option<usize>,
+
+        /// time zone to name states in: utc or local [default: utc]
+        ///
+        /// Local names are how states were named before, for scripts
+        /// expecting them. States named either way are read.
+        #[bpaf(argument("ZONE"))]
+        state_names: Option<StateNames>,
+
+        /// template to name states after, like "{timestamp}-{hostname}-{label}.state" [default: {timestamp}.state]
+        ///
+        /// For several hosts writing to a shared state directory, so their
+        /// states don't collide and can be told apart. {hostname} is the
+        /// name of the host up to the first dot, {label} the label given
+        /// with update --label, left out along with the character before
+        /// it if there's none. Templates have to start with {timestamp}.
+        #[bpaf(argument("TEMPLATE"))]
+        state_name_format: Option<NameFormat>,
+
+        /// directory to store the state in, created if it doesn't exist
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// directory of the archive
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: String,
+    },
+
     /// Update the archive state
     #[bpaf(command)]
     Update {
         /// Skip comparison of modification times and sizes and read all files
         read_all_files: bool,
 
+        /// algorithm to hash files with: sha256 or blake3 [default: from init or sha256]
+        ///
+        /// BLAKE3 is a lot faster and reads files of 64 MiB or more in
+        /// ranges hashed by all threads of their device at once, SHA-256
+        /// reads and hashes every file with a single thread.
+        /// This only takes effect when creating a new state, the algorithm
+        /// of an existing state can't be changed.
+        #[bpaf(argument("ALGORITHM"))]
+        hash: Option<HashAlgorithm>,
+
+        /// name of the disk DIRECTORY is on, for archives spread over several disks
+        ///
+        /// Files found are recorded as stored on this volume. Files of other
+        /// volumes are kept as they are instead of being marked as missing,
+        /// so every disk can be updated on its own.
+        #[bpaf(argument::<String>("NAME"), parse(volume_name), optional)]
+        volume: Option<String>,
+
+        /// Write the new state without asking, even if more than 10% of the archive is missing
+        ///
+        /// That many missing files usually means an unmounted disk or the
+        /// wrong directory. Without --force, update asks before writing the
+        /// new state, or fails if it isn't run in a terminal.
+        force: bool,
+
+        /// Keep files that aren't found as they are instead of marking them as missing
+        ///
+        /// For directories that are only a staging area: new and modified
+        /// files are added to the state, nothing is ever removed from it.
+        append: bool,
+
+        /// Record missing files even if their contents are found elsewhere
+        ///
+        /// By default, a missing file whose contents are still found at
+        /// another path is considered moved and isn't recorded in .missing.
+        /// The same goes for the previous contents of modified files.
+        no_dedupe_missing: bool,
+
+        /// where the contents of a missing file have to be found for it to count as moved: same-dir, same-top-level or anywhere [default: anywhere]
+        ///
+        /// With same-top-level, a file missing from originals/ isn't
+        /// considered moved just because a copy exists below trash/.
+        #[bpaf(argument("SCOPE"), fallback(DedupeScope::Anywhere))]
+        dedupe_scope: DedupeScope,
+
+        /// keep missing files in the state until they've been missing for DURATION, like 180d
+        ///
+        /// Missing files are recorded in .missing once and kept in the state,
+        /// so a disk that isn't mounted for a while doesn't erase its files
+        /// from the state. Files that show up again are tracked as before.
+        #[bpaf(argument::<String>("DURATION"), parse(duration), optional)]
+        forget_missing_after: Option<Duration>,
+
+        /// Write a state that only depends on the files, without times of this run
+        ///
+        /// The times files were last read and seen as well as the filesystem
+        /// are left out, so identical trees give byte-identical states.
+        canonical: bool,
+
+        /// Set the immutable flag of every file found, like `chattr +i`
+        ///
+        /// Protects the files from being modified, renamed or deleted until
+        /// the flag is removed with `chattr -i`. Needs root, only supported
+        /// on Linux. Files already immutable are left as they are.
+        set_immutable: bool,
+
+        /// only list files below PATH, relative to DIRECTORY
+        ///
+        /// Files of the state outside of all PATHs are kept as they are, so
+        /// a small addition doesn't need the whole archive to be listed.
+        #[bpaf(argument::<String>("PATH"), many)]
+        path: Vec<String>,
+
+        /// only check the files listed in FILE, one per line relative to DIRECTORY, - for stdin
+        ///
+        /// Nothing is listed, for changed files found by another tool like
+        /// `find . -newer`. Files of the state not in FILE are kept as they
+        /// are, files in FILE that are gone are missing.
+        #[bpaf(argument::<String>("FILE"), optional)]
+        files_from: Option<String>,
+
+        /// write the paths of new and modified files to FILE, one per line relative to DIRECTORY
+        ///
+        /// For `rsync --files-from` or `tar -T`, so an incremental copy only
+        /// transfers what this run found changed. With --print0 each path
+        /// is followed by a NUL byte instead, for `rsync --from0`. Written
+        /// once the state is.
+        #[bpaf(argument::<String>("FILE"), optional)]
+        changed_files: Option<String>,
+
+        /// state to compare to, a file name in STATE_DIR, a label or a path [default: the latest state]
+        #[bpaf(argument::<String>("STATE"), optional)]
+        previous_state: Option<String>,
+
+        /// name of the state to write in STATE_DIR, ending in .state [default: the current time]
+        ///
+        /// For scripts naming states themselves. States not named after
+        /// the time they were written are sorted by name to find the latest
+        /// one, so names have to sort in the order states are written, or
+        /// the previous state has to be given with --previous-state.
+        #[bpaf(argument::<String>("NAME"), optional)]
+        state_file: Option<String>,
+
+        /// template to name the new state after [default: from init or {timestamp}.state]
+        ///
+        /// See init --state-name-format.
+        #[bpaf(argument::<NameFormat>("TEMPLATE"), optional)]
+        state_name_format: Option<NameFormat>,
+
+        /// label to give the new state, to refer to it instead of its file name
+        ///
+        /// Like pre-migration, for --previous-state, verify --state-file,
+        /// rollback, pin and export-changes --since. Labels are unique
+        /// within STATE_DIR.
+        #[bpaf(argument::<String>("LABEL"), optional)]
+        label: Option<String>,
+
+        /// Write a partial state of this host, for merge to take into the next state
+        ///
+        /// For several hosts updating their own mounts of the same archive
+        /// into one STATE_DIR at the same time: each writes the files below
+        /// its --path to STATE_DIR/partial and nothing else. Run merge once
+        /// they're done.
+        partial: bool,
+
+        /// name of the host to record for --partial [default: the hostname up to the first dot]
+        #[bpaf(argument::<String>("NAME"), optional)]
+        host: Option<String>,
+
         /// directory to store the state in
         #[bpaf(positional::<String>("STATE_DIR"))]
         state_dir: String,
 
-        /// directory to search for files in [default: current directory]
+        /// directory to search for files in [default: from init or current directory]
         #[bpaf(positional::<String>("DIRECTORY"))]
         directory: Option<String>,
     },
@@ -36,11 +312,626 @@ pub enum Cmd {
         /// Just check files are in the archive, don't verify paths
         only_presence: bool,
 
+        /// name of the disk DIRECTORY is on, files of other volumes aren't checked
+        #[bpaf(argument::<String>("NAME"), parse(volume_name), optional)]
+        volume: Option<String>,
+
+        /// Fail if DIRECTORY isn't on the filesystem it was on during the last update
+        ///
+        /// Without this, a different filesystem UUID only causes a warning.
+        strict_volume: bool,
+
+        /// report N unmodified files as corrupted, to test alerting on failed verifies [default: 0]
+        ///
+        /// Nothing is written to the files, only the digest compared to the
+        /// state is changed in memory. Every simulated corruption is marked
+        /// as such in the output.
+        #[bpaf(argument("N"), fallback(0))]
+        simulate_corruption: usize,
+
+        /// Read modified files a second time before reporting them
+        ///
+        /// The second read bypasses the page cache where possible. Files
+        /// matching the state on the second read were likely hit by a
+        /// transient read error and aren't reported as modified. Files giving
+        /// different contents on every read are reported as read errors.
+        double_read: bool,
+
+        /// state to verify against, a file name in STATE_DIR, a label or a path [default: the golden or latest state]
+        ///
+        /// Answers whether the files still match an older state, like the
+        /// baseline taken when the archive was set up.
+        #[bpaf(argument::<String>("STATE"), optional)]
+        state_file: Option<String>,
+
+        /// verify against the state pinned with `pin` (golden) or the latest one (latest) [default: golden]
+        ///
+        /// Without a pinned state, both are the latest state.
+        #[bpaf(argument("WHICH"), fallback(Baseline::Golden))]
+        against: Baseline,
+
+        /// state file to verify against on its own, without a state directory
+        ///
+        /// For a copy of an archive handed on with just its state file. Only
+        /// DIRECTORY is given then, instead of STATE_DIR and DIRECTORY.
+        /// Nothing is written, not even an .errors file.
+        #[bpaf(argument::<String>("STATE"), optional)]
+        manifest: Option<String>,
+
+        /// succeed if no more than N files are modified, missing or not found in the archive
+        ///
+        /// The files are listed all the same. Useful for huge archives with
+        /// a known flaky file that shouldn't fail every run.
+        #[bpaf(argument("N"), optional)]
+        max_failures: Option<u64>,
+
+        /// succeed if no more than P percent of the files checked are modified, missing or not found in the archive
+        #[bpaf(argument("P"), optional)]
+        max_failure_percent: Option<f64>,
+
+        /// verify the directories of the profile below OLD at NEW instead
+        ///
+        /// For a copy of the archive mounted elsewhere, like a clone of the
+        /// disk. Applies to the directory and the roots from init, the state
+        /// is left as it is.
+        #[bpaf(argument("OLD=NEW"), many)]
+        map_prefix: Vec<PrefixMap>,
+
+        /// Match paths differing only in case, for a copy on a case-insensitive filesystem
+        ///
+        /// Without this, a file whose name changed case on the way is reported
+        /// as missing and as not found in the archive.
+        case_insensitive_paths: bool,
+
+        /// List all files and report missing and changed ones before reading any
+        ///
+        /// Missing files, new files and files with a different size or mtime
+        /// show up within seconds, the contents are checked afterwards.
+        /// Files are only read once all of them are listed.
+        metadata_first: bool,
+
+        /// Only check the size and the first and last 64 KiB of files larger than 128 KiB
+        ///
+        /// Much faster for large media files, catches truncated and replaced
+        /// files and most damage at either end, but not damage in between.
+        /// Files recorded before this was added and files that don't match
+        /// are read completely. Same as --tiers size,quick,full.
+        quick: bool,
+
+        /// checks to run before reading a file completely, e.g. size,quick,full [default: full]
+        ///
+        /// size compares the size, quick the size and the first and last 64
+        /// KiB. Files passing all of them count as unmodified, files failing
+        /// one are read completely. How many files passed and failed each
+        /// is printed at the end.
+        #[bpaf(argument::<String>("TIERS"), parse(tiers), fallback(Vec::new()))]
+        tiers: Vec<CheckTier>,
+
+        /// only check the files listed in FILE, one per line relative to DIRECTORY, - for stdin
+        ///
+        /// Files of the state not in FILE are neither checked nor missing.
+        #[bpaf(argument::<String>("FILE"), optional)]
+        files_from: Option<String>,
+
+        /// Go through the modified files afterwards, to accept them or flag them as corrupted
+        ///
+        /// Asks once for each directory with several modified files, which
+        /// can also be gone through one by one. Decisions end up in the
+        /// review.log of the state directory: accepted changes don't fail
+        /// verify anymore and are recorded by the next update as usual,
+        /// corrupted files keep their digest in the state until restored.
+        review: bool,
+
+        /// directory to store the state in, with --manifest the directory to search for files in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// directory to search for files in [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Accept files deleted on purpose and record them in deleted.log
+    ///
+    /// Files listed in the latest state but missing from DIRECTORY are
+    /// removed from the state, so verify doesn't report them anymore. The
+    /// log keeps a record of what existed.
+    #[bpaf(command)]
+    AcceptMissing {
+        /// only accept missing files below PATH, relative to DIRECTORY
+        #[bpaf(argument::<String>("PATH"), many)]
+        path: Vec<String>,
+
+        /// name of the disk DIRECTORY is on [default: the only volume recorded for its filesystem]
+        ///
+        /// Missing files of other volumes are kept, they can't be found in
+        /// DIRECTORY. Without a volume, only files not stored on one are
+        /// accepted.
+        #[bpaf(argument::<String>("NAME"), parse(volume_name), optional)]
+        volume: Option<String>,
+
         /// directory to store the state in
         #[bpaf(positional::<String>("STATE_DIR"))]
         state_dir: String,
 
-        /// directory to search for files in [default: current directory]
+        /// directory to search for files in [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Record the modified files a verify run found as they are, without an update
+    ///
+    /// For files that legitimately change between verifications, like
+    /// catalogs. A new state is written with the contents the run found,
+    /// nothing is read from DIRECTORY. Refused if a state was written since
+    /// the run.
+    #[bpaf(command)]
+    Accept {
+        /// only accept files matching PATTERN, like 'catalogs/**', where * doesn't match / and ** does
+        ///
+        /// A directory accepts everything below it.
+        #[bpaf(argument::<String>("PATTERN"), parse(pattern), many)]
+        path: Vec<Regex>,
+
+        /// ID of the verify run, as printed when it started, LAST for the latest one finding modified files [default: LAST]
+        #[bpaf(argument::<String>("RUN"), optional)]
+        run: Option<String>,
+
+        /// directory to store the state in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Pin a state as the golden baseline verify compares against
+    ///
+    /// For write-once archives where the first full state is the contract.
+    /// Verify uses it instead of the latest state unless given --against
+    /// latest. keep-states and compact never remove or rewrite it. Without
+    /// STATE, prints the state pinned.
+    #[bpaf(command)]
+    Pin {
+        /// Unpin the golden state, verify uses the latest state again
+        remove: bool,
+
+        /// directory to store the state in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// file name or label of the state in STATE_DIR
+        #[bpaf(positional::<String>("STATE"))]
+        state: Option<String>,
+    },
+
+    /// Find files in the archive state by their digest
+    #[bpaf(command)]
+    Find {
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// hex encoded digests to look for
+        #[bpaf(positional::<String>("DIGEST"), some("at least one digest is required"))]
+        digests: Vec<String>,
+    },
+
+    /// Check the state directory for damaged or inconsistent files
+    #[bpaf(command)]
+    Doctor {
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Remove redundant states and rewrite the others in the current format
+    ///
+    /// A state is redundant if the next newer one lists the same files with
+    /// the same contents. Duplicate entries are dropped as well.
+    #[bpaf(command)]
+    Compact {
+        /// Only print what would be done
+        dry_run: bool,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Make an older state the latest one again
+    ///
+    /// For when an update accepted a damaged tree as the new truth. Files
+    /// of newer runs are moved to STATE_DIR/.rolled-back instead of being
+    /// deleted.
+    #[bpaf(command)]
+    Rollback {
+        /// name or label of the state to roll back to, e.g. "20230315T132501Z"
+        #[bpaf(argument("TIMESTAMP"))]
+        to: String,
+
+        /// Only print what would be done
+        dry_run: bool,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Print the history of the archive from all states
+    ///
+    /// Every run is compared to the one before it. Moved files are listed
+    /// as added and missing.
+    #[bpaf(command)]
+    Audit {
+        /// Print the runs every path was first and last listed in
+        paths: bool,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Print how the archive changed between two states
+    ///
+    /// Unlike audit, files whose contents went to another path are listed
+    /// as moved, and the directories that changed the most are listed.
+    /// Files kept in a state after they went missing count as removed.
+    #[bpaf(command)]
+    Diff {
+        /// older state, a file name in STATE_DIR, a label or a path [default: the state before --to]
+        #[bpaf(argument::<String>("STATE"), optional)]
+        from: Option<String>,
+
+        /// newer state, a file name in STATE_DIR, a label, a path or latest [default: latest]
+        #[bpaf(argument::<String>("STATE"), fallback("latest".to_owned()))]
+        to: String,
+
+        /// what to print: summary or manifest [default: summary]
+        ///
+        /// A manifest lists the files added, changed and removed along with
+        /// their digests, for apply to bring the state of another copy up to
+        /// date.
+        #[bpaf(argument("FORMAT"), fallback(DiffFormat::Summary))]
+        format: DiffFormat,
+
+        /// Print every path added, modified, moved or removed instead of the directories
+        paths: bool,
+
+        /// number of directories with the most changes to print [default: 10]
+        #[bpaf(argument("N"), fallback(10))]
+        top: usize,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Apply a manifest written by diff to the state of another copy
+    ///
+    /// Writes a new state with the files added, changed and removed, so
+    /// copies in other places exchange manifests instead of whole states.
+    /// Nothing is written if the latest state doesn't match the state the
+    /// manifest starts from.
+    #[bpaf(command)]
+    Apply {
+        /// directory the state of the copy is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// manifest to apply, - for stdin
+        #[bpaf(positional::<String>("FILE"))]
+        manifest: String,
+    },
+
+    /// Merge the partial states written by update --partial into a new state
+    ///
+    /// Files below the paths a host covered are taken from its partial
+    /// state and recorded with its name, the rest is kept as it is. Where
+    /// hosts covering the same path found something different, the later
+    /// one is taken and the path listed. Partial states still being
+    /// written are left for the next merge.
+    #[bpaf(command)]
+    Merge {
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Print the number of files and bytes of every run and what grew the most
+    #[bpaf(command)]
+    Stats {
+        /// Print the numbers of every run as CSV
+        csv: bool,
+
+        /// number of path components to group growth by [default: 1]
+        #[bpaf(argument("DEPTH"), fallback(1))]
+        depth: usize,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// List files of the latest state that weren't fully read for a while
+    ///
+    /// Lists the files read longest ago first, to check that verifying
+    /// parts of the archive in turn covers all of it eventually.
+    #[bpaf(command)]
+    Stale {
+        /// list files not fully read for DURATION, like 180d
+        #[bpaf(argument::<String>("DURATION"), parse(duration))]
+        older_than: Duration,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Estimate how much the next run reads and how long it takes
+    ///
+    /// Goes by the size of the latest state and the throughput of the
+    /// last updates, from their .stats files. Nothing in DIRECTORY is
+    /// read. Without --verify or --read-all-files, an update is expected
+    /// to read as much as recent updates did on average.
+    #[bpaf(command)]
+    Estimate {
+        /// Estimate a verify, which reads all files
+        verify: bool,
+
+        /// Estimate an update reading all files
+        read_all_files: bool,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// List files with the same contents in the latest state
+    ///
+    /// Empty files are left out.
+    #[bpaf(command)]
+    Duplicates {
+        /// List directories with identical contents instead
+        ///
+        /// Names count as contents, so copies with renamed files are not
+        /// found. Only the outermost directory of a duplicated tree is listed.
+        dirs: bool,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Replace duplicated files with hardlinks to one copy
+    ///
+    /// Files are read again before being replaced, anything modified since
+    /// the last update is left alone. Hardlinked copies share their mtime,
+    /// so the next update reads them again.
+    #[bpaf(command)]
+    Dedupe {
+        /// Create reflinks instead, copies stay independent files
+        ///
+        /// Needs a filesystem supporting them, like btrfs, XFS or APFS.
+        reflinks: bool,
+
+        /// Only print what would be done
+        dry_run: bool,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// directory the archive is in [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Copy the archive into a deduplicated, content addressed layout
+    ///
+    /// Every distinct file is stored once as DEST/objects/ab/cdef..., named
+    /// after its digest. DEST/manifest lists the original paths. Both can be
+    /// checked with `sha256sum -c` alone.
+    #[bpaf(command)]
+    ExportCas {
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// directory to export to, objects already there are kept
+        #[bpaf(positional::<String>("DEST"))]
+        dest: String,
+
+        /// directory the archive is in [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Write the files an update found new or modified for restic or borg
+    ///
+    /// Lets the backup program copy exactly what changed since the state
+    /// before the latest one, instead of looking for changes itself. For
+    /// restic, a list for `restic backup --files-from-verbatim`, for borg,
+    /// patterns for `borg create --patterns-from` that exclude everything
+    /// else. Paths are absolute.
+    #[bpaf(command)]
+    ExportChanges {
+        /// backup program to write the file for: restic or borg
+        #[bpaf(argument("TOOL"))]
+        tool: BackupTool,
+
+        /// state to list changes since, a file name in STATE_DIR, a label or a path [default: the state before the latest]
+        ///
+        /// For backups that run less often than updates, the state of the
+        /// update the last backup followed.
+        #[bpaf(argument::<String>("STATE"), optional)]
+        since: Option<String>,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// file to write to, - for stdout
+        #[bpaf(positional::<String>("FILE"))]
+        output: String,
+
+        /// directory the archive is in [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Write a BitTorrent v2 torrent of the archive
+    ///
+    /// Every file is read again, v2 torrents hash files as merkle trees of
+    /// 16 KiB blocks and can't reuse the digests of the state. Those are
+    /// checked along the way, the torrent only describes what was verified.
+    #[bpaf(command)]
+    ExportTorrent {
+        /// size of the pieces, a power of two of at least 16k [default: 4M]
+        #[bpaf(argument::<String>("SIZE"), parse(size), fallback(4 * 1024 * 1024))]
+        piece_size: u64,
+
+        /// name of the torrent [default: name of DIRECTORY]
+        #[bpaf(argument("NAME"))]
+        name: Option<String>,
+
+        /// tracker to announce to, can be repeated
+        #[bpaf(argument("URL"), many)]
+        announce: Vec<String>,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// file to write the torrent to
+        #[bpaf(positional::<String>("TORRENT"))]
+        torrent: String,
+
+        /// directory the archive is in [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Print the git-annex key and path of every file in the latest state
+    ///
+    /// Same format as `git annex find --format='${key} ${file}\n'`. Needs a
+    /// state hashed with SHA-256.
+    #[bpaf(command)]
+    AnnexExport {
+        /// git-annex backend to write keys for: SHA256 or SHA256E [default: SHA256E]
+        #[bpaf(argument("BACKEND"), fallback(Backend::Sha256E))]
+        backend: Backend,
+
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+    },
+
+    /// Create the first state of an archive from git-annex keys, without reading files
+    ///
+    /// Expects the output of `git annex find --format='${key} ${file}\n'`.
+    /// Annexed files need to be unlocked, symlinks aren't followed.
+    #[bpaf(command)]
+    AnnexImport {
+        /// directory to store the state in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// file listing keys and paths, - to read from stdin
+        #[bpaf(positional::<String>("LISTING"))]
+        listing: String,
+
+        /// directory the files are in [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Copy the state directory to DEST and verify the copies
+    ///
+    /// Keeps the record of what the archive held when the disk it's on is
+    /// lost. Only files DEST doesn't have as they are are copied, each one
+    /// is read back and compared before it replaces anything. A state that
+    /// doesn't match its checksum isn't copied. DEST is a local path,
+    /// `sftp://[user@]host[:port]/path` or `s3://bucket/prefix`, the latter
+    /// two need the sftp of OpenSSH and the AWS CLI installed.
+    #[bpaf(command)]
+    SyncState {
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// directory, SFTP or S3 URL to copy it to, created if it doesn't exist
+        #[bpaf(positional::<String>("DEST"))]
+        dest: String,
+    },
+
+    /// Compare the files recorded by SnapRAID to the latest state
+    ///
+    /// Expects the output of `snapraid list`. SnapRAID's block hashes can't be
+    /// compared to digests of whole files, so paths and sizes are compared.
+    #[bpaf(command)]
+    SnapraidCheck {
+        /// directory the state is stored in
+        #[bpaf(positional::<String>("STATE_DIR"))]
+        state_dir: String,
+
+        /// output of `snapraid list`, - to read from stdin
+        #[bpaf(positional::<String>("LISTING"))]
+        listing: String,
+
+        /// directory the archive is in, stripped from paths listed by SnapRAID [default: from init or current directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Verify two mirrors against their states and each other
+    ///
+    /// Both mirrors are read at the same time. Where the copies of a file
+    /// differ, the states tell which one is damaged: the copy that doesn't
+    /// match its state while the other one does.
+    #[bpaf(command)]
+    CrossVerify {
+        /// state directory of the first mirror
+        #[bpaf(positional::<String>("STATE_DIR_A"))]
+        state_dir_a: String,
+
+        /// directory of the first mirror
+        #[bpaf(positional::<String>("DIRECTORY_A"))]
+        directory_a: String,
+
+        /// state directory of the second mirror
+        #[bpaf(positional::<String>("STATE_DIR_B"))]
+        state_dir_b: String,
+
+        /// directory of the second mirror
+        #[bpaf(positional::<String>("DIRECTORY_B"))]
+        directory_b: String,
+    },
+
+    /// Check that update and verify work as expected on this machine
+    ///
+    /// Creates a few files, updates, then modifies, corrupts, moves and
+    /// deletes some of them and checks that update and verify report every
+    /// change correctly. All files are removed afterwards.
+    #[bpaf(command)]
+    Selftest {
+        /// directory to create the test files in, to test its filesystem [default: the temporary directory]
+        #[bpaf(positional::<String>("DIRECTORY"))]
+        directory: Option<String>,
+    },
+
+    /// Measure hashing throughput and find a good number of threads
+    #[bpaf(command)]
+    Bench {
+        /// algorithm to hash files with: sha256 or blake3 [default: sha256]
+        #[bpaf(argument("ALGORITHM"), fallback(HashAlgorithm::Sha256))]
+        hash: HashAlgorithm,
+
+        /// amount of data to read per number of threads in MiB [default: 1024]
+        #[bpaf(argument("MIB"), fallback(1024))]
+        sample_size: u64,
+
+        /// directory to read sample files from [default: only hash in memory]
+        ///
+        /// Different files are read for every number of threads, so none
+        /// of them should be in the page cache. Pass --direct-io to be sure.
         #[bpaf(positional::<String>("DIRECTORY"))]
         directory: Option<String>,
     },
@@ -50,14 +941,75 @@ pub enum Cmd {
 #[bpaf(options, version)]
 /// Hash files in a directory tree
 pub struct CommandlineOptions {
-    /// number of threads to use for reading files [default: 1]
+    /// name of the archive within STATE_DIR, for several archives sharing one
+    ///
+    /// Every archive gets its own subdirectory of STATE_DIR with its own
+    /// states and profile. The subdirectory is created by init, or by
+    /// update if it doesn't exist yet.
+    #[bpaf(argument::<String>("NAME"), parse(archive_name), optional)]
+    pub archive: Option<String>,
+
+    /// number of threads to use for reading files [default: detected]
     ///
     /// Increasing this to about 8 increases performance
     /// for reading from SSDs. Increasing this when reading
     /// from HDDs will most likely hurt performance fairly badly.
+    /// By default, 1 thread is used for HDDs and up to 8 for SSDs,
+    /// based on the storage DIRECTORY is on (Linux only). With BLAKE3
+    /// all threads of a device read a file of 64 MiB or more together,
+    /// with SHA-256 every file is read by one thread.
     #[bpaf(short, long, argument("THREADS"))]
     pub threads: Option<usize>,
 
+    /// number of threads to use for files on the same device as PATH
+    ///
+    /// Every device gets its own threads, so an archive spanning an SSD
+    /// and some HDDs can use e.g. 8 threads for the SSD and 1 thread per
+    /// HDD. Devices not listed use --threads.
+    #[bpaf(argument::<String>("PATH=THREADS"), parse(device_threads), many)]
+    pub device_threads: Vec<DeviceThreads>,
+
+    /// Bypass the page cache when reading files
+    ///
+    /// Makes sure the contents are actually read from the storage
+    /// medium instead of being served from memory. Not all filesystems
+    /// support this (tmpfs for example does not).
+    pub direct_io: bool,
+
+    /// Read files with idle I/O priority and low CPU priority
+    ///
+    /// For runs that should go unnoticed by everyone else using the
+    /// machine. Supported on Linux and macOS.
+    pub nice: bool,
+
+    /// limit memory usage to roughly SIZE, e.g. 512M or 2G
+    ///
+    /// Read buffers and the number of queued files are reduced to fit, and
+    /// unmodified files are written to temporary files in the state directory
+    /// instead of being kept in memory. The previous state still needs to
+    /// fit into half of it, runs fail before loading it if it doesn't.
+    #[bpaf(argument::<String>("SIZE"), parse(size), optional)]
+    pub max_memory: Option<u64>,
+
+    /// order to read files in: path, inode or smallest-first [default: path]
+    ///
+    /// Reading in inode order greatly reduces seeking on HDDs, as inode
+    /// numbers tend to follow the physical layout. smallest-first checks
+    /// most files in the first minutes of a run and leaves the largest
+    /// files for last. Both collect the files to read before reading
+    /// starts, which needs more memory.
+    #[bpaf(argument("ORDER"), fallback(ReadOrder::Path))]
+    pub read_order: ReadOrder,
+
+    /// order to list the entries of directories in: sorted or fast [default: sorted]
+    ///
+    /// fast lists them as the filesystem returns them, which saves sorting
+    /// huge directories up front. States are sorted by path when they're
+    /// written either way, only the order files are read in with
+    /// --read-order path changes.
+    #[bpaf(argument("ORDER"), fallback(WalkOrder::Sorted))]
+    pub walk_order: WalkOrder,
+
     /// Exclude directories matching this regular expression
     ///
     /// Only the name of the directory is checked. Use --exclude-path
@@ -76,6 +1028,223 @@ pub struct CommandlineOptions {
     #[bpaf(argument::<String>("REGEX"), parse(regex), many)]
     pub exclude_path: Vec<Regex>,
 
+    /// Exclude files by their first bytes: zip, gzip, pdf, png, jpeg, elf, sqlite, zeros or magic:HEX
+    ///
+    /// zeros matches files starting with 4 KiB of zeros, like downloads
+    /// preallocated by torrent clients. magic:HEX matches files starting
+    /// with these bytes. The first bytes of every file are read while
+    /// listing files, which takes a while on HDDs.
+    #[bpaf(argument("TYPE"), many)]
+    pub exclude_content_type: Vec<ContentType>,
+
+    /// Exclude files and directories whose names start with a dot
+    ///
+    /// Like .git, .cache or .DS_Store, regardless of how deep they are.
+    /// DIRECTORY itself is listed even if its name starts with a dot.
+    pub skip_hidden: bool,
+
+    /// also keep track of DIRECTORY, recording its files below PREFIX, e.g. scans=/data/scans
+    ///
+    /// For archives spread over several directories, all in one state.
+    /// Works like a mount point: files DIRECTORY itself has below PREFIX
+    /// are hidden. Stored in the profile by init, roots given for a run
+    /// replace the ones with the same PREFIX there.
+    #[bpaf(argument("PREFIX=DIRECTORY"), many)]
+    pub root: Vec<Root>,
+
+    /// what to do with symlinks and junctions: skip, record or follow [default: skip]
+    ///
+    /// record keeps track of where links point to, without reading through
+    /// them. follow lists the files links point to at the path of the link,
+    /// links back to a directory above them are skipped. Following a junction
+    /// to another disk reads that disk as well.
+    #[bpaf(argument("POLICY"), fallback(Links::Skip))]
+    pub links: Links,
+
+    /// Skip files locked by other programs and list them, without failing verify
+    ///
+    /// Files that can't be read are always skipped and listed in an .errors
+    /// file. With this, files another program has open exclusively or has
+    /// locked are listed on their own and don't make verify fail.
+    pub skip_locked: bool,
+
+    /// seconds mtimes may differ by for a file to count as unchanged [default: detected]
+    ///
+    /// By default, mtimes may differ by 2 seconds on network filesystems
+    /// (NFS, SMB and the like), as they're not always reported at the
+    /// precision they were stored with, and have to match exactly otherwise.
+    #[bpaf(argument("SECONDS"))]
+    pub mtime_tolerance: Option<u64>,
+
+    /// number of times to retry reading a file that failed [default: detected]
+    ///
+    /// By default, failed reads are retried twice on network filesystems,
+    /// waiting a second longer every time, and not at all otherwise.
+    /// Missing files and missing permissions aren't retried.
+    #[bpaf(argument("RETRIES"))]
+    pub read_retries: Option<u32>,
+
+    /// write the files that are going to be read to FILE before reading them
+    ///
+    /// One line per file with why it's read (new, metadata changed or
+    /// reverify) and its size, in the order they're read. All files are
+    /// listed before the first one is read.
+    #[bpaf(argument("FILE"))]
+    pub plan: Option<PathBuf>,
+
+    /// Stop once the plan is written, without reading any file
+    ///
+    /// For reviewing what a run would touch, e.g. on a fragile disk.
+    /// Requires --plan.
+    pub plan_only: bool,
+
+    /// what to do with files that grew while they were read: record, skip or retry-later [default: record]
+    ///
+    /// Logs and downloads still being written are hashed half-written.
+    /// record keeps what was read and lists them, skip keeps them as they
+    /// were in the state and retry-later reads them again after all other
+    /// files, skipping them if they grew again.
+    #[bpaf(argument("ACTION"), fallback(GrowingFiles::Record))]
+    pub growing_files: GrowingFiles,
+
+    /// report files with these extensions together, e.g. video=mkv,mp4
+    ///
+    /// Files are grouped by their extension in the summary of an update,
+    /// extensions not in any group are reported on their own.
+    #[bpaf(argument::<String>("NAME=EXT,EXT"), parse(extension_group), many)]
+    pub extension_group: Vec<ExtensionGroup>,
+
+    /// print the N files that took the longest to read [default: 0]
+    ///
+    /// Files are listed with their effective read speed after a run.
+    /// Unusually slow files can point to a failing region of a disk.
+    #[bpaf(argument("N"), fallback(0))]
+    pub slowest: usize,
+
+    /// Print how long each phase of a run took and how busy every thread was
+    pub timings: bool,
+
+    /// how to print times: relative, epoch or iso [default: relative]
+    ///
+    /// Relative times like `fully read 94 days ago` are followed by the
+    /// seconds since the epoch stored in the states. iso prints the local time.
+    #[bpaf(argument("FORMAT"), fallback(TimeFormat::Relative))]
+    pub time_format: TimeFormat,
+
+    /// Only print the paths of new, modified and missing files, each followed by a NUL byte
+    ///
+    /// Messages go to stderr and the summary is left out, so the paths can
+    /// be piped into `xargs -0` or `rsync --from0 --files-from=-`.
+    pub print0: bool,
+
+    /// Also hash the members of zip files and tarballs
+    ///
+    /// Members are recorded next to the state, so verify can tell which
+    /// members of a modified container changed. Containers are read twice.
+    pub container_members: bool,
+
     #[bpaf(external)]
     pub cmd: Cmd,
 }
+
+/// Man page for all commands and options, written by the hidden --generate-man
+pub fn manpage() -> String {
+    commandline_options().render_manpage(
+        env!("CARGO_PKG_NAME"),
+        bpaf::doc::Section::General,
+        None,
+        None,
+        Some("rusty-archive manual"),
+    )
+}
+
+impl CommandlineOptions {
+    /// Directory the states of the archive are stored in
+    pub fn state_dir(&self, state_dir: &str) -> PathBuf {
+        match &self.archive {
+            Some(name) => Path::new(state_dir).join(name),
+            None => PathBuf::from(state_dir),
+        }
+    }
+
+    /// Options for the library, shared by update and verify
+    pub fn archive_options(&self) -> ArchiveOptions {
+        ArchiveOptions {
+            threads: self.threads,
+            device_threads: self.device_threads.clone(),
+            direct_io: self.direct_io,
+            nice: self.nice,
+            max_memory: self.max_memory,
+            read_order: self.read_order,
+            walk_order: self.walk_order,
+            excludes: Excludes {
+                exclude_directory: self.exclude_directory.clone(),
+                exclude_file: self.exclude_file.clone(),
+                exclude_path: self.exclude_path.clone(),
+                exclude_content_type: self.exclude_content_type.clone(),
+                skip_hidden: self.skip_hidden,
+            },
+            extension_groups: self.extension_group.clone(),
+            slowest_files: self.slowest,
+            container_members: self.container_members,
+            links: self.links,
+            skip_locked: self.skip_locked,
+            roots: self.root.clone(),
+            mtime_tolerance: self.mtime_tolerance.map(Duration::from_secs),
+            read_retries: self.read_retries,
+            plan: self.plan.clone(),
+            plan_only: self.plan_only,
+            growing_files: self.growing_files,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size() {
+        assert_eq!(size("123".to_string()), Ok(123));
+        assert_eq!(size("512M".to_string()), Ok(512 * 1024 * 1024));
+        assert_eq!(size("2GiB".to_string()), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(size("4k".to_string()), Ok(4096));
+        assert!(size("lots".to_string()).is_err());
+        assert!(size("G".to_string()).is_err());
+    }
+
+    #[test]
+    fn parse_duration() {
+        assert_eq!(
+            duration("180d".to_string()),
+            Ok(Duration::from_secs(180 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            duration("12h".to_string()),
+            Ok(Duration::from_secs(12 * 60 * 60))
+        );
+        assert!(duration("d".to_string()).is_err());
+        assert!(duration("3 months".to_string()).is_err());
+    }
+
+    #[test]
+    fn parse_archive_name() {
+        assert_eq!(archive_name("photos".to_string()), Ok("photos".to_string()));
+        assert!(archive_name("".to_string()).is_err());
+        assert!(archive_name("..".to_string()).is_err());
+        assert!(archive_name("photos/2023".to_string()).is_err());
+    }
+
+    #[test]
+    fn manpage_covers_commands() {
+        let manpage = manpage();
+        assert!(manpage.starts_with(".ie"));
+        for command in ["update", "verify", r"accept\-missing", "bench"] {
+            assert!(
+                manpage.contains(&format!(r"\fB{}\fP", command)),
+                "{}",
+                command
+            );
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use rusty_archive::checksum::{sidecar_path, write_sidecar, HashingWriter};
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::index::{digest_counts_path, index_path, write_index, DigestCounts};
+use rusty_archive::state::{
+    pinned_state, read_state_file, remove_state, state_order, StateFile, StateHeader,
+};
+
+use anyhow::{Context, Result};
+use std::fs::{read_dir, remove_file, rename, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Sorts entries by path and keeps only the most recently seen entry per path
+fn normalize(files: &mut Vec<FileInfo>) {
+    files.sort_by(|a, b| {
+        a.rel_path
+            .cmp(&b.rel_path)
+            .then(b.last_seen.cmp(&a.last_seen))
+    });
+    files.dedup_by(|a, b| a.rel_path == b.rel_path);
+}
+
+/// Compares everything except when files were last seen or read
+fn same_contents(a: &[FileInfo], b: &[FileInfo]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.rel_path == b.rel_path && a.digest == b.digest && a.mtime == b.mtime && a.len == b.len
+        })
+}
+
+/// Compares everything except the run that wrote the states
+fn same_header(a: &StateHeader, b: &StateHeader) -> bool {
+    let without_run = |header: &StateHeader| StateHeader {
+        run_id: None,
+        ..header.clone()
+    };
+    without_run(a) == without_run(b)
+}
+
+/// Writes files in the current format, returns true if the file changed
+///
+/// With dry_run, the new file is only written to check if it would change.
+///
+/// The new file is written next to the old one and renamed over it. The
+/// sidecar is removed before and written after the rename, so an
+/// interrupted run leaves a file without sidecar instead of one with a
+/// wrong sidecar.
+fn rewrite(path: &Path, original: &StateFile, files: &[FileInfo], dry_run: bool) -> Result<bool> {
+    let tmp_path = path.with_file_name(format!(".compact-{}.tmp", std::process::id()));
+    let mut f = BufWriter::with_capacity(
+        1024 * 1024,
+        HashingWriter::new(
+            File::options()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)
+                .with_context(|| format!("Unable to create {:?}", tmp_path))?,
+        ),
+    );
+    original.header.write(&mut f)?;
+    for fi in files {
+        fi.write(&mut f)?;
+    }
+    let digest = f.into_inner().map_err(|err| err.into_error())?.finalize();
+    if dry_run || digest == original.digest {
+        remove_file(&tmp_path)?;
+        return Ok(digest != original.digest);
+    }
+
+    let sidecar = sidecar_path(path);
+    if sidecar.exists() {
+        remove_file(&sidecar)?;
+    }
+    rename(&tmp_path, path).with_context(|| format!("Unable to replace {:?}", path))?;
+    write_sidecar(path, &digest)?;
+    Ok(true)
+}
+
+/// Writes the indexes again, they're derived from the state
+fn rewrite_index(state_path: &Path, header: &StateHeader, files: &[FileInfo]) -> Result<()> {
+    let path = digest_counts_path(state_path);
+    if path.exists() {
+        remove_file(&path)?;
+    }
+    DigestCounts::of(files.iter().map(|fi| fi.digest)).write(&path, header)?;
+    let path = index_path(state_path);
+    if path.exists() {
+        remove_file(&path)?;
+    }
+    write_index(
+        &path,
+        header,
+        files
+            .iter()
+            .map(|fi| (fi.digest, fi.rel_path.clone()))
+            .collect(),
+    )?;
+    Ok(())
+}
+
+/// Removes redundant states and rewrites all others in the current format
+///
+/// A state is redundant if the next newer state lists the same files with
+/// the same contents, as only the times files were last seen or read are
+/// lost by removing it. The latest state and states of runs that found
+/// modified or missing files are always kept. The golden state is left
+/// exactly as it is.
+pub fn run(state_dir: &Path, dry_run: bool) -> Result<()> {
+    let pinned = pinned_state(state_dir)?;
+    let mut paths = read_dir(state_dir)
+        .with_context(|| format!("Unable to list files in {:?}", state_dir))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    paths.sort_by_cached_key(|path| state_order(path));
+    let (states, others): (Vec<_>, Vec<_>) = paths
+        .into_iter()
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("state" | "modified" | "missing")
+            )
+        })
+        .partition(|path| path.extension().unwrap_or_default() == "state");
+
+    let mut removed = 0;
+    let mut rewritten = 0;
+    let mut freed = 0;
+    let mut newer: Option<(PathBuf, StateFile)> = None;
+    // Newest first, so every state can be compared to the next newer one
+    for path in states.iter().rev() {
+        let mut state = read_state_file(path)?;
+        normalize(&mut state.files);
+        if Some(path) == pinned.as_ref() {
+            newer = Some((path.clone(), state));
+            continue;
+        }
+
+        let has_changes = [
+            path.with_extension("modified"),
+            path.with_extension("missing"),
+        ]
+        .iter()
+        .any(|path| path.exists());
+        if let Some((newer_path, newer_state)) = &newer {
+            if !has_changes
+                && same_header(&state.header, &newer_state.header)
+                && same_contents(&state.files, &newer_state.files)
+            {
+                println!(
+                    "{} {:?}, it only differs from {:?} in when files were last seen",
+                    if dry_run { "would remove" } else { "removing" },
+                    path,
+                    newer_path
+                );
+                removed += 1;
+                freed += remove_state(path, dry_run)?;
+                continue;
+            }
+        }
+
+        let size = path.metadata()?.len();
+        if rewrite(path, &state, &state.files, dry_run)? {
+            println!(
+                "{} {:?}",
+                if dry_run { "would rewrite" } else { "rewrote" },
+                path
+            );
+            rewritten += 1;
+            if !dry_run {
+                rewrite_index(path, &state.header, &state.files)?;
+                freed += size.saturating_sub(path.metadata()?.len());
+            }
+        }
+        newer = Some((path.clone(), state));
+    }
+
+    for path in &others {
+        let mut file = read_state_file(path)?;
+        normalize(&mut file.files);
+        let size = path.metadata()?.len();
+        if rewrite(path, &file, &file.files, dry_run)? {
+            println!(
+                "{} {:?}",
+                if dry_run { "would rewrite" } else { "rewrote" },
+                path
+            );
+            rewritten += 1;
+            if !dry_run {
+                freed += size.saturating_sub(path.metadata()?.len());
+            }
+        }
+    }
+
+    println!(
+        "{} {} of {} state(s), {} {} file(s), {} {:.1} MiB",
+        if dry_run { "would remove" } else { "removed" },
+        removed,
+        states.len(),
+        if dry_run { "would rewrite" } else { "rewrote" },
+        rewritten,
+        if dry_run {
+            "would free at least"
+        } else {
+            "freed"
+        },
+        freed as f64 / 1024.0 / 1024.0
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn file_info(path: &str, last_seen: u64) -> FileInfo {
+        FileInfo {
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(1000),
+            last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(last_seen),
+            fully_read: SystemTime::UNIX_EPOCH + Duration::from_secs(1000),
+            ..FileInfo::new(path, [1; 32], 5)
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_latest_duplicate() {
+        let mut files = vec![
+            file_info("b", 10),
+            file_info("a", 10),
+            file_info("b", 30),
+            file_info("b", 20),
+        ];
+        normalize(&mut files);
+        assert_eq!(files, vec![file_info("a", 10), file_info("b", 30)]);
+        assert!(same_contents(
+            &files,
+            &[file_info("a", 50), file_info("b", 50)]
+        ));
+        assert!(!same_contents(&files, &[file_info("a", 50)]));
+    }
+}
@@ -0,0 +1,214 @@
+use crate::hash::HashAlgorithm;
+use crate::roots::Root;
+use crate::state::{NameFormat, StateNames};
+use crate::walk::Excludes;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fs::{create_dir_all, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the profile stored in the state directory
+pub const CONFIG_NAME: &str = "rusty-archive.conf";
+
+/// Profile of an archive, written to the state directory by `init`
+///
+/// The file consists of `key: value` lines, lines starting with `#` are
+/// comments. Excludes may be given multiple times.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Directory the archive is in, updates of other directories are refused
+    pub directory: Option<PathBuf>,
+    /// Algorithm for new states
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// Applied in addition to the excludes given for a run
+    pub excludes: Excludes,
+    /// Number of latest states kept by update, older ones are kept one per month
+    pub keep_states: Option<usize>,
+    /// Further directories of the archive, see [`Root`]
+    pub roots: Vec<Root>,
+    /// Time zone new states are named in
+    pub state_names: Option<StateNames>,
+    /// Template new states are named after
+    pub state_name_format: Option<NameFormat>,
+}
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Config> {
+        let mut config = Config::default();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .with_context(|| format!("invalid line, expected `key: value`: '{}'", line))?;
+            let value = value.trim();
+            let regex = || Regex::new(value).with_context(|| format!("invalid {}", key));
+            match key {
+                "directory" => config.directory = Some(PathBuf::from(value)),
+                "hash" => {
+                    config.hash_algorithm = Some(value.parse().map_err(anyhow::Error::msg)?);
+                }
+                "keep-states" => {
+                    config.keep_states = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("invalid keep-states: '{}'", value))?,
+                    );
+                }
+                "state-names" => {
+                    config.state_names = Some(value.parse().map_err(anyhow::Error::msg)?);
+                }
+                "state-name-format" => {
+                    config.state_name_format = Some(value.parse().map_err(anyhow::Error::msg)?);
+                }
+                "root" => config
+                    .roots
+                    .push(value.parse().map_err(anyhow::Error::msg)?),
+                "exclude-directory" => config.excludes.exclude_directory.push(regex()?),
+                "exclude-file" => config.excludes.exclude_file.push(regex()?),
+                "exclude-path" => config.excludes.exclude_path.push(regex()?),
+                "skip-hidden" => {
+                    config.excludes.skip_hidden = value
+                        .parse()
+                        .with_context(|| format!("invalid skip-hidden: '{}'", value))?;
+                }
+                "exclude-content-type" => config
+                    .excludes
+                    .exclude_content_type
+                    .push(value.parse().map_err(anyhow::Error::msg)?),
+                _ => return Err(anyhow::Error::msg(format!("unknown key: '{}'", key))),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Reads the profile of a state directory, None if it has none
+    pub fn read(state_dir: &Path) -> Result<Option<Config>> {
+        let path = state_dir.join(CONFIG_NAME);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Config::parse(&contents)
+                .with_context(|| format!("Unable to parse {:?}", path))
+                .map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Unable to read {:?}", path)),
+        }
+    }
+
+    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
+        writeln!(to, "# rusty-archive profile, see README.md")?;
+        if let Some(directory) = &self.directory {
+            writeln!(to, "directory: {}", directory.display())?;
+        }
+        if let Some(hash_algorithm) = self.hash_algorithm {
+            writeln!(to, "hash: {}", hash_algorithm)?;
+        }
+        if let Some(keep_states) = self.keep_states {
+            writeln!(to, "keep-states: {}", keep_states)?;
+        }
+        if let Some(state_names) = self.state_names {
+            writeln!(to, "state-names: {}", state_names)?;
+        }
+        if let Some(format) = &self.state_name_format {
+            writeln!(to, "state-name-format: {}", format)?;
+        }
+        for root in &self.roots {
+            writeln!(to, "root: {}", root)?;
+        }
+        for (key, regexes) in [
+            ("exclude-directory", &self.excludes.exclude_directory),
+            ("exclude-file", &self.excludes.exclude_file),
+            ("exclude-path", &self.excludes.exclude_path),
+        ] {
+            for regex in regexes {
+                writeln!(to, "{}: {}", key, regex.as_str())?;
+            }
+        }
+        for content_type in &self.excludes.exclude_content_type {
+            writeln!(to, "exclude-content-type: {}", content_type)?;
+        }
+        if self.excludes.skip_hidden {
+            writeln!(to, "skip-hidden: true")?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a state directory along with its profile
+///
+/// The directories of the archive and its roots are stored as absolute paths. Fails if
+/// the state directory already has a profile.
+pub fn init(state_dir: &Path, mut config: Config) -> Result<PathBuf> {
+    if let Some(directory) = &config.directory {
+        config.directory = Some(
+            directory
+                .canonicalize()
+                .with_context(|| format!("Unable to find {:?}", directory))?,
+        );
+    }
+    for root in &mut config.roots {
+        root.directory = root
+            .directory
+            .canonicalize()
+            .with_context(|| format!("Unable to find {:?}", root.directory))?;
+    }
+    create_dir_all(state_dir).with_context(|| format!("Unable to create {:?}", state_dir))?;
+    let path = state_dir.join(CONFIG_NAME);
+    let mut f = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("Unable to create {:?}", path))?;
+    config.write(&mut f)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config {
+            directory: Some(PathBuf::from("/srv/photos")),
+            hash_algorithm: Some(HashAlgorithm::Blake3),
+            excludes: Excludes {
+                exclude_directory: vec![Regex::new(r"^\.git$")?],
+                exclude_file: vec![Regex::new(r"\.tmp$")?, Regex::new("^Thumbs.db$")?],
+                exclude_path: vec![],
+                exclude_content_type: vec!["zeros".parse()?],
+                skip_hidden: true,
+            },
+            keep_states: Some(12),
+            roots: vec!["scans=/srv/scans".parse()?],
+            state_names: Some(StateNames::Local),
+            state_name_format: Some("{timestamp}-{hostname}.state".parse()?),
+        };
+        let mut written = Vec::new();
+        config.write(&mut written)?;
+        let parsed = Config::parse(std::str::from_utf8(&written)?)?;
+        assert_eq!(parsed.directory, config.directory);
+        assert_eq!(parsed.hash_algorithm, config.hash_algorithm);
+        assert_eq!(parsed.excludes.exclude_file[1].as_str(), "^Thumbs.db$");
+        assert_eq!(parsed.excludes.exclude_directory.len(), 1);
+        assert_eq!(parsed.keep_states, Some(12));
+        assert_eq!(parsed.roots, config.roots);
+        assert_eq!(parsed.state_names, Some(StateNames::Local));
+        assert_eq!(parsed.state_name_format, config.state_name_format);
+        assert!(parsed.excludes.skip_hidden);
+        assert_eq!(
+            parsed.excludes.exclude_content_type,
+            config.excludes.exclude_content_type
+        );
+
+        assert!(Config::parse("directroy: /srv/photos").is_err());
+        assert!(Config::parse("hash: md5").is_err());
+        assert!(Config::parse("keep-states: all").is_err());
+        assert!(Config::parse("state-names: gmt").is_err());
+        assert!(Config::parse("skip-hidden: yes").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+//! Digests of the members of zip files and tarballs
+//!
+//! Containers are recorded as a whole in the state like any other file. With
+//! their members hashed as well, a changed container can be narrowed down
+//! to the members that changed. Members are named `<container>!/<member>`.
+
+use crate::hash::HashAlgorithm;
+
+use anyhow::{Context, Result};
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A file inside a container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub digest: [u8; 32],
+    pub len: u64,
+}
+
+/// Members of every container, by path of the container
+pub type Members = BTreeMap<PathBuf, Vec<Member>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+        Some(Kind::TarZst)
+    } else {
+        None
+    }
+}
+
+/// Whether members of the file can be hashed, judging by its name
+///
+/// Zip files with stored or deflated members, plain tarballs and ones
+/// compressed with gzip or zstd are supported.
+pub fn is_container(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+/// Path of a member, like `photos.zip!/2023/a.jpg`
+pub fn member_path(container: &Path, member: &str) -> PathBuf {
+    PathBuf::from(format!("{}!/{}", container.to_string_lossy(), member))
+}
+
+fn hash_reader(
+    reader: &mut dyn Read,
+    hash_algorithm: HashAlgorithm,
+) -> io::Result<([u8; 32], u64)> {
+    let mut hasher = hash_algorithm.hasher();
+    let mut buf = vec![0_u8; 256 * 1024];
+    let mut len = 0;
+    loop {
+        let bytes_read = reader.read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok((hasher.finalize(), len));
+        }
+        hasher.update(&buf[..bytes_read]);
+        len += bytes_read as u64;
+    }
+}
+
+/// Reads all members of a container and hashes their contents, sorted by name
+pub fn hash_members(path: &Path, hash_algorithm: HashAlgorithm) -> Result<Vec<Member>> {
+    let f = File::open(path).with_context(|| format!("Unable to open {:?}", path))?;
+    let mut members = match kind(path) {
+        Some(Kind::Zip) => zip_members(f, hash_algorithm),
+        Some(Kind::Tar) => tar_members(BufReader::new(f), hash_algorithm),
+        Some(Kind::TarGz) => {
+            compressed_tar_members(MultiGzDecoder::new(BufReader::new(f)), hash_algorithm)
+        }
+        Some(Kind::TarZst) => zstd::Decoder::new(f)
+            .map_err(anyhow::Error::from)
+            .and_then(|decoder| compressed_tar_members(decoder, hash_algorithm)),
+        None => Err(anyhow::Error::msg("not a supported container")),
+    }
+    .with_context(|| format!("Unable to read the members of {:?}", path))?;
+    members.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(members)
+}
+
+/// Parses an octal or base-256 number of a tar header
+fn tar_number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold(0, |n, &byte| (n << 8) | byte as u64));
+    }
+    let digits = std::str::from_utf8(field)?.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(digits, 8)?)
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parses the records of a pax extended header into key and value
+fn pax_records(data: &[u8]) -> Vec<(String, String)> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let len = match std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+        {
+            Some(len) if len > space && len <= rest.len() => len,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            records.push((key.to_owned(), value.to_owned()));
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+/// Members of a compressed tarball, the checksums of the compression are checked
+///
+/// Tar stops at the end of archive marker, the rest is read to get to the
+/// checksums at the very end.
+fn compressed_tar_members(
+    mut reader: impl Read,
+    hash_algorithm: HashAlgorithm,
+) -> Result<Vec<Member>> {
+    let members = tar_members(&mut reader, hash_algorithm)?;
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(members)
+}
+
+fn tar_members(mut reader: impl Read, hash_algorithm: HashAlgorithm) -> Result<Vec<Member>> {
+    let mut members = Vec::new();
+    let mut header = [0_u8; 512];
+    // Set by GNU long name and pax headers for the next entry
+    let (mut next_name, mut next_len): (Option<String>, Option<u64>) = (None, None);
+    loop {
+        reader.read_exact(&mut header)?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(members);
+        }
+        let len = match next_len.take() {
+            Some(len) => len,
+            None => tar_number(&header[124..136])?,
+        };
+        let padding = (512 - len % 512) % 512;
+        let mut contents = (&mut reader).take(len);
+        match header[156] {
+            b'0' | 0 | b'7' => {
+                let name = match next_name.take() {
+                    Some(name) => name,
+                    None if &header[257..262] == b"ustar" && header[345] != 0 => format!(
+                        "{}/{}",
+                        tar_string(&header[345..500]),
+                        tar_string(&header[..100])
+                    ),
+                    None => tar_string(&header[..100]),
+                };
+                let (digest, hashed_len) = hash_reader(&mut contents, hash_algorithm)?;
+                if hashed_len != len {
+                    return Err(anyhow::Error::msg(format!("{} is truncated", name)));
+                }
+                members.push(Member { name, digest, len });
+            }
+            b'L' => {
+                let mut name = Vec::new();
+                contents.read_to_end(&mut name)?;
+                next_name = Some(tar_string(&name));
+            }
+            b'x' => {
+                let mut data = Vec::new();
+                contents.read_to_end(&mut data)?;
+                for (key, value) in pax_records(&data) {
+                    match key.as_str() {
+                        "path" => next_name = Some(value),
+                        "size" => next_len = Some(value.parse()?),
+                        _ => {}
+                    }
+                }
+            }
+            // Directories, links, devices and global headers have no members
+            _ => {
+                io::copy(&mut contents, &mut io::sink())?;
+            }
+        }
+        io::copy(&mut (&mut reader).take(padding), &mut io::sink())?;
+    }
+}
+
+fn le16(b: &[u8], at: usize) -> u64 {
+    u16::from_le_bytes([b[at], b[at + 1]]) as u64
+}
+
+fn le32(b: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap()) as u64
+}
+
+fn le64(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+/// Finds the central directory, returns its offset and number of entries
+fn zip_central_directory(f: &mut File) -> Result<(u64, u64)> {
+    let file_len = f.seek(SeekFrom::End(0))?;
+    // The end of central directory record is followed by a comment of up to 64 KiB
+    let tail_len = file_len.min(22 + 65535 + 20);
+    f.seek(SeekFrom::Start(file_len - tail_len))?;
+    let mut tail = vec![0_u8; tail_len as usize];
+    f.read_exact(&mut tail)?;
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| tail[i..i + 4] == [0x50, 0x4b, 0x05, 0x06])
+        .context("no end of central directory record found")?;
+    let (entries, offset) = (le16(&tail, eocd + 10), le32(&tail, eocd + 16));
+    if entries != 0xffff && offset != 0xffff_ffff {
+        return Ok((offset, entries));
+    }
+    // Zip64, the locator directly precedes the end of central directory record
+    let locator = eocd
+        .checked_sub(20)
+        .filter(|&i| tail[i..i + 4] == [0x50, 0x4b, 0x06, 0x07])
+        .context("no zip64 end of central directory locator found")?;
+    f.seek(SeekFrom::Start(le64(&tail, locator + 8)))?;
+    let mut record = [0_u8; 56];
+    f.read_exact(&mut record)?;
+    if record[..4] != [0x50, 0x4b, 0x06, 0x06] {
+        return Err(anyhow::Error::msg(
+            "invalid zip64 end of central directory record",
+        ));
+    }
+    Ok((le64(&record, 48), le64(&record, 32)))
+}
+
+fn zip_members(mut f: File, hash_algorithm: HashAlgorithm) -> Result<Vec<Member>> {
+    let (offset, entries) = zip_central_directory(&mut f)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut directory = BufReader::new(f.try_clone()?);
+    let mut entries_read = Vec::new();
+    for _ in 0..entries {
+        let mut header = [0_u8; 46];
+        directory.read_exact(&mut header)?;
+        if header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+            return Err(anyhow::Error::msg("invalid central directory entry"));
+        }
+        let (flags, method) = (le16(&header, 8), le16(&header, 10));
+        let (mut compressed_len, mut len) = (le32(&header, 20), le32(&header, 24));
+        let mut local_offset = le32(&header, 42);
+        let mut name = vec![0_u8; le16(&header, 28) as usize];
+        directory.read_exact(&mut name)?;
+        let mut extra = vec![0_u8; le16(&header, 30) as usize];
+        directory.read_exact(&mut extra)?;
+        io::copy(
+            &mut (&mut directory).take(le16(&header, 32)),
+            &mut io::sink(),
+        )?;
+
+        // Sizes and offset too large for 32 bits are in the zip64 extra field
+        let mut at = 0;
+        while at + 4 <= extra.len() {
+            let (id, size) = (le16(&extra, at), le16(&extra, at + 2) as usize);
+            if id == 1 {
+                let mut field = at + 4;
+                for value in [&mut len, &mut compressed_len, &mut local_offset] {
+                    if *value == 0xffff_ffff && field + 8 <= at + 4 + size {
+                        *value = le64(&extra, field);
+                        field += 8;
+                    }
+                }
+            }
+            at += 4 + size;
+        }
+
+        let name = String::from_utf8_lossy(&name).into_owned();
+        if name.ends_with('/') {
+            continue;
+        }
+        if flags & 1 != 0 {
+            return Err(anyhow::Error::msg(format!("{} is encrypted", name)));
+        }
+        entries_read.push((name, method, compressed_len, len, local_offset));
+    }
+
+    let mut members = Vec::new();
+    for (name, method, compressed_len, len, local_offset) in entries_read {
+        f.seek(SeekFrom::Start(local_offset))?;
+        let mut header = [0_u8; 30];
+        f.read_exact(&mut header)?;
+        if header[..4] != [0x50, 0x4b, 0x03, 0x04] {
+            return Err(anyhow::Error::msg(format!(
+                "invalid local header of {}",
+                name
+            )));
+        }
+        f.seek(SeekFrom::Current(
+            (le16(&header, 26) + le16(&header, 28)) as i64,
+        ))?;
+        let mut data = BufReader::new(&mut f).take(compressed_len);
+        let (digest, hashed_len) = match method {
+            0 => hash_reader(&mut data, hash_algorithm)?,
+            8 => hash_reader(&mut DeflateDecoder::new(data), hash_algorithm)?,
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "{} uses unsupported compression method {}",
+                    name, method
+                )))
+            }
+        };
+        if hashed_len != len {
+            return Err(anyhow::Error::msg(format!(
+                "{} is {} bytes instead of {}",
+                name, hashed_len, len
+            )));
+        }
+        members.push(Member { name, digest, len });
+    }
+    Ok(members)
+}
+
+/// Path of the list of members written along with a state
+pub fn members_path(state_path: &Path) -> PathBuf {
+    state_path.with_extension("members")
+}
+
+/// Writes the members of all containers, one line per member
+///
+/// Lines look like the ones of states: `<hexdigest> <container>!/<member> # size <len>`
+pub fn write_members(state_path: &Path, members: &Members) -> Result<()> {
+    let path = members_path(state_path);
+    let mut f = BufWriter::new(
+        File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Unable to create {:?}", path))?,
+    );
+    for (container, members) in members {
+        for member in members {
+            writeln!(
+                f,
+                "{} {} # size {}",
+                hex::encode(member.digest),
+                member_path(container, &member.name).to_string_lossy(),
+                member.len
+            )?;
+        }
+    }
+    f.flush()
+        .with_context(|| format!("Unable to write {:?}", path))
+}
+
+/// Reads the members written along with a state, empty if there are none
+pub fn read_members(state_path: &Path) -> Result<Members> {
+    let path = members_path(state_path);
+    let f = match File::open(&path) {
+        Ok(f) => f,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Members::new()),
+        Err(err) => return Err(err).with_context(|| format!("Unable to open {:?}", path)),
+    };
+    let mut members = Members::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.with_context(|| format!("Unable to read {:?}", path))?;
+        let invalid = || format!("invalid line in {:?}: '{}'", path, line);
+        let (hexdigest, rest) = line.split_once(' ').with_context(invalid)?;
+        let (member_path, len) = rest.rsplit_once(" # size ").with_context(invalid)?;
+        let (container, name) = member_path.split_once("!/").with_context(invalid)?;
+        let mut digest = [0_u8; 32];
+        hex::decode_to_slice(hexdigest, &mut digest).with_context(invalid)?;
+        members
+            .entry(PathBuf::from(container))
+            .or_default()
+            .push(Member {
+                name: name.to_owned(),
+                digest,
+                len: len.parse().with_context(invalid)?,
+            });
+    }
+    Ok(members)
+}
+
+/// Names of members that differ between two lists sorted by name
+///
+/// Members only in one of the lists are included.
+pub fn changed_members(older: &[Member], newer: &[Member]) -> Vec<String> {
+    let older_by_name = older
+        .iter()
+        .map(|m| (m.name.as_str(), m))
+        .collect::<BTreeMap<_, _>>();
+    let newer_by_name = newer
+        .iter()
+        .map(|m| (m.name.as_str(), m))
+        .collect::<BTreeMap<_, _>>();
+    let mut changed = older_by_name
+        .iter()
+        .filter(|(name, m)| newer_by_name.get(*name).map(|n| n.digest) != Some(m.digest))
+        .map(|(name, _)| name.to_string())
+        .chain(
+            newer_by_name
+                .keys()
+                .filter(|name| !older_by_name.contains_key(*name))
+                .map(|name| name.to_string()),
+        )
+        .collect::<Vec<_>>();
+    changed.sort();
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn tar_header(name: &str, len: usize, kind: u8) -> Vec<u8> {
+        let mut header = vec![0_u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", len).as_bytes());
+        header[156] = kind;
+        header
+    }
+
+    #[test]
+    fn tar_with_long_names() -> Result<()> {
+        let long_name = "d/".repeat(80) + "b.txt";
+        let mut tar = tar_header("a.txt", 5, b'0');
+        tar.extend_from_slice(b"hello");
+        tar.resize(1024, 0);
+        tar.extend(tar_header("d", 0, b'5'));
+        // The length of a record includes the digits of the length itself
+        let body = format!(" path={}\n", long_name);
+        let mut len = body.len();
+        while len != len.to_string().len() + body.len() {
+            len = len.to_string().len() + body.len();
+        }
+        let record = format!("{}{}", len, body);
+        tar.extend(tar_header("PaxHeader", record.len(), b'x'));
+        tar.extend_from_slice(record.as_bytes());
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+        tar.extend(tar_header("overridden-name", 3, b'0'));
+        tar.extend_from_slice(b"abc");
+        tar.resize(tar.len().div_ceil(512) * 512 + 1024, 0);
+
+        let members = tar_members(&tar[..], HashAlgorithm::Sha256)?;
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name, "a.txt");
+        assert_eq!(
+            hex::encode(members[0].digest),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(
+            (members[1].name.as_str(), members[1].len),
+            (long_name.as_str(), 3)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_tarballs() -> Result<()> {
+        use flate2::{write::GzEncoder, Compression};
+
+        let dir = TestDir::new("compressed-tarballs");
+        let mut tar = tar_header("a.txt", 5, b'0');
+        tar.extend_from_slice(b"hello");
+        tar.resize(2048, 0);
+        // Split in two members, like concatenated gzip files or pigz output
+        let mut gz = Vec::new();
+        for part in [&tar[..700], &tar[700..]] {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(part)?;
+            gz.extend(encoder.finish()?);
+        }
+        let tar_gz = dir.join("a.tar.gz");
+        std::fs::write(&tar_gz, &gz)?;
+        let tar_zst = dir.join("a.tar.zst");
+        std::fs::write(&tar_zst, zstd::encode_all(&tar[..], 0)?)?;
+        for path in [&tar_gz, &tar_zst] {
+            assert!(is_container(path));
+            let members = hash_members(path, HashAlgorithm::Sha256)?;
+            assert_eq!((members[0].name.as_str(), members[0].len), ("a.txt", 5));
+        }
+
+        // The CRC-32 and size of the contents at the end of the last member
+        let len = gz.len();
+        gz[len - 8] ^= 1;
+        std::fs::write(&tar_gz, &gz)?;
+        assert!(hash_members(&tar_gz, HashAlgorithm::Sha256).is_err());
+        gz[len - 8] ^= 1;
+        gz[len - 1] ^= 1;
+        std::fs::write(&tar_gz, &gz)?;
+        assert!(hash_members(&tar_gz, HashAlgorithm::Sha256).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn members_round_trip() -> Result<()> {
+        let dir = TestDir::new("members");
+        let state_path = dir.join("20230315 142501.state");
+        let mut members = Members::new();
+        members.insert(
+            PathBuf::from("photos/2023.zip"),
+            vec![Member {
+                name: "a b/c.jpg".to_owned(),
+                digest: [3; 32],
+                len: 42,
+            }],
+        );
+        write_members(&state_path, &members)?;
+        assert_eq!(read_members(&state_path)?, members);
+
+        let changed = Member {
+            digest: [4; 32],
+            ..members[Path::new("photos/2023.zip")][0].clone()
+        };
+        let added = Member {
+            name: "d.jpg".to_owned(),
+            ..changed.clone()
+        };
+        assert_eq!(
+            changed_members(&members[Path::new("photos/2023.zip")], &[changed, added]),
+            vec!["a b/c.jpg", "d.jpg"]
+        );
+        Ok(())
+    }
+}
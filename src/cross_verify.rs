@@ -0,0 +1,76 @@
+use rusty_archive::mirrors::{cross_verify, Disagreement, Mirror, MirrorReport};
+use rusty_archive::{Archive, ArchiveOptions};
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Prints a list of files, if there are any
+fn print_files(what: &str, paths: &[PathBuf]) {
+    if paths.is_empty() {
+        return;
+    }
+    println!("{} files {}:", paths.len(), what);
+    for path in paths {
+        println!("└ {}", path.to_string_lossy());
+    }
+}
+
+/// Prints how a mirror compares to its own state
+fn print_mirror(mirror: Mirror, report: &MirrorReport) {
+    println!(
+        "mirror {}: {} files checked{}",
+        mirror,
+        report.files_checked,
+        if report.is_ok() {
+            ", all match its state"
+        } else {
+            ""
+        }
+    );
+    print_files("modified", &report.modified);
+    print_files("missing", &report.missing);
+    print_files("not in its state", &report.not_in_state);
+    print_files("couldn't be read", &report.unreadable);
+}
+
+/// Verifies both mirrors and prints the three-way comparison
+pub fn run(a: (&Path, &Path), b: (&Path, &Path), options: ArchiveOptions) -> Result<()> {
+    println!("mirror A: {:?}, state in {:?}", a.1, a.0);
+    println!("mirror B: {:?}, state in {:?}", b.1, b.0);
+    let report = cross_verify(
+        Archive::new(a.0, a.1, options.clone()),
+        Archive::new(b.0, b.1, options),
+    )?;
+    print_mirror(Mirror::A, &report.a);
+    print_mirror(Mirror::B, &report.b);
+    if !report.disagreements.is_empty() {
+        println!(
+            "{} files differ between the mirrors:",
+            report.disagreements.len()
+        );
+    }
+    for (path, disagreement) in &report.disagreements {
+        let reason = match disagreement {
+            Disagreement::Damaged(mirror) => format!(
+                "damaged in {}, the copy in {} matches its state",
+                mirror,
+                mirror.other()
+            ),
+            Disagreement::Added(mirror) => format!("only added to {}", mirror),
+            Disagreement::StatesDiffer => {
+                "both copies match their states, the mirrors weren't updated together".to_owned()
+            }
+            Disagreement::BothChanged => {
+                "neither copy matches its state, unclear which one is right".to_owned()
+            }
+        };
+        println!("└ {}: {}", path.to_string_lossy(), reason);
+    }
+    if !report.is_ok() {
+        return Err(anyhow::Error::msg(
+            "mirrors don't match their states and / or each other",
+        ));
+    }
+    println!("└ both mirrors match their states and each other");
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use rusty_archive::duplicates::duplicate_files;
+use rusty_archive::file_check::{hash_file, ReadOptions};
+use rusty_archive::state::read_state;
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Re-hashes a file and makes sure it still has the contents in the state
+fn verify(
+    directory: &Path,
+    rel_path: &Path,
+    digest: &[u8; 32],
+    read_options: &ReadOptions,
+) -> Result<()> {
+    let path = directory.join(rel_path);
+    let metadata =
+        fs::metadata(&path).with_context(|| format!("Unable to get metadata of {:?}", path))?;
+    let fi = hash_file(rel_path, &path, &metadata, read_options)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    if &fi.digest != digest {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} was modified since the last update, run an update first",
+            path
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Creates `to` sharing the extents of `from`, which has to be a new path
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let src = fs::File::open(from)?;
+    let dst = fs::File::options().write(true).create_new(true).open(to)?;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == -1 {
+        let err = io::Error::last_os_error();
+        drop(dst);
+        let _ = fs::remove_file(to);
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are not supported on this platform",
+    ))
+}
+
+/// Gives `path` the owner, mode and mtime of the file it's about to replace
+///
+/// The owner is set first, changing it clears the setuid and setgid bits.
+/// Keeping the mtime means the next update doesn't read the file again.
+fn take_metadata(path: &Path, of: &fs::Metadata) -> io::Result<()> {
+    let f = fs::File::open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        std::os::unix::fs::fchown(&f, Some(of.uid()), Some(of.gid()))?;
+    }
+    f.set_permissions(of.permissions())?;
+    f.set_modified(of.modified()?)
+}
+
+/// Replaces `target` with a link to `source`
+///
+/// The link is created next to the target and renamed over it, so the
+/// target is never lost should linking fail halfway. A reflink is a file
+/// of its own and keeps the owner, mode and mtime of the target, a
+/// hardlink shares them with the source.
+fn link(source: &Path, target: &Path, reflinks: bool) -> Result<()> {
+    let mut tmp_name = target.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".rusty-archive-dedupe");
+    let tmp = target.with_file_name(tmp_name);
+    if reflinks {
+        let metadata = fs::metadata(target)
+            .with_context(|| format!("Unable to get metadata of {:?}", target))?;
+        reflink(source, &tmp)
+            .with_context(|| format!("Unable to reflink {:?} to {:?}", source, tmp))?;
+        if let Err(err) = take_metadata(&tmp, &metadata) {
+            let _ = fs::remove_file(&tmp);
+            return Err(anyhow::Error::new(err).context(format!(
+                "Unable to copy the metadata of {:?} to {:?}",
+                target, tmp
+            )));
+        }
+    } else {
+        fs::hard_link(source, &tmp)
+            .with_context(|| format!("Unable to link {:?} to {:?}", source, tmp))?;
+    }
+    if let Err(err) = fs::rename(&tmp, target) {
+        let _ = fs::remove_file(&tmp);
+        return Err(anyhow::Error::new(err).context(format!("Unable to replace {:?}", target)));
+    }
+    Ok(())
+}
+
+/// Replaces duplicated files in the latest state with links to one copy
+///
+/// Both copies are read again right before linking, a file modified since
+/// the last update is never replaced. A file that can't be linked, for
+/// example because it's on another filesystem than its copy, is reported
+/// and skipped, and the run fails at the end.
+pub fn run(
+    state_dir: &Path,
+    directory: &Path,
+    reflinks: bool,
+    dry_run: bool,
+    direct_io: bool,
+) -> Result<()> {
+    let (header, files) = read_state(state_dir)?;
+    let read_options = ReadOptions {
+        direct_io,
+        hash_algorithm: header.hash_algorithm,
+        ..Default::default()
+    };
+    let (mut linked, mut bytes, mut failed) = (0, 0, 0);
+    for group in duplicate_files(files.values()) {
+        let source = directory.join(&group.paths[0]);
+        let mut source_verified = false;
+        for rel_path in &group.paths[1..] {
+            let target = directory.join(rel_path);
+            if same_file(&source, &target).unwrap_or(false) {
+                continue;
+            }
+            if dry_run {
+                println!(
+                    "would link {} to {}",
+                    rel_path.to_string_lossy(),
+                    group.paths[0].to_string_lossy()
+                );
+            } else {
+                if !source_verified {
+                    verify(directory, &group.paths[0], &group.digest, &read_options)?;
+                    source_verified = true;
+                }
+                verify(directory, rel_path, &group.digest, &read_options)?;
+                println!(
+                    "linking {} to {}",
+                    rel_path.to_string_lossy(),
+                    group.paths[0].to_string_lossy()
+                );
+                if let Err(err) = link(&source, &target, reflinks) {
+                    println!("└ skipped: {:#}", err);
+                    failed += 1;
+                    continue;
+                }
+            }
+            linked += 1;
+            bytes += group.len;
+        }
+    }
+    println!(
+        "└ {} {} file(s), {:.3} GiB",
+        if dry_run { "would link" } else { "linked" },
+        linked,
+        bytes as f64 / 1024.0 / 1024.0 / 1024.0
+    );
+    if failed > 0 {
+        return Err(anyhow::Error::msg(format!(
+            "{} file(s) couldn't be linked",
+            failed
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crate::hash::HashAlgorithm;
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the log of deleted files in the state directory
+pub const DELETED_LOG: &str = "deleted.log";
+
+/// Record of a file deleted on purpose
+///
+/// Stored as one line per file in the deleted log, which is only ever
+/// appended to: `<hexdigest> <path> # size <len> hash <algorithm> deleted_in <run>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    pub rel_path: PathBuf,
+    pub digest: [u8; 32],
+    pub len: u64,
+    pub hash_algorithm: HashAlgorithm,
+    /// Name of the first state no longer listing the file
+    pub deleted_in: String,
+}
+
+impl Tombstone {
+    pub fn parse(line: &str) -> Result<Tombstone> {
+        let invalid = || format!("invalid line: '{}'", line);
+        let (hexdigest, rest) = line.split_once(' ').with_context(invalid)?;
+        let mut digest = [0_u8; 32];
+        hex::decode_to_slice(hexdigest, &mut digest).with_context(invalid)?;
+        let (path, fields) = rest.rsplit_once(" # ").with_context(invalid)?;
+        // The run is named after a timestamp containing a space, so it's last
+        let (fields, deleted_in) = fields.split_once(" deleted_in ").with_context(invalid)?;
+        let (mut len, mut hash_algorithm) = (None, None);
+        let mut fields = fields.split(' ');
+        while let Some(key) = fields.next() {
+            let value = fields.next().with_context(invalid)?;
+            match key {
+                "size" => len = Some(value.parse().with_context(invalid)?),
+                "hash" => {
+                    hash_algorithm = Some(
+                        value
+                            .parse()
+                            .map_err(anyhow::Error::msg)
+                            .with_context(invalid)?,
+                    )
+                }
+                _ => {}
+            }
+        }
+        Ok(Tombstone {
+            rel_path: PathBuf::from(path),
+            digest,
+            len: len.with_context(invalid)?,
+            hash_algorithm: hash_algorithm.with_context(invalid)?,
+            deleted_in: deleted_in.to_owned(),
+        })
+    }
+
+    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            to,
+            "{} {} # size {} hash {} deleted_in {}",
+            hex::encode(self.digest),
+            self.rel_path.to_str().unwrap(),
+            self.len,
+            self.hash_algorithm,
+            self.deleted_in
+        )
+    }
+}
+
+/// Appends tombstones to the deleted log of a state directory
+pub fn append(state_dir: &Path, tombstones: &[Tombstone]) -> Result<()> {
+    let path = state_dir.join(DELETED_LOG);
+    let mut f = BufWriter::new(
+        File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Unable to open {:?}", path))?,
+    );
+    for tombstone in tombstones {
+        tombstone.write(&mut f)?;
+    }
+    f.flush()
+        .with_context(|| format!("Unable to write to {:?}", path))
+}
+
+/// Reads all tombstones of a state directory, oldest first
+pub fn read(state_dir: &Path) -> Result<Vec<Tombstone>> {
+    let path = state_dir.join(DELETED_LOG);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Unable to read {:?}", path)),
+    };
+    contents
+        .lines()
+        .map(Tombstone::parse)
+        .collect::<Result<_>>()
+        .with_context(|| format!("Unable to parse {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tombstone_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let tombstone = Tombstone {
+            rel_path: PathBuf::from("photos/a # b.jpg"),
+            digest: [7; 32],
+            len: 1234,
+            hash_algorithm: HashAlgorithm::Blake3,
+            deleted_in: "20230315 142501".to_owned(),
+        };
+        let mut line = Vec::new();
+        tombstone.write(&mut line)?;
+        let line = String::from_utf8(line)?;
+        assert_eq!(Tombstone::parse(line.trim_end())?, tombstone);
+        assert!(Tombstone::parse("07 photos/a.jpg # size 12").is_err());
+        Ok(())
+    }
+}
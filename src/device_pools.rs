@@ -0,0 +1,387 @@
+use crate::priority::lower_current_thread;
+
+use anyhow::{Context, Result};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::{HashMap, VecDeque};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+/// Number of threads to use for files on the same device as a path
+#[derive(Clone, Debug)]
+pub struct DeviceThreads {
+    pub path: PathBuf,
+    pub threads: usize,
+}
+
+/// Identifies the device a file is stored on
+pub type DeviceId = u64;
+
+/// Thread pools for reading files, one per storage device
+///
+/// Different devices need very different amounts of parallelism
+/// (SSDs like lots of it, HDDs don't), so every device gets its
+/// own pool instead of sharing a global thread count.
+pub struct DevicePools {
+    default_threads: usize,
+    /// Whether threads run with low priority, see --nice
+    nice: bool,
+    threads_by_device: HashMap<DeviceId, usize>,
+    pools: HashMap<DeviceId, ThreadPool>,
+}
+
+impl DevicePools {
+    pub fn new(
+        default_threads: usize,
+        device_threads: &[DeviceThreads],
+        nice: bool,
+    ) -> Result<Self> {
+        let mut threads_by_device = HashMap::new();
+        for dt in device_threads {
+            let device = device_of_path(&dt.path)
+                .with_context(|| format!("Unable to determine device of {:?}", dt.path))?;
+            threads_by_device.insert(device, dt.threads);
+        }
+        Ok(DevicePools {
+            default_threads,
+            nice,
+            threads_by_device,
+            pools: HashMap::new(),
+        })
+    }
+
+    /// Whether files need to be assigned to devices at all
+    ///
+    /// If no threads were configured for specific devices, all files
+    /// share a single pool.
+    pub fn is_per_device(&self) -> bool {
+        !self.threads_by_device.is_empty()
+    }
+
+    /// Number of threads used for files on a device
+    pub fn threads(&self, device: DeviceId) -> usize {
+        *self
+            .threads_by_device
+            .get(&device)
+            .unwrap_or(&self.default_threads)
+    }
+
+    /// Upper bound for the number of threads reading at the same time
+    pub fn max_threads(&self) -> usize {
+        self.default_threads + self.threads_by_device.values().sum::<usize>()
+    }
+
+    /// Returns the thread pool for a device, creating it if necessary
+    pub fn get(&mut self, device: DeviceId) -> Result<&ThreadPool> {
+        if !self.pools.contains_key(&device) {
+            let nice = self.nice;
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(self.threads(device))
+                .start_handler(move |_| {
+                    if nice {
+                        // Already checked on the main thread in main
+                        lower_current_thread().ok();
+                    }
+                })
+                .build()?;
+            self.pools.insert(device, pool);
+        }
+        Ok(&self.pools[&device])
+    }
+}
+
+/// Files waiting to be read, queued by the device they're on
+///
+/// Every device works through its own queue, so files listed for a slow
+/// device don't hold back the files of the others while it's busy.
+pub struct DeviceQueues<T> {
+    waiting: HashMap<DeviceId, VecDeque<T>>,
+    in_flight: HashMap<DeviceId, usize>,
+    len: usize,
+}
+
+impl<T> Default for DeviceQueues<T> {
+    fn default() -> Self {
+        DeviceQueues {
+            waiting: HashMap::new(),
+            in_flight: HashMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> DeviceQueues<T> {
+    pub fn push(&mut self, device: DeviceId, item: T) {
+        self.waiting.entry(device).or_default().push_back(item);
+        self.len += 1;
+    }
+
+    /// Takes the next file of a device reading fewer than `limit` of them
+    ///
+    /// The file counts as read by the device until it's `finished`.
+    pub fn start(&mut self, limit: impl Fn(DeviceId) -> usize) -> Option<(DeviceId, T)> {
+        let device = *self
+            .waiting
+            .keys()
+            .find(|&&device| self.in_flight.get(&device).copied().unwrap_or(0) < limit(device))?;
+        let queue = self.waiting.get_mut(&device)?;
+        let item = queue.pop_front()?;
+        if queue.is_empty() {
+            self.waiting.remove(&device);
+        }
+        *self.in_flight.entry(device).or_default() += 1;
+        Some((device, item))
+    }
+
+    pub fn finished(&mut self, device: DeviceId) {
+        *self.in_flight.get_mut(&device).expect("no file started") -= 1;
+        self.len -= 1;
+    }
+
+    /// Number of files waiting or being read
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Maximum number of threads picked automatically for SSDs
+///
+/// More threads rarely help and just use more memory for read buffers.
+const MAX_DEFAULT_THREADS: usize = 8;
+
+/// Picks the number of threads to use if --threads isn't given
+///
+/// SSDs benefit from reading lots of files in parallel, HDDs get a lot
+/// slower as they have to seek between files. If the storage type can't
+/// be determined, a single thread is used to be on the safe side.
+pub fn default_threads(rotational: Option<bool>) -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    threads_for_storage(rotational, cores)
+}
+
+/// Checks whether a path is on rotational storage, None if unknown
+pub fn storage_is_rotational(path: &Path) -> Option<bool> {
+    is_rotational(path).unwrap_or(None)
+}
+
+fn threads_for_storage(rotational: Option<bool>, cores: usize) -> usize {
+    match rotational {
+        Some(false) => cores.min(MAX_DEFAULT_THREADS),
+        Some(true) | None => 1,
+    }
+}
+
+/// Checks whether the block device a path is stored on is rotational
+///
+/// Returns None for filesystems without a block device (tmpfs, network
+/// filesystems). Partitions don't have a queue of their own, the one of
+/// the disk they're on is used.
+#[cfg(target_os = "linux")]
+fn is_rotational(path: &Path) -> std::io::Result<Option<bool>> {
+    let dev = device_of_path(path)?;
+    let sys_path =
+        Path::new("/sys/dev/block").join(format!("{}:{}", libc::major(dev), libc::minor(dev)));
+    let sys_path = match sys_path.canonicalize() {
+        Ok(sys_path) => sys_path,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    for dir in [Some(sys_path.as_path()), sys_path.parent()]
+        .into_iter()
+        .flatten()
+    {
+        match std::fs::read_to_string(dir.join("queue/rotational")) {
+            Ok(rotational) => return Ok(Some(rotational.trim() == "1")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_path: &Path) -> std::io::Result<Option<bool>> {
+    Ok(None)
+}
+
+/// Type of the network filesystem a path is on, None for local filesystems or if unknown
+///
+/// Network filesystems break assumptions that hold for local ones: mtimes
+/// can be rounded differently than on the server, reads fail when the
+/// connection drops and every stat is a round trip.
+pub fn network_filesystem(path: &Path) -> Option<&'static str> {
+    network_filesystem_of(path).unwrap_or(None)
+}
+
+/// Looks up the filesystem magic number of a path
+#[cfg(target_os = "linux")]
+fn network_filesystem_of(path: &Path) -> std::io::Result<Option<&'static str>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // From linux/magic.h, the ones of CIFS and SMB2 don't fit into an i32
+    const NETWORK_FILESYSTEMS: [(u32, &str); 7] = [
+        (0x6969, "nfs"),
+        (0x517b, "smb"),
+        (0xff534d42, "cifs"),
+        (0xfe534d42, "smb2"),
+        (0x5346414f, "afs"),
+        (0x00c36400, "ceph"),
+        (0x01021997, "9p"),
+    ];
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // f_type is signed on some architectures, the magic numbers are 32 bits
+    let f_type = unsafe { stat.assume_init() }.f_type as u32;
+    Ok(NETWORK_FILESYSTEMS
+        .iter()
+        .find(|(magic, _)| *magic == f_type)
+        .map(|(_, name)| *name))
+}
+
+/// Looks up the name of the filesystem type of a path
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn network_filesystem_of(path: &Path) -> std::io::Result<Option<&'static str>> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    const NETWORK_FILESYSTEMS: [&str; 5] = ["nfs", "smbfs", "afpfs", "webdav", "cifs"];
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Ok(NETWORK_FILESYSTEMS
+        .into_iter()
+        .find(|fs| name.to_bytes() == fs.as_bytes()))
+}
+
+/// Only UNC paths are detected, drive letters mapped to shares aren't
+#[cfg(windows)]
+fn network_filesystem_of(path: &Path) -> std::io::Result<Option<&'static str>> {
+    use std::path::{Component, Prefix};
+
+    let path = std::path::absolute(path)?;
+    Ok(match path.components().next() {
+        Some(Component::Prefix(prefix))
+            if matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..)) =>
+        {
+            Some("smb")
+        }
+        _ => None,
+    })
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    windows
+)))]
+fn network_filesystem_of(_path: &Path) -> std::io::Result<Option<&'static str>> {
+    Ok(None)
+}
+
+/// UUID or serial number of the filesystem a path is on, None if unknown
+pub fn filesystem_uuid(path: &Path) -> Option<String> {
+    uuid_of_filesystem(path).unwrap_or(None)
+}
+
+/// Looks up the block device of a path in /dev/disk/by-uuid
+///
+/// The links there are maintained by udev, filesystems without a block
+/// device or a UUID (tmpfs, network filesystems, btrfs subvolumes) aren't
+/// listed.
+#[cfg(target_os = "linux")]
+fn uuid_of_filesystem(path: &Path) -> std::io::Result<Option<String>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = device_of_path(path)?;
+    let entries = match std::fs::read_dir("/dev/disk/by-uuid") {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        // Follows the link to the device node
+        if std::fs::metadata(entry.path()).is_ok_and(|m| m.rdev() == dev) {
+            return Ok(Some(entry.file_name().to_string_lossy().into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn uuid_of_filesystem(_path: &Path) -> std::io::Result<Option<String>> {
+    Ok(None)
+}
+
+/// Determines the device a file is stored on
+#[cfg(unix)]
+pub fn device(metadata: &Metadata) -> DeviceId {
+    use std::os::unix::fs::MetadataExt;
+    metadata.dev()
+}
+
+/// Determines the device a file is stored on
+#[cfg(not(unix))]
+pub fn device(_metadata: &Metadata) -> DeviceId {
+    // DevicePools::new refuses per-device threads on these platforms
+    0
+}
+
+#[cfg(unix)]
+fn device_of_path(path: &Path) -> std::io::Result<DeviceId> {
+    Ok(device(&path.metadata()?))
+}
+
+#[cfg(not(unix))]
+fn device_of_path(_path: &Path) -> std::io::Result<DeviceId> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "per-device threads are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads_for_storage_types() {
+        assert_eq!(threads_for_storage(Some(true), 16), 1);
+        assert_eq!(threads_for_storage(None, 16), 1);
+        assert_eq!(threads_for_storage(Some(false), 4), 4);
+        assert_eq!(threads_for_storage(Some(false), 64), MAX_DEFAULT_THREADS);
+    }
+
+    #[test]
+    fn busy_device_doesnt_block_others() {
+        let mut queues = DeviceQueues::default();
+        for file in ["a1", "a2", "a3"] {
+            queues.push(1, file);
+        }
+        queues.push(2, "b1");
+        let limit = |_| 1;
+        let mut started = vec![queues.start(limit), queues.start(limit)];
+        started.sort();
+        assert_eq!(started, [Some((1, "a1")), Some((2, "b1"))]);
+        // Both devices are busy, the files of the slow one wait
+        assert_eq!(queues.start(limit), None);
+        queues.push(2, "b2");
+        queues.finished(2);
+        assert_eq!(queues.start(limit), Some((2, "b2")));
+        queues.finished(1);
+        assert_eq!(queues.start(limit), Some((1, "a2")));
+        assert_eq!(queues.len(), 3);
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Directories are only reported once they lost at least this many files
+pub const MIN_FILES_LOST: u64 = 10;
+
+/// Directories are only reported once they lost at least this share of their files
+pub const MIN_SHARE_LOST: f64 = 0.5;
+
+/// A directory that lost a large part of its files since the state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShrunkDirectory {
+    /// Empty for the directory of the archive itself
+    pub path: PathBuf,
+    /// Files in the directory and below it in the state
+    pub files: u64,
+    /// How many of them are missing now
+    pub missing: u64,
+}
+
+/// Number of files in every directory of a state, including the ones below it
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryCounts {
+    counts: BTreeMap<PathBuf, u64>,
+}
+
+/// Directories a file is in, from the directory of the archive inwards
+fn ancestors(rel_path: &Path) -> impl Iterator<Item = &Path> {
+    rel_path.ancestors().skip(1)
+}
+
+impl DirectoryCounts {
+    pub fn of<'a>(rel_paths: impl IntoIterator<Item = &'a Path>) -> DirectoryCounts {
+        let mut counts = BTreeMap::new();
+        for rel_path in rel_paths {
+            for directory in ancestors(rel_path) {
+                *counts.entry(directory.to_path_buf()).or_default() += 1;
+            }
+        }
+        DirectoryCounts { counts }
+    }
+
+    /// Directories that shrunk sharply because of the missing files, sorted by path
+    ///
+    /// Each loss is reported once, for the innermost directory all of it is
+    /// in: a vanished folder shows up by itself, not along with the folders
+    /// above it or its subfolders.
+    pub fn shrunk(&self, missing: &[PathBuf]) -> Vec<ShrunkDirectory> {
+        let lost = DirectoryCounts::of(missing.iter().map(PathBuf::as_path));
+        // Directories whose missing files are all in one subdirectory
+        let in_subdirectory = lost
+            .counts
+            .iter()
+            .filter_map(|(path, missing)| {
+                let parent = path.parent()?;
+                (lost.counts.get(parent) == Some(missing)).then_some(parent)
+            })
+            .collect::<HashSet<_>>();
+        let mut shrunk: Vec<ShrunkDirectory> = Vec::new();
+        // Sorted by path, so directories come before the ones below them
+        for (path, &missing) in &lost.counts {
+            let files = self.counts.get(path).copied().unwrap_or(missing);
+            if missing < MIN_FILES_LOST
+                || (missing as f64) < files as f64 * MIN_SHARE_LOST
+                || in_subdirectory.contains(path.as_path())
+                || shrunk.iter().any(|outer| path.starts_with(&outer.path))
+            {
+                continue;
+            }
+            shrunk.push(ShrunkDirectory {
+                path: path.clone(),
+                files,
+                missing,
+            });
+        }
+        shrunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outermost_shrunk_directories() {
+        let mut state = Vec::new();
+        for i in 0..20 {
+            state.push(PathBuf::from(format!("photos/2019/jan/{i}.jpg")));
+            state.push(PathBuf::from(format!("photos/2019/feb/{i}.jpg")));
+            state.push(PathBuf::from(format!("photos/2020/{i}.jpg")));
+        }
+        for i in 0..40 {
+            state.push(PathBuf::from(format!("docs/{i}.pdf")));
+        }
+        let counts = DirectoryCounts::of(state.iter().map(PathBuf::as_path));
+        let missing = state
+            .iter()
+            .filter(|path| path.starts_with("photos/2019") || path.ends_with("1.pdf"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts.shrunk(&missing),
+            vec![ShrunkDirectory {
+                path: PathBuf::from("photos/2019"),
+                files: 40,
+                missing: 40,
+            }]
+        );
+        // Half of jan and feb each, which is half of 2019 as well
+        let missing = &missing[..20];
+        assert_eq!(
+            counts.shrunk(missing),
+            vec![ShrunkDirectory {
+                path: PathBuf::from("photos/2019"),
+                files: 40,
+                missing: 20,
+            }]
+        );
+        assert_eq!(counts.shrunk(&missing[..8]), vec![]);
+        // Half of everything is missing, but all of it is in one folder
+        let state = (0..30)
+            .map(|i| PathBuf::from(format!("{}/{i}", ["kept", "gone"][i % 2])))
+            .collect::<Vec<_>>();
+        let counts = DirectoryCounts::of(state.iter().map(PathBuf::as_path));
+        let missing = state
+            .iter()
+            .filter(|path| path.starts_with("gone"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts.shrunk(&missing),
+            vec![ShrunkDirectory {
+                path: PathBuf::from("gone"),
+                files: 15,
+                missing: 15,
+            }]
+        );
+    }
+}
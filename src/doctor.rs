@@ -0,0 +1,296 @@
+use rusty_archive::checksum::{sidecar_path, verify_sidecar};
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::index::DigestCounts;
+use rusty_archive::state::{state_name_time, state_order, StateHeader};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Kinds of files written to a state directory
+const EXTENSIONS: [&str; 7] = [
+    "state", "modified", "missing", "index", "digests", "stats", "members",
+];
+
+/// A problem found in the state directory
+struct Problem {
+    file: PathBuf,
+    message: String,
+    fix: String,
+}
+
+/// What's known about a state, .modified or .missing file after reading it
+struct FileSummary {
+    header: StateHeader,
+    entries: usize,
+    latest_seen: Option<SystemTime>,
+}
+
+/// Reads a state, .modified or .missing file completely, noting every problem
+fn check_file(path: &Path, is_state: bool, problems: &mut Vec<Problem>) -> Result<FileSummary> {
+    let contents = std::fs::read(path).with_context(|| format!("Unable to read {:?}", path))?;
+    let mut problem = |message: String, fix: &str| {
+        problems.push(Problem {
+            file: path.to_path_buf(),
+            message,
+            fix: fix.to_owned(),
+        })
+    };
+    let mut summary = FileSummary {
+        header: StateHeader::default(),
+        entries: 0,
+        latest_seen: None,
+    };
+    if verify_sidecar(path, &Sha256::digest(&contents).into()).is_err() {
+        problem(
+            format!("doesn't match its checksum in {:?}", sidecar_path(path)),
+            "restore it from a backup, it was changed after it was written",
+        );
+    }
+    let contents = match String::from_utf8(contents) {
+        Ok(contents) => contents,
+        Err(_) => {
+            problem(
+                "is not valid UTF-8".to_owned(),
+                "restore it from a backup or delete it",
+            );
+            return Ok(summary);
+        }
+    };
+
+    if contents.is_empty() {
+        problem(
+            "is empty".to_owned(),
+            "delete it, it was most likely left behind by an aborted run",
+        );
+        return Ok(summary);
+    }
+    if !contents.ends_with('\n') {
+        problem(
+            "is truncated, the last line is incomplete".to_owned(),
+            "restore it from a backup or delete it and run update again",
+        );
+    }
+
+    let mut invalid_lines = 0;
+    let mut first_invalid = None;
+    let mut previous_path: Option<PathBuf> = None;
+    let mut unsorted = false;
+    let mut duplicates = 0;
+    for (line_number, line) in contents.lines().enumerate() {
+        match summary.header.parse_line(line) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(err) => {
+                invalid_lines += 1;
+                first_invalid.get_or_insert((line_number + 1, format!("{:#}", err)));
+                continue;
+            }
+        }
+        let fi = match FileInfo::parse(line) {
+            Ok(fi) => fi,
+            Err(err) => {
+                invalid_lines += 1;
+                first_invalid.get_or_insert((line_number + 1, format!("{:#}", err)));
+                continue;
+            }
+        };
+        summary.entries += 1;
+        summary.latest_seen = summary.latest_seen.max(Some(fi.last_seen));
+        if let Some(previous_path) = &previous_path {
+            if *previous_path == fi.rel_path {
+                duplicates += 1;
+            } else if *previous_path > fi.rel_path {
+                unsorted = true;
+            }
+        }
+        previous_path = Some(fi.rel_path);
+    }
+
+    if let Some((line_number, err)) = first_invalid {
+        problem(
+            format!(
+                "has {} invalid line(s), the first one is line {}: {}",
+                invalid_lines, line_number, err
+            ),
+            "restore it from a backup or delete it and run update again",
+        );
+    }
+    if is_state && unsorted {
+        problem(
+            "is not sorted by path, so it wasn't written by rusty-archive".to_owned(),
+            "sort the lines by path or delete it and run update again",
+        );
+    }
+    if is_state && duplicates > 0 {
+        problem(
+            format!("lists {} path(s) more than once", duplicates),
+            "remove the duplicate lines or delete it and run update again",
+        );
+    }
+    Ok(summary)
+}
+
+/// Counts the entries of an index, which are all lines after the header
+fn index_entries(path: &Path) -> Result<usize> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Unable to read {:?}", path))?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.starts_with("# "))
+        .count())
+}
+
+/// Checks the state directory and prints all problems found
+pub fn run(state_dir: &Path) -> Result<()> {
+    let mut problems = Vec::new();
+
+    // Files of every run, in the order of the timestamps they're named after
+    let mut runs: BTreeMap<_, Vec<(String, PathBuf)>> = BTreeMap::new();
+    for entry in
+        read_dir(state_dir).with_context(|| format!("Unable to list files in {:?}", state_dir))?
+    {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if file_name.starts_with(".spill-") || file_name.starts_with(".compact-") {
+            problems.push(Problem {
+                file: path.clone(),
+                message: "is a temporary file left behind by an aborted run".to_owned(),
+                fix: "delete it".to_owned(),
+            });
+            continue;
+        }
+        let (stem, extension) = match file_name.rsplit_once('.') {
+            Some((stem, extension)) if EXTENSIONS.contains(&extension) => (stem, extension),
+            _ => continue,
+        };
+        if state_name_time(stem).is_none() {
+            problems.push(Problem {
+                file: path.clone(),
+                message: "is not named after the time it was written".to_owned(),
+                fix: format!(
+                    "rename it to YYYYMMDDTHHMMSSZ.{} or move it out of the state directory, \
+                     the name decides which state is the latest",
+                    extension
+                ),
+            });
+            continue;
+        }
+        runs.entry(state_order(Path::new(stem)))
+            .or_default()
+            .push((extension.to_owned(), path.clone()));
+    }
+
+    let mut files_checked = 0;
+    let mut previous_state: Option<(PathBuf, SystemTime)> = None;
+    for files in runs.values() {
+        let state = files.iter().find(|(extension, _)| extension == "state");
+        let state_summary = match state {
+            Some((_, path)) => {
+                files_checked += 1;
+                Some((path, check_file(path, true, &mut problems)?))
+            }
+            None => None,
+        };
+
+        for (extension, path) in files {
+            if extension == "state" {
+                continue;
+            }
+            files_checked += 1;
+            let (state_path, state_summary) = match &state_summary {
+                Some(state_summary) => state_summary,
+                None => {
+                    problems.push(Problem {
+                        file: path.clone(),
+                        message: "has no matching .state file".to_owned(),
+                        fix: "restore the .state file from a backup or delete this one".to_owned(),
+                    });
+                    continue;
+                }
+            };
+            if extension == "stats" || extension == "members" {
+                continue;
+            }
+            if extension == "digests" {
+                let (_, counts) = DigestCounts::read(path)?;
+                if counts.files() != state_summary.entries as u64 {
+                    problems.push(Problem {
+                        file: path.clone(),
+                        message: format!("doesn't match {:?}", state_path),
+                        fix: "delete it, verify falls back to reading the state".to_owned(),
+                    });
+                }
+                continue;
+            }
+            if extension == "index" {
+                if index_entries(path)? != state_summary.entries {
+                    problems.push(Problem {
+                        file: path.clone(),
+                        message: format!("doesn't match {:?}", state_path),
+                        fix: "delete it, find falls back to reading the state".to_owned(),
+                    });
+                }
+                continue;
+            }
+            let summary = check_file(path, false, &mut problems)?;
+            if summary.entries > 0 && summary.header != state_summary.header {
+                problems.push(Problem {
+                    file: path.clone(),
+                    message: format!(
+                        "uses {} while {:?} uses {}",
+                        summary.header.hash_algorithm,
+                        state_path,
+                        state_summary.header.hash_algorithm
+                    ),
+                    fix: "restore both from a backup".to_owned(),
+                });
+            }
+        }
+
+        // Newer states have to contain files seen more recently, otherwise
+        // the clock was off or files were renamed
+        if let Some((
+            state_path,
+            FileSummary {
+                latest_seen: Some(latest_seen),
+                ..
+            },
+        )) = &state_summary
+        {
+            if let Some((previous_path, previous_seen)) = &previous_state {
+                if previous_seen > latest_seen {
+                    problems.push(Problem {
+                        file: previous_path.clone(),
+                        message: format!(
+                            "contains files seen more recently than the newer {:?}",
+                            state_path
+                        ),
+                        fix: "check the system clock and the names of both files, \
+                              the newest one by name is used for the next run"
+                            .to_owned(),
+                    });
+                }
+            }
+            previous_state = Some((state_path.to_path_buf(), *latest_seen));
+        }
+    }
+
+    for problem in &problems {
+        println!("PROBLEM: {:?} {}", problem.file, problem.message);
+        println!("└ fix: {}", problem.fix);
+    }
+    println!(
+        "checked {} file(s) in {:?}, {} problem(s) found",
+        files_checked,
+        state_dir,
+        problems.len()
+    );
+    if !problems.is_empty() {
+        return Err(anyhow::Error::msg("problems found in the state directory"));
+    }
+    Ok(())
+}
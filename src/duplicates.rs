@@ -0,0 +1,197 @@
+use crate::file_info::FileInfo;
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Files or directories with identical contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Digest of the files, or of the contents of the directories
+    pub digest: [u8; 32],
+    /// Size of every copy in bytes
+    pub len: u64,
+    /// Number of files in every copy, 1 for files
+    pub files: u64,
+    /// Sorted by path
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// Bytes freed by keeping only one copy
+    pub fn wasted(&self) -> u64 {
+        self.len * (self.paths.len() as u64 - 1)
+    }
+}
+
+/// Sorts the groups wasting the most space first
+fn sort_groups(groups: &mut [DuplicateGroup]) {
+    groups.sort_by(|a, b| b.wasted().cmp(&a.wasted()).then(a.paths.cmp(&b.paths)));
+}
+
+/// Groups files with the same digest, empty files are left out
+pub fn duplicate_files<'a>(files: impl IntoIterator<Item = &'a FileInfo>) -> Vec<DuplicateGroup> {
+    let mut by_digest: HashMap<[u8; 32], DuplicateGroup> = HashMap::new();
+    for fi in files.into_iter().filter(|fi| fi.len > 0) {
+        by_digest
+            .entry(fi.digest)
+            .or_insert_with(|| DuplicateGroup {
+                digest: fi.digest,
+                len: fi.len,
+                files: 1,
+                paths: Vec::new(),
+            })
+            .paths
+            .push(fi.rel_path.clone());
+    }
+    let mut groups = by_digest
+        .into_values()
+        .filter(|group| group.paths.len() > 1)
+        .map(|mut group| {
+            group.paths.sort();
+            group
+        })
+        .collect::<Vec<_>>();
+    sort_groups(&mut groups);
+    groups
+}
+
+/// Contents of a directory, entries are name, kind (b'f' or b'd') and digest
+#[derive(Default)]
+struct DirectoryContents {
+    entries: Vec<(OsString, u8, [u8; 32])>,
+    len: u64,
+    files: u64,
+}
+
+/// Groups directories with identical contents, including names
+///
+/// Only the outermost directories of a duplicated subtree are reported,
+/// directories within them are duplicates as well, of course.
+pub fn duplicate_dirs<'a>(files: impl IntoIterator<Item = &'a FileInfo>) -> Vec<DuplicateGroup> {
+    let mut dirs: HashMap<PathBuf, DirectoryContents> = HashMap::new();
+    for fi in files {
+        let parent = fi.rel_path.parent().unwrap_or(Path::new(""));
+        let dir = dirs.entry(parent.to_path_buf()).or_default();
+        dir.entries.push((
+            fi.rel_path.file_name().unwrap_or_default().to_owned(),
+            b'f',
+            fi.digest,
+        ));
+        dir.len += fi.len;
+        dir.files += 1;
+        // Directories only containing other directories need an entry as well
+        for ancestor in parent.ancestors().skip(1) {
+            dirs.entry(ancestor.to_path_buf()).or_default();
+        }
+    }
+
+    // Children have to be hashed before their parents
+    let mut paths = dirs.keys().cloned().collect::<Vec<_>>();
+    paths.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+    let mut hashed: HashMap<PathBuf, DuplicateGroup> = HashMap::new();
+    for path in paths {
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let mut dir = dirs.remove(&path).unwrap();
+        dir.entries.sort();
+        let mut hasher = Sha256::new();
+        for (name, kind, digest) in &dir.entries {
+            hasher.update(name.to_string_lossy().as_bytes());
+            hasher.update([0, *kind]);
+            hasher.update(digest);
+        }
+        let digest: [u8; 32] = hasher.finalize().into();
+
+        let parent = dirs
+            .get_mut(path.parent().unwrap_or(Path::new("")))
+            .unwrap();
+        parent.entries.push((
+            path.file_name().unwrap_or_default().to_owned(),
+            b'd',
+            digest,
+        ));
+        parent.len += dir.len;
+        parent.files += dir.files;
+        hashed.insert(
+            path.clone(),
+            DuplicateGroup {
+                digest,
+                len: dir.len,
+                files: dir.files,
+                paths: vec![path],
+            },
+        );
+    }
+
+    let mut by_digest: HashMap<[u8; 32], DuplicateGroup> = HashMap::new();
+    for group in hashed.values() {
+        match by_digest.get_mut(&group.digest) {
+            Some(existing) => existing.paths.extend(group.paths.iter().cloned()),
+            None => {
+                by_digest.insert(group.digest, group.clone());
+            }
+        }
+    }
+    let duplicated = |path: &Path| {
+        hashed
+            .get(path)
+            .is_some_and(|group| by_digest[&group.digest].paths.len() > 1)
+    };
+    let mut groups = by_digest
+        .values()
+        .filter(|group| group.paths.len() > 1)
+        .filter(|group| {
+            !group
+                .paths
+                .iter()
+                .all(|path| duplicated(path.parent().unwrap_or(Path::new(""))))
+        })
+        .cloned()
+        .map(|mut group| {
+            group.paths.sort();
+            group
+        })
+        .collect::<Vec<_>>();
+    sort_groups(&mut groups);
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicated_files_and_dirs() {
+        let files = [
+            FileInfo::new("photos/2020/a.jpg", [1; 32], 10),
+            FileInfo::new("photos/2020/raw/a.cr2", [2; 32], 10),
+            FileInfo::new("backup/old/2020/a.jpg", [1; 32], 10),
+            FileInfo::new("backup/old/2020/raw/a.cr2", [2; 32], 10),
+            FileInfo::new("backup/old/notes.txt", [3; 32], 10),
+            FileInfo::new("other/a.jpg", [1; 32], 10),
+        ];
+
+        let groups = duplicate_files(&files);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].paths.len(), 3);
+        assert_eq!(groups[0].wasted(), 20);
+
+        // Only the outermost duplicated directories are reported
+        let groups = duplicate_dirs(&files);
+        assert_eq!(
+            groups,
+            vec![DuplicateGroup {
+                digest: groups[0].digest,
+                len: 20,
+                files: 2,
+                paths: vec![
+                    PathBuf::from("backup/old/2020"),
+                    PathBuf::from("photos/2020")
+                ],
+            }]
+        );
+    }
+}
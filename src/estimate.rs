@@ -0,0 +1,147 @@
+use rusty_archive::history::state_paths;
+use rusty_archive::state::read_state;
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// Number of latest updates the throughput is measured over
+const RUNS_MEASURED: usize = 5;
+
+/// What an update read, from the .stats file it wrote
+#[derive(Debug, PartialEq)]
+struct PastRun {
+    bytes_read: u64,
+    duration: Duration,
+}
+
+/// Value of a top level number in a .stats file
+///
+/// The file is written one top level key per line, nested values are
+/// indented further and not found.
+fn stats_value(contents: &str, key: &str) -> Option<f64> {
+    let prefix = format!("  \"{}\": ", key);
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .and_then(|value| value.trim_end_matches(',').parse().ok())
+}
+
+fn parse_run(contents: &str) -> Option<PastRun> {
+    Some(PastRun {
+        bytes_read: stats_value(contents, "bytes_read")? as u64,
+        duration: Duration::try_from_secs_f64(stats_value(contents, "duration_secs")?).ok()?,
+    })
+}
+
+/// The latest updates that read anything, newest first
+fn past_runs(state_dir: &Path) -> Result<Vec<PastRun>> {
+    let mut runs = Vec::new();
+    for state_path in state_paths(state_dir)?.iter().rev() {
+        let stats_path = state_path.with_extension("stats");
+        if !stats_path.exists() {
+            continue;
+        }
+        let contents = std::fs::read_to_string(&stats_path)
+            .with_context(|| format!("Unable to read {:?}", stats_path))?;
+        match parse_run(&contents) {
+            Some(run) if run.bytes_read > 0 => runs.push(run),
+            _ => {}
+        }
+        if runs.len() == RUNS_MEASURED {
+            break;
+        }
+    }
+    Ok(runs)
+}
+
+/// Bytes per second over all runs, None without any
+fn throughput(runs: &[PastRun]) -> Option<f64> {
+    let bytes: u64 = runs.iter().map(|run| run.bytes_read).sum();
+    let secs: f64 = runs.iter().map(|run| run.duration.as_secs_f64()).sum();
+    (secs > 0.0).then(|| bytes as f64 / secs)
+}
+
+/// Hours and minutes, rounded up to the next minute
+pub(crate) fn hours_minutes(duration: Duration) -> String {
+    let minutes = duration.as_secs().div_ceil(60);
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+/// Prints how much a run would read and how long that takes, going by earlier updates
+///
+/// verify and update with read_all_files read every file of the latest
+/// state. Other updates are expected to read as much as the recent ones
+/// did on average. Nothing is read from the archive itself.
+pub fn run(state_dir: &Path, verify: bool, read_all_files: bool) -> Result<()> {
+    let (_, files) = read_state(state_dir)?;
+    let runs = past_runs(state_dir)?;
+    let gib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+    let total = files.values().map(|fi| fi.len).sum();
+    println!(
+        "latest state: {} files ({:.1} GiB)",
+        files.len(),
+        gib(total)
+    );
+    let (what, bytes) = match (verify, read_all_files) {
+        (true, _) => ("verify", total),
+        (false, true) => ("update --read-all-files", total),
+        (false, false) if runs.is_empty() => ("update", total),
+        (false, false) => (
+            "update",
+            runs.iter().map(|run| run.bytes_read).sum::<u64>() / runs.len() as u64,
+        ),
+    };
+    let Some(throughput) = throughput(&runs) else {
+        println!(
+            "└ {} reads about {:.1} GiB, no stats of earlier updates to estimate the duration",
+            what,
+            gib(bytes)
+        );
+        return Ok(());
+    };
+    println!(
+        "measured {:.0} MiB/s over the last {} updates",
+        throughput / 1024.0 / 1024.0,
+        runs.len()
+    );
+    println!(
+        "└ {} reads about {:.1} GiB and takes about {}",
+        what,
+        gib(bytes),
+        hours_minutes(Duration::from_secs_f64(bytes as f64 / throughput))
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusty_archive::stats::Stats;
+
+    #[test]
+    fn throughput_from_stats() -> Result<(), Box<dyn std::error::Error>> {
+        let stats = Stats {
+            bytes_read: 3000,
+            ..Default::default()
+        };
+        let mut written = Vec::new();
+        stats.write_json(Duration::from_secs(3), Some("0123"), &mut written)?;
+        let run = parse_run(std::str::from_utf8(&written)?).unwrap();
+        assert_eq!(
+            run,
+            PastRun {
+                bytes_read: 3000,
+                duration: Duration::from_secs(3),
+            }
+        );
+        let slow = PastRun {
+            bytes_read: 1000,
+            duration: Duration::from_secs(7),
+        };
+        assert_eq!(throughput(&[run, slow]), Some(400.0));
+        assert_eq!(throughput(&[]), None);
+        assert_eq!(hours_minutes(Duration::from_secs(3 * 3600 + 61)), "3h 02m");
+        Ok(())
+    }
+}
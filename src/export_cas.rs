@@ -0,0 +1,135 @@
+use rusty_archive::hash::HashAlgorithm;
+use rusty_archive::state::read_state;
+
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Path of the object with the given digest, like `objects/ab/cdef...`
+fn object_path(hexdigest: &str) -> PathBuf {
+    Path::new("objects")
+        .join(&hexdigest[..2])
+        .join(&hexdigest[2..])
+}
+
+/// Copies a file to `to`, failing if it doesn't have the expected digest
+///
+/// The copy is written next to `to` and only renamed once its contents
+/// are confirmed, so an interrupted export never leaves a broken object.
+fn copy_verified(
+    from: &Path,
+    to: &Path,
+    digest: &[u8; 32],
+    hash_algorithm: HashAlgorithm,
+) -> Result<()> {
+    let mut tmp_name = to.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = to.with_file_name(tmp_name);
+    let mut src = File::open(from)?;
+    let mut dst = BufWriter::with_capacity(
+        1024 * 1024,
+        File::create(&tmp).with_context(|| format!("Unable to create {:?}", tmp))?,
+    );
+    let mut hasher = hash_algorithm.hasher();
+    let mut buf = vec![0_u8; 1024 * 1024];
+    loop {
+        let bytes_read = src
+            .read(&mut buf)
+            .with_context(|| format!("Unable to read {:?}", from))?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+        dst.write_all(&buf[..bytes_read])
+            .with_context(|| format!("Unable to write {:?}", tmp))?;
+    }
+    dst.into_inner()
+        .map_err(|err| err.into_error())
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("Unable to write {:?}", tmp))?;
+    if &hasher.finalize() != digest {
+        fs::remove_file(&tmp)?;
+        return Err(anyhow::Error::msg(format!(
+            "{:?} was modified since the last update, run an update first",
+            from
+        )));
+    }
+    fs::rename(&tmp, to).with_context(|| format!("Unable to rename {:?} to {:?}", tmp, to))
+}
+
+/// Copies the latest state of the archive into a content addressed layout
+///
+/// Every distinct file is stored once as `objects/ab/cdef...`, named after
+/// its digest. `manifest` lists the original paths and
+/// `objects.<algorithm>` the objects, both in the format of `sha256sum`
+/// (or `b3sum`), so the copy can be checked with those alone. Objects
+/// already in `dest` are kept, running the export again only copies what's
+/// new.
+pub fn run(state_dir: &Path, directory: &Path, dest: &Path) -> Result<()> {
+    let (header, files) = read_state(state_dir)?;
+    let mut files = files.into_values().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+
+    let mut manifest = String::new();
+    let mut objects = String::new();
+    let mut exported = HashSet::new();
+    let (mut copied, mut bytes_copied, mut missing) = (0, 0, 0);
+    for fi in &files {
+        let hexdigest = hex::encode(fi.digest);
+        let object = object_path(&hexdigest);
+        if exported.insert(fi.digest) {
+            let object_path = dest.join(&object);
+            if !object_path.exists() {
+                fs::create_dir_all(object_path.parent().unwrap())
+                    .with_context(|| format!("Unable to create {:?}", object_path.parent()))?;
+                match copy_verified(
+                    &directory.join(&fi.rel_path),
+                    &object_path,
+                    &fi.digest,
+                    header.hash_algorithm,
+                ) {
+                    Ok(()) => {
+                        copied += 1;
+                        bytes_copied += fi.len;
+                    }
+                    Err(err)
+                        if err
+                            .downcast_ref::<io::Error>()
+                            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound) =>
+                    {
+                        println!("MISSING: {}", fi.rel_path.to_string_lossy());
+                        exported.remove(&fi.digest);
+                        missing += 1;
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            objects.push_str(&format!("{}  {}\n", hexdigest, object.to_string_lossy()));
+        }
+        manifest.push_str(&format!(
+            "{}  {}\n",
+            hexdigest,
+            fi.rel_path.to_string_lossy()
+        ));
+    }
+
+    fs::write(dest.join("manifest"), manifest)
+        .with_context(|| format!("Unable to write the manifest to {:?}", dest))?;
+    fs::write(
+        dest.join(format!("objects.{}", header.hash_algorithm)),
+        objects,
+    )
+    .with_context(|| format!("Unable to write the list of objects to {:?}", dest))?;
+    println!(
+        "└ {} objects for {} files exported, {} copied ({:.1} GiB), {} missing",
+        exported.len(),
+        files.len() - missing,
+        copied,
+        bytes_copied as f64 / 1024.0 / 1024.0 / 1024.0,
+        missing
+    );
+    Ok(())
+}
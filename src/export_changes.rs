@@ -0,0 +1,144 @@
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::history::{diff, state_paths};
+use rusty_archive::state::{read_state_file, resolve_state};
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Backup program to write the changes for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupTool {
+    /// Paths for `restic backup --files-from-verbatim`
+    Restic,
+    /// Patterns for `borg create --patterns-from`, excluding everything else
+    Borg,
+}
+
+impl FromStr for BackupTool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restic" => Ok(BackupTool::Restic),
+            "borg" => Ok(BackupTool::Borg),
+            _ => Err(format!("expected `restic` or `borg`, got `{}`", s)),
+        }
+    }
+}
+
+/// Files of a state, sorted by path like [`diff`] expects
+fn sorted_files(path: &Path) -> Result<Vec<FileInfo>> {
+    let mut files = read_state_file(path)?.files;
+    files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    Ok(files)
+}
+
+/// Lines of the file for tool, which backs up the changed files below directory
+///
+/// Neither tool has a way to quote a line break, paths containing one are
+/// an error instead of a list that backs up something else.
+fn patterns(tool: BackupTool, directory: &Path, changed: &[&Path]) -> Result<String> {
+    let mut lines = Vec::new();
+    if tool == BackupTool::Borg {
+        lines.push(format!("R {}", directory.to_string_lossy()));
+    }
+    for rel_path in changed {
+        let path = directory.join(rel_path).to_string_lossy().into_owned();
+        if path.contains(['\n', '\r']) {
+            return Err(anyhow::Error::msg(format!(
+                "{:?} can't be listed, it contains a line break",
+                rel_path
+            )));
+        }
+        lines.push(match tool {
+            BackupTool::Restic => path,
+            BackupTool::Borg => format!("+ pf:{}", path),
+        });
+    }
+    // Excluded with `-` instead of `!`, so borg still looks for the
+    // files included above below it
+    if tool == BackupTool::Borg {
+        lines.push(format!("- pp:{}", directory.to_string_lossy()));
+    }
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+/// Writes the files new or modified in the latest state for a backup tool
+///
+/// They're compared to since, or the state before the latest one. Paths
+/// are absolute, below the resolved directory. Files gone since aren't
+/// listed, both tools keep them in the snapshots they were backed up in.
+pub fn run(
+    state_dir: &Path,
+    tool: BackupTool,
+    since: Option<&Path>,
+    output: &str,
+    directory: &Path,
+) -> Result<()> {
+    let paths = state_paths(state_dir)?;
+    let Some(latest) = paths.last() else {
+        return Err(anyhow::Error::msg(format!(
+            "no state in {:?}, run an update first",
+            state_dir
+        )));
+    };
+    let older_path = match since {
+        Some(since) => Some(resolve_state(state_dir, &since.to_string_lossy())?),
+        None => paths.len().checked_sub(2).map(|i| paths[i].clone()),
+    };
+    let newer = sorted_files(latest)?;
+    let older = match &older_path {
+        Some(path) => sorted_files(path)?,
+        None => Vec::new(),
+    };
+    let diff = diff(&older, &newer);
+    let mut changed = diff
+        .added
+        .iter()
+        .map(|fi| fi.rel_path.as_path())
+        .chain(diff.modified.iter().map(|(_, fi)| fi.rel_path.as_path()))
+        .collect::<Vec<_>>();
+    changed.sort();
+    let directory = directory
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve {:?}", directory))?;
+    let contents = patterns(tool, &directory, &changed)?;
+    if output == "-" {
+        print!("{}", contents);
+        return Ok(());
+    }
+    std::fs::write(output, contents).with_context(|| format!("Unable to write {:?}", output))?;
+    println!(
+        "{} files new or modified since {} written to {:?}",
+        changed.len(),
+        match &older_path {
+            Some(path) => path.file_name().unwrap_or_default().to_string_lossy(),
+            None => "the first update".into(),
+        },
+        output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_of_changed_files() -> Result<(), Box<dyn std::error::Error>> {
+        let changed = [Path::new("a"), Path::new("photos/b.jpg")];
+        assert_eq!(
+            patterns(BackupTool::Restic, Path::new("/data"), &changed)?,
+            "/data/a\n/data/photos/b.jpg\n"
+        );
+        assert_eq!(
+            patterns(BackupTool::Borg, Path::new("/data"), &changed)?,
+            "R /data\n+ pf:/data/a\n+ pf:/data/photos/b.jpg\n- pp:/data\n"
+        );
+        assert!(patterns(BackupTool::Restic, Path::new("/data"), &[Path::new("a\nb")]).is_err());
+        assert_eq!("borg".parse(), Ok(BackupTool::Borg));
+        assert!("tar".parse::<BackupTool>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::hash::HashAlgorithm;
+use rusty_archive::state::read_state;
+use rusty_archive::torrent::{metainfo, FileHashes, MerkleHasher, BLOCK_SIZE};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Reads a file, building its merkle tree and checking its digest on the way
+fn hash_file(
+    directory: &Path,
+    fi: &FileInfo,
+    piece_length: u64,
+    hash_algorithm: HashAlgorithm,
+) -> Result<FileHashes> {
+    let path = directory.join(&fi.rel_path);
+    let mut f = File::open(&path).with_context(|| format!("Unable to open {:?}", path))?;
+    let mut merkle = MerkleHasher::new(piece_length);
+    let mut hasher = hash_algorithm.hasher();
+    let mut buf = vec![0_u8; 64 * BLOCK_SIZE];
+    loop {
+        let bytes_read = f
+            .read(&mut buf)
+            .with_context(|| format!("Unable to read {:?}", path))?;
+        if bytes_read == 0 {
+            break;
+        }
+        merkle.update(&buf[..bytes_read]);
+        hasher.update(&buf[..bytes_read]);
+    }
+    if hasher.finalize() != fi.digest {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} was modified since the last update, run an update first",
+            path
+        )));
+    }
+    Ok(merkle.finalize())
+}
+
+/// Writes a BitTorrent v2 torrent of all files in the latest state
+///
+/// Every file is read again, v2 torrents need merkle trees of 16 KiB
+/// blocks. The digests in the state are checked along the way.
+pub fn run(
+    state_dir: &Path,
+    directory: &Path,
+    torrent: &Path,
+    name: Option<&str>,
+    piece_length: u64,
+    announce: &[String],
+) -> Result<()> {
+    if !piece_length.is_power_of_two() || piece_length < BLOCK_SIZE as u64 {
+        return Err(anyhow::Error::msg(format!(
+            "piece size must be a power of two of at least 16 KiB, got {}",
+            piece_length
+        )));
+    }
+    let (header, files) = read_state(state_dir)?;
+    let mut files = files.into_values().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    let hashes = files
+        .par_iter()
+        .map(|fi| hash_file(directory, fi, piece_length, header.hash_algorithm))
+        .collect::<Result<Vec<_>>>()?;
+
+    let name = match name {
+        Some(name) => name.to_owned(),
+        None => directory
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve {:?}", directory))?
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let (encoded, info_hash) = metainfo(
+        &name,
+        piece_length,
+        files.iter().map(|fi| fi.rel_path.as_path()).zip(&hashes),
+        announce,
+    );
+    std::fs::write(torrent, encoded).with_context(|| format!("Unable to write {:?}", torrent))?;
+    println!(
+        "└ wrote {:?} with {} files, magnet:?xt=urn:btmh:1220{}",
+        torrent,
+        files.len(),
+        hex::encode(info_hash)
+    );
+    Ok(())
+}
@@ -1,26 +1,116 @@
-use crate::file_info::FileInfo;
+use crate::file_info::{Attributes, FileInfo};
+use crate::hash::HashAlgorithm;
+use crate::roots::Roots;
 use anyhow::{Context, Result};
+use blake3::hazmat::{
+    left_subtree_len, merge_subtrees_non_root, merge_subtrees_root, ChainingValue, HasherExt, Mode,
+};
 
-use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 use std::fmt::Debug;
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
-use std::time::SystemTime;
-use walkdir::DirEntry;
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default buffer size used for reading files
+pub const DEFAULT_BUF_SIZE: usize = 4 * 1024 * 1024;
+
+/// Alignment of the read buffer, required for direct IO
+pub const BUF_ALIGNMENT: usize = 4096;
+
+/// Files at least this large are read in ranges hashed in parallel, for BLAKE3
+const PARALLEL_HASHING_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Ranges of a file longer than this are split up again, see [`blake3_in_ranges`]
+const PARALLEL_RANGE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Bytes from the start and from the end of a file its quick digest covers
+pub const QUICK_LEN: usize = 64 * 1024;
+
+/// Options controlling how files are read and hashed
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    /// Bypass the page cache
+    pub direct_io: bool,
+    /// Algorithm to hash the contents with
+    pub hash_algorithm: HashAlgorithm,
+    /// Size of the read buffer of every thread, a multiple of BUF_ALIGNMENT
+    pub buffer_size: usize,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            direct_io: false,
+            hash_algorithm: HashAlgorithm::default(),
+            buffer_size: DEFAULT_BUF_SIZE,
+        }
+    }
+}
+
+/// A check cheaper than reading a file completely, see [`FileToCheck::first_failed_tier`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckTier {
+    /// The size matches the state
+    Size,
+    /// The quick digest matches the state, see [`quick_digest`]
+    Quick,
+}
+
+impl FromStr for CheckTier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(CheckTier::Size),
+            "quick" => Ok(CheckTier::Quick),
+            _ => Err(format!("expected `size` or `quick`, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for CheckTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CheckTier::Size => "size",
+            CheckTier::Quick => "quick",
+        })
+    }
+}
+
+/// How many files passed a tier and how many were read completely because of it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TierStats {
+    pub tier: CheckTier,
+    pub passed: u64,
+    /// Failed it, or it couldn't be run for them
+    pub escalated: u64,
+}
 
 /// A file that needs to be checked
-#[derive(Debug)]
+///
+/// The metadata is the one retrieved while listing files. It's reused
+/// when hashing, so every file is only stat'ed once.
+#[derive(Clone, Debug)]
 pub enum FileToCheck {
     /// not seen before
-    New(DirEntry),
+    New {
+        path: PathBuf,
+        rel_path: PathBuf,
+        metadata: Metadata,
+    },
 
     /// previously seen with different metadata
-    NeedsChecking(FileInfo),
+    NeedsChecking {
+        previous: FileInfo,
+        metadata: Metadata,
+    },
 }
 
 /// Information about a modified file
+#[derive(Debug, Clone)]
 pub struct FileCheckResultModified {
     /// FileInfo of the previous state
     pub previous: FileInfo,
@@ -29,71 +119,785 @@ pub struct FileCheckResultModified {
 }
 
 /// Result of checking a file
+#[derive(Debug, Clone)]
 pub enum FileCheckResult {
     /// The file was not seen before
     New(FileInfo),
     /// The file was previously seen with different metadata but the same contents
     Unmodifed(FileInfo),
     /// The file was previously seen with different metadata and different contents
-    Modified(FileCheckResultModified),
+    Modified(Box<FileCheckResultModified>),
     /// The file was previously seen but is now missing
     Missing(FileInfo),
 }
 
+impl FileCheckResult {
+    /// Path of the file relative to the base directory
+    pub fn rel_path(&self) -> &Path {
+        match self {
+            FileCheckResult::New(fi)
+            | FileCheckResult::Unmodifed(fi)
+            | FileCheckResult::Missing(fi) => &fi.rel_path,
+            FileCheckResult::Modified(fi_mod) => &fi_mod.current.rel_path,
+        }
+    }
+
+    /// Leaves out the times the file was read and seen and by which host, see [`crate::state::StateHeader::canonical`]
+    pub fn without_run_times(self) -> Self {
+        let clear = |mut fi: FileInfo| {
+            fi.fully_read = UNIX_EPOCH;
+            fi.last_seen = UNIX_EPOCH;
+            fi.host = None;
+            fi
+        };
+        match self {
+            FileCheckResult::New(fi) => FileCheckResult::New(clear(fi)),
+            FileCheckResult::Unmodifed(fi) => FileCheckResult::Unmodifed(clear(fi)),
+            FileCheckResult::Modified(fi_mod) => {
+                FileCheckResult::Modified(Box::new(FileCheckResultModified {
+                    previous: clear(fi_mod.previous),
+                    current: clear(fi_mod.current),
+                }))
+            }
+            FileCheckResult::Missing(fi) => FileCheckResult::Missing(clear(fi)),
+        }
+    }
+
+    /// Records the file as stored on volume, unless it's missing
+    pub fn set_volume(&mut self, volume: &str) {
+        match self {
+            FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => {
+                fi.volume = Some(volume.to_owned())
+            }
+            FileCheckResult::Modified(fi_mod) => fi_mod.current.volume = Some(volume.to_owned()),
+            FileCheckResult::Missing(_) => {}
+        }
+    }
+}
+
+/// A file that couldn't be read, the run carries on without it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadError {
+    pub rel_path: PathBuf,
+    pub kind: io::ErrorKind,
+    /// Error number of the OS, if the error came from it
+    pub errno: Option<i32>,
+    /// The error along with its context
+    pub message: String,
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl ReadError {
+    /// None if the error isn't an I/O error, those still end a run
+    pub fn new(rel_path: &Path, err: &anyhow::Error) -> Option<ReadError> {
+        let io_err = err.chain().find_map(|e| e.downcast_ref::<io::Error>())?;
+        Some(ReadError {
+            rel_path: rel_path.to_owned(),
+            kind: io_err.kind(),
+            errno: io_err.raw_os_error(),
+            message: format!("{:#}", err),
+        })
+    }
+
+    /// Whether another program has the file open exclusively or locked
+    pub fn is_locked(&self) -> bool {
+        // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+        #[cfg(windows)]
+        const LOCKED: [i32; 2] = [32, 33];
+        #[cfg(unix)]
+        const LOCKED: [i32; 3] = [libc::EBUSY, libc::ETXTBSY, libc::EWOULDBLOCK];
+        #[cfg(not(any(windows, unix)))]
+        const LOCKED: [i32; 0] = [];
+        self.errno.is_some_and(|errno| LOCKED.contains(&errno))
+    }
+
+    /// Whether reading the file again could work, e.g. after a network filesystem reconnected
+    pub fn may_be_transient(&self) -> bool {
+        !matches!(
+            self.kind,
+            io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+        )
+    }
+
+    /// Writes one line of an .errors file: `<path> # kind <kind> errno <errno> error <message>`
+    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            to,
+            "{} # kind {:?} errno {} error {}",
+            self.rel_path.to_string_lossy(),
+            self.kind,
+            self.errno.map_or("-".to_owned(), |errno| errno.to_string()),
+            self.message
+        )
+    }
+}
+
 impl FileToCheck {
+    /// Path of the file in the state
+    pub fn rel_path(&self) -> &Path {
+        match self {
+            FileToCheck::New { rel_path, .. } => rel_path,
+            FileToCheck::NeedsChecking { previous, .. } => &previous.rel_path,
+        }
+    }
+
+    /// Metadata of the file when it was listed
+    pub fn metadata(&self) -> &Metadata {
+        match self {
+            FileToCheck::New { metadata, .. } | FileToCheck::NeedsChecking { metadata, .. } => {
+                metadata
+            }
+        }
+    }
+
+    /// The same file with its current metadata, to read it again later in a run
+    pub fn listed_again(self, roots: &Roots) -> io::Result<FileToCheck> {
+        // Recorded links are hashed by where they point to, others are read through
+        let stat = |path: &Path, listed: &Metadata| match listed.file_type().is_symlink() {
+            true => std::fs::symlink_metadata(path),
+            false => std::fs::metadata(path),
+        };
+        Ok(match self {
+            FileToCheck::New {
+                path,
+                rel_path,
+                metadata,
+            } => FileToCheck::New {
+                metadata: stat(&path, &metadata)?,
+                path,
+                rel_path,
+            },
+            FileToCheck::NeedsChecking { previous, metadata } => {
+                let path = roots.full_path(&previous.rel_path);
+                FileToCheck::NeedsChecking {
+                    metadata: stat(&path, &metadata)?,
+                    previous,
+                }
+            }
+        })
+    }
+
+    /// Runs cheap checks against the state in order, returns the first one the file fails
+    ///
+    /// None if it passed all of them, it's considered unmodified then. New
+    /// files fail the first tier, there's nothing to compare them to. So do
+    /// files without a quick digest at [`CheckTier::Quick`].
+    pub fn first_failed_tier(
+        &self,
+        tiers: &[CheckTier],
+        roots: &Roots,
+        read_options: &ReadOptions,
+    ) -> Result<Option<CheckTier>> {
+        let FileToCheck::NeedsChecking {
+            previous, metadata, ..
+        } = self
+        else {
+            return Ok(tiers.first().copied());
+        };
+        for &tier in tiers {
+            let passed = match tier {
+                CheckTier::Size => metadata.len() == previous.len,
+                CheckTier::Quick => match previous.quick_digest {
+                    Some(recorded) => {
+                        let full_path = roots.full_path(&previous.rel_path);
+                        read_quick_digest(&full_path, read_options)
+                            .with_context(|| format!("Failed to read {:?}", full_path))?
+                            == Some(recorded)
+                    }
+                    None => false,
+                },
+            };
+            if !passed {
+                return Ok(Some(tier));
+            }
+        }
+        Ok(None)
+    }
+
     /// Determine the current FileInfo for a file and if it's been modified
     ///
     /// This function will always read the file completely and hash
-    /// it's contents.
-    pub fn check(self, base_path: &Path) -> Result<FileCheckResult> {
+    /// it's contents. The metadata recorded is the one of the file opened,
+    /// not the one it was listed with, see [`hash_file`].
+    pub fn check(self, roots: &Roots, read_options: &ReadOptions) -> Result<FileCheckResult> {
         match self {
-            FileToCheck::New(new_file) => Ok(FileCheckResult::New(
-                hash_file(base_path, new_file.path())
-                    .with_context(|| format!("Failed to read new file {:?}", new_file.path()))?,
+            FileToCheck::New {
+                path,
+                rel_path,
+                metadata,
+            } => Ok(FileCheckResult::New(
+                hash_file(&rel_path, &path, &metadata, read_options)
+                    .with_context(|| format!("Failed to read new file {:?}", path))?,
             )),
-            FileToCheck::NeedsChecking(file_needs_checking) => {
-                let full_path = base_path.join(file_needs_checking.rel_path.as_path());
-                let file_info = hash_file(base_path, full_path.as_path()).with_context(|| {
+            FileToCheck::NeedsChecking {
+                previous: file_needs_checking,
+                metadata,
+            } => {
+                let full_path = roots.full_path(&file_needs_checking.rel_path);
+                let mut file_info = hash_file(
+                    &file_needs_checking.rel_path,
+                    &full_path,
+                    &metadata,
+                    read_options,
+                )
+                .with_context(|| {
                     format!("Failed to read potentially modified file {:?}", full_path)
                 })?;
-                if file_info.sha256_digest == file_needs_checking.sha256_digest {
+                file_info.volume.clone_from(&file_needs_checking.volume);
+                if file_info.digest == file_needs_checking.digest {
                     Ok(FileCheckResult::Unmodifed(file_info))
                 } else {
-                    Ok(FileCheckResult::Modified(FileCheckResultModified {
-                        previous: file_needs_checking,
-                        current: file_info,
-                    }))
+                    Ok(FileCheckResult::Modified(Box::new(
+                        FileCheckResultModified {
+                            previous: file_needs_checking,
+                            current: file_info,
+                        },
+                    )))
                 }
             }
         }
     }
 }
 
-/// Reads a file, hashes it's contents and returns the current FileInfo
-fn hash_file(base_path: &Path, file: &Path) -> Result<FileInfo, io::Error> {
-    thread_local!(static BUF: RefCell<Vec<u8>>  = RefCell::new(vec![0_u8; 4 * 1024 * 1024]));
+/// Path of a directory files below it can be opened with, however deep
+///
+/// Windows refuses paths longer than 260 characters unless they start with
+/// `\\?\`, which also turns off all normalization of them. The path is
+/// made absolute first, resolving drive-relative paths like `D:photos`.
+/// Paths are returned unchanged on other platforms.
+pub fn long_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    if let Some(absolute) = std::path::absolute(path)
+        .ok()
+        .and_then(|p| p.to_str().map(verbatim))
+    {
+        return PathBuf::from(absolute);
+    }
+    path.to_path_buf()
+}
+
+/// Adds the `\\?\` prefix to an absolute Windows path, UNC shares become `\\?\UNC\`
+#[cfg(any(windows, test))]
+fn verbatim(path: &str) -> String {
+    if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        path.to_owned()
+    } else if let Some(share) = path.strip_prefix(r"\\") {
+        format!(r"\\?\UNC\{}", share)
+    } else {
+        format!(r"\\?\{}", path)
+    }
+}
+
+/// Opens a file for reading, honoring the given ReadOptions
+fn open_file(file: &Path, read_options: &ReadOptions) -> Result<File, io::Error> {
+    let mut options = File::options();
+    options.read(true);
+    if read_options.direct_io {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_DIRECT);
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            // FILE_FLAG_NO_BUFFERING
+            options.custom_flags(0x20000000);
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "direct IO is not supported on this platform",
+            ));
+        }
+    }
+    let f = options.open(file)?;
+    #[cfg(target_os = "macos")]
+    if read_options.direct_io {
+        use std::os::unix::io::AsRawFd;
+        // macOS has no O_DIRECT, but F_NOCACHE disables caching for this descriptor
+        if unsafe { libc::fcntl(f.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(f)
+}
+
+/// Digest of the size, the first and the last QUICK_LEN bytes of a file
+///
+/// Meant for large media files: a bad sector, a truncated copy or a file
+/// replaced by another one usually changes one of them, while reading a
+/// few hundred KiB is a lot faster than reading gigabytes. None for files
+/// of up to twice QUICK_LEN, they're read completely in about the same time.
+pub fn quick_digest(
+    hash_algorithm: HashAlgorithm,
+    len: u64,
+    head: &[u8],
+    tail: &[u8],
+) -> Option<[u8; 32]> {
+    if len <= 2 * QUICK_LEN as u64 {
+        return None;
+    }
+    let mut hasher = hash_algorithm.hasher();
+    hasher.update(&len.to_le_bytes());
+    hasher.update(head);
+    hasher.update(tail);
+    Some(hasher.finalize())
+}
+
+/// Reads only the start and the end of a file for its quick digest
+///
+/// The file is read like hash_file reads it, with direct IO if asked for
+/// and into the aligned buffer of the current thread.
+pub fn read_quick_digest(file: &Path, read_options: &ReadOptions) -> io::Result<Option<[u8; 32]>> {
+    let f = open_file(file, read_options)?;
+    let len = f.metadata()?.len();
+    if len <= 2 * QUICK_LEN as u64 {
+        return Ok(None);
+    }
+    let parts = read_quick_parts(&f, len, read_options.buffer_size)?;
+    Ok(quick_digest(
+        read_options.hash_algorithm,
+        len,
+        &parts.head,
+        &parts.tail,
+    ))
+}
+
+/// The first and last QUICK_LEN bytes of a file while it's read front to back
+#[derive(Default)]
+struct QuickParts {
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl QuickParts {
+    fn push(&mut self, chunk: &[u8]) {
+        let head_missing = QUICK_LEN - self.head.len();
+        self.head
+            .extend_from_slice(&chunk[..head_missing.min(chunk.len())]);
+        if chunk.len() >= QUICK_LEN {
+            self.tail.clear();
+            self.tail
+                .extend_from_slice(&chunk[chunk.len() - QUICK_LEN..]);
+        } else {
+            self.tail.extend_from_slice(chunk);
+            let excess = self.tail.len().saturating_sub(QUICK_LEN);
+            self.tail.drain(..excess);
+        }
+    }
+}
+
+/// Runs `f` with the aligned read buffer of the current thread
+///
+/// Nothing run in `f` may wait for other rayon jobs, a waiting thread could
+/// run one of them in the meantime and borrow the buffer a second time.
+fn with_buffer<T>(buffer_size: usize, f: impl FnOnce(&mut [u8]) -> T) -> T {
+    thread_local!(static BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) });
 
     BUF.with(|buf| {
-        let mut f = File::open(file)?;
-        let mut hasher = Sha256::new();
-        let mut total_bytes_read = 0;
-        loop {
-            let bytes_read = f.read(buf.borrow_mut().as_mut_slice())?;
-            if bytes_read > 0 {
-                total_bytes_read += bytes_read;
-                hasher.update(&buf.borrow()[0..bytes_read]);
-            } else {
-                break;
-            }
+        let mut buf = buf.borrow_mut();
+        // Allocate some extra space, so an aligned slice can always be found
+        if buf.len() != buffer_size + BUF_ALIGNMENT {
+            *buf = vec![0_u8; buffer_size + BUF_ALIGNMENT];
         }
-        let file_digest = hasher.finalize();
-        Ok(FileInfo {
-            rel_path: file.strip_prefix(base_path).unwrap().to_path_buf(),
-            sha256_digest: file_digest.try_into().expect("wrong length"),
-            mtime: file.metadata()?.modified()?,
-            len: total_bytes_read as u64,
-            fully_read: SystemTime::now(),
-            last_seen: SystemTime::now(),
+        let offset = buf.as_ptr().align_offset(BUF_ALIGNMENT);
+        f(&mut buf[offset..offset + buffer_size])
+    })
+}
+
+/// Reads at `offset` without using the position of `f`, so threads can share it
+fn read_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        f.read_at(buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        f.seek_read(buf, offset)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (f, buf, offset);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reading at an offset is not supported on this platform",
+        ))
+    }
+}
+
+/// Passes the `len` bytes of `f` from `offset` on to `on_data`, one `buf` at a time
+///
+/// For direct IO `offset` has to be a multiple of BUF_ALIGNMENT, the length
+/// of every read is rounded up to one and what's past the range dropped.
+fn read_range(
+    f: &File,
+    offset: u64,
+    len: u64,
+    buf: &mut [u8],
+    mut on_data: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let end = offset + len;
+    let mut pos = offset;
+    while pos < end {
+        let missing = end - pos;
+        let aligned = missing.div_ceil(BUF_ALIGNMENT as u64) * BUF_ALIGNMENT as u64;
+        let want = aligned.min(buf.len() as u64) as usize;
+        let bytes_read = read_at(f, &mut buf[..want], pos)?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file got shorter while it was read",
+            ));
+        }
+        let used = (bytes_read as u64).min(missing);
+        on_data(&buf[..used as usize]);
+        pos += used;
+    }
+    Ok(())
+}
+
+/// Chaining value of the `len` bytes of `f` from `offset` on, a subtree of its BLAKE3 tree
+fn blake3_subtree(
+    f: &File,
+    offset: u64,
+    len: u64,
+    range_len: u64,
+    buffer_size: usize,
+) -> io::Result<ChainingValue> {
+    if len > range_len {
+        let (left, right) = blake3_halves(f, offset, len, range_len, buffer_size)?;
+        return Ok(merge_subtrees_non_root(&left, &right, Mode::Hash));
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(offset);
+    with_buffer(buffer_size, |buf| {
+        read_range(f, offset, len, buf, |data| {
+            hasher.update(data);
+        })
+    })?;
+    Ok(hasher.finalize_non_root())
+}
+
+/// Chaining values of both halves of a subtree, split where BLAKE3 splits it
+///
+/// The halves are read and hashed on two threads of the current pool,
+/// which for update and verify is the one of the device the file is on.
+fn blake3_halves(
+    f: &File,
+    offset: u64,
+    len: u64,
+    range_len: u64,
+    buffer_size: usize,
+) -> io::Result<(ChainingValue, ChainingValue)> {
+    let left_len = left_subtree_len(len);
+    let (left, right) = rayon::join(
+        || blake3_subtree(f, offset, left_len, range_len, buffer_size),
+        || blake3_subtree(f, offset + left_len, len - left_len, range_len, buffer_size),
+    );
+    Ok((left?, right?))
+}
+
+/// BLAKE3 digest of the first `len` bytes of `f`, read in ranges of up to `range_len` bytes
+///
+/// A single thread reading and hashing a huge file would leave the others
+/// idle at the end of a run, the ranges are read in parallel instead. That
+/// only works because BLAKE3 hashes a tree, SHA-256 is inherently sequential.
+/// `range_len` has to be a multiple of BUF_ALIGNMENT.
+fn blake3_in_ranges(
+    f: &File,
+    len: u64,
+    range_len: u64,
+    buffer_size: usize,
+) -> io::Result<[u8; 32]> {
+    if len <= range_len {
+        let mut hasher = blake3::Hasher::new();
+        with_buffer(buffer_size, |buf| {
+            read_range(f, 0, len, buf, |data| {
+                hasher.update(data);
+            })
+        })?;
+        return Ok(hasher.finalize().into());
+    }
+    let (left, right) = blake3_halves(f, 0, len, range_len, buffer_size)?;
+    Ok(merge_subtrees_root(&left, &right, Mode::Hash).into())
+}
+
+/// Reads the first and last QUICK_LEN bytes of a file of `len` bytes for its quick digest
+fn read_quick_parts(f: &File, len: u64, buffer_size: usize) -> io::Result<QuickParts> {
+    let mut parts = QuickParts::default();
+    with_buffer(buffer_size, |buf| {
+        read_range(f, 0, QUICK_LEN as u64, buf, |data| parts.push(data))?;
+        // Start at an aligned offset for direct IO, QuickParts keeps the end of it
+        let tail_start = (len - QUICK_LEN as u64) / BUF_ALIGNMENT as u64 * BUF_ALIGNMENT as u64;
+        read_range(f, tail_start, len - tail_start, buf, |data| {
+            parts.push(data)
         })
+    })?;
+    Ok(parts)
+}
+
+/// Reads a file, hashes it's contents and returns the current FileInfo at rel_path
+///
+/// The file is opened first and its mtime is taken from the open handle,
+/// so it belongs to the bytes hashed even if the file was replaced since
+/// metadata was retrieved while listing files. The same goes for its
+/// attributes, links have the ones of the link itself. Should the
+/// file be modified while it's read, the next run will see a different mtime
+/// and read it again.
+pub fn hash_file(
+    rel_path: &Path,
+    file: &Path,
+    metadata: &Metadata,
+    read_options: &ReadOptions,
+) -> Result<FileInfo, io::Error> {
+    // Links are only listed with Links::Record, where they point to is
+    // hashed instead of the contents
+    let opened;
+    let mut quick = None;
+    let attributes;
+    let file_digest;
+    let (metadata, len) = if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(file)?;
+        let mut hasher = read_options.hash_algorithm.hasher();
+        hasher.update(target.as_os_str().as_encoded_bytes());
+        file_digest = hasher.finalize();
+        attributes = Attributes::at(file);
+        (metadata, metadata.len())
+    } else {
+        let mut f = open_file(file, read_options)?;
+        opened = f.metadata()?;
+        attributes = Attributes::of(&f);
+        if read_options.hash_algorithm == HashAlgorithm::Blake3
+            && opened.len() >= PARALLEL_HASHING_THRESHOLD
+        {
+            let len = opened.len();
+            file_digest = blake3_in_ranges(&f, len, PARALLEL_RANGE_LEN, read_options.buffer_size)?;
+            let parts = read_quick_parts(&f, len, read_options.buffer_size)?;
+            quick = quick_digest(read_options.hash_algorithm, len, &parts.head, &parts.tail);
+            (&opened, len)
+        } else {
+            let mut hasher = read_options.hash_algorithm.hasher();
+            // Small files don't get a quick digest, no need to collect anything for them
+            let mut quick_parts = (opened.len() > 2 * QUICK_LEN as u64).then(QuickParts::default);
+            let total_bytes_read = with_buffer(read_options.buffer_size, |buf| {
+                let mut total_bytes_read = 0;
+                loop {
+                    let bytes_read = f.read(buf)?;
+                    if bytes_read > 0 {
+                        total_bytes_read += bytes_read as u64;
+                        if let Some(parts) = &mut quick_parts {
+                            parts.push(&buf[0..bytes_read]);
+                        }
+                        hasher.update(&buf[0..bytes_read]);
+                    } else {
+                        break;
+                    }
+                }
+                Ok::<_, io::Error>(total_bytes_read)
+            })?;
+            file_digest = hasher.finalize();
+            if let Some(parts) = quick_parts {
+                quick = quick_digest(
+                    read_options.hash_algorithm,
+                    total_bytes_read,
+                    &parts.head,
+                    &parts.tail,
+                );
+            }
+            (&opened, total_bytes_read)
+        }
+    };
+    Ok(FileInfo {
+        rel_path: rel_path.to_path_buf(),
+        digest: file_digest,
+        mtime: metadata.modified()?,
+        len,
+        fully_read: SystemTime::now(),
+        last_seen: SystemTime::now(),
+        volume: None,
+        missing_since: None,
+        attributes,
+        quick_digest: quick,
+        host: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn verbatim_paths() {
+        assert_eq!(verbatim(r"C:\archive\photos"), r"\\?\C:\archive\photos");
+        assert_eq!(verbatim(r"\\nas\share\photos"), r"\\?\UNC\nas\share\photos");
+        assert_eq!(verbatim(r"\\?\D:\photos"), r"\\?\D:\photos");
+        assert_eq!(verbatim(r"\\.\pipe\x"), r"\\.\pipe\x");
+    }
+
+    #[test]
+    fn read_error_lines() -> Result<(), Box<dyn std::error::Error>> {
+        let err = anyhow::Error::new(io::Error::from_raw_os_error(13))
+            .context("Failed to read new file \"photos/a.jpg\"");
+        let read_error = ReadError::new(Path::new("photos/a.jpg"), &err).unwrap();
+        assert_eq!(read_error.errno, Some(13));
+        let mut line = Vec::new();
+        read_error.write(&mut line)?;
+        assert!(String::from_utf8(line)?.starts_with(
+            "photos/a.jpg # kind PermissionDenied errno 13 error Failed to read new file"
+        ));
+        assert!(!read_error.is_locked());
+        assert!(!read_error.may_be_transient());
+        assert!(ReadError::new(Path::new("a"), &anyhow::Error::msg("no I/O")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_of_file_read() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("replaced");
+        let path = dir.join("a");
+        std::fs::write(&path, "old")?;
+        let listed = path.metadata()?;
+        // Replaced after it was listed, by a file with another mtime
+        let replacement = dir.join("b");
+        std::fs::write(&replacement, "replaced")?;
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&replacement)?
+            .set_modified(mtime)?;
+        std::fs::rename(&replacement, &path)?;
+
+        let read_options = ReadOptions {
+            direct_io: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            buffer_size: 4096,
+        };
+        let fi = hash_file(Path::new("a"), &path, &listed, &read_options)?;
+        assert_eq!((fi.mtime, fi.len), (mtime, 8));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn attributes_of_file_read() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::file_info::{clear_immutable, set_immutable};
+
+        let dir = TestDir::new("replaced-attributes");
+        let path = dir.join("a");
+        std::fs::write(&path, "old")?;
+        let listed = FileToCheck::New {
+            path: path.clone(),
+            rel_path: PathBuf::from("a"),
+            metadata: path.metadata()?,
+        };
+        // Replaced after it was listed, by an immutable file
+        let replacement = dir.join("b");
+        std::fs::write(&replacement, "replaced")?;
+        std::fs::rename(&replacement, &path)?;
+        if set_immutable(&path).is_err() {
+            // Needs CAP_LINUX_IMMUTABLE and a filesystem with the flag
+            return Ok(());
+        }
+        let checked = listed.check(&Roots::new(&dir, &[]), &ReadOptions::default());
+        clear_immutable(&path)?;
+
+        let FileCheckResult::New(fi) = checked? else {
+            panic!("not a new file");
+        };
+        assert_eq!(fi.len, 8);
+        assert!(fi.attributes.unwrap().immutable);
+        Ok(())
+    }
+
+    #[test]
+    fn quick_digest_of_start_and_end() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("quick");
+        let path = dir.join("video");
+        let contents = (0..3 * QUICK_LEN + 123)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&path, &contents)?;
+        let read = |buffer_size| {
+            let read_options = ReadOptions {
+                buffer_size,
+                ..Default::default()
+            };
+            hash_file(Path::new("video"), &path, &path.metadata()?, &read_options)
+        };
+        let quick = read_quick_digest(&path, &ReadOptions::default())?;
+        assert!(quick.is_some());
+        let direct_io = ReadOptions {
+            direct_io: true,
+            ..Default::default()
+        };
+        // Not every filesystem supports direct IO
+        if let Ok(direct) = read_quick_digest(&path, &direct_io) {
+            assert_eq!(direct, quick);
+        }
+        // Independent of how the file is split up into reads
+        for buffer_size in [4096, QUICK_LEN, DEFAULT_BUF_SIZE] {
+            assert_eq!(read(buffer_size)?.quick_digest, quick);
+        }
+
+        let mut changed = contents.clone();
+        changed[QUICK_LEN * 3 / 2] ^= 1;
+        std::fs::write(&path, &changed)?;
+        assert_eq!(read_quick_digest(&path, &ReadOptions::default())?, quick);
+        changed[contents.len() - 1] ^= 1;
+        std::fs::write(&path, &changed)?;
+        assert_ne!(read_quick_digest(&path, &ReadOptions::default())?, quick);
+
+        std::fs::write(&path, &contents[..2 * QUICK_LEN])?;
+        assert_eq!(read_quick_digest(&path, &ReadOptions::default())?, None);
+        assert_eq!(read(4096)?.quick_digest, None);
+        Ok(())
+    }
+
+    #[test]
+    fn blake3_of_ranges() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("ranges");
+        let path = dir.join("video");
+        for direct_io in [false, true] {
+            let read_options = ReadOptions {
+                direct_io,
+                ..Default::default()
+            };
+            for len in [1, 8192, 8193, 3 * QUICK_LEN + 123, 1_000_000] {
+                let contents = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+                std::fs::write(&path, &contents)?;
+                let Ok(f) = open_file(&path, &read_options) else {
+                    // Not every filesystem supports direct IO
+                    assert!(direct_io);
+                    continue;
+                };
+                let digest = blake3_in_ranges(&f, len as u64, 8192, 4096)?;
+                assert_eq!(digest, *blake3::hash(&contents).as_bytes());
+                if len > 2 * QUICK_LEN {
+                    let mut expected = QuickParts::default();
+                    expected.push(&contents);
+                    let parts = read_quick_parts(&f, len as u64, 4096)?;
+                    assert_eq!((parts.head, parts.tail), (expected.head, expected.tail));
+                }
+            }
+        }
+
+        // Truncated after it was opened
+        let f = File::open(&path)?;
+        std::fs::write(&path, [0_u8; 500_000])?;
+        let err = blake3_in_ranges(&f, 1_000_000, 8192, 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+}
@@ -1,95 +1,351 @@
 use anyhow::{Context, Result};
-use regex::Regex;
+use std::fs::{File, Metadata};
 use std::io::{self};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use walkdir::DirEntry;
+
+/// Parses seconds since the epoch with an optional fraction, e.g. `1653660805.1332488`
+fn parse_timestamp(value: &str) -> Result<Duration> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if !secs.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow::Error::msg("invalid seconds"));
+    }
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow::Error::msg("invalid fraction of a second"));
+    }
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u32>()? * 10_u32.pow(9 - fraction.len() as u32)
+    };
+    Ok(Duration::new(secs.parse()?, nanos))
+}
+
+/// Attributes of a file not covered by its mtime
+///
+/// Read-only, hidden and system are the attributes of Windows, immutable
+/// and append-only the flags set by `chattr` on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes {
+    pub read_only: bool,
+    pub hidden: bool,
+    pub system: bool,
+    pub immutable: bool,
+    pub append_only: bool,
+}
+
+impl Attributes {
+    /// Attributes of an open file on Windows and Linux, None elsewhere
+    ///
+    /// Taken from the handle, not the path, so they're the ones of the file
+    /// read even if another one was put in its place since.
+    pub fn of(file: &File) -> Option<Attributes> {
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::MetadataExt;
+            // FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_HIDDEN and FILE_ATTRIBUTE_SYSTEM
+            let attributes = file.metadata().ok()?.file_attributes();
+            Some(Attributes {
+                read_only: attributes & 0x1 != 0,
+                hidden: attributes & 0x2 != 0,
+                system: attributes & 0x4 != 0,
+                ..Default::default()
+            })
+        }
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            let mut statx = std::mem::MaybeUninit::<libc::statx>::uninit();
+            // No fields are requested, the attributes are always filled in
+            if unsafe {
+                libc::statx(
+                    file.as_raw_fd(),
+                    c"".as_ptr(),
+                    libc::AT_EMPTY_PATH,
+                    0,
+                    statx.as_mut_ptr(),
+                )
+            } != 0
+            {
+                return None;
+            }
+            let attributes = unsafe { statx.assume_init() }.stx_attributes;
+            Some(Attributes {
+                immutable: attributes & libc::STATX_ATTR_IMMUTABLE as u64 != 0,
+                append_only: attributes & libc::STATX_ATTR_APPEND as u64 != 0,
+                ..Default::default()
+            })
+        }
+        #[cfg(not(any(windows, target_os = "linux")))]
+        {
+            let _ = file;
+            None
+        }
+    }
+
+    /// Attributes of the file at path, links aren't followed
+    ///
+    /// For files that aren't read. The handle is opened without access to
+    /// the contents, so read permissions and access times don't matter.
+    pub fn at(path: &Path) -> Option<Attributes> {
+        #[cfg(target_os = "linux")]
+        let handle = {
+            use std::os::unix::fs::OpenOptionsExt;
+            File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+                .open(path)
+        };
+        #[cfg(windows)]
+        let handle = {
+            use std::os::windows::fs::OpenOptionsExt;
+            // FILE_READ_ATTRIBUTES, FILE_FLAG_BACKUP_SEMANTICS and FILE_FLAG_OPEN_REPARSE_POINT
+            File::options()
+                .access_mode(0x80)
+                .custom_flags(0x02000000 | 0x00200000)
+                .open(path)
+        };
+        #[cfg(not(any(windows, target_os = "linux")))]
+        let handle: io::Result<File> = {
+            let _ = path;
+            return None;
+        };
+        Attributes::of(&handle.ok()?)
+    }
+}
+
+/// FS_IMMUTABLE_FL of linux/fs.h, set by `chattr +i`
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+/// Sets the immutable flag of a file, like `chattr +i`
+///
+/// Needs the CAP_LINUX_IMMUTABLE capability, usually only root has it.
+#[cfg(target_os = "linux")]
+pub fn set_immutable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let f = std::fs::File::options()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(f.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    flags |= FS_IMMUTABLE_FL;
+    if unsafe { libc::ioctl(f.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Clears the flag again, the directory of a test can't be removed otherwise
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) fn clear_immutable(path: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let f = std::fs::File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(f.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    flags &= !FS_IMMUTABLE_FL;
+    if unsafe { libc::ioctl(f.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_immutable(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "immutable files are only supported on Linux",
+    ))
+}
+
+/// Letters like `attrib` and `lsattr` print them, e.g. `RH` or `ia`, or `-` if none are set
+impl std::fmt::Display for Attributes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if *self == Attributes::default() {
+            return f.write_str("-");
+        }
+        for (set, letter) in [
+            (self.read_only, "R"),
+            (self.hidden, "H"),
+            (self.system, "S"),
+            (self.immutable, "i"),
+            (self.append_only, "a"),
+        ] {
+            if set {
+                f.write_str(letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Attributes {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut attributes = Attributes::default();
+        if s == "-" {
+            return Ok(attributes);
+        }
+        for c in s.chars() {
+            match c {
+                'R' => attributes.read_only = true,
+                'H' => attributes.hidden = true,
+                'S' => attributes.system = true,
+                'i' => attributes.immutable = true,
+                'a' => attributes.append_only = true,
+                _ => return Err(format!("unknown attribute `{}`", c)),
+            }
+        }
+        Ok(attributes)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileInfo {
     pub rel_path: PathBuf,
-    pub sha256_digest: [u8; 32],
+    pub digest: [u8; 32],
     pub mtime: SystemTime,
     pub len: u64,
     pub last_seen: SystemTime,
     pub fully_read: SystemTime,
+    /// Volume the file is stored on, for archives spread over several disks
+    pub volume: Option<String>,
+    /// When the file was first found missing, if it's kept in the state anyway
+    pub missing_since: Option<SystemTime>,
+    /// Attributes of the file, only recorded on Windows and Linux
+    pub attributes: Option<Attributes>,
+    /// Digest of the size and the first and last bytes, see [`crate::file_check::quick_digest`]
+    ///
+    /// Only recorded for files large enough for it to save reading most of them.
+    pub quick_digest: Option<[u8; 32]>,
+    /// Host that last saw the file, for states merged from partial states
+    pub host: Option<String>,
 }
 
 impl FileInfo {
+    /// An entry with all times at the epoch and nothing else recorded
+    ///
+    /// The other fields are set with struct update syntax, like
+    /// `FileInfo { volume, ..FileInfo::new(rel_path, digest, len) }`.
+    pub fn new(rel_path: impl Into<PathBuf>, digest: [u8; 32], len: u64) -> FileInfo {
+        FileInfo {
+            rel_path: rel_path.into(),
+            digest,
+            mtime: UNIX_EPOCH,
+            len,
+            last_seen: UNIX_EPOCH,
+            fully_read: UNIX_EPOCH,
+            volume: None,
+            missing_since: None,
+            attributes: None,
+            quick_digest: None,
+            host: None,
+        }
+    }
+
+    /// Parses a line of a state file
+    ///
+    /// Unknown fields after the path are ignored, so files written by
+    /// newer versions can still be read. Anything after the first
+    /// line break is ignored as well.
     pub fn parse(line: &str) -> Result<FileInfo> {
-        lazy_static! {
-            static ref RE: Regex =
-                Regex::new("([a-f0-9]{64}) /?([^/].*) # mtime (\\d+)\\.(\\d+) size (\\d+) fully_read (\\d+)(?:\\.\\d+)? last_seen (\\d+)(?:\\.\\d+)?")
-                    .unwrap();
-        }
-        match RE.captures(line) {
-            Some(m) => {
-                let mut sha256_digest = [0_u8; 32];
-                if let Err(err) =
-                    hex::decode_to_slice(m.get(1).unwrap().as_str(), &mut sha256_digest)
-                {
-                    return Err(err).context(format!(
-                        "invalid line (couldn't parse sha256_digest): '{}'",
-                        line
-                    ));
-                }
-                let mtime_s: Result<u64, _> = m.get(3).unwrap().as_str().parse();
-                if let Err(err) = mtime_s {
-                    return Err(err)
-                        .context(format!("invalid line (couldn't parse mtime): '{}'", line));
+        let line = line.split('\n').next().unwrap_or_default();
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let invalid = |what: &str| format!("invalid line (couldn't parse {}): '{}'", what, line);
+
+        let (hexdigest, rest) = line
+            .split_once(' ')
+            .with_context(|| format!("invalid line: '{}'", line))?;
+        let mut digest = [0_u8; 32];
+        hex::decode_to_slice(hexdigest, &mut digest).with_context(|| invalid("digest"))?;
+
+        // Paths may contain " # " as well, the fields start after the last one
+        let (path, fields) = rest
+            .rsplit_once(" # ")
+            .with_context(|| format!("invalid line: '{}'", line))?;
+        let path = path.strip_prefix('/').unwrap_or(path);
+        if path.is_empty() || path.starts_with('/') {
+            return Err(io::Error::from(io::ErrorKind::InvalidData)).context(invalid("path"));
+        }
+
+        let mut mtime = None;
+        let mut len = None;
+        let mut fully_read = None;
+        let mut last_seen = None;
+        let mut volume = None;
+        let mut missing_since = None;
+        let mut attributes = None;
+        let mut quick_digest = None;
+        let mut host = None;
+        let mut fields = fields.split(' ');
+        while let Some(key) = fields.next() {
+            let value = fields.next().with_context(|| invalid(key))?;
+            match key {
+                "mtime" => mtime = Some(parse_timestamp(value).with_context(|| invalid(key))?),
+                "size" => len = Some(value.parse().with_context(|| invalid(key))?),
+                "fully_read" => {
+                    fully_read = Some(parse_timestamp(value).with_context(|| invalid(key))?)
                 }
-                let mut mtime_ns: Result<u64, _> = m.get(4).unwrap().as_str().parse();
-                if let Err(err) = mtime_ns {
-                    return Err(err)
-                        .context(format!("invalid line (couldn't parse mtime): '{}'", line));
-                } else if let Ok(v) = mtime_ns {
-                    mtime_ns = Ok(v * (10_u64.pow(9 - m.get(4).unwrap().as_str().len() as u32)));
+                "last_seen" => {
+                    last_seen = Some(parse_timestamp(value).with_context(|| invalid(key))?)
                 }
-
-                let size = m.get(5).unwrap().as_str().parse();
-                if let Err(err) = size {
-                    return Err(err)
-                        .context(format!("invalid line (couldn't parse size): '{}'", line));
+                "volume" => volume = Some(value.to_owned()),
+                "missing_since" => {
+                    missing_since = Some(parse_timestamp(value).with_context(|| invalid(key))?)
                 }
-                let fully_read = m.get(6).unwrap().as_str().parse();
-                if let Err(err) = fully_read {
-                    return Err(err).context(format!(
-                        "invalid line (couldn't parse fully_read): '{}'",
-                        line
-                    ));
+                "attributes" => {
+                    attributes = Some(
+                        value
+                            .parse()
+                            .map_err(anyhow::Error::msg)
+                            .with_context(|| invalid(key))?,
+                    )
                 }
-                let last_seen = m.get(7).unwrap().as_str().parse();
-                if let Err(err) = last_seen {
-                    return Err(err).context(format!(
-                        "invalid line (couldn't parse last_seen): '{}'",
-                        line
-                    ));
+                "quick" => {
+                    let mut digest = [0_u8; 32];
+                    hex::decode_to_slice(value, &mut digest).with_context(|| invalid(key))?;
+                    quick_digest = Some(digest);
                 }
-
-                Ok(FileInfo {
-                    rel_path: PathBuf::from(m.get(2).unwrap().as_str()),
-                    sha256_digest,
-                    mtime: SystemTime::UNIX_EPOCH
-                        + Duration::from_nanos(
-                            mtime_s.unwrap() * 1_000_000_000 + mtime_ns.unwrap(),
-                        ),
-                    fully_read: SystemTime::UNIX_EPOCH + Duration::from_secs(fully_read.unwrap()),
-                    len: size.unwrap(),
-                    last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(last_seen.unwrap()),
-                })
+                "host" => host = Some(value.to_owned()),
+                _ => {}
             }
-            _ => Err(io::Error::from(io::ErrorKind::InvalidData))
-                .context(format!("invalid line: '{}'", line)),
         }
+
+        Ok(FileInfo {
+            rel_path: PathBuf::from(path),
+            digest,
+            mtime: SystemTime::UNIX_EPOCH + mtime.with_context(|| invalid("mtime"))?,
+            len: len.with_context(|| invalid("size"))?,
+            // Only whole seconds are stored for these
+            fully_read: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(fully_read.with_context(|| invalid("fully_read"))?.as_secs()),
+            last_seen: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(last_seen.with_context(|| invalid("last_seen"))?.as_secs()),
+            volume,
+            missing_since: missing_since
+                .map(|since| SystemTime::UNIX_EPOCH + Duration::from_secs(since.as_secs())),
+            attributes,
+            quick_digest,
+            host,
+        })
     }
 
     pub fn write(&self, to: &mut dyn std::io::Write) -> std::io::Result<()> {
-        let mut sha256_hexdigest = [0_u8; 64];
-        hex::encode_to_slice(self.sha256_digest, &mut sha256_hexdigest).unwrap();
-        writeln!(
+        let mut hexdigest = [0_u8; 64];
+        hex::encode_to_slice(self.digest, &mut hexdigest).unwrap();
+        write!(
             to,
             "{} {} # mtime {}.{:>09} size {} fully_read {} last_seen {}",
-            std::str::from_utf8(&sha256_hexdigest).unwrap(),
+            std::str::from_utf8(&hexdigest).unwrap(),
             self.rel_path.to_str().unwrap(),
             self.mtime.duration_since(UNIX_EPOCH).unwrap().as_secs(),
             self.mtime.duration_since(UNIX_EPOCH).unwrap().as_nanos()
@@ -100,31 +356,46 @@ impl FileInfo {
                 .unwrap()
                 .as_secs(),
             self.last_seen.duration_since(UNIX_EPOCH).unwrap().as_secs()
-        )
+        )?;
+        if let Some(volume) = &self.volume {
+            write!(to, " volume {}", volume)?;
+        }
+        if let Some(since) = self.missing_since {
+            write!(
+                to,
+                " missing_since {}",
+                since.duration_since(UNIX_EPOCH).unwrap().as_secs()
+            )?;
+        }
+        if let Some(attributes) = self.attributes {
+            write!(to, " attributes {}", attributes)?;
+        }
+        if let Some(quick_digest) = self.quick_digest {
+            write!(to, " quick {}", hex::encode(quick_digest))?;
+        }
+        if let Some(host) = &self.host {
+            write!(to, " host {}", host)?;
+        }
+        writeln!(to)
     }
 
-    pub fn needs_reading(&self, dir_entry: &DirEntry) -> Result<bool> {
-        let m = dir_entry
-            .metadata()
-            .with_context(|| format!("Unable to get metadata for '{:?}'", dir_entry.path()))?;
-        let mtime = m
-            .modified()
-            .with_context(|| format!("Unable to read mtime for '{:?}'", dir_entry.path()))?;
-        let len = m.len();
-        // println!(
-        //     "{:?} mtime: {:?} ?= {:?}, len: {} ?= {}",
-        //     dir_entry.path(),
-        //     self.mtime,
-        //     mtime,
-        //     self.len,
-        //     len
-        // );
-        Ok(self.mtime != mtime || self.len != len)
+    /// Compares the current metadata of the file to the stored one
+    ///
+    /// The mtimes may differ by up to mtime_tolerance, network filesystems
+    /// don't always report them at the precision they were stored with.
+    pub fn needs_reading(&self, metadata: &Metadata, mtime_tolerance: Duration) -> Result<bool> {
+        let mtime = metadata.modified().context("Unable to read mtime")?;
+        let difference = self
+            .mtime
+            .duration_since(mtime)
+            .unwrap_or_else(|err| err.duration());
+        Ok(difference > mtime_tolerance || self.len != metadata.len())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::test_dir::TestDir;
     use std::{
         path::PathBuf,
         time::{Duration, SystemTime},
@@ -136,7 +407,7 @@ mod tests {
     fn round_trip() -> Result<(), Box<dyn std::error::Error>> {
         let fi = FileInfo {
             rel_path: PathBuf::from("test/äöüß/#!,.\"§$%&()=?{[]}/something"),
-            sha256_digest: [5; 32],
+            digest: [5; 32],
             mtime: SystemTime::UNIX_EPOCH
                 .checked_add(Duration::from_nanos(1653660805133248800))
                 .unwrap(),
@@ -147,6 +418,15 @@ mod tests {
             fully_read: SystemTime::UNIX_EPOCH
                 .checked_add(Duration::from_secs(1653660817))
                 .unwrap(),
+            volume: Some("disk-2".to_owned()),
+            missing_since: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1653660900)),
+            attributes: Some(Attributes {
+                read_only: true,
+                hidden: true,
+                ..Default::default()
+            }),
+            quick_digest: Some([7; 32]),
+            host: Some("nas".to_owned()),
         };
         let mut line = [0_u8; 500];
         fi.write(&mut line.as_mut_slice())?;
@@ -162,4 +442,103 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn parse_edge_cases() -> Result<(), Box<dyn std::error::Error>> {
+        let fi = FileInfo::parse(
+            "0505050505050505050505050505050505050505050505050505050505050505 /a # mtime 1.5 b \
+             # mtime 1653660805.1332488 size 3 fully_read 1653660817.25 last_seen 1653660810 \
+             future_field 42",
+        )?;
+        assert_eq!(fi.rel_path, PathBuf::from("a # mtime 1.5 b"));
+        assert_eq!(
+            fi.mtime,
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(1653660805133248800)
+        );
+        assert_eq!(fi.len, 3);
+        assert_eq!(
+            fi.fully_read,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1653660817)
+        );
+
+        assert!(FileInfo::parse("05 a # mtime 1.5 size 3 fully_read 1 last_seen 1").is_err());
+        assert!(FileInfo::parse(
+            "0505050505050505050505050505050505050505050505050505050505050505 a # mtime 1.5 size 3"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn attribute_letters() {
+        assert_eq!(Attributes::default().to_string(), "-");
+        assert_eq!("-".parse(), Ok(Attributes::default()));
+        let all = Attributes {
+            read_only: true,
+            hidden: true,
+            system: true,
+            immutable: true,
+            append_only: true,
+        };
+        assert_eq!(all.to_string(), "RHSia");
+        assert_eq!("RHSia".parse(), Ok(all));
+        assert!("RA".parse::<Attributes>().is_err());
+    }
+
+    #[test]
+    fn mtime_tolerance() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("mtime-tolerance");
+        let path = dir.join("mtime-tolerance");
+        std::fs::write(&path, "contents")?;
+        let metadata = path.metadata()?;
+        let mut fi = FileInfo {
+            mtime: metadata.modified()? - Duration::from_secs(1),
+            ..FileInfo::new("a", [0; 32], metadata.len())
+        };
+        assert!(fi.needs_reading(&metadata, Duration::ZERO)?);
+        assert!(!fi.needs_reading(&metadata, Duration::from_secs(2))?);
+        fi.mtime = metadata.modified()? + Duration::from_secs(1);
+        assert!(!fi.needs_reading(&metadata, Duration::from_secs(2))?);
+        fi.len += 1;
+        assert!(fi.needs_reading(&metadata, Duration::from_secs(2))?);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn chattr_flags() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("chattr");
+        let path = dir.join("chattr");
+        std::fs::write(&path, "contents")?;
+        assert_eq!(
+            Attributes::of(&File::open(&path)?),
+            Some(Attributes::default())
+        );
+        assert_eq!(Attributes::at(&path), Some(Attributes::default()));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn attributes_of_the_file_opened() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("attributes-opened");
+        let path = dir.join("file");
+        std::fs::write(&path, "opened")?;
+        let opened = File::open(&path)?;
+        let swapped = dir.join("swapped");
+        std::fs::write(&swapped, "swapped")?;
+        std::fs::rename(&swapped, &path)?;
+        if set_immutable(&path).is_err() {
+            // Needs CAP_LINUX_IMMUTABLE and a filesystem with the flag
+            return Ok(());
+        }
+        let attributes_of_path = Attributes::at(&path);
+        let attributes_of_opened = Attributes::of(&opened);
+        clear_immutable(&path)?;
+
+        assert_eq!(attributes_of_opened, Some(Attributes::default()));
+        assert!(attributes_of_path.unwrap().immutable);
+        Ok(())
+    }
 }
@@ -0,0 +1,67 @@
+use rusty_archive::index::{index_path, DigestIndex};
+use rusty_archive::state::{latest_state_path, read_state};
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Prints the paths of all files in the latest state with the given digests
+pub fn run(state_dir: &Path, hexdigests: &[String]) -> Result<()> {
+    let digests = hexdigests
+        .iter()
+        .map(|hexdigest| {
+            let mut digest = [0_u8; 32];
+            hex::decode_to_slice(hexdigest, &mut digest)
+                .with_context(|| format!("invalid digest: '{}'", hexdigest))?;
+            Ok(digest)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let state_path = latest_state_path(state_dir)?
+        .with_context(|| format!("no state found in {:?}", state_dir))?;
+
+    let index_path = index_path(&state_path);
+    let mut paths_by_digest: HashMap<[u8; 32], Vec<PathBuf>> = HashMap::new();
+    if index_path.exists() {
+        let mut index = DigestIndex::open(&index_path)?;
+        for digest in &digests {
+            paths_by_digest.insert(*digest, index.lookup(digest)?);
+        }
+    } else {
+        println!(
+            "no index found for {:?}, searching the state instead",
+            state_path
+        );
+        let (_, files) = read_state(state_dir)?;
+        for fi in files.into_values() {
+            if digests.contains(&fi.digest) {
+                paths_by_digest
+                    .entry(fi.digest)
+                    .or_default()
+                    .push(fi.rel_path);
+            }
+        }
+    }
+
+    let mut not_found = 0;
+    for (hexdigest, digest) in hexdigests.iter().zip(&digests) {
+        match paths_by_digest.get_mut(digest) {
+            Some(paths) if !paths.is_empty() => {
+                paths.sort();
+                for path in paths {
+                    println!("{} {}", hexdigest, path.to_string_lossy());
+                }
+            }
+            _ => {
+                println!("NOT FOUND: {}", hexdigest);
+                not_found += 1;
+            }
+        }
+    }
+    if not_found > 0 {
+        return Err(anyhow::Error::msg(format!(
+            "{} digest(s) not found in archive",
+            not_found
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use rusty_archive::annex::{key, parse_key, Backend};
+use rusty_archive::file_check::FileCheckResult;
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::hash::HashAlgorithm;
+use rusty_archive::history::state_paths;
+use rusty_archive::state::{read_state, write_state, StateHeader};
+
+use anyhow::{Context, Result};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Prints the key and path of every file in the latest state
+///
+/// The format matches `git annex find --format='${key} ${file}\n'`.
+pub fn export(state_dir: &Path, backend: Backend) -> Result<()> {
+    let (header, files) = read_state(state_dir)?;
+    if header.hash_algorithm != HashAlgorithm::Sha256 {
+        return Err(anyhow::Error::msg(format!(
+            "git-annex keys need SHA-256 digests, the state uses {}",
+            header.hash_algorithm
+        )));
+    }
+    let mut files = files.into_values().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    let mut out = BufWriter::new(io::stdout().lock());
+    for fi in &files {
+        writeln!(
+            out,
+            "{} {}",
+            key(backend, &fi.rel_path, &fi.digest, fi.len),
+            fi.rel_path.to_string_lossy()
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes the first state of an archive from a listing of git-annex keys
+///
+/// The listing is the output of `git annex find --format='${key} ${file}\n'`,
+/// "-" reads it from stdin. Only the mtimes are taken from the files, so the
+/// next update doesn't read them either. Files imported this way were never
+/// fully read by rusty-archive, the next verify will be the first time.
+pub fn import(state_dir: &Path, listing: &str, directory: &Path) -> Result<()> {
+    if !state_paths(state_dir)?.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} already contains states, import into an empty state directory",
+            state_dir
+        )));
+    }
+    let reader: Box<dyn BufRead> = if listing == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(io::BufReader::new(
+            std::fs::File::open(listing)
+                .with_context(|| format!("Unable to open {:?}", listing))?,
+        ))
+    };
+
+    let now = SystemTime::now();
+    let mut files = Vec::new();
+    for line in reader.lines() {
+        let line = line.with_context(|| format!("Unable to read {:?}", listing))?;
+        if line.is_empty() {
+            continue;
+        }
+        let (key, path) = line
+            .split_once(' ')
+            .with_context(|| format!("invalid line: '{}'", line))?;
+        let (digest, len) = parse_key(key)?;
+        let rel_path = PathBuf::from(path);
+        let full_path = directory.join(&rel_path);
+        let metadata = std::fs::symlink_metadata(&full_path)
+            .with_context(|| format!("Unable to get metadata of {:?}", full_path))?;
+        // Updates don't follow symlinks, these would be missing right away
+        if metadata.file_type().is_symlink() {
+            return Err(anyhow::Error::msg(format!(
+                "{:?} is a symlink, unlock annexed files with `git annex unlock` first",
+                full_path
+            )));
+        }
+        if metadata.len() != len {
+            return Err(anyhow::Error::msg(format!(
+                "{:?} is {} bytes, but its key {} says {}",
+                full_path,
+                metadata.len(),
+                key,
+                len
+            )));
+        }
+        files.push(FileInfo {
+            mtime: metadata.modified()?,
+            last_seen: now,
+            ..FileInfo::new(rel_path, digest, len)
+        });
+    }
+    files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    files.dedup_by(|a, b| a.rel_path == b.rel_path);
+
+    let count = files.len();
+    let state_path = write_state(
+        state_dir,
+        &StateHeader {
+            hash_algorithm: HashAlgorithm::Sha256,
+            ..Default::default()
+        },
+        files.into_iter().map(|fi| Ok(FileCheckResult::New(fi))),
+    )?;
+    println!("└ imported {} files into {:?}", count, state_path);
+    Ok(())
+}
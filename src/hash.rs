@@ -0,0 +1,177 @@
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Algorithms available for hashing file contents
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Describes the implementation used on this CPU
+    pub fn implementation(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => sha256_implementation(),
+            HashAlgorithm::Blake3 => blake3_implementation(),
+        }
+    }
+
+    /// Whether hashing a single input can make use of multiple threads
+    pub fn is_parallel(&self) -> bool {
+        matches!(self, HashAlgorithm::Blake3)
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+}
+
+// These mirror the runtime detection done by sha2 and blake3 themselves
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn sha256_implementation() -> &'static str {
+    if is_x86_feature_detected!("sha")
+        && is_x86_feature_detected!("sse2")
+        && is_x86_feature_detected!("ssse3")
+        && is_x86_feature_detected!("sse4.1")
+    {
+        "SHA-NI"
+    } else if cfg!(feature = "asm") {
+        "assembly"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn sha256_implementation() -> &'static str {
+    // sha2 only uses the crypto extensions with the asm feature
+    if cfg!(feature = "asm") && std::arch::is_aarch64_feature_detected!("sha2") {
+        "ARMv8 crypto extensions"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn sha256_implementation() -> &'static str {
+    "portable"
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn blake3_implementation() -> &'static str {
+    if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vl") {
+        "AVX-512"
+    } else if is_x86_feature_detected!("avx2") {
+        "AVX2"
+    } else if is_x86_feature_detected!("sse4.1") {
+        "SSE4.1"
+    } else if is_x86_feature_detected!("sse2") {
+        "SSE2"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn blake3_implementation() -> &'static str {
+    "NEON"
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn blake3_implementation() -> &'static str {
+    "portable"
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("expected `sha256` or `blake3`, got `{}`", s)),
+        }
+    }
+}
+
+/// Data shorter than this is hashed by a single thread, see [`Hasher::update_parallel`]
+const PARALLEL_MIN_LEN: usize = 1024 * 1024;
+
+/// Incrementally hashes data with one of the supported algorithms
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Like update, but uses idle threads of the current rayon pool
+    ///
+    /// Only BLAKE3 can be split up like this, SHA-256 is inherently
+    /// sequential and falls back to update. So does BLAKE3 for less than
+    /// PARALLEL_MIN_LEN bytes, splitting them up costs more than it saves.
+    pub fn update_parallel(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) if data.len() < PARALLEL_MIN_LEN => {
+                h.update(data);
+            }
+            Hasher::Blake3(h) => {
+                h.update_rayon(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            Hasher::Sha256(h) => h.finalize().into(),
+            Hasher::Blake3(h) => h.finalize().into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let data = (0..10_000_000_u32).map(|i| i as u8).collect::<Vec<_>>();
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            let mut sequential = algorithm.hasher();
+            sequential.update(&data);
+            let mut parallel = algorithm.hasher();
+            parallel.update_parallel(&data[..1_000_000]);
+            parallel.update_parallel(&data[1_000_000..]);
+            assert_eq!(sequential.finalize(), parallel.finalize());
+        }
+    }
+}
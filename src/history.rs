@@ -0,0 +1,109 @@
+use crate::file_info::FileInfo;
+use crate::state::{read_state_file, state_order, StateFile};
+
+use anyhow::{Context, Result};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Paths of all states in a state directory, oldest first, see [`state_order`]
+pub fn state_paths(state_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = WalkDir::new(state_dir)
+        .max_depth(1)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Unable to list files in state directory {:?}", state_dir))?
+        .into_iter()
+        .filter(|f| f.file_type().is_file() && f.file_name().to_string_lossy().ends_with(".state"))
+        .map(|f| f.into_path())
+        .collect::<Vec<_>>();
+    paths.sort_by_cached_key(|path| state_order(path));
+    Ok(paths)
+}
+
+/// A state along with the name of the run that wrote it
+pub struct HistoryEntry {
+    /// Timestamp the state is named after
+    pub name: String,
+    pub path: PathBuf,
+    /// Files are sorted by path
+    pub state: StateFile,
+}
+
+/// Reads all states of a state directory one by one, oldest first
+pub fn history(state_dir: &Path) -> Result<impl Iterator<Item = Result<HistoryEntry>>> {
+    Ok(state_paths(state_dir)?.into_iter().map(|path| {
+        let mut state = read_state_file(&path)?;
+        // States written by rusty-archive are sorted already, this is cheap then
+        state.files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        Ok(HistoryEntry {
+            name: path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            path,
+            state,
+        })
+    }))
+}
+
+/// Differences between two states
+#[derive(Debug, Default)]
+pub struct Diff<'a> {
+    /// Paths only in the newer state
+    pub added: Vec<&'a FileInfo>,
+    /// Paths with different contents, the older and the newer entry
+    pub modified: Vec<(&'a FileInfo, &'a FileInfo)>,
+    /// Paths only in the older state, including files moved elsewhere
+    pub missing: Vec<&'a FileInfo>,
+}
+
+/// Compares two lists of files sorted by path
+pub fn diff<'a>(older: &'a [FileInfo], newer: &'a [FileInfo]) -> Diff<'a> {
+    let mut diff = Diff::default();
+    let mut older = older.iter().peekable();
+    let mut newer = newer.iter().peekable();
+    loop {
+        let ordering = match (older.peek(), newer.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(o), Some(n)) => o.rel_path.cmp(&n.rel_path),
+        };
+        match ordering {
+            Ordering::Less => diff.missing.push(older.next().unwrap()),
+            Ordering::Greater => diff.added.push(newer.next().unwrap()),
+            Ordering::Equal => {
+                let (o, n) = (older.next().unwrap(), newer.next().unwrap());
+                if o.digest != n.digest {
+                    diff.modified.push((o, n));
+                }
+            }
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_sorted_states() {
+        let older = vec![
+            FileInfo::new("a", [1; 32], 1),
+            FileInfo::new("b", [2; 32], 1),
+            FileInfo::new("c", [3; 32], 1),
+        ];
+        let newer = vec![
+            FileInfo::new("b", [2; 32], 1),
+            FileInfo::new("c", [4; 32], 1),
+            FileInfo::new("d", [5; 32], 1),
+        ];
+        let diff = diff(&older, &newer);
+        assert_eq!(diff.added, vec![&newer[2]]);
+        assert_eq!(diff.modified, vec![(&older[2], &newer[1])]);
+        assert_eq!(diff.missing, vec![&older[0]]);
+    }
+}
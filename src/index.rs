@@ -0,0 +1,255 @@
+use crate::state::StateHeader;
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Length of a hex encoded digest at the start of every index line
+const HEXDIGEST_LEN: usize = 64;
+
+/// Writes the digest index for a state
+///
+/// The index is a text file with one `<hexdigest> <path>` line per file,
+/// sorted by digest. Finding all paths with a given digest is a binary
+/// search, so it doesn't need to be loaded into memory.
+pub fn write_index(
+    path: &Path,
+    header: &StateHeader,
+    mut entries: Vec<([u8; 32], PathBuf)>,
+) -> Result<(), io::Error> {
+    entries.sort_unstable();
+    let mut f = BufWriter::with_capacity(
+        1024 * 1024,
+        File::options().write(true).create_new(true).open(path)?,
+    );
+    header.write(&mut f)?;
+    for (digest, rel_path) in entries {
+        writeln!(f, "{} {}", hex::encode(digest), rel_path.to_str().unwrap())?;
+    }
+    f.flush()
+}
+
+/// Path of the index belonging to a state file
+pub fn index_path(state_path: &Path) -> PathBuf {
+    state_path.with_extension("index")
+}
+
+/// Path of the digest counts belonging to a state file
+pub fn digest_counts_path(state_path: &Path) -> PathBuf {
+    state_path.with_extension("digests")
+}
+
+/// How many files of a state have each digest
+///
+/// Written next to every state as a `.digests` file with one
+/// `<hexdigest> <count>` line per digest, sorted by digest. Checking whether
+/// contents are anywhere in the archive only needs these, not the paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DigestCounts {
+    counts: HashMap<[u8; 32], u64>,
+}
+
+impl DigestCounts {
+    pub fn of(digests: impl IntoIterator<Item = [u8; 32]>) -> DigestCounts {
+        let mut counts = HashMap::new();
+        for digest in digests {
+            *counts.entry(digest).or_default() += 1;
+        }
+        DigestCounts { counts }
+    }
+
+    pub fn contains(&self, digest: &[u8; 32]) -> bool {
+        self.counts.contains_key(digest)
+    }
+
+    /// Number of files with the digest, 0 if there are none
+    pub fn count(&self, digest: &[u8; 32]) -> u64 {
+        self.counts.get(digest).copied().unwrap_or(0)
+    }
+
+    /// Number of files counted
+    pub fn files(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn digests(&self) -> impl Iterator<Item = &[u8; 32]> {
+        self.counts.keys()
+    }
+
+    pub fn write(&self, path: &Path, header: &StateHeader) -> Result<(), io::Error> {
+        let mut counts = self.counts.iter().collect::<Vec<_>>();
+        counts.sort_unstable();
+        let mut f = BufWriter::new(File::options().write(true).create_new(true).open(path)?);
+        header.write(&mut f)?;
+        for (digest, count) in counts {
+            writeln!(f, "{} {}", hex::encode(digest), count)?;
+        }
+        f.flush()
+    }
+
+    /// Reads digest counts along with the header they were written with
+    pub fn read(path: &Path) -> Result<(StateHeader, DigestCounts)> {
+        let reader = BufReader::new(
+            File::open(path).with_context(|| format!("Unable to open digest counts {:?}", path))?,
+        );
+        let mut header = StateHeader::default();
+        let mut in_header = true;
+        let mut counts = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if in_header && header.parse_line(&line)? {
+                continue;
+            }
+            in_header = false;
+            let invalid = || anyhow::Error::msg(format!("invalid line in {:?}: {}", path, line));
+            let (hexdigest, count) = line.split_once(' ').ok_or_else(invalid)?;
+            let mut digest = [0; 32];
+            hex::decode_to_slice(hexdigest, &mut digest).map_err(|_| invalid())?;
+            counts.insert(digest, count.parse().map_err(|_| invalid())?);
+        }
+        Ok((header, DigestCounts { counts }))
+    }
+}
+
+/// A digest index opened for lookups
+pub struct DigestIndex {
+    reader: BufReader<File>,
+    /// Offset of the first line after the header
+    data_start: u64,
+    len: u64,
+}
+
+impl DigestIndex {
+    pub fn open(path: &Path) -> Result<DigestIndex> {
+        let mut reader = BufReader::new(
+            File::open(path).with_context(|| format!("Unable to open index {:?}", path))?,
+        );
+        let len = reader.get_ref().metadata()?.len();
+        let mut header = StateHeader::default();
+        let mut data_start = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let line_len = reader.read_line(&mut line)?;
+            if line_len == 0 || !header.parse_line(line.trim_end_matches('\n'))? {
+                break;
+            }
+            data_start += line_len as u64;
+        }
+        Ok(DigestIndex {
+            reader,
+            data_start,
+            len,
+        })
+    }
+
+    /// Reads the line starting at offset
+    fn line_at(&mut self, offset: u64, line: &mut String) -> io::Result<usize> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        line.clear();
+        self.reader.read_line(line)
+    }
+
+    /// Finds the offset of the first line starting at or after offset
+    fn next_line_start(&mut self, offset: u64) -> io::Result<u64> {
+        if offset <= self.data_start {
+            return Ok(self.data_start);
+        }
+        let mut skipped = Vec::new();
+        self.reader.seek(SeekFrom::Start(offset - 1))?;
+        let skipped_len = self.reader.read_until(b'\n', &mut skipped)?;
+        Ok(offset - 1 + skipped_len as u64)
+    }
+
+    /// Returns the paths of all files with the given digest
+    pub fn lookup(&mut self, digest: &[u8; 32]) -> Result<Vec<PathBuf>> {
+        let target = hex::encode(digest);
+        let mut line = String::new();
+
+        // All lines starting before lo have smaller digests, all lines
+        // starting at or after hi are at least as large as the target
+        let mut lo = self.data_start;
+        let mut hi = self.len;
+        while lo < hi {
+            let start = self.next_line_start(lo + (hi - lo) / 2)?;
+            let start = if start < hi { start } else { lo };
+            let line_len = self.line_at(start, &mut line)?;
+            if line.get(..HEXDIGEST_LEN).context("invalid index line")? < target.as_str() {
+                lo = start + line_len as u64;
+            } else {
+                hi = start;
+            }
+        }
+
+        let mut paths = Vec::new();
+        let mut offset = lo;
+        loop {
+            let line_len = self.line_at(offset, &mut line)?;
+            match line.trim_end_matches('\n').split_once(' ') {
+                Some((hexdigest, path)) if hexdigest == target => {
+                    paths.push(PathBuf::from(path));
+                    offset += line_len as u64;
+                }
+                _ => break,
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn lookup() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("index");
+        let path = dir.join("index");
+        let entries = (0..200_u8).map(|i| [i / 2; 32]).collect::<Vec<_>>();
+        let paths = (0..200)
+            .map(|i| PathBuf::from(format!("dir/file {}", i)))
+            .collect::<Vec<_>>();
+        write_index(
+            &path,
+            &StateHeader::default(),
+            entries
+                .iter()
+                .copied()
+                .zip(paths.iter().cloned())
+                .rev()
+                .collect(),
+        )?;
+
+        let mut index = DigestIndex::open(&path)?;
+        for i in 0..100_u8 {
+            let mut expected = vec![
+                PathBuf::from(format!("dir/file {}", i as usize * 2)),
+                PathBuf::from(format!("dir/file {}", i as usize * 2 + 1)),
+            ];
+            expected.sort();
+            assert_eq!(index.lookup(&[i; 32])?, expected);
+        }
+        assert!(index.lookup(&[255; 32])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn digest_counts_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("digest-counts");
+        let path = dir.join("digests");
+        let counts = DigestCounts::of([[2; 32], [1; 32], [2; 32]]);
+        assert_eq!((counts.count(&[2; 32]), counts.count(&[3; 32])), (2, 0));
+        counts.write(&path, &StateHeader::default())?;
+        let contents = std::fs::read_to_string(&path)?;
+        assert!(contents.ends_with(&format!(
+            "{} 1\n{} 2\n",
+            hex::encode([1; 32]),
+            hex::encode([2; 32])
+        )));
+        assert_eq!(DigestCounts::read(&path)?, (StateHeader::default(), counts));
+        Ok(())
+    }
+}
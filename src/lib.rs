@@ -0,0 +1,62 @@
+//! Don't let your digital archive rust away!
+//!
+//! Keeps track of the contents of a directory tree in a state directory
+//! and checks for modifications (intentional or not), missing files and
+//! new additions. This is the library behind the `rusty-archive` command.
+//!
+//! ```no_run
+//! use rusty_archive::{Archive, ArchiveOptions, UpdateOptions, VerifyOptions};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let archive = Archive::new("/mnt/archive-state", "/mnt/archive", ArchiveOptions::default());
+//! let report = archive.update(&UpdateOptions::default())?;
+//! println!("{} files modified", report.modified.len());
+//!
+//! let report = archive.verify(&VerifyOptions::default())?;
+//! if !report.is_ok() {
+//!     println!("files missing or modified: {:?}", report.missing);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod annex;
+pub mod archive;
+pub mod changeset;
+pub mod checksum;
+pub mod config;
+pub mod container;
+pub mod deleted;
+pub mod device_pools;
+pub mod directory_counts;
+pub mod duplicates;
+pub mod file_check;
+pub mod file_info;
+pub mod hash;
+pub mod history;
+pub mod index;
+mod memory;
+pub mod mirrors;
+pub mod observer;
+pub mod partial;
+pub mod plan;
+mod priority;
+pub mod review;
+pub mod roots;
+mod spill;
+pub mod state;
+pub mod stats;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(test)]
+mod test_dir;
+pub mod throughput;
+pub mod torrent;
+pub mod walk;
+
+pub use archive::{
+    verify_iter, Archive, ArchiveOptions, AttributeChange, Baseline, DedupeScope, GrowingFiles,
+    MetadataPass, ReadOrder, UpdateOptions, UpdateReport, VerifyIter, VerifyOptions, VerifyReport,
+};
+pub use hash::HashAlgorithm;
+pub use observer::Observer;
@@ -0,0 +1,43 @@
+use rusty_archive::duplicates::{duplicate_dirs, duplicate_files};
+use rusty_archive::state::read_state;
+
+use anyhow::Result;
+use std::path::Path;
+
+/// Prints groups of duplicated files or directories, most space wasted first
+pub fn run(state_dir: &Path, dirs: bool) -> Result<()> {
+    let (_, files) = read_state(state_dir)?;
+    let groups = if dirs {
+        duplicate_dirs(files.values())
+    } else {
+        duplicate_files(files.values())
+    };
+
+    for group in &groups {
+        if dirs {
+            println!(
+                "{} directories with {} file(s) ({:.3} GiB each):",
+                group.paths.len(),
+                group.files,
+                group.len as f64 / 1024.0 / 1024.0 / 1024.0
+            );
+        } else {
+            println!(
+                "{} ({} copies, {:.3} GiB each):",
+                hex::encode(group.digest),
+                group.paths.len(),
+                group.len as f64 / 1024.0 / 1024.0 / 1024.0
+            );
+        }
+        for path in &group.paths {
+            println!("  {}", path.to_string_lossy());
+        }
+    }
+    println!(
+        "└ {} group(s) of duplicated {}, {:.3} GiB could be freed",
+        groups.len(),
+        if dirs { "directories" } else { "files" },
+        groups.iter().map(|group| group.wasted()).sum::<u64>() as f64 / 1024.0 / 1024.0 / 1024.0
+    );
+    Ok(())
+}
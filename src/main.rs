@@ -1,297 +1,944 @@
-use crate::cli::commandline_options;
-use crate::file_check::{FileCheckResult, FileToCheck};
-use crate::state::{read_state, write_state};
+use crate::cli::{commandline_options, CommandlineOptions};
+use rusty_archive::changeset::{self, Changeset};
+use rusty_archive::config::{self, Config};
+use rusty_archive::deleted::DELETED_LOG;
+use rusty_archive::directory_counts::ShrunkDirectory;
+use rusty_archive::file_check::{CheckTier, FileCheckResult, ReadError, TierStats};
+use rusty_archive::partial;
+use rusty_archive::roots;
+use rusty_archive::state;
+use rusty_archive::stats::{SizeHistogram, Stats, Timings, SIZE_BUCKETS};
+use rusty_archive::throughput::Progress;
+use rusty_archive::{
+    Archive, ArchiveOptions, AttributeChange, MetadataPass, Observer, UpdateOptions, UpdateReport,
+    VerifyOptions,
+};
 
+mod archive_stats;
+mod audit;
+mod bench;
 mod cli;
-mod file_check;
-mod file_info;
-mod state;
-mod stats;
+mod compact;
+mod cross_verify;
+mod dedupe;
+mod doctor;
+mod estimate;
+mod export_cas;
+mod export_changes;
+mod export_torrent;
+mod find;
+mod git_annex;
+mod list_duplicates;
+mod review_changes;
+mod rollback;
+mod selftest;
+mod snapraid;
+mod stale;
+mod state_diff;
+mod sync_state;
+#[cfg(test)]
+mod test_dir;
+mod time_format;
 
-use anyhow::{Context, Result};
-use std::collections::HashSet;
+use crate::time_format::TimeFormat;
+use anyhow::Context;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
-use std::time::{Instant, SystemTime};
-use walkdir::WalkDir;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-#[macro_use]
-extern crate lazy_static;
+/// Number of extensions listed in the summary of an update
+const MAX_EXTENSIONS_PRINTED: usize = 10;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opts = commandline_options().run();
+/// How often the progress of reading files is printed at most
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
 
-    let (state_dir, directory, read_all_files) = match &opts.cmd {
-        cli::Cmd::Update {
-            state_dir,
-            directory,
-            read_all_files,
-        } => (state_dir.as_str(), directory.as_deref(), *read_all_files),
-        cli::Cmd::Verify {
-            ignore_missing: _,
-            state_dir,
-            directory,
-            only_presence: _,
-        } => (state_dir.as_str(), directory.as_deref(), true),
-    };
+/// Prints every file read and all messages to stdout
+struct PrintObserver {
+    time_format: TimeFormat,
+    /// When the progress was printed last, or the observer was created
+    progress_printed: Mutex<Instant>,
+}
 
-    let num_threads = opts.threads.unwrap_or(1);
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .build_global()?;
-    println!("using {num_threads} thread(s)");
+impl PrintObserver {
+    fn new(time_format: TimeFormat) -> Self {
+        PrintObserver {
+            time_format,
+            progress_printed: Mutex::new(Instant::now()),
+        }
+    }
+}
 
-    let start_load_old_state = Instant::now();
-    let mut old_states_by_filename = read_state(Path::new(state_dir))?;
-    println!(
-        "loaded previous states of {} files in {:.1?} from {}",
-        old_states_by_filename.len(),
-        start_load_old_state.elapsed(),
-        state_dir
-    );
+impl Observer for PrintObserver {
+    fn on_result(&self, result: &FileCheckResult) {
+        match result {
+            FileCheckResult::New(fi) => println!("+ {:}", fi.rel_path.to_string_lossy()),
+            FileCheckResult::Unmodifed(fi) => println!("  {:}", fi.rel_path.to_string_lossy()),
+            FileCheckResult::Modified(fi_mod)
+                if fi_mod.previous.fully_read > SystemTime::UNIX_EPOCH =>
+            {
+                println!(
+                    "M {:} (fully read {})",
+                    fi_mod.current.rel_path.to_string_lossy(),
+                    self.time_format
+                        .format(fi_mod.previous.fully_read, SystemTime::now())
+                )
+            }
+            FileCheckResult::Modified(fi_mod) => {
+                println!("M {:}", fi_mod.current.rel_path.to_string_lossy())
+            }
+            FileCheckResult::Missing(_) => {}
+        }
+    }
 
-    let start = Instant::now();
-    let stats = stats::StatsCollector::new();
-    let base_path_buf = PathBuf::from(directory.unwrap_or("."));
-    let base_path = base_path_buf.as_path();
-    let mut files_checked = 0;
-
-    let (check_files_sender, check_files_recv) = mpsc::channel();
-    let mut checked_files = Vec::new();
-
-    rayon::in_place_scope_fifo(|s| -> Result<()> {
-        let files = WalkDir::new(base_path)
-            .sort_by_file_name()
-            .into_iter()
-            .filter_entry(|e| {
-                let file_name = e.file_name().to_str().unwrap();
-                if e.file_type().is_dir() {
-                    opts.exclude_directory
-                        .iter()
-                        .all(|re| !re.is_match(file_name))
-                } else {
-                    opts.exclude_file.iter().all(|re| !re.is_match(file_name))
-                }
-            })
-            .filter(|e| match e {
-                Ok(e) => e.file_type().is_file(),
-                _ => true,
-            });
+    fn on_progress(&self, progress: &Progress) {
+        let mut printed = self.progress_printed.lock().unwrap();
+        if printed.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        *printed = Instant::now();
+        let gib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+        println!(
+            "read {:.1} of {:.1} GiB{}, {}",
+            gib(progress.bytes_done),
+            gib(progress.bytes_queued),
+            match progress.listed {
+                true => "",
+                false => " listed so far",
+            },
+            match progress.remaining {
+                Some(remaining) => format!("about {} left", estimate::hours_minutes(remaining)),
+                None => "no idea how long the rest takes yet".to_owned(),
+            }
+        );
+    }
 
-        for file_result in files {
-            let file = file_result.context("Listing files failed")?;
-            let path_str = file.path().as_os_str().to_str().unwrap();
-            if opts.exclude_path.iter().any(|re| re.is_match(path_str)) {
+    fn on_metadata_pass(&self, pass: &MetadataPass) {
+        println!(
+            "listed all files: {} missing, {} new, {} with a different size or mtime, reading them now",
+            pass.missing.len(),
+            pass.new.len(),
+            pass.changed.len()
+        );
+        for (what, paths) in [
+            ("missing", &pass.missing),
+            ("not in the state", &pass.new),
+            ("with a different size or mtime", &pass.changed),
+        ] {
+            if paths.is_empty() {
                 continue;
             }
+            println!("{} files {}:", paths.len(), what);
+            for path in paths {
+                println!("└ {}", path.to_string_lossy());
+            }
+        }
+    }
 
-            files_checked += 1;
-
-            let handle = |file: FileToCheck| {
-                let sender = check_files_sender.clone();
-                let stats = stats.clone();
-                s.spawn_fifo(move |_| {
-                    let result = file.check(base_path);
-                    if let Ok(check_result) = &result {
-                        match check_result {
-                            FileCheckResult::New(file_info) => {
-                                stats.file_read_new(file_info);
-                            }
-                            FileCheckResult::Unmodifed(file_info) => {
-                                stats.file_read_unmodifed(file_info);
-                            }
-                            FileCheckResult::Modified(file_infos) => {
-                                stats.file_read_modified(&file_infos.current);
-                            }
-                            FileCheckResult::Missing(_) => {
-                                stats.file_not_found();
-                            }
-                        }
-                    }
-                    sender.send(result).unwrap();
-                });
-            };
+    fn on_message(&self, message: &str) {
+        println!("{}", message);
+    }
 
-            match old_states_by_filename.remove(file.path().strip_prefix(base_path).unwrap()) {
-                None => {
-                    handle(FileToCheck::New(file));
-                }
-                Some(fi) => match fi.needs_reading(&file) {
-                    Ok(needs_reading) if (needs_reading || read_all_files) => {
-                        handle(FileToCheck::NeedsChecking(fi));
-                    }
-                    Ok(_) => {
-                        stats.file_unchanged(&fi);
+    fn confirm(&self, question: &str) -> bool {
+        ask(question, &mut std::io::stdout())
+    }
+}
 
-                        let mut new_fi = fi;
-                        new_fi.last_seen = SystemTime::now();
-                        checked_files.push(FileCheckResult::Unmodifed(new_fi));
-                    }
-                    Err(err) => check_files_sender
-                        .send(Err(err).context(format!(
-                            "Failed to check if file needs to be read: {:?}",
-                            file.path()
-                        )))
-                        .unwrap(),
+/// Prints the paths of new and modified files for --print0, messages go to stderr
+struct Print0Observer {
+    /// Whether to print files as they're read, verify prints its report instead
+    results: bool,
+}
+
+impl Observer for Print0Observer {
+    fn on_result(&self, result: &FileCheckResult) {
+        match result {
+            FileCheckResult::New(_) | FileCheckResult::Modified(_) if self.results => {
+                print0(result.rel_path())
+            }
+            _ => {}
+        }
+    }
+
+    fn on_message(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn confirm(&self, question: &str) -> bool {
+        ask(question, &mut std::io::stderr())
+    }
+}
+
+/// Asks a yes or no question on the terminal, no if stdin isn't one
+fn ask(question: &str, prompt: &mut dyn std::io::Write) -> bool {
+    use std::io::{BufRead, IsTerminal};
+
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    write!(prompt, "{} [y/N] ", question).ok();
+    prompt.flush().ok();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Prints a path followed by a NUL byte
+///
+/// The path is written as it is, tools reading NUL separated paths expect
+/// the bytes of the filesystem and not a lossy conversion to UTF-8.
+fn print0(path: &Path) {
+    use std::io::Write;
+
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(path.as_os_str().as_encoded_bytes())
+        .and_then(|()| stdout.write_all(b"\0"))
+        .expect("Unable to write to stdout");
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Hidden from --help, it's meant for packagers
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--generate-man")
+    {
+        print!("{}", cli::manpage());
+        return Ok(());
+    }
+    let opts = commandline_options().run();
+
+    match &opts.cmd {
+        cli::Cmd::Update { .. } | cli::Cmd::Verify { .. } => update_or_verify(opts),
+        cli::Cmd::Bench {
+            hash,
+            sample_size,
+            directory,
+        } => Ok(bench::run(
+            &opts,
+            *hash,
+            *sample_size,
+            directory.as_deref().map(Path::new),
+        )?),
+        cli::Cmd::CrossVerify {
+            state_dir_a,
+            directory_a,
+            state_dir_b,
+            directory_b,
+        } => Ok(cross_verify::run(
+            (&opts.state_dir(state_dir_a), Path::new(directory_a)),
+            (&opts.state_dir(state_dir_b), Path::new(directory_b)),
+            opts.archive_options(),
+        )?),
+        cli::Cmd::Selftest { directory } => Ok(selftest::run(
+            directory.as_deref().map(Path::new),
+            ArchiveOptions {
+                // The files created are known, excludes could only get in the way
+                excludes: Default::default(),
+                ..opts.archive_options()
+            },
+        )?),
+        cli::Cmd::AcceptMissing {
+            path,
+            volume,
+            state_dir,
+            directory,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            let archive = Archive::new(
+                &state_dir,
+                directory_or_default(&state_dir, directory)?,
+                opts.archive_options(),
+            );
+            let paths = path.iter().map(PathBuf::from).collect::<Vec<_>>();
+            let tombstones = archive.accept_missing(&paths, volume.as_deref())?;
+            for tombstone in &tombstones {
+                println!("DELETED: {}", tombstone.rel_path.to_string_lossy());
+            }
+            println!(
+                "└ {} missing files recorded in {:?}",
+                tombstones.len(),
+                state_dir.join(DELETED_LOG)
+            );
+            Ok(())
+        }
+        cli::Cmd::Accept {
+            path,
+            run,
+            state_dir,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            let archive = Archive::new(
+                &state_dir,
+                directory_or_default(&state_dir, &None)?,
+                opts.archive_options(),
+            );
+            let run = run.as_deref().filter(|run| *run != "LAST");
+            let (run_id, accepted) = archive.accept_changes(run, path)?;
+            for path in &accepted {
+                println!("ACCEPTED: {}", path.to_string_lossy());
+            }
+            println!(
+                "└ {} files modified in run {} recorded as they were found",
+                accepted.len(),
+                run_id
+            );
+            Ok(())
+        }
+        cli::Cmd::Pin {
+            remove,
+            state_dir,
+            state,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            match (remove, state) {
+                (true, Some(_)) => {
+                    return Err(anyhow::Error::msg("--remove doesn't take a STATE").into())
+                }
+                (true, None) => {
+                    state::pin_state(&state_dir, None)?;
+                    println!("no golden state pinned, verify uses the latest state");
+                }
+                (false, Some(state)) => {
+                    state::pin_state(&state_dir, Some(&state::resolve_state(&state_dir, state)?))?;
+                    println!("{} pinned as the golden state", state);
+                }
+                (false, None) => match state::pinned_state(&state_dir)? {
+                    Some(pinned) => println!("golden state: {:?}", pinned),
+                    None => println!("no golden state pinned"),
                 },
             }
+            Ok(())
         }
-        Ok(())
-    })?;
-    drop(check_files_sender);
-    stats.files_checked(files_checked + old_states_by_filename.len() as u64);
-    stats.files_not_found(old_states_by_filename.len() as u64);
-
-    checked_files.extend(
-        old_states_by_filename
-            .into_values()
-            .into_iter()
-            .map(FileCheckResult::Missing),
-    );
-    checked_files.extend(
-        check_files_recv
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?,
-    );
+        cli::Cmd::Find { state_dir, digests } => {
+            Ok(find::run(&opts.state_dir(state_dir), digests)?)
+        }
+        cli::Cmd::Init {
+            hash,
+            keep_states,
+            state_names,
+            state_name_format,
+            state_dir,
+            directory,
+        } => {
+            let path = config::init(
+                &opts.state_dir(state_dir),
+                Config {
+                    directory: Some(PathBuf::from(directory)),
+                    hash_algorithm: *hash,
+                    excludes: opts.archive_options().excludes,
+                    keep_states: *keep_states,
+                    roots: opts.root.clone(),
+                    state_names: *state_names,
+                    state_name_format: state_name_format.clone(),
+                },
+            )?;
+            println!("wrote profile {:?}", path);
+            Ok(())
+        }
+        cli::Cmd::Doctor { state_dir } => Ok(doctor::run(&opts.state_dir(state_dir))?),
+        cli::Cmd::Rollback {
+            to,
+            dry_run,
+            state_dir,
+        } => Ok(rollback::run(&opts.state_dir(state_dir), to, *dry_run)?),
+        cli::Cmd::Audit { paths, state_dir } => Ok(audit::run(
+            &opts.state_dir(state_dir),
+            *paths,
+            opts.time_format,
+        )?),
+        cli::Cmd::Diff {
+            from,
+            to,
+            format,
+            paths,
+            top,
+            state_dir,
+        } => Ok(state_diff::run(
+            &opts.state_dir(state_dir),
+            from.as_deref(),
+            to,
+            *format,
+            *paths,
+            *top,
+        )?),
+        cli::Cmd::Apply {
+            state_dir,
+            manifest,
+        } => {
+            let contents = match manifest.as_str() {
+                "-" => std::io::read_to_string(std::io::stdin()).context("Unable to read stdin"),
+                path => std::fs::read_to_string(path)
+                    .with_context(|| format!("Unable to read {:?}", path)),
+            }?;
+            let changeset = Changeset::parse(&contents)?;
+            let applied = changeset::apply(&opts.state_dir(state_dir), &changeset)?;
+            match &applied.state_path {
+                Some(path) => println!(
+                    "APPLIED: changes from {} to {} written to {:?}",
+                    changeset.from, changeset.to, path
+                ),
+                None => println!(
+                    "the state has all changes from {} to {} already",
+                    changeset.from, changeset.to
+                ),
+            }
+            println!(
+                "└ {} files added, {} changed, {} removed, {} applied already",
+                applied.added, applied.changed, applied.removed, applied.already
+            );
+            Ok(())
+        }
+        cli::Cmd::Merge { state_dir } => {
+            let merged = partial::merge(&opts.state_dir(state_dir))?;
+            let Some(state_path) = &merged.state_path else {
+                println!("no partial states to merge");
+                return Ok(());
+            };
+            println!(
+                "MERGED: {} partial states written to {:?}",
+                merged.partial_states.len(),
+                state_path
+            );
+            for (host, changes) in &merged.by_host {
+                println!(
+                    "└ {}: {} states, {} files, {} new, {} modified, {} missing",
+                    host,
+                    changes.states,
+                    changes.files,
+                    changes.new,
+                    changes.modified,
+                    changes.missing
+                );
+            }
+            if !merged.disagreements.is_empty() {
+                println!(
+                    "{} files found differently by hosts covering them, the later one was taken:",
+                    merged.disagreements.len()
+                );
+                for disagreement in &merged.disagreements {
+                    println!(
+                        "└ {} ({}, then {})",
+                        disagreement.rel_path.to_string_lossy(),
+                        disagreement.earlier,
+                        disagreement.later
+                    );
+                }
+            }
+            if !merged.removed_states.is_empty() {
+                println!(
+                    "└ {} old state(s) removed, as set by keep-states in the profile",
+                    merged.removed_states.len()
+                );
+            }
+            Ok(())
+        }
+        cli::Cmd::Stats {
+            csv,
+            depth,
+            state_dir,
+        } => Ok(archive_stats::run(
+            &opts.state_dir(state_dir),
+            *csv,
+            *depth,
+        )?),
+        cli::Cmd::Stale {
+            older_than,
+            state_dir,
+        } => Ok(stale::run(
+            &opts.state_dir(state_dir),
+            *older_than,
+            opts.time_format,
+        )?),
+        cli::Cmd::Estimate {
+            verify,
+            read_all_files,
+            state_dir,
+        } => Ok(estimate::run(
+            &opts.state_dir(state_dir),
+            *verify,
+            *read_all_files,
+        )?),
+        cli::Cmd::Compact { dry_run, state_dir } => {
+            Ok(compact::run(&opts.state_dir(state_dir), *dry_run)?)
+        }
+        cli::Cmd::Dedupe {
+            reflinks,
+            dry_run,
+            state_dir,
+            directory,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            Ok(dedupe::run(
+                &state_dir,
+                &directory_or_default(&state_dir, directory)?,
+                *reflinks,
+                *dry_run,
+                opts.direct_io,
+            )?)
+        }
+        cli::Cmd::ExportCas {
+            state_dir,
+            dest,
+            directory,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            Ok(export_cas::run(
+                &state_dir,
+                &directory_or_default(&state_dir, directory)?,
+                Path::new(dest),
+            )?)
+        }
+        cli::Cmd::ExportChanges {
+            tool,
+            since,
+            state_dir,
+            output,
+            directory,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            Ok(export_changes::run(
+                &state_dir,
+                *tool,
+                since.as_deref().map(Path::new),
+                output,
+                &directory_or_default(&state_dir, directory)?,
+            )?)
+        }
+        cli::Cmd::ExportTorrent {
+            piece_size,
+            name,
+            announce,
+            state_dir,
+            torrent,
+            directory,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            Ok(export_torrent::run(
+                &state_dir,
+                &directory_or_default(&state_dir, directory)?,
+                Path::new(torrent),
+                name.as_deref(),
+                *piece_size,
+                announce,
+            )?)
+        }
+        cli::Cmd::AnnexExport { backend, state_dir } => {
+            Ok(git_annex::export(&opts.state_dir(state_dir), *backend)?)
+        }
+        cli::Cmd::AnnexImport {
+            state_dir,
+            listing,
+            directory,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            Ok(git_annex::import(
+                &state_dir,
+                listing,
+                &directory_or_default(&state_dir, directory)?,
+            )?)
+        }
+        cli::Cmd::SyncState { state_dir, dest } => Ok(sync_state::run(
+            &opts.state_dir(state_dir),
+            Path::new(dest),
+        )?),
+        cli::Cmd::SnapraidCheck {
+            state_dir,
+            listing,
+            directory,
+        } => {
+            let state_dir = opts.state_dir(state_dir);
+            Ok(snapraid::run(
+                &state_dir,
+                listing,
+                &directory_or_default(&state_dir, directory)?,
+            )?)
+        }
+        cli::Cmd::Duplicates { dirs, state_dir } => {
+            Ok(list_duplicates::run(&opts.state_dir(state_dir), *dirs)?)
+        }
+    }
+}
 
-    checked_files.sort_by_cached_key(|f| match f {
-        FileCheckResult::New(fi)
-        | FileCheckResult::Unmodifed(fi)
-        | FileCheckResult::Missing(fi) => fi.rel_path.clone(),
-        FileCheckResult::Modified(fi_mod) => fi_mod.current.rel_path.clone(),
-    });
+/// The directory given, otherwise the one from the profile or the current directory
+fn directory_or_default(state_dir: &Path, directory: &Option<String>) -> anyhow::Result<PathBuf> {
+    if let Some(directory) = directory {
+        return Ok(PathBuf::from(directory));
+    }
+    Ok(Config::read(state_dir)?
+        .and_then(|config| config.directory)
+        .unwrap_or_else(|| PathBuf::from(".")))
+}
+
+/// Paths from a file or stdin for `-`, one per line, blank lines are skipped
+fn read_file_list(path: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let contents = match path {
+        "-" => std::io::read_to_string(std::io::stdin()).context("Unable to read stdin"),
+        path => std::fs::read_to_string(path).with_context(|| format!("Unable to read {:?}", path)),
+    }?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Writes the new and modified files of an update sorted by path, followed by a newline or NUL byte each
+fn write_changed_files(path: &Path, report: &UpdateReport, nul: bool) -> anyhow::Result<()> {
+    let mut changed = report
+        .new
+        .iter()
+        .chain(&report.modified)
+        .collect::<Vec<_>>();
+    changed.sort();
+    let end = if nul { b'\0' } else { b'\n' };
+    let mut contents = Vec::new();
+    for path in changed {
+        contents.extend_from_slice(path.as_os_str().as_encoded_bytes());
+        contents.push(end);
+    }
+    std::fs::write(path, contents).with_context(|| format!("Unable to write {:?}", path))
+}
 
-    match opts.cmd {
+fn update_or_verify(opts: CommandlineOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if opts.plan_only && opts.plan.is_none() {
+        return Err(anyhow::Error::msg("--plan-only requires --plan FILE").into());
+    }
+    match &opts.cmd {
         cli::Cmd::Update {
-            read_all_files: _,
+            read_all_files,
+            hash,
+            volume,
+            force,
+            append,
+            no_dedupe_missing,
+            dedupe_scope,
+            forget_missing_after,
+            canonical,
+            set_immutable,
+            path,
+            files_from,
+            changed_files,
+            previous_state,
+            state_file,
+            state_name_format,
+            label,
+            partial,
+            host,
             state_dir,
-            directory: _,
+            directory,
         } => {
-            let present_sha256_digests = checked_files
-                .iter()
-                .filter_map(|f| match &f {
-                    FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => {
-                        Some(fi.sha256_digest)
-                    }
-                    FileCheckResult::Modified(fi_mod) => Some(fi_mod.current.sha256_digest),
-                    _ => None,
-                })
-                .collect::<HashSet<_>>();
-
-            let mut duplicates_removed: u64 = 0;
-            let checked_files_deduped = checked_files
-                .into_iter()
-                .filter_map(|f| match f {
-                    FileCheckResult::Missing(fi) => {
-                        if present_sha256_digests.contains(&fi.sha256_digest) {
-                            duplicates_removed += 1;
-                            None
-                        } else {
-                            Some(FileCheckResult::Missing(fi))
-                        }
-                    }
-                    FileCheckResult::Modified(mod_fi) => {
-                        if present_sha256_digests.contains(&mod_fi.previous.sha256_digest) {
-                            duplicates_removed += 1;
-                            // Previous version was a duplicate, consider this to be new
-                            Some(FileCheckResult::New(mod_fi.current))
-                        } else {
-                            Some(FileCheckResult::Modified(mod_fi))
-                        }
+            if host.is_some() && !partial {
+                return Err(anyhow::Error::msg("--host requires --partial").into());
+            }
+            let state_dir = opts.state_dir(state_dir);
+            if opts.archive.is_some() {
+                // Only the archive's own directory, STATE_DIR has to exist
+                match std::fs::create_dir(&state_dir) {
+                    Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => {
+                        return Err(anyhow::Error::new(err)
+                            .context(format!("Unable to create {:?}", state_dir))
+                            .into())
                     }
-                    other => Some(other),
-                })
-                .collect::<Vec<_>>();
-            stats.duplicates_removed(duplicates_removed);
-
-            write_state(Path::new(state_dir.as_str()), checked_files_deduped.iter())?;
-
-            let newly_missing = checked_files_deduped
-                .iter()
-                .filter(|f| {
-                    matches!(
-                        f,
-                        FileCheckResult::Missing(_) | FileCheckResult::Modified(_)
-                    )
-                })
-                .count() as u64;
-
-            stats.print_results_for_update(start.elapsed(), newly_missing);
+                    _ => {}
+                }
+            }
+            let archive = Archive::new(
+                &state_dir,
+                directory_or_default(&state_dir, directory)?,
+                opts.archive_options(),
+            )
+            .with_observer(match opts.print0 {
+                true => Arc::new(Print0Observer { results: true }),
+                false => Arc::new(PrintObserver::new(opts.time_format)),
+            });
+            let result = archive.update(&UpdateOptions {
+                read_all_files: *read_all_files,
+                hash_algorithm: *hash,
+                volume: volume.clone(),
+                force: *force,
+                append: *append,
+                keep_duplicates: *no_dedupe_missing,
+                dedupe_scope: *dedupe_scope,
+                forget_missing_after: *forget_missing_after,
+                canonical: *canonical,
+                set_immutable: *set_immutable,
+                paths: path.iter().map(PathBuf::from).collect(),
+                files: files_from.as_deref().map(read_file_list).transpose()?,
+                previous_state: previous_state.as_ref().map(PathBuf::from),
+                state_file: state_file.as_ref().map(PathBuf::from),
+                label: label.clone(),
+                state_name_format: state_name_format.clone(),
+                partial: partial.then(|| host.clone().unwrap_or_else(state::hostname)),
+            });
+            let report = result?;
+            if let (true, Some(plan)) = (opts.plan_only, &report.plan) {
+                println!(
+                    "└ plan of {} files ({} bytes) written to {:?}, stopped before reading them",
+                    plan.files, plan.bytes, plan.path
+                );
+                return Ok(());
+            }
+            if let Some(path) = changed_files {
+                write_changed_files(Path::new(path), &report, opts.print0)?;
+            }
+            if opts.print0 {
+                report.missing.iter().for_each(|path| print0(path));
+                return Ok(());
+            }
+            print_results_for_update(&report.stats, report.duration, report.newly_missing());
+            for (volume, files) in &report.other_volumes {
+                println!("└ {} files on volume {} kept as they are", files, volume);
+            }
+            if *partial {
+                println!(
+                    "└ partial state written to {:?}, merge takes it into the next state",
+                    report.state_path
+                );
+            } else if !path.is_empty() {
+                println!(
+                    "└ {} files outside of --path kept as they are",
+                    report.kept_outside_paths
+                );
+            }
+            if files_from.is_some() {
+                println!(
+                    "└ {} files not in --files-from kept as they are",
+                    report.kept_outside_paths
+                );
+            }
+            if forget_missing_after.is_some() || report.still_missing > 0 {
+                println!(
+                    "└ {} missing files kept in the state, {} forgotten",
+                    report.still_missing, report.forgotten
+                );
+            }
+            if *set_immutable {
+                println!(
+                    "└ {} files made immutable, {} couldn't be",
+                    report.made_immutable, report.not_made_immutable
+                );
+            }
+            if !report.removed_states.is_empty() {
+                println!(
+                    "└ {} old state(s) removed, as set by keep-states in the profile",
+                    report.removed_states.len()
+                );
+            }
+            if *append {
+                println!(
+                    "└ {} files not found kept as they are",
+                    report.kept_not_found
+                );
+            }
+            if !report.accepted.is_empty() {
+                println!(
+                    "└ {} of the modified files were accepted in a review",
+                    report.accepted.len()
+                );
+            }
+            if !report.flagged_corrupted.is_empty() {
+                println!(
+                    "{} files flagged as corrupted in a review are still corrupted, the state keeps their previous digest:",
+                    report.flagged_corrupted.len()
+                );
+                for path in &report.flagged_corrupted {
+                    println!("└ {}", path.to_string_lossy());
+                }
+            }
+            if !report.truncated.is_empty() {
+                println!(
+                    "{} files were truncated to zero bytes, their previous contents are likely lost:",
+                    report.truncated.len()
+                );
+                for path in &report.truncated {
+                    println!("└ {}", path.to_string_lossy());
+                }
+            }
+            print_shrunk_directories(&report.shrunk_directories);
+            print_attribute_changes(&report.attribute_changes);
+            print_read_errors(&report.read_errors, report.errors_path.as_deref());
+            print_locked(&report.locked);
+            print_growing(&report.growing);
+            if opts.timings {
+                print_timings(&report.stats.timings);
+            }
         }
         cli::Cmd::Verify {
             ignore_missing,
             only_presence,
-            state_dir: _,
-            directory: _,
+            volume,
+            strict_volume,
+            simulate_corruption,
+            double_read,
+            state_file,
+            against,
+            manifest,
+            max_failures,
+            max_failure_percent,
+            map_prefix,
+            case_insensitive_paths,
+            metadata_first,
+            quick,
+            tiers,
+            files_from,
+            review,
+            state_dir,
+            directory,
         } => {
-            let archive_sha256_digests = checked_files
-                .iter()
-                .filter_map(|f| match &f {
-                    FileCheckResult::Unmodifed(fi) | FileCheckResult::Missing(fi) => {
-                        Some(fi.sha256_digest)
+            use std::io::IsTerminal;
+            if *review && (manifest.is_some() || opts.print0) {
+                return Err(anyhow::Error::msg(
+                    "--review records the decisions in the state directory, it can't be combined with --manifest or --print0",
+                )
+                .into());
+            }
+            if *review && !std::io::stdin().is_terminal() {
+                return Err(
+                    anyhow::Error::msg("--review asks on a terminal, stdin isn't one").into(),
+                );
+            }
+            let (state_dir, directory) = match manifest {
+                // The only positional is the directory
+                Some(manifest) => {
+                    if directory.is_some() || state_file.is_some() {
+                        return Err(anyhow::Error::msg(
+                            "--manifest only takes DIRECTORY and can't be combined with --state-file",
+                        )
+                        .into());
                     }
-                    FileCheckResult::Modified(fi_mod) => Some(fi_mod.previous.sha256_digest),
-                    FileCheckResult::New(_) => None,
-                })
-                .collect::<HashSet<_>>();
+                    // Only shown in messages, nothing is read from or written to it
+                    let manifest_dir = Path::new(manifest).parent().unwrap_or(Path::new("."));
+                    (manifest_dir.to_path_buf(), PathBuf::from(state_dir))
+                }
+                None => {
+                    let state_dir = opts.state_dir(state_dir);
+                    let directory = directory_or_default(&state_dir, directory)?;
+                    (state_dir, roots::map_prefix(map_prefix, &directory))
+                }
+            };
+            let archive = Archive::new(&state_dir, directory, opts.archive_options())
+                .with_observer(match opts.print0 {
+                    true => Arc::new(Print0Observer { results: false }),
+                    false => Arc::new(PrintObserver::new(opts.time_format)),
+                });
+            let result = archive.verify(&VerifyOptions {
+                ignore_missing: *ignore_missing,
+                only_presence: *only_presence,
+                volume: volume.clone(),
+                strict_volume: *strict_volume,
+                simulate_corruption: *simulate_corruption,
+                double_read: *double_read,
+                state_file: state_file.as_ref().map(PathBuf::from),
+                against: *against,
+                manifest: manifest.as_ref().map(PathBuf::from),
+                map_prefix: map_prefix.clone(),
+                case_insensitive_paths: *case_insensitive_paths,
+                metadata_first: *metadata_first,
+                tiers: match quick {
+                    true => vec![CheckTier::Size, CheckTier::Quick],
+                    false => tiers.clone(),
+                },
+                files: files_from.as_deref().map(read_file_list).transpose()?,
+            });
+            let report = result?;
+            if let (true, Some(plan)) = (opts.plan_only, &report.plan) {
+                println!(
+                    "└ plan of {} files ({} bytes) written to {:?}, stopped before reading them",
+                    plan.files, plan.bytes, plan.path
+                );
+                return Ok(());
+            }
+            let failed = !report.failures_below(*max_failures, *max_failure_percent);
+            if opts.print0 {
+                for path in [&report.modified, &report.missing, &report.not_in_archive]
+                    .into_iter()
+                    .flatten()
+                {
+                    print0(path);
+                }
+                if failed {
+                    return Err(anyhow::Error::msg(
+                        "files missing, modified and / or not found in archive",
+                    )
+                    .into());
+                }
+                return Ok(());
+            }
 
+            for path in &report.modified_members {
+                println!("M {}", path.to_string_lossy());
+            }
+            for (volume, paths) in &report.missing_on_volumes {
+                println!(
+                    "{} missing files are on volume {}, plug it in and verify with --volume {}:",
+                    paths.len(),
+                    volume,
+                    volume
+                );
+                for path in paths {
+                    println!("└ {}", path.to_string_lossy());
+                }
+            }
+            for (volume, files) in &report.other_volumes {
+                println!("{} files on volume {} weren't checked", files, volume);
+            }
+            print_shrunk_directories(&report.shrunk_directories);
+            print_attribute_changes(&report.attribute_changes);
+            print_read_errors(&report.read_errors, report.errors_path.as_deref());
+            print_locked(&report.locked);
+            print_growing(&report.growing);
+            print_tier_stats(&report.tier_stats);
+            if *simulate_corruption > 0 {
+                println!(
+                    "SIMULATED: {} unmodified files are reported as modified, their contents are fine",
+                    report.simulated_corruptions
+                );
+            }
+            print_slowest_files(&report.stats);
+            if opts.timings {
+                print_timings(&report.stats.timings);
+            }
+            if !report.accepted.is_empty() {
+                println!(
+                    "{} modified files were accepted in a review, the next update records them:",
+                    report.accepted.len()
+                );
+                for path in &report.accepted {
+                    println!("└ {}", path.to_string_lossy());
+                }
+            }
+            if *review && !report.changes.is_empty() {
+                review_changes::run(&state_dir, &report.run_id, &report.changes)?;
+            }
             match (ignore_missing, only_presence) {
                 (true, true) => {
-                    // ensure all files found are present in the archive
-                    let not_present = checked_files
-                        .iter()
-                        .filter(|f| match f {
-                            FileCheckResult::New(fi) => {
-                                if !archive_sha256_digests.contains(&fi.sha256_digest) {
-                                    println!("NOT IN ARCHIVE: {:}", fi.rel_path.to_string_lossy());
-                                    return true;
-                                }
-                                false
-                            }
-                            FileCheckResult::Modified(fi_mod) => {
-                                if !archive_sha256_digests.contains(&fi_mod.current.sha256_digest) {
-                                    println!(
-                                        "NOT IN ARCHIVE: {:}",
-                                        fi_mod.current.rel_path.to_string_lossy()
-                                    );
-                                    return true;
-                                }
-                                false
-                            }
-                            FileCheckResult::Unmodifed(_) | FileCheckResult::Missing(_) => false,
-                        })
-                        .count();
-                    stats.print_results_for_verify(start.elapsed());
-                    println!("└ {} files not found in archive", not_present);
-                    if not_present > 0 {
+                    for path in &report.not_in_archive {
+                        println!("NOT IN ARCHIVE: {:}", path.to_string_lossy());
+                    }
+                    print_results_for_verify(&report.stats, report.duration);
+                    println!(
+                        "└ {} files not found in archive",
+                        report.not_in_archive.len()
+                    );
+                    if failed {
                         return Err(
                             anyhow::Error::msg("not all files were found in the archive").into(),
                         );
                     }
                 }
                 (true, false) => {
-                    // ensure the files found match the ones in the archive at that path
-                    let missing_or_modified = checked_files
-                        .iter()
-                        .filter(|f| {
-                            if let FileCheckResult::Modified(fi_mod) = f {
-                                println!(
-                                    "MODIFIED: {:}",
-                                    fi_mod.current.rel_path.to_string_lossy()
-                                );
-                                return true;
-                            }
-                            false
-                        })
-                        .count();
-                    let new = checked_files
-                        .iter()
-                        .filter(|f| matches!(f, FileCheckResult::New(_)))
-                        .count();
-                    stats.print_results_for_verify(start.elapsed());
-                    println!("└ {} files modified", missing_or_modified);
-                    println!("└ {} files not found in archive", new);
-                    if missing_or_modified > 0 || new > 0 {
+                    for path in &report.modified {
+                        println!("MODIFIED: {:}", path.to_string_lossy());
+                    }
+                    print_results_for_verify(&report.stats, report.duration);
+                    println!("└ {} files modified", report.modified.len());
+                    println!(
+                        "└ {} files not found in archive",
+                        report.not_in_archive.len()
+                    );
+                    if failed {
                         return Err(anyhow::Error::msg(
                             "files modified or files not found in archive",
                         )
@@ -299,34 +946,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 }
                 (false, true) => {
-                    // ensure all files in the archive are found somewhere
-                    let not_present = checked_files
-                        .iter()
-                        .filter(|f| match f {
-                            FileCheckResult::New(fi) => {
-                                !archive_sha256_digests.contains(&fi.sha256_digest)
-                            }
-                            FileCheckResult::Modified(fi_mod) => {
-                                !archive_sha256_digests.contains(&fi_mod.current.sha256_digest)
-                            }
-                            FileCheckResult::Unmodifed(_) | FileCheckResult::Missing(_) => false,
-                        })
-                        .count();
-                    let mut missing_sha256 = archive_sha256_digests;
-                    for file in &checked_files {
-                        match file {
-                            FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => {
-                                missing_sha256.remove(&fi.sha256_digest);
-                            }
-                            FileCheckResult::Modified(fi_mod) => {
-                                missing_sha256.remove(&fi_mod.current.sha256_digest);
-                            }
-                            _ => {}
-                        }
-                    }
-                    println!("└ {} files not found in archive", not_present);
-                    println!("└ {} files in archive not found", missing_sha256.len());
-                    if not_present > 0 || !missing_sha256.is_empty() {
+                    println!(
+                        "└ {} files not found in archive",
+                        report.not_in_archive.len()
+                    );
+                    println!(
+                        "└ {} files in archive not found",
+                        report.archive_files_not_found
+                    );
+                    if failed {
                         return Err(anyhow::Error::msg(
                             "files not found in archive and / or files in archive not found",
                         )
@@ -334,24 +962,34 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 }
                 (false, false) => {
-                    // ensure all files in the archive are found at their path
-                    let missing_or_modified = checked_files
-                        .iter()
-                        .filter(|f| {
-                            matches!(
-                                f,
-                                FileCheckResult::Missing(_) | FileCheckResult::Modified(_)
-                            )
-                        })
-                        .count();
-                    let new = checked_files
-                        .iter()
-                        .filter(|f| matches!(f, FileCheckResult::New(_)))
-                        .count();
-                    println!("└ {} files missing or modified", missing_or_modified);
-                    println!("└ {} files not found in archive", new);
-
-                    if missing_or_modified > 0 || new > 0 {
+                    let now = SystemTime::now();
+                    for path in &report.missing {
+                        match report.last_seen.get(path) {
+                            Some(last_seen) => println!(
+                                "MISSING: {} (last seen {})",
+                                path.to_string_lossy(),
+                                opts.time_format.format(*last_seen, now)
+                            ),
+                            None => println!("MISSING: {}", path.to_string_lossy()),
+                        }
+                    }
+                    for (path, since) in &report.still_missing {
+                        println!(
+                            "STILL MISSING: {} (missing since {})",
+                            path.to_string_lossy(),
+                            opts.time_format.format(*since, now)
+                        );
+                    }
+                    println!(
+                        "└ {} files missing or modified",
+                        report.missing.len() + report.modified.len()
+                    );
+                    println!(
+                        "└ {} files not found in archive",
+                        report.not_in_archive.len()
+                    );
+
+                    if failed {
                         return Err(anyhow::Error::msg(
                             "files missing, modified and / or not found in archive",
                         )
@@ -359,8 +997,271 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 }
             }
+            if !report.is_ok() {
+                println!(
+                    "└ {} failures accepted, within --max-failures / --max-failure-percent",
+                    report.failures()
+                );
+            }
         }
+        _ => unreachable!("handled in main"),
     }
 
     Ok(())
 }
+
+/// Lists files whose Windows attributes changed, like `notes.txt (R, now RH)`
+fn print_attribute_changes(changes: &[AttributeChange]) {
+    if changes.is_empty() {
+        return;
+    }
+    println!("{} files with changed attributes:", changes.len());
+    for change in changes {
+        println!(
+            "└ {} ({}, now {})",
+            change.path.to_string_lossy(),
+            change.previous,
+            change.current
+        );
+    }
+}
+
+/// Lists files that couldn't be read along with the .errors file they're in
+fn print_read_errors(errors: &[ReadError], errors_path: Option<&Path>) {
+    let Some(errors_path) = errors_path else {
+        return;
+    };
+    println!(
+        "{} files couldn't be read, listed in {:?}:",
+        errors.len(),
+        errors_path
+    );
+    for error in errors {
+        println!("└ {} ({:?})", error.rel_path.to_string_lossy(), error.kind);
+    }
+}
+
+/// Prints directories that lost a large part of their files, a vanished folder stands out
+fn print_shrunk_directories(shrunk: &[ShrunkDirectory]) {
+    if shrunk.is_empty() {
+        return;
+    }
+    println!(
+        "{} directories lost at least half of their files:",
+        shrunk.len()
+    );
+    for directory in shrunk {
+        let path = match directory.path.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => &directory.path,
+        };
+        println!(
+            "└ {}: {} of {} files missing",
+            path.to_string_lossy(),
+            directory.missing,
+            directory.files
+        );
+    }
+}
+
+/// Lists files skipped because other programs locked them
+fn print_locked(locked: &[PathBuf]) {
+    if locked.is_empty() {
+        return;
+    }
+    println!(
+        "{} files locked by other programs were skipped:",
+        locked.len()
+    );
+    for path in locked {
+        println!("└ {}", path.to_string_lossy());
+    }
+}
+
+/// Lists files that grew while they were read, their digest may be of a half-written file
+fn print_growing(growing: &[PathBuf]) {
+    if growing.is_empty() {
+        return;
+    }
+    println!(
+        "{} files grew while they were read, they're likely still being written:",
+        growing.len()
+    );
+    for path in growing {
+        println!("└ {}", path.to_string_lossy());
+    }
+}
+
+/// Prints how many files each tier settled and how many it passed on to be read completely
+fn print_tier_stats(tier_stats: &[TierStats]) {
+    if tier_stats.is_empty() {
+        return;
+    }
+    println!("checked before reading files completely:");
+    for stats in tier_stats {
+        println!(
+            "└ {}: {} files passed, {} files read completely",
+            stats.tier, stats.passed, stats.escalated
+        );
+    }
+}
+
+fn print_results_for_update(r: &Stats, duration: Duration, newly_missing: u64) {
+    println!("{} files checked in {:.1?}:", r.files_checked, duration,);
+
+    println!(
+        "└ {} files read ({:.1} GiB, {:.0} MiB/s):",
+        r.files_read,
+        (r.bytes_read as f64) / 1024.0 / 1024.0 / 1024.0,
+        (r.bytes_read as f64) / 1024.0 / 1024.0 / duration.as_secs_f64(),
+    );
+
+    println!("  └ {} new files", r.files_new);
+    println!("  └ {} files modified", r.files_modified,);
+    println!("└ {} files not found:", r.files_not_found,);
+    println!(
+        "  └ {} files found elsewhere (moved or duplicates removed)",
+        r.files_duplicate_removed,
+    );
+    println!("  └ {} files newly missing", newly_missing,);
+    println!(
+        "{} files unchanged ({:.1} GiB)",
+        r.files_unchanged,
+        r.files_unchanged_size as f64 / 1024.0 / 1024.0 / 1024.0,
+    );
+    print_skipped(r);
+    if r.logical_size > 0 {
+        println!(
+            "{:.1} GiB in the archive, {:.1} GiB of unique contents ({:.1} GiB in duplicates)",
+            r.logical_size as f64 / 1024.0 / 1024.0 / 1024.0,
+            r.unique_size as f64 / 1024.0 / 1024.0 / 1024.0,
+            (r.logical_size - r.unique_size) as f64 / 1024.0 / 1024.0 / 1024.0,
+        );
+    }
+    print_sizes("sizes of files checked", &r.sizes_checked);
+    if r.files_modified > 0 {
+        print_sizes("sizes of files modified", &r.sizes_modified);
+    }
+    print_extensions(r);
+    print_slowest_files(r);
+}
+
+fn print_timings(t: &Timings) {
+    println!("timings:");
+    println!("└ loading the previous state: {:.1?}", t.load_state);
+    println!("└ listing files: {:.1?}", t.walk);
+    println!("└ waiting for files to be read: {:.1?}", t.wait_for_reads);
+    if !t.write_state.is_zero() {
+        println!("└ writing the state: {:.1?}", t.write_state);
+    }
+    let per_device = t.workers.keys().any(|(device, _)| *device != 0);
+    for ((device, thread), busy) in &t.workers {
+        println!(
+            "└ {}thread {}: busy for {:.1?} ({:.0}%)",
+            if per_device {
+                format!("device {} ", device)
+            } else {
+                String::new()
+            },
+            thread,
+            busy,
+            busy.as_secs_f64() / t.check.as_secs_f64() * 100.0
+        );
+    }
+}
+
+fn print_slowest_files(r: &Stats) {
+    if r.slowest_files.is_empty() {
+        return;
+    }
+    println!("slowest files:");
+    for f in &r.slowest_files {
+        println!(
+            "└ {:.1?} ({:.1} MiB/s): {}",
+            f.duration,
+            f.bytes_per_second() / 1024.0 / 1024.0,
+            f.rel_path.to_string_lossy()
+        );
+    }
+}
+
+/// Formats sizes like 64 KiB or 100 MiB
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024 || !size.is_multiple_of(1024) {
+            return format!("{} {}", size, unit);
+        }
+        size /= 1024;
+    }
+    format!("{} TiB", size)
+}
+
+fn print_sizes(title: &str, histogram: &SizeHistogram) {
+    println!("{}:", title);
+    for (bucket, files) in histogram.iter().enumerate() {
+        let label = match bucket {
+            0 => format!("< {}", format_size(SIZE_BUCKETS[0])),
+            b if b == SIZE_BUCKETS.len() => format!(">= {}", format_size(SIZE_BUCKETS[b - 1])),
+            b => format!(
+                "{} - {}",
+                format_size(SIZE_BUCKETS[b - 1]),
+                format_size(SIZE_BUCKETS[b])
+            ),
+        };
+        println!("└ {}: {} files", label, files);
+    }
+}
+
+/// Prints the extensions that grew the most, followed by the largest ones
+fn print_extensions(r: &Stats) {
+    let mut extensions = r.by_extension.iter().collect::<Vec<_>>();
+    extensions.sort_by_key(|(_, e)| std::cmp::Reverse((e.bytes_new + e.bytes_modified, e.bytes)));
+    if !extensions.is_empty() {
+        println!("by extension:");
+    }
+    for (extension, e) in extensions.iter().take(MAX_EXTENSIONS_PRINTED) {
+        println!(
+            "└ {}: {} files ({:.1} GiB), {} new ({:.1} GiB), {} modified ({:.1} GiB)",
+            extension,
+            e.files,
+            e.bytes as f64 / 1024.0 / 1024.0 / 1024.0,
+            e.files_new,
+            e.bytes_new as f64 / 1024.0 / 1024.0 / 1024.0,
+            e.files_modified,
+            e.bytes_modified as f64 / 1024.0 / 1024.0 / 1024.0,
+        );
+    }
+    if extensions.len() > MAX_EXTENSIONS_PRINTED {
+        println!(
+            "└ {} more extensions",
+            extensions.len() - MAX_EXTENSIONS_PRINTED
+        );
+    }
+}
+
+fn print_results_for_verify(r: &Stats, duration: Duration) {
+    println!("{} files in archive:", r.files_checked);
+
+    println!(
+        "└ {} files read in {:.1?} ({:.1} GiB, {:.0} MiB/s)",
+        r.files_read,
+        duration,
+        (r.bytes_read as f64) / 1024.0 / 1024.0 / 1024.0,
+        (r.bytes_read as f64) / 1024.0 / 1024.0 / duration.as_secs_f64(),
+    );
+    print_skipped(r);
+}
+
+/// Numbers of files skipped because they couldn't be read, if there are any
+fn print_skipped(r: &Stats) {
+    if r.files_unreadable > 0 {
+        println!("└ {} files couldn't be read", r.files_unreadable);
+    }
+    if r.files_locked > 0 {
+        println!(
+            "└ {} files skipped, locked by other programs",
+            r.files_locked
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::file_check::{BUF_ALIGNMENT, DEFAULT_BUF_SIZE};
+use crate::file_info::FileInfo;
+
+use anyhow::Result;
+
+/// Rough estimate of the memory needed per file kept in memory
+///
+/// Covers the FileInfo itself, hash map overhead and the heap allocations
+/// for paths of average length.
+pub const ESTIMATED_BYTES_PER_FILE: u64 = 2 * std::mem::size_of::<FileInfo>() as u64 + 160;
+
+/// Smallest read buffer used, even with very little memory
+const MIN_BUF_SIZE: usize = 64 * 1024;
+
+/// Splits the memory given with --max-memory between the parts of a run
+///
+/// An eighth goes to read buffers, a sixteenth to files waiting to be read
+/// and a quarter to results kept in memory. Half is reserved for the
+/// previous state, which always has to be loaded completely. A run fails
+/// before loading it if it doesn't fit, see [`MemoryBudget::check_state_files`].
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryBudget {
+    pub total: u64,
+}
+
+impl MemoryBudget {
+    /// Size of the read buffer of every thread
+    pub fn buffer_size(&self, threads: usize) -> usize {
+        let per_thread = (self.total / 8 / threads.max(1) as u64) as usize;
+        let aligned = per_thread / BUF_ALIGNMENT * BUF_ALIGNMENT;
+        aligned.clamp(MIN_BUF_SIZE, DEFAULT_BUF_SIZE)
+    }
+
+    /// Number of files queued for reading per thread
+    pub fn queued_files_per_thread(&self, threads: usize, default: usize) -> usize {
+        let files = self.total / 16 / threads.max(1) as u64 / ESTIMATED_BYTES_PER_FILE;
+        (files as usize).clamp(1, default)
+    }
+
+    /// Number of unmodified files kept in memory before writing them to disk
+    pub fn max_files_in_memory(&self) -> usize {
+        (self.total / 4 / ESTIMATED_BYTES_PER_FILE) as usize
+    }
+
+    /// Number of files the previous state may contain to stay within the budget
+    pub fn max_state_files(&self) -> usize {
+        (self.total / 2 / ESTIMATED_BYTES_PER_FILE) as usize
+    }
+
+    /// Fails if a previous state of that many files doesn't fit into its half
+    pub fn check_state_files(&self, files: usize) -> Result<()> {
+        if files <= self.max_state_files() {
+            return Ok(());
+        }
+        let needed = files as u64 * ESTIMATED_BYTES_PER_FILE;
+        Err(anyhow::Error::msg(format!(
+            "the previous state has {} files, loading them needs about {} MiB, more than half of --max-memory, use at least --max-memory {}M",
+            files,
+            needed.div_ceil(1 << 20),
+            (2 * needed).div_ceil(1 << 20)
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_size() {
+        let small = MemoryBudget {
+            total: 16 * 1024 * 1024,
+        };
+        assert_eq!(small.buffer_size(8), 256 * 1024);
+        assert_eq!(small.buffer_size(1000), MIN_BUF_SIZE);
+        let large = MemoryBudget {
+            total: 16 * 1024 * 1024 * 1024,
+        };
+        assert_eq!(large.buffer_size(8), DEFAULT_BUF_SIZE);
+    }
+
+    #[test]
+    fn state_files() {
+        let budget = MemoryBudget {
+            total: 64 * 1024 * 1024,
+        };
+        let fitting = budget.max_state_files();
+        assert!(budget.check_state_files(fitting).is_ok());
+        let err = budget.check_state_files(4 * fitting).unwrap_err();
+        assert!(err.to_string().ends_with("use at least --max-memory 256M"));
+    }
+}
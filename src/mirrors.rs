@@ -0,0 +1,272 @@
+use crate::archive::Archive;
+use crate::file_check::{FileCheckResult, ReadError};
+
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// One of the two mirrors compared by [`cross_verify`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirror {
+    A,
+    B,
+}
+
+impl Mirror {
+    pub fn other(&self) -> Mirror {
+        match self {
+            Mirror::A => Mirror::B,
+            Mirror::B => Mirror::A,
+        }
+    }
+}
+
+impl std::fmt::Display for Mirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Mirror::A => "A",
+            Mirror::B => "B",
+        })
+    }
+}
+
+/// How the files of one mirror compare to its own state
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorReport {
+    pub files_checked: u64,
+    /// Sorted by path, like the lists below
+    pub modified: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+    pub not_in_state: Vec<PathBuf>,
+    /// Files that couldn't be read, they aren't compared to the other mirror
+    pub unreadable: Vec<PathBuf>,
+}
+
+impl MirrorReport {
+    pub fn is_ok(&self) -> bool {
+        self.modified.is_empty()
+            && self.missing.is_empty()
+            && self.not_in_state.is_empty()
+            && self.unreadable.is_empty()
+    }
+}
+
+/// Why the copies of a file in the two mirrors differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disagreement {
+    /// The copy in this mirror was modified or is missing, the other one matches its state
+    Damaged(Mirror),
+    /// The file was added to this mirror after its state was written
+    Added(Mirror),
+    /// Both copies match their states, but the states differ
+    ///
+    /// The mirrors weren't updated with the same files, neither copy is damaged.
+    StatesDiffer,
+    /// Neither copy matches its state, it's unclear which one is right
+    BothChanged,
+}
+
+/// Outcome of [`cross_verify`]
+#[derive(Debug, Clone, Default)]
+pub struct CrossVerifyReport {
+    pub a: MirrorReport,
+    pub b: MirrorReport,
+    /// Files with different contents in the two mirrors, sorted by path
+    pub disagreements: Vec<(PathBuf, Disagreement)>,
+}
+
+impl CrossVerifyReport {
+    /// Whether both mirrors match their states and each other
+    pub fn is_ok(&self) -> bool {
+        self.a.is_ok() && self.b.is_ok() && self.disagreements.is_empty()
+    }
+}
+
+/// Digests of a file in one mirror, None where it's not found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Copies {
+    recorded: Option<[u8; 32]>,
+    current: Option<[u8; 32]>,
+}
+
+impl Copies {
+    fn matches_state(&self) -> bool {
+        self.recorded == self.current
+    }
+}
+
+/// Reads all files of a mirror, recording what its state says about them as well
+fn read_mirror(archive: Archive) -> Result<(MirrorReport, HashMap<PathBuf, Copies>)> {
+    let mut report = MirrorReport::default();
+    let mut copies = HashMap::new();
+    for result in archive.verify_iter() {
+        let file = match result {
+            Ok(file) => file,
+            Err(err) => match err.downcast::<ReadError>() {
+                Ok(read_error) => {
+                    report.unreadable.push(read_error.rel_path);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            },
+        };
+        let (path, found) = match file {
+            FileCheckResult::Unmodifed(fi) => (
+                fi.rel_path,
+                Copies {
+                    recorded: Some(fi.digest),
+                    current: Some(fi.digest),
+                },
+            ),
+            FileCheckResult::Modified(fi_mod) => {
+                report.modified.push(fi_mod.current.rel_path.clone());
+                (
+                    fi_mod.current.rel_path,
+                    Copies {
+                        recorded: Some(fi_mod.previous.digest),
+                        current: Some(fi_mod.current.digest),
+                    },
+                )
+            }
+            FileCheckResult::Missing(fi) => {
+                report.missing.push(fi.rel_path.clone());
+                (
+                    fi.rel_path,
+                    Copies {
+                        recorded: Some(fi.digest),
+                        current: None,
+                    },
+                )
+            }
+            FileCheckResult::New(fi) => {
+                report.not_in_state.push(fi.rel_path.clone());
+                (
+                    fi.rel_path,
+                    Copies {
+                        recorded: None,
+                        current: Some(fi.digest),
+                    },
+                )
+            }
+        };
+        if found.current.is_some() {
+            report.files_checked += 1;
+        }
+        copies.insert(path, found);
+    }
+    report.modified.sort();
+    report.missing.sort();
+    report.not_in_state.sort();
+    report.unreadable.sort();
+    Ok((report, copies))
+}
+
+/// Decides which copy is to blame, None if the copies are the same
+fn disagreement(a: Copies, b: Copies) -> Option<Disagreement> {
+    if a.current == b.current {
+        return None;
+    }
+    Some(match (a.matches_state(), b.matches_state()) {
+        (true, true) => Disagreement::StatesDiffer,
+        (true, false) if b.recorded.is_none() => Disagreement::Added(Mirror::B),
+        (true, false) => Disagreement::Damaged(Mirror::B),
+        (false, true) if a.recorded.is_none() => Disagreement::Added(Mirror::A),
+        (false, true) => Disagreement::Damaged(Mirror::A),
+        (false, false) => Disagreement::BothChanged,
+    })
+}
+
+/// Verifies two mirrors against their own states and compares them to each other
+///
+/// Both mirrors are read at the same time, so this takes about as long as
+/// verifying the slower one. Where the copies of a file differ, the states
+/// decide which copy is damaged.
+pub fn cross_verify(a: Archive, b: Archive) -> Result<CrossVerifyReport> {
+    let (a, b) = std::thread::scope(|scope| {
+        let b = scope.spawn(|| read_mirror(b));
+        let a = read_mirror(a);
+        (a, b.join().expect("reading mirror B panicked"))
+    });
+    let ((a, a_copies), (b, mut b_copies)) = (a?, b?);
+    let mut by_path = BTreeMap::new();
+    for (path, a_copy) in a_copies {
+        let b_copy = b_copies.remove(&path).unwrap_or_default();
+        by_path.insert(path, (a_copy, b_copy));
+    }
+    by_path.extend(
+        b_copies
+            .into_iter()
+            .map(|(path, b_copy)| (path, (Copies::default(), b_copy))),
+    );
+    // It's unknown what's in a copy that couldn't be read
+    for path in a.unreadable.iter().chain(&b.unreadable) {
+        by_path.remove(path);
+    }
+    let disagreements = by_path
+        .into_iter()
+        .filter_map(|(path, (a_copy, b_copy))| Some((path, disagreement(a_copy, b_copy)?)))
+        .collect();
+    Ok(CrossVerifyReport {
+        a,
+        b,
+        disagreements,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use crate::{ArchiveOptions, UpdateOptions};
+
+    #[test]
+    fn blames_the_damaged_mirror() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("mirrors");
+        for mirror in ["a", "b"] {
+            let files_dir = dir.join(mirror).join("files");
+            std::fs::create_dir_all(&files_dir)?;
+            std::fs::create_dir_all(dir.join(mirror).join("state"))?;
+            for name in ["same", "damaged", "out-of-sync", "both"] {
+                std::fs::write(files_dir.join(name), name)?;
+            }
+        }
+        let archive = |mirror: &str| {
+            Archive::new(
+                dir.join(mirror).join("state"),
+                dir.join(mirror).join("files"),
+                ArchiveOptions::default(),
+            )
+        };
+        archive("a").update(&UpdateOptions::default())?;
+        archive("b").update(&UpdateOptions::default())?;
+
+        // Only b is updated after the change
+        std::fs::write(dir.join("b/files/out-of-sync"), "changed")?;
+        archive("b").update(&UpdateOptions::default())?;
+
+        std::fs::write(dir.join("a/files/damaged"), "DAMAGED")?;
+        std::fs::write(dir.join("a/files/both"), "changed in a")?;
+        std::fs::write(dir.join("b/files/both"), "changed in b")?;
+        std::fs::write(dir.join("b/files/added"), "added")?;
+
+        let report = cross_verify(archive("a"), archive("b"))?;
+        assert!(!report.is_ok());
+        assert_eq!(report.a.files_checked, 4);
+        assert_eq!(
+            report.a.modified,
+            vec![PathBuf::from("both"), PathBuf::from("damaged")]
+        );
+        assert_eq!(report.b.modified, vec![PathBuf::from("both")]);
+        assert_eq!(report.b.not_in_state, vec![PathBuf::from("added")]);
+        assert_eq!(
+            report.disagreements,
+            vec![
+                (PathBuf::from("added"), Disagreement::Added(Mirror::B)),
+                (PathBuf::from("both"), Disagreement::BothChanged),
+                (PathBuf::from("damaged"), Disagreement::Damaged(Mirror::A)),
+                (PathBuf::from("out-of-sync"), Disagreement::StatesDiffer),
+            ]
+        );
+        Ok(())
+    }
+}
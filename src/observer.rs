@@ -0,0 +1,51 @@
+use crate::archive::MetadataPass;
+use crate::file_check::FileCheckResult;
+use crate::stats::Stats;
+use crate::throughput::Progress;
+
+use std::path::Path;
+use std::time::Duration;
+
+/// Gets notified about the progress of an update or verify
+///
+/// All methods do nothing by default. Files are read by several threads,
+/// so the methods are called from all of them.
+pub trait Observer: Send + Sync {
+    /// Called before a file is read, with its path relative to the directory
+    fn on_file_start(&self, _rel_path: &Path) {}
+
+    /// Called after a file has been read
+    ///
+    /// Files skipped because their modification time and size are
+    /// unchanged aren't read, so they're not reported here.
+    fn on_result(&self, _result: &FileCheckResult) {}
+
+    /// Called after every file read, with how much is left to read
+    fn on_progress(&self, _progress: &Progress) {}
+
+    /// Called by a verify with metadata_first once all files are listed, before any is read
+    fn on_metadata_pass(&self, _pass: &MetadataPass) {}
+
+    /// Called once all files are checked
+    fn on_summary(&self, _stats: &Stats, _duration: Duration) {}
+
+    /// Informational messages, like the number of threads used
+    fn on_message(&self, _message: &str) {}
+
+    /// Asks whether to go ahead with something likely unintended, refused by default
+    ///
+    /// An update asks before marking a large part of the archive as missing.
+    fn confirm(&self, _question: &str) -> bool {
+        false
+    }
+
+    /// Checked regularly, the run is aborted with an error once this returns true
+    fn should_stop(&self) -> bool {
+        false
+    }
+}
+
+/// Ignores everything
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
@@ -0,0 +1,430 @@
+use crate::checksum::sidecar_path;
+use crate::config::Config;
+use crate::file_check::{FileCheckResult, FileCheckResultModified};
+use crate::history::state_paths;
+use crate::state::{
+    named_run_path, new_run_id, read_state, read_state_file, remove_state, rotate_states,
+    write_state, NameFormat,
+};
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{create_dir_all, remove_file, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory in the state directory partial states are written to
+///
+/// Hosts sharing a state directory, each updating its own mount of the
+/// archive, only ever create new files in there, named after the host,
+/// so any number of them can write at the same time. [`merge`] then
+/// takes them into the next state. Nothing else is written by them.
+pub const PARTIAL_DIR: &str = "partial";
+
+/// File in the partial directory that exists while a merge is running
+const MERGE_LOCK: &str = "merge.lock";
+
+/// Held by a merge from reading the state until rotation is done, the lock file is removed on drop
+struct MergeLock(PathBuf);
+
+impl MergeLock {
+    fn take(state_dir: &Path) -> Result<MergeLock> {
+        let partial_dir = state_dir.join(PARTIAL_DIR);
+        let path = partial_dir.join(MERGE_LOCK);
+        let mut f = match File::options().write(true).create_new(true).open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(anyhow::Error::msg(format!(
+                    "Another merge is running, remove {:?} if it isn't",
+                    path
+                )));
+            }
+            Err(e) => return Err(e).with_context(|| format!("Unable to create {:?}", path)),
+        };
+        let lock = MergeLock(path);
+        writeln!(f, "{}", std::process::id())
+            .with_context(|| format!("Unable to write {:?}", lock.0))?;
+        Ok(lock)
+    }
+}
+
+impl Drop for MergeLock {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+/// Checks a host name can be recorded in states, it's a single word
+pub fn check_host(host: &str) -> Result<()> {
+    if host.is_empty()
+        || !host
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(anyhow::Error::msg(format!(
+            "`{}` can't name a host, host names only have letters, digits, `-`, `_` and `.`",
+            host
+        )));
+    }
+    Ok(())
+}
+
+/// Path of a new partial state of host, the partial directory is created if needed
+///
+/// Named after the time in UTC and the host, so hosts writing at the same
+/// time don't pick the same name.
+pub fn partial_state_path(state_dir: &Path, host: &str) -> Result<PathBuf> {
+    let partial_dir = state_dir.join(PARTIAL_DIR);
+    create_dir_all(&partial_dir).with_context(|| format!("Unable to create {:?}", partial_dir))?;
+    // The host takes the place of a label, it's cleaned up the same way
+    let format = "{timestamp}-{label}.state".parse::<NameFormat>().unwrap();
+    named_run_path(&partial_dir, "state", Some(&format), Some(host))
+}
+
+/// Partial states of the state directory written completely, oldest first
+///
+/// The .stats file is the last one written by an update, partial states
+/// without one are still being written and are left for the next merge.
+pub fn partial_states(state_dir: &Path) -> Result<Vec<PathBuf>> {
+    let partial_dir = state_dir.join(PARTIAL_DIR);
+    if !partial_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut paths = state_paths(&partial_dir)?;
+    paths.retain(|path| path.with_extension("stats").is_file());
+    Ok(paths)
+}
+
+/// Changes a host found in the files it covers, compared to the state before the merge
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HostChanges {
+    /// Partial states of the host merged
+    pub states: u64,
+    /// Files in them
+    pub files: u64,
+    pub new: u64,
+    pub modified: u64,
+    pub missing: u64,
+}
+
+/// A file two hosts found differently, both covering its path
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disagreement {
+    pub rel_path: PathBuf,
+    /// Host of the earlier partial state
+    pub earlier: String,
+    /// Host of the later partial state, whose entry was taken
+    pub later: String,
+}
+
+/// What a merge did
+#[derive(Debug, Default)]
+pub struct Merged {
+    /// The state written, None if there were no partial states to merge
+    pub state_path: Option<PathBuf>,
+    /// Partial states merged, removed afterwards
+    pub partial_states: Vec<PathBuf>,
+    pub by_host: BTreeMap<String, HostChanges>,
+    /// Sorted by path
+    pub disagreements: Vec<Disagreement>,
+    /// States removed as set by keep-states in the profile
+    pub removed_states: Vec<PathBuf>,
+}
+
+/// Writes the next state of the state directory with the partial states written since
+///
+/// Partial states are taken in the order they were written. All files
+/// below the paths a partial state covers are replaced by the ones it
+/// has, files it doesn't have are missing. Paths outside of them are kept
+/// as they are. If two hosts cover the same path and found something
+/// different there, like when their mounts drifted apart, the later one
+/// is taken and the disagreement reported. Files a host saw are recorded
+/// with its name. Partial states are removed once the state is written.
+///
+/// Only one merge of a state directory runs at a time, another one started
+/// meanwhile fails right away.
+pub fn merge(state_dir: &Path) -> Result<Merged> {
+    if !state_dir.join(PARTIAL_DIR).is_dir() {
+        return Ok(Merged::default());
+    }
+    let _lock = MergeLock::take(state_dir)?;
+    let paths = partial_states(state_dir)?;
+    if paths.is_empty() {
+        return Ok(Merged::default());
+    }
+    let (mut header, before) = read_state(state_dir)?;
+    let mut files = before.clone();
+    let mut merged = Merged::default();
+    // Host and digest of the last partial state covering a path, None if it didn't have it
+    let mut covered_by = HashMap::<PathBuf, (String, Option<[u8; 32]>)>::new();
+    for (i, path) in paths.iter().enumerate() {
+        let partial = read_state_file(path)
+            .with_context(|| format!("Unable to read partial state {:?}", path))?;
+        let host = partial
+            .header
+            .host
+            .clone()
+            .with_context(|| format!("{:?} doesn't name the host that wrote it", path))?;
+        if i == 0 && before.is_empty() {
+            header.hash_algorithm = partial.header.hash_algorithm;
+        }
+        if partial.header.hash_algorithm != header.hash_algorithm {
+            return Err(anyhow::Error::msg(format!(
+                "{:?} uses {}, but the state uses {}",
+                path, partial.header.hash_algorithm, header.hash_algorithm
+            )));
+        }
+        let covers = &partial.header.covers;
+        let covered =
+            |rel_path: &Path| covers.is_empty() || covers.iter().any(|c| rel_path.starts_with(c));
+        let changes = merged.by_host.entry(host.clone()).or_default();
+        changes.states += 1;
+        changes.files += partial.files.len() as u64;
+        header.filesystems.extend(partial.header.filesystems);
+
+        let found = partial
+            .files
+            .into_iter()
+            .map(|fi| (fi.rel_path.clone(), fi))
+            .collect::<HashMap<_, _>>();
+        let gone = files
+            .keys()
+            .filter(|rel_path| covered(rel_path) && !found.contains_key(*rel_path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for rel_path in gone {
+            files.remove(&rel_path);
+            let earlier = covered_by.insert(rel_path.clone(), (host.clone(), None));
+            if let Some((earlier, Some(_))) = earlier {
+                if earlier != host {
+                    merged.disagreements.push(Disagreement {
+                        rel_path,
+                        earlier,
+                        later: host.clone(),
+                    });
+                }
+            }
+        }
+        for (rel_path, fi) in found {
+            let earlier = covered_by.insert(rel_path.clone(), (host.clone(), Some(fi.digest)));
+            if let Some((earlier, digest)) = earlier {
+                if earlier != host && digest != Some(fi.digest) {
+                    merged.disagreements.push(Disagreement {
+                        rel_path: rel_path.clone(),
+                        earlier,
+                        later: host.clone(),
+                    });
+                }
+            }
+            files.insert(rel_path, fi);
+        }
+    }
+    merged
+        .disagreements
+        .sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+
+    let all_paths = before.keys().chain(files.keys()).collect::<BTreeSet<_>>();
+    let mut results = Vec::with_capacity(all_paths.len());
+    for rel_path in all_paths {
+        let result = match (before.get(rel_path), files.get(rel_path)) {
+            (Some(previous), Some(current)) if previous.digest == current.digest => {
+                FileCheckResult::Unmodifed(current.clone())
+            }
+            (Some(previous), Some(current)) => {
+                FileCheckResult::Modified(Box::new(FileCheckResultModified {
+                    previous: previous.clone(),
+                    current: current.clone(),
+                }))
+            }
+            (None, Some(current)) => FileCheckResult::New(current.clone()),
+            (Some(previous), None) => FileCheckResult::Missing(previous.clone()),
+            (None, None) => unreachable!(),
+        };
+        if let Some((host, _)) = covered_by.get(rel_path) {
+            let changes = merged.by_host.get_mut(host).unwrap();
+            match &result {
+                FileCheckResult::New(_) => changes.new += 1,
+                FileCheckResult::Modified(_) => changes.modified += 1,
+                FileCheckResult::Missing(_) => changes.missing += 1,
+                FileCheckResult::Unmodifed(_) => {}
+            }
+        }
+        results.push(result);
+    }
+
+    header.run_id = Some(new_run_id());
+    header.label = None;
+    merged.state_path = Some(write_state(
+        state_dir,
+        &header,
+        results.into_iter().map(Ok),
+    )?);
+    for path in &paths {
+        remove_partial_state(path)?;
+    }
+    merged.partial_states = paths;
+    if let Some(keep) = Config::read(state_dir)?.and_then(|config| config.keep_states) {
+        merged.removed_states = rotate_states(state_dir, keep)?;
+    }
+    Ok(merged)
+}
+
+/// Removes a partial state along with all files of its run, they're in the merged state now
+fn remove_partial_state(path: &Path) -> Result<()> {
+    remove_state(path, false)?;
+    for extension in ["modified", "missing", "errors"] {
+        let path = path.with_extension(extension);
+        for path in [sidecar_path(&path), path] {
+            if path.exists() {
+                remove_file(&path).with_context(|| format!("Unable to remove {:?}", path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_info::FileInfo;
+    use crate::state::{write_state_at, StateHeader};
+    use crate::test_dir::TestDir;
+
+    fn write_partial(state_dir: &Path, host: &str, covers: &[&str], files: &[FileInfo]) {
+        let header = StateHeader {
+            host: Some(host.to_owned()),
+            covers: covers.iter().map(PathBuf::from).collect(),
+            ..Default::default()
+        };
+        let path = partial_state_path(state_dir, host).unwrap();
+        write_state_at(
+            &path,
+            &header,
+            files.iter().cloned().map(FileCheckResult::New).map(Ok),
+        )
+        .unwrap();
+        std::fs::write(path.with_extension("stats"), "{}").unwrap();
+    }
+
+    #[test]
+    fn partial_states_merged_by_host() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("partial");
+        assert!(merge(&dir)?.state_path.is_none());
+        assert!(check_host("nas 2").is_err());
+
+        write_state(
+            &dir,
+            &StateHeader::default(),
+            [
+                FileInfo::new("other/x", [1; 32], 1),
+                FileInfo::new("photos/a", [2; 32], 1),
+                FileInfo::new("photos/b", [3; 32], 1),
+                FileInfo::new("scans/c", [4; 32], 1),
+            ]
+            .into_iter()
+            .map(FileCheckResult::New)
+            .map(Ok),
+        )?;
+        let seen = |path, digest, host: &str| FileInfo {
+            host: Some(host.to_owned()),
+            ..FileInfo::new(path, [digest; 32], 1)
+        };
+        write_partial(
+            &dir,
+            "nas",
+            &["photos"],
+            &[seen("photos/a", 2, "nas"), seen("photos/d", 5, "nas")],
+        );
+        write_partial(
+            &dir,
+            "pc",
+            &["scans", "photos/a"],
+            &[seen("photos/a", 6, "pc"), seen("scans/c", 4, "pc")],
+        );
+        // Still being written, it has no .stats file yet
+        let unfinished = partial_state_path(&dir, "laptop")?;
+        write_state_at(&unfinished, &StateHeader::default(), std::iter::empty())?;
+
+        let merged = merge(&dir)?;
+        assert_eq!(merged.partial_states.len(), 2);
+        assert_eq!(
+            merged.by_host["nas"],
+            HostChanges {
+                states: 1,
+                files: 2,
+                new: 1,
+                modified: 0,
+                missing: 1,
+            }
+        );
+        assert_eq!(merged.by_host["pc"].modified, 1);
+        assert_eq!(
+            merged.disagreements,
+            vec![Disagreement {
+                rel_path: PathBuf::from("photos/a"),
+                earlier: "nas".to_owned(),
+                later: "pc".to_owned(),
+            }]
+        );
+        let (_, files) = read_state(&dir)?;
+        let mut files = files.into_values().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        assert_eq!(
+            files,
+            vec![
+                FileInfo::new("other/x", [1; 32], 1),
+                seen("photos/a", 6, "pc"),
+                seen("photos/d", 5, "nas"),
+                seen("scans/c", 4, "pc"),
+            ]
+        );
+        assert!(partial_states(&dir)?.is_empty());
+        assert!(unfinished.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_merges() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("concurrent-merges");
+        for host in ["nas", "pc"] {
+            write_partial(&dir, host, &[host], &[FileInfo::new(host, [1; 32], 1)]);
+        }
+
+        let start = std::sync::Barrier::new(2);
+        let results = std::thread::scope(|s| {
+            let threads = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        start.wait();
+                        merge(&dir).map(|merged| merged.state_path)
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        // The other one either found the lock or nothing left to merge
+        let written = results
+            .iter()
+            .filter(|result| matches!(result, Ok(Some(_))))
+            .count();
+        assert_eq!(written, 1);
+        for result in &results {
+            if let Err(e) = result {
+                assert!(e.to_string().starts_with("Another merge is running"));
+            }
+        }
+        assert_eq!(state_paths(&dir)?.len(), 1);
+        assert_eq!(read_state(&dir)?.1.len(), 2);
+        assert!(!dir.join(PARTIAL_DIR).join(MERGE_LOCK).exists());
+
+        // Left by a merge that didn't get to finish
+        std::fs::write(dir.join(PARTIAL_DIR).join(MERGE_LOCK), "1\n")?;
+        write_partial(&dir, "nas", &[], &[]);
+        assert!(merge(&dir).is_err());
+        assert_eq!(partial_states(&dir)?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Why a run reads a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadReason {
+    /// Not in the state
+    New,
+    /// Its mtime or size differ from the state
+    MetadataChanged,
+    /// Unchanged, but all files are read, like by verify or with read_all_files
+    Reverify,
+}
+
+impl std::fmt::Display for ReadReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReadReason::New => "new",
+            ReadReason::MetadataChanged => "metadata changed",
+            ReadReason::Reverify => "reverify",
+        })
+    }
+}
+
+/// A file a run is going to read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedRead {
+    pub rel_path: PathBuf,
+    pub len: u64,
+    pub reason: ReadReason,
+}
+
+/// A plan written by [`write_plan`], with the totals of the files in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub path: PathBuf,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Writes the files to read in the order they're read, one per line
+///
+/// Lines look like `<path> # <reason>, <len> bytes`, after a comment with
+/// the totals.
+pub fn write_plan(path: &Path, reads: &[PlannedRead]) -> Result<Plan> {
+    let written = Plan {
+        path: path.to_owned(),
+        files: reads.len() as u64,
+        bytes: reads.iter().map(|read| read.len).sum(),
+    };
+    let mut f = BufWriter::new(
+        File::create(path).with_context(|| format!("Unable to create plan {:?}", path))?,
+    );
+    writeln!(
+        f,
+        "# {} files, {} bytes to read",
+        written.files, written.bytes
+    )?;
+    for read in reads {
+        writeln!(
+            f,
+            "{} # {}, {} bytes",
+            read.rel_path.to_string_lossy(),
+            read.reason,
+            read.len
+        )?;
+    }
+    f.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn plan_lines() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("plan");
+        let path = dir.join("plan");
+        let written = write_plan(
+            &path,
+            &[
+                PlannedRead {
+                    rel_path: PathBuf::from("b"),
+                    len: 10,
+                    reason: ReadReason::New,
+                },
+                PlannedRead {
+                    rel_path: PathBuf::from("a"),
+                    len: 5,
+                    reason: ReadReason::MetadataChanged,
+                },
+            ],
+        )?;
+        assert_eq!((written.files, written.bytes), (2, 15));
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "# 2 files, 15 bytes to read\nb # new, 10 bytes\na # metadata changed, 5 bytes\n"
+        );
+        Ok(())
+    }
+}
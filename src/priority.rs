@@ -0,0 +1,49 @@
+use std::io;
+
+/// Lowers the I/O and CPU priority of the calling thread for --nice
+///
+/// Reads are only done when the disks are otherwise idle and the threads
+/// yield to everything else running on the machine.
+#[cfg(all(feature = "nice", target_os = "linux"))]
+pub fn lower_current_thread() -> io::Result<()> {
+    // Not exposed by libc, see linux/ioprio.h
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+    // A pid of 0 refers to the calling thread for both of these
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, 19) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lowers the I/O and CPU priority of the calling thread for --nice
+///
+/// Background threads get both throttled I/O and a low scheduling priority.
+#[cfg(all(feature = "nice", target_os = "macos"))]
+pub fn lower_current_thread() -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_DARWIN_THREAD, 0, libc::PRIO_DARWIN_BG) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "nice", any(target_os = "linux", target_os = "macos"))))]
+pub fn lower_current_thread() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "not supported on this platform or disabled at compile time",
+    ))
+}
@@ -0,0 +1,346 @@
+use crate::file_check::FileCheckResultModified;
+use crate::file_info::FileInfo;
+use crate::hash::HashAlgorithm;
+use crate::state::{run_path, StateHeader};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Name of the log of reviewed modifications in the state directory
+pub const REVIEW_LOG: &str = "review.log";
+
+/// Extension of the files listing what verify runs found modified
+pub const CHANGES_EXTENSION: &str = "changes";
+
+/// What a review made of a modified file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// The file was changed on purpose, updates record it like any other change
+    Accepted,
+    /// The contents are damaged, updates keep the digest of the state until it's restored
+    Corrupted,
+}
+
+impl FromStr for Decision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accepted" => Ok(Decision::Accepted),
+            "corrupted" => Ok(Decision::Corrupted),
+            _ => Err(format!("expected `accepted` or `corrupted`, got `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Decision::Accepted => "accepted",
+            Decision::Corrupted => "corrupted",
+        })
+    }
+}
+
+/// Decision about one modification of a file
+///
+/// Stored as one line per file in the review log, which is only ever
+/// appended to: `<hexdigest> <path> # was <hexdigest> decision <decision> run <run>`.
+/// It only applies as long as the file changes from the contents it was
+/// to the ones it is, a file modified again needs another review.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub rel_path: PathBuf,
+    /// Digest of the contents found
+    pub digest: [u8; 32],
+    /// Digest of the contents in the state
+    pub was: [u8; 32],
+    pub decision: Decision,
+    /// ID of the verify run that found the modification
+    pub run: String,
+}
+
+fn parse_digest(hexdigest: &str) -> Option<[u8; 32]> {
+    let mut digest = [0_u8; 32];
+    hex::decode_to_slice(hexdigest, &mut digest).ok()?;
+    Some(digest)
+}
+
+impl Verdict {
+    /// Decision about a modification found by a verify run
+    pub fn of(change: &FileCheckResultModified, decision: Decision, run: &str) -> Verdict {
+        Verdict {
+            rel_path: change.current.rel_path.clone(),
+            digest: change.current.digest,
+            was: change.previous.digest,
+            decision,
+            run: run.to_owned(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<Verdict> {
+        let invalid = || format!("invalid line: '{}'", line);
+        let (hexdigest, rest) = line.split_once(' ').with_context(invalid)?;
+        let (path, fields) = rest.rsplit_once(" # ").with_context(invalid)?;
+        let (mut was, mut decision, mut run) = (None, None, None);
+        let mut fields = fields.split(' ');
+        while let Some(key) = fields.next() {
+            let value = fields.next().with_context(invalid)?;
+            match key {
+                "was" => was = parse_digest(value),
+                "decision" => decision = value.parse().ok(),
+                "run" => run = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        Ok(Verdict {
+            rel_path: PathBuf::from(path),
+            digest: parse_digest(hexdigest).with_context(invalid)?,
+            was: was.with_context(invalid)?,
+            decision: decision.with_context(invalid)?,
+            run: run.with_context(invalid)?,
+        })
+    }
+
+    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            to,
+            "{} {} # was {} decision {} run {}",
+            hex::encode(self.digest),
+            self.rel_path.to_str().unwrap(),
+            hex::encode(self.was),
+            self.decision,
+            self.run
+        )
+    }
+}
+
+/// Appends verdicts to the review log of a state directory
+pub fn append(state_dir: &Path, verdicts: &[Verdict]) -> Result<()> {
+    let path = state_dir.join(REVIEW_LOG);
+    let mut f = BufWriter::new(
+        File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Unable to open {:?}", path))?,
+    );
+    for verdict in verdicts {
+        verdict.write(&mut f)?;
+    }
+    f.flush()
+        .with_context(|| format!("Unable to write to {:?}", path))
+}
+
+/// The latest verdict about every file reviewed
+#[derive(Debug, Clone, Default)]
+pub struct Verdicts {
+    by_path: HashMap<PathBuf, Verdict>,
+}
+
+impl Verdicts {
+    /// Reads the review log of a state directory, none if there's no such file
+    pub fn read(state_dir: &Path) -> Result<Verdicts> {
+        let path = state_dir.join(REVIEW_LOG);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Verdicts::default()),
+            Err(err) => return Err(err).with_context(|| format!("Unable to read {:?}", path)),
+        };
+        let mut by_path = HashMap::new();
+        for line in contents.lines() {
+            let verdict =
+                Verdict::parse(line).with_context(|| format!("Unable to parse {:?}", path))?;
+            // Later reviews overrule earlier ones
+            by_path.insert(verdict.rel_path.clone(), verdict);
+        }
+        Ok(Verdicts { by_path })
+    }
+
+    /// What was decided about a modification, None if it wasn't reviewed
+    pub fn decision(&self, change: &FileCheckResultModified) -> Option<Decision> {
+        let verdict = self.by_path.get(&change.current.rel_path)?;
+        (verdict.was == change.previous.digest && verdict.digest == change.current.digest)
+            .then_some(verdict.decision)
+    }
+}
+
+/// Modified files found by a verify run, see [`write_changes`]
+#[derive(Debug, Clone)]
+pub struct FoundChanges {
+    pub path: PathBuf,
+    /// Algorithm of the digests, the one of the state verified against
+    pub hash_algorithm: HashAlgorithm,
+    /// ID of the verify run
+    pub run_id: String,
+    /// Name of the state verified against
+    pub verified: String,
+    /// The files as they were found, with the digest of their contents then
+    pub files: Vec<FileInfo>,
+}
+
+/// Writes what the files modified in a verify run are now, to a file named after the time and run
+///
+/// Lines are those of a state, after a header naming the run and the state
+/// verified against, so the changes can be recorded later without reading
+/// the files again. The run ID keeps runs finishing in the same second apart.
+pub fn write_changes(
+    state_dir: &Path,
+    hash_algorithm: HashAlgorithm,
+    run_id: &str,
+    verified: &Path,
+    changes: &[FileCheckResultModified],
+) -> Result<PathBuf> {
+    let path = run_path(state_dir, &format!("{}.{}", run_id, CHANGES_EXTENSION))?;
+    let mut f = BufWriter::new(
+        File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Unable to create {:?}", path))?,
+    );
+    let header = StateHeader {
+        hash_algorithm,
+        run_id: Some(run_id.to_owned()),
+        ..Default::default()
+    };
+    header.write(&mut f)?;
+    writeln!(
+        f,
+        "# verified: {}",
+        verified.file_name().unwrap_or_default().to_string_lossy()
+    )?;
+    for change in changes {
+        change.current.write(&mut f)?;
+    }
+    f.flush()
+        .with_context(|| format!("Unable to write to {:?}", path))?;
+    Ok(path)
+}
+
+fn read_changes_at(path: &Path) -> Result<FoundChanges> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Unable to read {:?}", path))?;
+    let mut header = StateHeader::default();
+    let mut verified = None;
+    let mut files = Vec::new();
+    for line in contents.lines() {
+        if let Some(name) = line.strip_prefix("# verified: ") {
+            verified = Some(name.to_owned());
+        } else if !header.parse_line(line)? {
+            files.push(FileInfo::parse(line)?);
+        }
+    }
+    let missing = |what| format!("{:?} doesn't name the {}", path, what);
+    Ok(FoundChanges {
+        path: path.to_path_buf(),
+        hash_algorithm: header.hash_algorithm,
+        run_id: header.run_id.with_context(|| missing("run"))?,
+        verified: verified.with_context(|| missing("state verified against"))?,
+        files,
+    })
+}
+
+/// Reads the changes a verify run found, those of the latest run that found any if run is None
+pub fn read_changes(state_dir: &Path, run: Option<&str>) -> Result<FoundChanges> {
+    let mut paths = std::fs::read_dir(state_dir)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .with_context(|| format!("Unable to list {:?}", state_dir))?
+        .into_iter()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == CHANGES_EXTENSION))
+        .collect::<Vec<_>>();
+    paths.sort();
+    // Newest first, the run asked for is usually a recent one
+    for path in paths.iter().rev() {
+        let changes = read_changes_at(path)?;
+        if run.is_none_or(|run| run == changes.run_id) {
+            return Ok(changes);
+        }
+    }
+    Err(anyhow::Error::msg(match run {
+        Some(run) => format!("no modified files of run {} found in {:?}", run, state_dir),
+        None => format!("no verify run found modified files in {:?}", state_dir),
+    }))
+}
+
+/// Regex matching paths relative to the archive by a pattern like `catalogs/**`
+///
+/// `*` matches anything but `/`, `**` anything including it and `?` a
+/// single character. A pattern matching a directory matches everything
+/// below it, so `catalogs` is the same as `catalogs/**`.
+pub fn path_pattern(pattern: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.trim_end_matches('/').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push_str("(/.*)?$");
+    Regex::new(&regex).with_context(|| format!("invalid pattern '{}'", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_info::FileInfo;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn latest_matching_verdict_applies() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("review");
+        let file = |digest| FileInfo::new("scans/a # b.tif", [digest; 32], 5);
+        let change = FileCheckResultModified {
+            previous: file(1),
+            current: file(2),
+        };
+        let verdict = Verdict::of(&change, Decision::Corrupted, "6c3e785512e6a892");
+        let mut line = Vec::new();
+        verdict.write(&mut line)?;
+        assert_eq!(
+            Verdict::parse(String::from_utf8(line)?.trim_end())?,
+            verdict
+        );
+        assert!(Verdict::parse("07 a # was 07 decision accepted").is_err());
+
+        assert_eq!(Verdicts::read(&dir)?.decision(&change), None);
+        append(&dir, &[verdict])?;
+        append(&dir, &[Verdict::of(&change, Decision::Accepted, "run-2")])?;
+        let verdicts = Verdicts::read(&dir)?;
+        assert_eq!(verdicts.decision(&change), Some(Decision::Accepted));
+        // Modified again since the review
+        let again = FileCheckResultModified {
+            previous: file(1),
+            current: file(3),
+        };
+        assert_eq!(verdicts.decision(&again), None);
+        Ok(())
+    }
+
+    #[test]
+    fn path_patterns() -> Result<(), Box<dyn std::error::Error>> {
+        let matches = |pattern, path| path_pattern(pattern).map(|regex| regex.is_match(path));
+        assert!(matches("catalogs/**", "catalogs/2024/index.db")?);
+        assert!(matches("catalogs", "catalogs/index.db")?);
+        assert!(!matches("catalogs", "catalogs.old/index.db")?);
+        assert!(matches("*.db", "index.db")?);
+        assert!(!matches("*.db", "catalogs/index.db")?);
+        assert!(matches("**/*.db", "catalogs/index.db")?);
+        assert!(matches("scan-?.tif", "scan-1.tif")?);
+        assert!(!matches("scan-?.tif", "scan-10.tif")?);
+        Ok(())
+    }
+}
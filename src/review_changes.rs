@@ -0,0 +1,145 @@
+use rusty_archive::file_check::FileCheckResultModified;
+use rusty_archive::review::{self, Decision, Verdict, REVIEW_LOG};
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// Decides about each change, asking once for each directory with several of them
+///
+/// ask gets a question and returns the answer, None if there are no more.
+/// Questions are asked again until the answer is one of the choices.
+/// Skipped files have no decision.
+fn decide<'a>(
+    changes: &'a [FileCheckResultModified],
+    ask: &mut dyn FnMut(&str) -> Option<String>,
+) -> Vec<(&'a FileCheckResultModified, Decision)> {
+    let mut by_directory = BTreeMap::<&Path, Vec<&FileCheckResultModified>>::new();
+    for change in changes {
+        let directory = change.current.rel_path.parent().unwrap_or(Path::new(""));
+        by_directory.entry(directory).or_default().push(change);
+    }
+    let mut ask_for = |question: &str, choices: &str| loop {
+        let answer = ask(question)?.trim().to_ascii_lowercase();
+        match answer.chars().next() {
+            Some(choice) if answer.len() == 1 && choices.contains(choice) => return Some(choice),
+            _ => {}
+        }
+    };
+    let decision = |choice| match choice {
+        'a' => Some(Decision::Accepted),
+        'c' => Some(Decision::Corrupted),
+        _ => None,
+    };
+    let mut decided = Vec::new();
+    for (directory, changes) in by_directory {
+        if changes.len() > 1 {
+            let question = format!(
+                "{} files modified in {}: [a]ccept all, flag all as [c]orrupted, [s]kip all or go through them [o]ne by one?",
+                changes.len(),
+                match directory.as_os_str().is_empty() {
+                    true => "the top directory".into(),
+                    false => directory.to_string_lossy(),
+                }
+            );
+            let Some(choice) = ask_for(&question, "acso") else {
+                return decided;
+            };
+            if choice != 'o' {
+                if let Some(decision) = decision(choice) {
+                    decided.extend(changes.iter().map(|change| (*change, decision)));
+                }
+                continue;
+            }
+        }
+        for change in changes {
+            let question = format!(
+                "{} modified: [a]ccept, flag as [c]orrupted or [s]kip?",
+                change.current.rel_path.to_string_lossy()
+            );
+            let Some(choice) = ask_for(&question, "acs") else {
+                return decided;
+            };
+            decided.extend(decision(choice).map(|d| (change, d)));
+        }
+    }
+    decided
+}
+
+/// Asks what to make of the modified files a verify run found and records the decisions
+pub fn run(state_dir: &Path, run_id: &str, changes: &[FileCheckResultModified]) -> Result<()> {
+    let mut ask = |question: &str| {
+        print!("{} ", question);
+        std::io::stdout().flush().ok();
+        let mut answer = String::new();
+        match std::io::stdin().lock().read_line(&mut answer) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(answer),
+        }
+    };
+    let verdicts = decide(changes, &mut ask)
+        .into_iter()
+        .map(|(change, decision)| Verdict::of(change, decision, run_id))
+        .collect::<Vec<_>>();
+    if verdicts.is_empty() {
+        println!("└ nothing decided, verify again to review the files");
+        return Ok(());
+    }
+    review::append(state_dir, &verdicts)?;
+    let accepted = verdicts
+        .iter()
+        .filter(|v| v.decision == Decision::Accepted)
+        .count();
+    println!(
+        "└ {} changes accepted and {} files flagged as corrupted, recorded in {:?}",
+        accepted,
+        verdicts.len() - accepted,
+        state_dir.join(REVIEW_LOG)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusty_archive::file_info::FileInfo;
+
+    #[test]
+    fn decisions_per_directory_or_file() {
+        let change = |path: &str| {
+            let fi = FileInfo::new(path, [1; 32], 5);
+            FileCheckResultModified {
+                previous: fi.clone(),
+                current: fi,
+            }
+        };
+        let changes = ["catalog", "scans/a", "scans/b", "raw/c", "raw/d"].map(change);
+        let mut answers = ["c", "A", "maybe", "o", "s", "a"].into_iter();
+        let mut questions = Vec::new();
+        let decided = decide(&changes, &mut |question| {
+            questions.push(question.to_owned());
+            answers.next().map(str::to_owned)
+        });
+        let decided = decided
+            .into_iter()
+            .map(|(change, decision)| (change.current.rel_path.to_str().unwrap(), decision))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decided,
+            vec![
+                ("catalog", Decision::Corrupted),
+                ("raw/c", Decision::Accepted),
+                ("raw/d", Decision::Accepted),
+                ("scans/b", Decision::Accepted),
+            ]
+        );
+        assert_eq!(
+            questions[0],
+            "catalog modified: [a]ccept, flag as [c]orrupted or [s]kip?"
+        );
+        assert!(questions[1].starts_with("2 files modified in raw: "));
+        // Asked again after an answer that isn't a choice
+        assert_eq!(questions[2], questions[3]);
+    }
+}
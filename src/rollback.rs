@@ -0,0 +1,91 @@
+use rusty_archive::state::{labeled_state, state_name_time, state_order, STATE_NAME_FORMAT};
+
+use anyhow::{Context, Result};
+use std::fs::{create_dir_all, read_dir, rename};
+use std::path::Path;
+use time::OffsetDateTime;
+
+/// Directory newer states are moved to, hidden so it can't clash with named archives
+const ROLLED_BACK_DIR: &str = ".rolled-back";
+
+/// Makes the state named timestamp the latest one again, timestamp can be a label as well
+///
+/// All files of newer runs are moved to a subdirectory named after the
+/// time of the rollback, so nothing is lost and they can be moved back.
+pub fn run(state_dir: &Path, timestamp: &str, dry_run: bool) -> Result<()> {
+    let timestamp: &str = &match state_name_time(timestamp) {
+        Some(_) => timestamp.to_owned(),
+        None => match labeled_state(state_dir, timestamp)? {
+            Some(path) => path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            None => {
+                return Err(anyhow::Error::msg(format!(
+                    "invalid timestamp '{}', expected the name or label of a state like 20230315T132501Z",
+                    timestamp
+                )))
+            }
+        },
+    };
+    let order = state_order(Path::new(timestamp));
+
+    let mut states = Vec::new();
+    let mut newer_files = Vec::new();
+    for entry in
+        read_dir(state_dir).with_context(|| format!("Unable to list files in {:?}", state_dir))?
+    {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let stem = match file_name.split_once('.') {
+            Some((stem, _)) if state_name_time(stem).is_some() => stem,
+            _ => continue,
+        };
+        if file_name.ends_with(".state") {
+            states.push(stem.to_owned());
+        }
+        if state_order(Path::new(stem)) > order {
+            newer_files.push(path.clone());
+        }
+    }
+    states.sort_by_cached_key(|state| state_order(Path::new(state)));
+    if !states.iter().any(|state| state == timestamp) {
+        return Err(anyhow::Error::msg(format!(
+            "no state named '{}' found in {:?}, available are: {}",
+            timestamp,
+            state_dir,
+            states.join(", ")
+        )));
+    }
+    if newer_files.is_empty() {
+        println!("'{}' is already the latest state", timestamp);
+        return Ok(());
+    }
+
+    let format = time::format_description::parse(STATE_NAME_FORMAT).unwrap();
+    let now = OffsetDateTime::now_utc();
+    let target = state_dir
+        .join(ROLLED_BACK_DIR)
+        .join(now.format(&format).unwrap());
+    if !dry_run {
+        create_dir_all(&target).with_context(|| format!("Unable to create {:?}", target))?;
+    }
+    newer_files.sort();
+    for path in &newer_files {
+        let to = target.join(path.file_name().unwrap());
+        println!(
+            "{} {:?} to {:?}",
+            if dry_run { "would move" } else { "moving" },
+            path,
+            to
+        );
+        if !dry_run {
+            rename(path, &to).with_context(|| format!("Unable to move {:?}", path))?;
+        }
+    }
+    println!(
+        "{} {} file(s) of newer runs, '{}' {} the latest state",
+        if dry_run { "would move" } else { "moved" },
+        newer_files.len(),
+        timestamp,
+        if dry_run { "would be" } else { "is now" }
+    );
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// A further directory of an archive, its files are recorded below prefix
+///
+/// Like a mount point: files of the main directory below prefix are
+/// hidden by the ones of the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Root {
+    pub prefix: PathBuf,
+    pub directory: PathBuf,
+}
+
+impl FromStr for Root {
+    type Err = String;
+
+    /// Parses `PREFIX=DIRECTORY`, the prefix has to be a relative path without `..`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, directory) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=DIRECTORY, got `{}`", s))?;
+        let prefix = Path::new(prefix);
+        if prefix.as_os_str().is_empty()
+            || prefix
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "expected a relative path like scans/2024 as the prefix, got `{}`",
+                prefix.display()
+            ));
+        }
+        if directory.is_empty() {
+            return Err(format!("expected a directory after the `=`, got `{}`", s));
+        }
+        Ok(Root {
+            // Rebuilt from its components, so it compares equal to paths of the state
+            prefix: prefix.components().collect(),
+            directory: PathBuf::from(directory),
+        })
+    }
+}
+
+impl Root {
+    /// Path of the state of a file below directory
+    pub fn rel_path(&self, path: &Path) -> PathBuf {
+        let below = path.strip_prefix(&self.directory).unwrap();
+        match below.as_os_str().is_empty() {
+            true => self.prefix.clone(),
+            false => self.prefix.join(below),
+        }
+    }
+}
+
+impl std::fmt::Display for Root {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.prefix.display(), self.directory.display())
+    }
+}
+
+/// The directory of an archive along with the roots mounted into it
+///
+/// Paths in the state are relative to the directory, or to the directory
+/// of the root whose prefix they start with.
+#[derive(Clone, Debug)]
+pub struct Roots {
+    /// The directory itself first with an empty prefix, then the roots with
+    /// the longest prefixes first, so the first match is the innermost
+    roots: Vec<Root>,
+}
+
+impl Roots {
+    pub fn new(directory: &Path, roots: &[Root]) -> Roots {
+        let mut mounted = roots.to_vec();
+        mounted.sort_by_key(|root| std::cmp::Reverse(root.prefix.components().count()));
+        let mut all = vec![Root {
+            prefix: PathBuf::new(),
+            directory: directory.to_path_buf(),
+        }];
+        all.extend(mounted);
+        Roots { roots: all }
+    }
+
+    /// The root a path of the state is in
+    fn root_of(&self, rel_path: &Path) -> &Root {
+        self.roots[1..]
+            .iter()
+            .find(|root| rel_path.starts_with(&root.prefix))
+            .unwrap_or(&self.roots[0])
+    }
+
+    /// Where the file at a path of the state is stored
+    pub fn full_path(&self, rel_path: &Path) -> PathBuf {
+        let root = self.root_of(rel_path);
+        let below = rel_path.strip_prefix(&root.prefix).unwrap();
+        match below.as_os_str().is_empty() {
+            true => root.directory.clone(),
+            false => root.directory.join(below),
+        }
+    }
+
+    /// Directories to list to find all files below paths, with the path of the state each is at
+    ///
+    /// All files are listed if paths is empty. Files found there have to be
+    /// checked with [`Roots::owns`], a root can hide files of another one.
+    pub fn walk_starts(&self, paths: &[PathBuf]) -> Vec<Root> {
+        if paths.is_empty() {
+            return self.roots.clone();
+        }
+        let mut starts = Vec::new();
+        for path in paths {
+            starts.push(Root {
+                prefix: path.clone(),
+                directory: self.full_path(path),
+            });
+            // Roots mounted below the path are listed completely
+            for root in &self.roots[1..] {
+                if root.prefix.starts_with(path) && root.prefix != *path {
+                    starts.push(root.clone());
+                }
+            }
+        }
+        starts.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+        starts.dedup();
+        starts
+    }
+
+    /// Whether a file found when listing from start belongs to the root listed
+    pub fn owns(&self, start: &Root, rel_path: &Path) -> bool {
+        self.root_of(rel_path).prefix == self.root_of(&start.prefix).prefix
+    }
+}
+
+/// Where a directory recorded under one path is found now, like a clone mounted elsewhere
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixMap {
+    pub old: PathBuf,
+    pub new: PathBuf,
+}
+
+impl FromStr for PrefixMap {
+    type Err = String;
+
+    /// Parses `OLD=NEW`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((old, new)) if !old.is_empty() && !new.is_empty() => Ok(PrefixMap {
+                old: PathBuf::from(old),
+                new: PathBuf::from(new),
+            }),
+            _ => Err(format!("expected OLD=NEW, got `{}`", s)),
+        }
+    }
+}
+
+/// Path with the longest matching old prefix replaced by its new one
+///
+/// Only whole components match, paths no old prefix matches are returned as they are.
+pub fn map_prefix(maps: &[PrefixMap], path: &Path) -> PathBuf {
+    let Some(map) = maps
+        .iter()
+        .filter(|map| path.starts_with(&map.old))
+        .max_by_key(|map| map.old.components().count())
+    else {
+        return path.to_path_buf();
+    };
+    let below = path.strip_prefix(&map.old).unwrap();
+    match below.as_os_str().is_empty() {
+        true => map.new.clone(),
+        false => map.new.join(below),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roots_mounted_into_directory() -> Result<(), String> {
+        let roots = Roots::new(
+            Path::new("/data/photos"),
+            &["scans=/data/scans".parse()?, "scans/old=/mnt/old".parse()?],
+        );
+        assert_eq!(
+            roots.full_path(Path::new("2024/a.jpg")),
+            PathBuf::from("/data/photos/2024/a.jpg")
+        );
+        assert_eq!(
+            roots.full_path(Path::new("scans/b.png")),
+            PathBuf::from("/data/scans/b.png")
+        );
+        assert_eq!(
+            roots.full_path(Path::new("scans/old/c.png")),
+            PathBuf::from("/mnt/old/c.png")
+        );
+        assert_eq!(
+            roots.full_path(Path::new("scansx")),
+            PathBuf::from("/data/photos/scansx")
+        );
+
+        let starts = roots.walk_starts(&[PathBuf::from("2024"), PathBuf::from("scans")]);
+        assert_eq!(
+            starts,
+            vec![
+                "2024=/data/photos/2024".parse()?,
+                "scans=/data/scans".parse()?,
+                "scans/old=/mnt/old".parse()?,
+            ]
+        );
+        assert!(roots.owns(&starts[0], Path::new("2024/a.jpg")));
+        assert!(roots.owns(&starts[1], Path::new("scans/b.png")));
+        assert!(!roots.owns(&starts[1], Path::new("scans/old/c.png")));
+        assert!(!roots.owns(&roots.walk_starts(&[])[0], Path::new("scans/b.png")));
+        assert_eq!(
+            starts[1].rel_path(Path::new("/data/scans/b.png")),
+            PathBuf::from("scans/b.png")
+        );
+
+        assert!("../up=/data".parse::<Root>().is_err());
+        assert!("/abs=/data".parse::<Root>().is_err());
+        assert!("scans".parse::<Root>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn longest_prefix_mapped() -> Result<(), String> {
+        let maps = [
+            "/mnt/archive=/media/usb-clone".parse()?,
+            "/mnt/archive/scans=/media/scans".parse()?,
+        ];
+        assert_eq!(
+            map_prefix(&maps, Path::new("/mnt/archive")),
+            PathBuf::from("/media/usb-clone")
+        );
+        assert_eq!(
+            map_prefix(&maps, Path::new("/mnt/archive/photos")),
+            PathBuf::from("/media/usb-clone/photos")
+        );
+        assert_eq!(
+            map_prefix(&maps, Path::new("/mnt/archive/scans/2024")),
+            PathBuf::from("/media/scans/2024")
+        );
+        assert_eq!(
+            map_prefix(&maps, Path::new("/mnt/archive2")),
+            PathBuf::from("/mnt/archive2")
+        );
+        assert!("/mnt/archive".parse::<PrefixMap>().is_err());
+        Ok(())
+    }
+}
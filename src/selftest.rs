@@ -0,0 +1,152 @@
+use rusty_archive::{Archive, ArchiveOptions, UpdateOptions, VerifyOptions};
+
+use anyhow::{Context, Result};
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Files created before the first update, named after what happens to them
+const FILES: [&str; 5] = ["unchanged", "modified", "corrupted", "moved", "deleted"];
+
+/// Compares what was reported to what was expected, prints the outcome
+fn check<T: PartialEq + Debug>(what: &str, expected: T, got: T) -> bool {
+    if expected == got {
+        println!("ok: {}", what);
+        true
+    } else {
+        println!("FAILED: {}, expected {:?}, got {:?}", what, expected, got);
+        false
+    }
+}
+
+/// Sorted copy of paths, so the order they're reported in doesn't matter
+fn sorted(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths = paths.to_vec();
+    paths.sort();
+    paths
+}
+
+/// Sorted paths of the given file names
+fn paths(names: &[&str]) -> Vec<PathBuf> {
+    sorted(&names.iter().map(PathBuf::from).collect::<Vec<_>>())
+}
+
+/// Changes the files like the names in FILES say
+///
+/// The corrupted file gets different contents of the same size and its
+/// mtime is restored, like bit rot would leave it.
+fn mutate(files_dir: &Path) -> Result<()> {
+    fs::write(files_dir.join("modified"), "modified contents, longer")?;
+    let corrupted = files_dir.join("corrupted");
+    let mtime = fs::metadata(&corrupted)?.modified()?;
+    fs::write(&corrupted, "contents of CORRUPTED")?;
+    File::options()
+        .write(true)
+        .open(&corrupted)?
+        .set_modified(mtime)?;
+    fs::rename(files_dir.join("moved"), files_dir.join("moved-to"))?;
+    fs::remove_file(files_dir.join("deleted"))?;
+    fs::write(files_dir.join("new"), "contents of new")?;
+    Ok(())
+}
+
+/// Runs update and verify on files with known changes and checks what they report
+fn run_checks(files_dir: &Path, state_dir: &Path, options: ArchiveOptions) -> Result<bool> {
+    fs::create_dir_all(files_dir)?;
+    fs::create_dir_all(state_dir)?;
+    for name in FILES {
+        fs::write(files_dir.join(name), format!("contents of {}", name))?;
+    }
+    let archive = Archive::new(state_dir, files_dir, options);
+    let mut ok = true;
+
+    let report = archive.update(&UpdateOptions::default())?;
+    ok &= check(
+        "first update finds all files",
+        FILES.len() as u64,
+        report.stats.files_new,
+    );
+    ok &= check(
+        "verify of an untouched directory finds nothing",
+        true,
+        archive.verify(&VerifyOptions::default())?.is_ok(),
+    );
+
+    mutate(files_dir)?;
+
+    let report = archive.verify(&VerifyOptions::default())?;
+    ok &= check(
+        "verify finds modified and corrupted files",
+        paths(&["modified", "corrupted"]),
+        sorted(&report.modified),
+    );
+    ok &= check(
+        "verify finds moved and deleted files missing",
+        paths(&["moved", "deleted"]),
+        sorted(&report.missing),
+    );
+    ok &= check(
+        "verify finds new files",
+        paths(&["moved-to", "new"]),
+        sorted(&report.not_in_archive),
+    );
+
+    let report = archive.update(&UpdateOptions {
+        force: true,
+        ..Default::default()
+    })?;
+    ok &= check(
+        "update only reads the modified file, the corrupted one kept its mtime",
+        paths(&["modified"]),
+        sorted(&report.modified),
+    );
+    ok &= check(
+        "update finds the deleted file missing, the moved one elsewhere",
+        paths(&["deleted"]),
+        sorted(&report.missing),
+    );
+
+    let report = archive.verify(&VerifyOptions::default())?;
+    ok &= check(
+        "verify still finds the corrupted file",
+        paths(&["corrupted"]),
+        sorted(&report.modified),
+    );
+    ok &= check(
+        "verify finds nothing else",
+        true,
+        report.missing.is_empty() && report.not_in_archive.is_empty(),
+    );
+    Ok(ok)
+}
+
+/// Checks that update and verify classify changes to files correctly
+///
+/// Files are created below directory, so the filesystem is tested as well,
+/// and removed afterwards.
+pub fn run(directory: Option<&Path>, options: ArchiveOptions) -> Result<()> {
+    let dir = directory
+        .map(Path::to_path_buf)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("rusty-archive-selftest-{}", std::process::id()));
+    println!("testing in {:?}", dir);
+    let result = run_checks(&dir.join("files"), &dir.join("state"), options);
+    fs::remove_dir_all(&dir).with_context(|| format!("Unable to remove {:?}", dir))?;
+    if !result? {
+        return Err(anyhow::Error::msg(
+            "self-test failed, update and verify don't work as expected here",
+        ));
+    }
+    println!("└ all checks passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selftest_passes() -> Result<()> {
+        run(None, ArchiveOptions::default())
+    }
+}
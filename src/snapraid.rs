@@ -0,0 +1,104 @@
+use rusty_archive::state::read_state;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// Size, date, time and path of a file in the output of `snapraid list`
+const LIST_LINE: &str = r"^\s*(\d+)\s+\d{4}/\d{2}/\d{2}\s+\d{2}:\d{2}\s+(.+)$";
+
+/// Parses a line of `snapraid list` into size and path
+///
+/// Lines look like `        1234 2023/03/15 14:25 /mnt/disk1/photos/a.jpg`,
+/// everything else, like the summary at the end, is skipped.
+fn parse_line(re: &Regex, line: &str) -> Option<(u64, PathBuf)> {
+    let captures = re.captures(line)?;
+    Some((captures[1].parse().ok()?, PathBuf::from(&captures[2])))
+}
+
+/// Compares the files recorded by SnapRAID to the latest state
+///
+/// SnapRAID stores seeded, non-cryptographic hashes of blocks in its content
+/// file, which can't be compared to SHA-256 or BLAKE3 digests of whole files.
+/// Paths and sizes are compared instead, both systems should agree on those
+/// after a sync and an update. The archive directory is stripped from the
+/// paths listed by SnapRAID.
+pub fn run(state_dir: &Path, listing: &str, directory: &Path) -> Result<()> {
+    let prefix = directory
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve {:?}", directory))?;
+    let reader: Box<dyn BufRead> = if listing == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(io::BufReader::new(
+            std::fs::File::open(listing)
+                .with_context(|| format!("Unable to open {:?}", listing))?,
+        ))
+    };
+    let re = Regex::new(LIST_LINE).unwrap();
+    let mut snapraid = BTreeMap::new();
+    for line in reader.lines() {
+        let line = line.with_context(|| format!("Unable to read {:?}", listing))?;
+        if let Some((len, path)) = parse_line(&re, &line) {
+            let rel_path = path.strip_prefix(&prefix).unwrap_or(&path).to_path_buf();
+            snapraid.insert(rel_path, len);
+        }
+    }
+    if snapraid.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "no files found in {:?}, expected the output of `snapraid list`",
+            listing
+        )));
+    }
+
+    let (_, files) = read_state(state_dir)?;
+    let mut files = files.into_values().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    let (mut only_state, mut different) = (0, 0);
+    for fi in &files {
+        match snapraid.remove(&fi.rel_path) {
+            None => {
+                println!("NOT IN SNAPRAID: {}", fi.rel_path.to_string_lossy());
+                only_state += 1;
+            }
+            Some(len) if len != fi.len => {
+                println!(
+                    "SIZE DIFFERS: {} ({} bytes in the state, {} in SnapRAID)",
+                    fi.rel_path.to_string_lossy(),
+                    fi.len,
+                    len
+                );
+                different += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    for path in snapraid.keys() {
+        println!("NOT IN STATE: {}", path.to_string_lossy());
+    }
+    println!(
+        "└ {} files compared, {} sizes differ, {} only in the state, {} only in SnapRAID",
+        files.len() - only_state,
+        different,
+        only_state,
+        snapraid.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_list_output() {
+        let re = Regex::new(LIST_LINE).unwrap();
+        assert_eq!(
+            parse_line(&re, "        1234 2023/03/15 14:25 /mnt/disk1/a b.jpg"),
+            Some((1234, PathBuf::from("/mnt/disk1/a b.jpg")))
+        );
+        assert_eq!(parse_line(&re, "      2 files"), None);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::file_check::FileCheckResult;
+use crate::file_info::FileInfo;
+
+use anyhow::{Context, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::{remove_file, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::{Path, PathBuf};
+
+/// Unmodified files found during a run
+///
+/// These make up the bulk of the results for most runs. Once more than
+/// max_in_memory of them are collected, they're sorted and written to a
+/// temporary file, only their digests are kept in memory. When writing the
+/// new state, all of these runs are merged again.
+pub struct UnmodifiedFiles {
+    in_memory: Vec<FileInfo>,
+    max_in_memory: usize,
+    /// Directory for spilled runs, files are only kept in memory without one
+    spill_dir: Option<PathBuf>,
+    runs: Vec<PathBuf>,
+    spilled_digests: Vec<[u8; 32]>,
+    /// Whether the files are needed at all, verify only needs the digests
+    keep_files: bool,
+}
+
+impl UnmodifiedFiles {
+    pub fn new(spill_dir: Option<&Path>, max_in_memory: usize, keep_files: bool) -> Self {
+        UnmodifiedFiles {
+            in_memory: Vec::new(),
+            max_in_memory,
+            spill_dir: spill_dir.map(Path::to_path_buf),
+            runs: Vec::new(),
+            spilled_digests: Vec::new(),
+            keep_files,
+        }
+    }
+
+    pub fn push(&mut self, fi: FileInfo) -> Result<()> {
+        if !self.keep_files {
+            self.spilled_digests.push(fi.digest);
+            return Ok(());
+        }
+        self.in_memory.push(fi);
+        if self.in_memory.len() >= self.max_in_memory && self.spill_dir.is_some() {
+            self.spill()
+                .context("Failed to write unmodified files to a temporary file")?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let spill_dir = self.spill_dir.as_ref().unwrap();
+        let path = spill_dir.join(format!(
+            ".spill-{}-{}.tmp",
+            std::process::id(),
+            self.runs.len()
+        ));
+        self.runs.push(path.clone());
+
+        self.in_memory
+            .sort_unstable_by(|a, b| a.rel_path.cmp(&b.rel_path));
+        let mut f = BufWriter::new(File::options().write(true).create_new(true).open(&path)?);
+        for fi in self.in_memory.drain(..) {
+            fi.write(&mut f)?;
+            self.spilled_digests.push(fi.digest);
+        }
+        f.flush()
+    }
+
+    /// Digests of all unmodified files
+    pub fn digests(&self) -> impl Iterator<Item = &[u8; 32]> {
+        self.in_memory
+            .iter()
+            .map(|fi| &fi.digest)
+            .chain(self.spilled_digests.iter())
+    }
+
+    /// Paths and digests of the files with one of the digests given
+    ///
+    /// Spilled runs only keep their digests in memory, they're read again.
+    pub fn with_digests(&self, digests: &HashSet<[u8; 32]>) -> Result<Vec<(PathBuf, [u8; 32])>> {
+        let mut found = self
+            .in_memory
+            .iter()
+            .filter(|fi| digests.contains(&fi.digest))
+            .map(|fi| (fi.rel_path.clone(), fi.digest))
+            .collect::<Vec<_>>();
+        for path in &self.runs {
+            let reader = BufReader::new(File::open(path)?);
+            for line in reader.lines() {
+                let fi = FileInfo::parse(&line?)?;
+                if digests.contains(&fi.digest) {
+                    found.push((fi.rel_path, fi.digest));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Returns all files sorted by path
+    pub fn into_sorted(mut self) -> Result<SortedFiles> {
+        let mut in_memory = std::mem::take(&mut self.in_memory);
+        in_memory.sort_unstable_by(|a, b| b.rel_path.cmp(&a.rel_path));
+
+        let runs = std::mem::take(&mut self.runs);
+        let mut sorted = SortedFiles {
+            in_memory,
+            readers: Vec::new(),
+            heads: BinaryHeap::new(),
+            runs,
+        };
+        for path in &sorted.runs {
+            sorted
+                .readers
+                .push(BufReader::new(File::open(path)?).lines());
+        }
+        for i in 0..sorted.readers.len() {
+            sorted.advance(i)?;
+        }
+        Ok(sorted)
+    }
+}
+
+impl Drop for UnmodifiedFiles {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            remove_file(path).ok();
+        }
+    }
+}
+
+/// Iterator merging the in-memory and spilled unmodified files by path
+pub struct SortedFiles {
+    /// Sorted in reverse, so the next file can be popped off the end
+    in_memory: Vec<FileInfo>,
+    readers: Vec<Lines<BufReader<File>>>,
+    /// The next file of every run
+    heads: BinaryHeap<Reverse<(PathBuf, usize, FileInfoByPath)>>,
+    runs: Vec<PathBuf>,
+}
+
+/// Wrapper so FileInfo can be stored in the heap, which only compares the paths
+struct FileInfoByPath(FileInfo);
+
+impl PartialEq for FileInfoByPath {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for FileInfoByPath {}
+
+impl PartialOrd for FileInfoByPath {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FileInfoByPath {
+    fn cmp(&self, _other: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl SortedFiles {
+    /// Reads the next file from a run into the heap
+    fn advance(&mut self, run: usize) -> Result<()> {
+        if let Some(line) = self.readers[run].next() {
+            let fi = FileInfo::parse(&line?)?;
+            self.heads
+                .push(Reverse((fi.rel_path.clone(), run, FileInfoByPath(fi))));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SortedFiles {
+    type Item = Result<FileInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next_spilled = self.heads.peek().map(|Reverse((path, _, _))| path);
+        let next_in_memory = self.in_memory.last().map(|fi| &fi.rel_path);
+        match (next_in_memory, next_spilled) {
+            (None, None) => None,
+            (Some(in_memory), Some(spilled)) if in_memory > spilled => self.next_spilled(),
+            (Some(_), _) => self.in_memory.pop().map(Ok),
+            (None, Some(_)) => self.next_spilled(),
+        }
+    }
+}
+
+impl SortedFiles {
+    fn next_spilled(&mut self) -> Option<Result<FileInfo>> {
+        let Reverse((_, run, FileInfoByPath(fi))) = self.heads.pop()?;
+        match self.advance(run) {
+            Ok(()) => Some(Ok(fi)),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+impl Drop for SortedFiles {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            remove_file(path).ok();
+        }
+    }
+}
+
+/// Merges results and unmodified files, both sorted by path, into one sorted iterator
+pub fn merge_by_path(
+    checked_files: impl Iterator<Item = FileCheckResult>,
+    unmodified: SortedFiles,
+) -> impl Iterator<Item = Result<FileCheckResult>> {
+    let mut checked_files = checked_files.peekable();
+    let mut unmodified = unmodified.peekable();
+    std::iter::from_fn(move || {
+        let next_is_checked = match (checked_files.peek(), unmodified.peek()) {
+            (None, None) => return None,
+            (Some(checked), Some(Ok(fi))) => checked.rel_path() < fi.rel_path.as_path(),
+            (Some(_), None) => true,
+            (_, Some(_)) => false,
+        };
+        if next_is_checked {
+            checked_files.next().map(Ok)
+        } else {
+            unmodified
+                .next()
+                .map(|fi| fi.map(FileCheckResult::Unmodifed))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spilled_files_are_merged_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir();
+        let mut files = UnmodifiedFiles::new(Some(&dir), 7, true);
+        let mut expected = Vec::new();
+        for i in 0..50 {
+            let fi = FileInfo::new(format!("dir/{}", (i * 37) % 50), [i as u8; 32], i);
+            expected.push(fi.rel_path.clone());
+            files.push(fi)?;
+        }
+        assert_eq!(files.digests().count(), 50);
+        let runs = files.runs.clone();
+        assert_eq!(runs.len(), 7);
+
+        expected.sort();
+        let sorted = files
+            .into_sorted()?
+            .map(|fi| fi.map(|fi| fi.rel_path))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(sorted, expected);
+        assert!(runs.iter().all(|run| !run.exists()));
+        Ok(())
+    }
+}
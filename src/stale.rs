@@ -0,0 +1,78 @@
+use crate::time_format::TimeFormat;
+
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::state::read_state;
+
+use anyhow::Result;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Files not fully read since cutoff, the ones read longest ago first
+fn stale_files<'a>(
+    files: impl IntoIterator<Item = &'a FileInfo>,
+    cutoff: SystemTime,
+) -> Vec<&'a FileInfo> {
+    let mut stale = files
+        .into_iter()
+        .filter(|fi| fi.fully_read < cutoff)
+        .collect::<Vec<_>>();
+    stale.sort_by(|a, b| {
+        a.fully_read
+            .cmp(&b.fully_read)
+            .then(a.rel_path.cmp(&b.rel_path))
+    });
+    stale
+}
+
+/// Prints the files of the latest state that weren't fully read within older_than
+pub fn run(state_dir: &Path, older_than: Duration, time_format: TimeFormat) -> Result<()> {
+    let (_, files) = read_state(state_dir)?;
+    let now = SystemTime::now();
+    let cutoff = now - older_than;
+    let stale = stale_files(files.values(), cutoff);
+    for fi in &stale {
+        if fi.fully_read > SystemTime::UNIX_EPOCH {
+            println!(
+                "{} (fully read {})",
+                fi.rel_path.to_string_lossy(),
+                time_format.format(fi.fully_read, now)
+            );
+        } else {
+            println!("{} (never fully read)", fi.rel_path.to_string_lossy());
+        }
+    }
+    let gib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+    println!(
+        "└ {} of {} files ({:.3} of {:.3} GiB) not fully read since {}",
+        stale.len(),
+        files.len(),
+        gib(stale.iter().map(|fi| fi.len).sum()),
+        gib(files.values().map(|fi| fi.len).sum()),
+        time_format.format(cutoff, now)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_reads_first() {
+        let file_info = |path: &str, fully_read: u64| FileInfo {
+            fully_read: SystemTime::UNIX_EPOCH + Duration::from_secs(fully_read),
+            ..FileInfo::new(path, [0; 32], 10)
+        };
+        let files = [
+            file_info("recent", 300),
+            file_info("old", 100),
+            file_info("never", 0),
+            file_info("older", 50),
+        ];
+        let stale = stale_files(&files, SystemTime::UNIX_EPOCH + Duration::from_secs(200))
+            .iter()
+            .map(|fi| fi.rel_path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(stale, vec!["never", "older", "old"]);
+    }
+}
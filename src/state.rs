@@ -1,83 +1,695 @@
-use crate::file_check::FileCheckResult;
+use crate::checksum::{sidecar_path, verify_sidecar, write_sidecar, HashingReader, HashingWriter};
+use crate::config::Config;
+use crate::container::members_path;
+use crate::file_check::{FileCheckResult, ReadError};
 use crate::file_info::FileInfo;
+use crate::hash::HashAlgorithm;
+use crate::history::state_paths;
+use crate::index::{digest_counts_path, index_path, write_index, DigestCounts};
+use crate::stats::Stats;
 
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::{remove_file, File};
-use std::io::{self, BufRead, BufReader, BufWriter};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
-use time::OffsetDateTime;
-use time_tz::OffsetDateTimeExt;
-use walkdir::WalkDir;
-
-pub fn read_state(state_dir: &Path) -> Result<HashMap<PathBuf, FileInfo>> {
-    let state_path = WalkDir::new(state_dir)
-        .max_depth(1)
-        .sort_by_file_name()
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()
-        .with_context(|| format!("Unable to list files in state directory {:?}", state_dir))?
-        .into_iter()
-        .filter(|f| f.file_name().to_string_lossy().ends_with(".state"))
-        .last();
-    if let Some(state_path) = state_path {
-        BufReader::new(File::open(state_path.path())?)
-            .lines()
-            .map(|l| FileInfo::parse(l.unwrap().as_str()))
-            .map(|f| match f {
-                Ok(f) => Ok((f.rel_path.clone(), f)),
-                Err(err) => {
-                    Err(err).context(format!("Failed to read state from {:?}", state_path.path()))
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use time::{OffsetDateTime, PrimitiveDateTime};
+use time_tz::{OffsetDateTimeExt, PrimitiveDateTimeExt};
+
+/// Format of the timestamp state files are named after, ISO 8601 in UTC
+///
+/// Further states written in the same second get a suffix, -1 for the
+/// second one, see [`run_path`].
+pub const STATE_NAME_FORMAT: &str = "[year][month][day]T[hour][minute][second]Z";
+
+/// Format state files were named after before, in local time
+///
+/// Still read, and written with `state-names: local` in the profile.
+pub const LOCAL_STATE_NAME_FORMAT: &str = "[year][month][day] [hour][minute][second]";
+
+/// Time zone of the timestamps new states are named after
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StateNames {
+    /// Like 20230315T132501Z, see [`STATE_NAME_FORMAT`]
+    #[default]
+    Utc,
+    /// Like 20230315 142501, see [`LOCAL_STATE_NAME_FORMAT`]
+    Local,
+}
+
+impl FromStr for StateNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utc" => Ok(StateNames::Utc),
+            "local" => Ok(StateNames::Local),
+            _ => Err(format!("expected `utc` or `local`, got `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for StateNames {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StateNames::Utc => "utc",
+            StateNames::Local => "local",
+        })
+    }
+}
+
+/// Splits the suffix of a further state of the same second off a state name
+fn split_suffix(name: &str) -> (&str, u64) {
+    match name.rsplit_once('-') {
+        Some((name, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+            (name, n.parse().unwrap_or(u64::MAX))
+        }
+        _ => (name, 0),
+    }
+}
+
+/// Splits the timestamp a state name starts with off, None if it doesn't start with one
+///
+/// Names in local time written in the hour repeated when daylight saving
+/// time ends are taken to be in the first one.
+fn split_time(name: &str) -> Option<(OffsetDateTime, &str)> {
+    let utc = time::format_description::parse(STATE_NAME_FORMAT).unwrap();
+    // Both formats have a fixed width
+    if let Some(time) = name
+        .get(..16)
+        .and_then(|timestamp| PrimitiveDateTime::parse(timestamp, &utc).ok())
+    {
+        return Some((time.assume_utc(), &name[16..]));
+    }
+    let local = time::format_description::parse(LOCAL_STATE_NAME_FORMAT).unwrap();
+    let system_tz = time_tz::system::get_timezone().expect("Failed to find system timezone");
+    let time = PrimitiveDateTime::parse(name.get(..15)?, &local)
+        .ok()?
+        .assume_timezone(system_tz)
+        .take_first()?;
+    Some((time, &name[15..]))
+}
+
+/// Time a state named name (without .state) was written at, None if it isn't named after it
+///
+/// The name has to start with the timestamp, like all names of
+/// [`NameFormat`]s do.
+pub fn state_name_time(name: &str) -> Option<OffsetDateTime> {
+    Some(split_time(name)?.0)
+}
+
+/// Key to sort states by, oldest first
+///
+/// States are in the order they were written in, whether they're named in
+/// UTC or local time. States of the same second are sorted by their
+/// suffix, and then by what follows the timestamp, like the host. States
+/// named otherwise are sorted by name among them.
+pub fn state_order(path: &Path) -> (String, u64, String, String) {
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let utc = time::format_description::parse(STATE_NAME_FORMAT).unwrap();
+    match split_time(&name) {
+        Some((time, rest)) => {
+            let (rest, n) = split_suffix(rest);
+            (
+                time.to_offset(time::UtcOffset::UTC).format(&utc).unwrap(),
+                n,
+                rest.to_owned(),
+                name.clone().into_owned(),
+            )
+        }
+        None => (
+            name.clone().into_owned(),
+            0,
+            String::new(),
+            name.into_owned(),
+        ),
+    }
+}
+
+/// Template for the names of new states, like `{timestamp}-{hostname}-{label}.state`
+///
+/// `{timestamp}` is the time the state is written, as
+/// [`STATE_NAME_FORMAT`] or in local time with `state-names: local`.
+/// `{hostname}` is the name of the host up to the first dot and `{label}`
+/// the label of the state, left out along with the character before it
+/// for states without one. Characters other than letters, digits, `-` and
+/// `_` in either are replaced by `_`, so names stay a single file name
+/// with a single extension. Templates have to start with `{timestamp}`,
+/// so states sort in the order they're written, and end in `.state`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameFormat(String);
+
+impl Default for NameFormat {
+    fn default() -> Self {
+        NameFormat("{timestamp}.state".to_owned())
+    }
+}
+
+impl FromStr for NameFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(stem) = s.strip_suffix(".state") else {
+            return Err(format!("`{}` doesn't end in .state", s));
+        };
+        if !stem.starts_with("{timestamp}") {
+            return Err(format!(
+                "`{}` doesn't start with {{timestamp}}, states wouldn't sort in the order they're written",
+                s
+            ));
+        }
+        let mut literal = String::new();
+        let mut rest = stem;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            let placeholder = rest[start..]
+                .find('}')
+                .map(|end| &rest[start..start + end + 1]);
+            match placeholder {
+                Some(placeholder @ ("{timestamp}" | "{hostname}" | "{label}")) => {
+                    rest = &rest[start + placeholder.len()..]
+                }
+                _ => {
+                    return Err(format!(
+                    "`{}` has a placeholder other than {{timestamp}}, {{hostname}} and {{label}}",
+                    s
+                ))
                 }
-            })
-            .collect::<Result<HashMap<_, _>, _>>()
+            }
+        }
+        literal.push_str(rest);
+        if literal.contains(['/', '\\', '.', '}']) {
+            return Err(format!(
+                "`{}` has to be a single file name with a single extension, .state",
+                s
+            ));
+        }
+        Ok(NameFormat(s.to_owned()))
+    }
+}
+
+impl fmt::Display for NameFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl NameFormat {
+    /// Name of a file of a run, without the extension
+    fn stem(&self, timestamp: &str, label: Option<&str>) -> String {
+        let clean = |value: &str| {
+            value
+                .chars()
+                .map(|c| match c.is_alphanumeric() || c == '-' || c == '_' {
+                    true => c,
+                    false => '_',
+                })
+                .collect::<String>()
+        };
+        let mut stem = self.0.strip_suffix(".state").unwrap_or(&self.0).to_owned();
+        if label.is_none() {
+            while let Some(start) = stem.find("{label}") {
+                // Along with the separator, like the - of {hostname}-{label}
+                let from = match stem[..start].chars().next_back() {
+                    Some(c) if !c.is_alphanumeric() && c != '}' => start - c.len_utf8(),
+                    _ => start,
+                };
+                stem.replace_range(from..start + "{label}".len(), "");
+            }
+        }
+        stem.replace("{timestamp}", timestamp)
+            .replace("{hostname}", &clean(&hostname()))
+            .replace("{label}", &clean(label.unwrap_or_default()))
+    }
+}
+
+/// Name of the host this runs on, up to the first dot
+pub fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut name = [0_u8; 256];
+        if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } == 0 {
+            let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            let name = String::from_utf8_lossy(&name[..len]);
+            return name.split('.').next().unwrap_or_default().to_owned();
+        }
+    }
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
+
+/// Name of the file in the state directory naming the golden state, see [`pinned_state`]
+pub const GOLDEN_NAME: &str = "golden";
+
+/// Name referring to the latest state wherever a state is selected, see [`resolve_state`]
+pub const LATEST_NAME: &str = "latest";
+
+/// Number of state lines parsed at once
+const PARSE_CHUNK_LINES: usize = 64 * 1024;
+
+/// Information about a state, stored at the beginning of every state file
+///
+/// Header lines start with `# ` followed by `key: value`. Files written
+/// by older versions have no header and therefore use the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateHeader {
+    /// Algorithm used for all digests in the file
+    pub hash_algorithm: HashAlgorithm,
+    /// UUIDs of the filesystems the files were on, by volume
+    ///
+    /// Archives without volumes use an empty name.
+    pub filesystems: BTreeMap<String, String>,
+    /// Whether states are written without anything specific to a run
+    ///
+    /// Times files were read and seen are left out, as well as the
+    /// filesystems, so identical trees give byte-identical states.
+    pub canonical: bool,
+    /// ID of the run that wrote the state, see [`new_run_id`]
+    pub run_id: Option<String>,
+    /// Name given to the state, to refer to it instead of its file name, see [`resolve_state`]
+    pub label: Option<String>,
+    /// Host that wrote the state, for partial states, see [`crate::partial`]
+    pub host: Option<String>,
+    /// Paths below which a partial state has all files, the whole archive if empty
+    pub covers: Vec<PathBuf>,
+}
+
+/// Creates an ID for a run, to tell apart the output of concurrent runs
+///
+/// 16 hex digits of a digest of the time, the process ID and a counter,
+/// so IDs are unique in practice across processes and machines.
+pub fn new_run_id() -> String {
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = Sha256::new();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.update(now.as_nanos().to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.update(RUNS.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+impl StateHeader {
+    /// Parses a line, returns false if the line is not part of the header
+    pub fn parse_line(&mut self, line: &str) -> Result<bool> {
+        let header_line = match line.strip_prefix("# ") {
+            Some(header_line) => header_line,
+            None => return Ok(false),
+        };
+        // Keys written by newer versions are ignored
+        match header_line.split_once(": ") {
+            Some(("hash", value)) => {
+                self.hash_algorithm = value
+                    .parse()
+                    .map_err(anyhow::Error::msg)
+                    .with_context(|| format!("invalid header line: '{}'", line))?;
+            }
+            Some(("canonical", value)) => self.canonical = value == "true",
+            Some(("run", value)) => self.run_id = Some(value.to_owned()),
+            Some(("label", value)) => self.label = Some(value.to_owned()),
+            Some(("host", value)) => self.host = Some(value.to_owned()),
+            Some(("covers", value)) => self.covers.push(PathBuf::from(value)),
+            Some(("filesystem", value)) => {
+                self.filesystems.insert(String::new(), value.to_owned());
+            }
+            Some((key, value)) => {
+                if let Some(volume) = key.strip_prefix("filesystem ") {
+                    self.filesystems.insert(volume.to_owned(), value.to_owned());
+                }
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+
+    pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
+        writeln!(to, "# hash: {}", self.hash_algorithm)?;
+        // Given for the state, not specific to the run, so canonical states keep it
+        if let Some(label) = &self.label {
+            writeln!(to, "# label: {}", label)?;
+        }
+        if self.canonical {
+            return writeln!(to, "# canonical: true");
+        }
+        if let Some(run_id) = &self.run_id {
+            writeln!(to, "# run: {}", run_id)?;
+        }
+        if let Some(host) = &self.host {
+            writeln!(to, "# host: {}", host)?;
+        }
+        for path in &self.covers {
+            writeln!(to, "# covers: {}", path.to_string_lossy())?;
+        }
+        for (volume, uuid) in &self.filesystems {
+            if volume.is_empty() {
+                writeln!(to, "# filesystem: {}", uuid)?;
+            } else {
+                writeln!(to, "# filesystem {}: {}", volume, uuid)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the most recent state file in a state directory, see [`state_order`]
+pub fn latest_state_path(state_dir: &Path) -> Result<Option<PathBuf>> {
+    Ok(state_paths(state_dir)?.pop())
+}
+
+/// Checks a label can be given to a new state of the state directory
+///
+/// Labels are single lines that can't be mistaken for a file name or
+/// [`LATEST_NAME`] and are unique among the states, so they keep referring
+/// to one state.
+pub fn check_new_label(state_dir: &Path, label: &str) -> Result<()> {
+    if label.is_empty()
+        || label != label.trim()
+        || label.contains(['\n', '\r'])
+        || label.ends_with(".state")
+        || label == LATEST_NAME
+    {
+        return Err(anyhow::Error::msg(format!(
+            "invalid label '{}', labels can't be empty, start or end with spaces, contain line breaks, end in .state or be {}",
+            label, LATEST_NAME
+        )));
+    }
+    if let Some(path) = labeled_state(state_dir, label)? {
+        return Err(anyhow::Error::msg(format!(
+            "label '{}' is already given to {:?}",
+            label, path
+        )));
+    }
+    Ok(())
+}
+
+/// The state with a label, None if no state has it
+pub fn labeled_state(state_dir: &Path, label: &str) -> Result<Option<PathBuf>> {
+    for path in state_paths(state_dir)? {
+        if read_header(&path)?.label.as_deref() == Some(label) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Finds a state by its file name, its name without .state or its label
+///
+/// Names are relative to the state directory, paths elsewhere work as well.
+/// [`LATEST_NAME`] is the latest state, unless there's a file named like it.
+pub fn resolve_state(state_dir: &Path, name: &str) -> Result<PathBuf> {
+    for path in [
+        state_dir.join(name),
+        state_dir.join(format!("{}.state", name)),
+    ] {
+        if path.is_file() {
+            return Ok(path);
+        }
+    }
+    if name == LATEST_NAME {
+        return latest_state_path(state_dir)?
+            .with_context(|| format!("no state in {:?}, run an update first", state_dir));
+    }
+    labeled_state(state_dir, name)?.with_context(|| {
+        format!(
+            "state {:?} not found, neither as a file in {:?} nor as a label",
+            name, state_dir
+        )
+    })
+}
+
+/// The state pinned as the golden baseline, None if there's none
+///
+/// Verify compares against it by default instead of the latest state,
+/// for write-once archives where the first full state is the contract.
+/// Fails if the state pinned is gone.
+pub fn pinned_state(state_dir: &Path) -> Result<Option<PathBuf>> {
+    let path = state_dir.join(GOLDEN_NAME);
+    let name = match std::fs::read_to_string(&path) {
+        Ok(name) => name,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Unable to read {:?}", path)),
+    };
+    let state_path = state_dir.join(name.trim_end_matches(['\n', '\r']));
+    if !state_path.is_file() {
+        return Err(anyhow::Error::msg(format!(
+            "the golden state {:?} pinned in {:?} is gone",
+            state_path, path
+        )));
+    }
+    Ok(Some(state_path))
+}
+
+/// Pins a state of the state directory as the golden baseline, or unpins it with None
+pub fn pin_state(state_dir: &Path, state: Option<&Path>) -> Result<()> {
+    let path = state_dir.join(GOLDEN_NAME);
+    let Some(state) = state else {
+        return match remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Unable to remove {:?}", path))
+            }
+            _ => Ok(()),
+        };
+    };
+    let name = state.file_name().unwrap_or_default().to_string_lossy();
+    if !name.ends_with(".state") || !state_dir.join(&*name).is_file() {
+        return Err(anyhow::Error::msg(format!(
+            "no state {:?} in {:?}",
+            name, state_dir
+        )));
+    }
+    std::fs::write(&path, format!("{}\n", name))
+        .with_context(|| format!("Unable to write {:?}", path))
+}
+
+/// Reads only the header of a state file, without checking its checksum
+pub fn read_header(path: &Path) -> Result<StateHeader> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Unable to open state {:?}", path))?,
+    );
+    let mut header = StateHeader::default();
+    while let Some(line) =
+        read_line(&mut reader).with_context(|| format!("Unable to read state {:?}", path))?
+    {
+        if !header.parse_line(&line)? {
+            break;
+        }
+    }
+    Ok(header)
+}
+
+/// Number of files in a state file, counted without parsing or keeping them
+pub fn count_entries(path: &Path) -> Result<usize> {
+    let reader = BufReader::new(
+        File::open(path).with_context(|| format!("Unable to open state {:?}", path))?,
+    );
+    let mut entries = 0;
+    for line in reader.split(b'\n') {
+        let line = line.with_context(|| format!("Unable to read state {:?}", path))?;
+        if !line.is_empty() && !line.starts_with(b"#") {
+            entries += 1;
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads a line without the line break, None at the end of the file
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    Ok(Some(line.strip_suffix('\r').unwrap_or(line).to_owned()))
+}
+
+/// Reads a state, .modified or .missing file chunk by chunk
+///
+/// The checksum sidecar is verified once everything is read, the digest
+/// of the file is returned along with the header.
+fn read_chunks(
+    state_path: &Path,
+    mut on_chunk: impl FnMut(Vec<FileInfo>),
+) -> Result<(StateHeader, [u8; 32])> {
+    let mut header = StateHeader::default();
+    // Everything read is hashed to compare it with the checksum sidecar
+    let mut reader = BufReader::new(HashingReader::new(File::open(state_path)?));
+    let mut chunk = Vec::with_capacity(PARSE_CHUNK_LINES);
+    loop {
+        // Only a chunk of lines is kept in memory at a time, so the raw
+        // lines don't need as much memory as the parsed state
+        chunk.clear();
+        while let Some(line) = read_line(&mut reader)
+            .with_context(|| format!("Failed to read state from {:?}", state_path))?
+        {
+            let is_header_line = header
+                .parse_line(&line)
+                .with_context(|| format!("Failed to read state from {:?}", state_path))?;
+            if !is_header_line {
+                chunk.push(line);
+                if chunk.len() == PARSE_CHUNK_LINES {
+                    break;
+                }
+            }
+        }
+        if chunk.is_empty() {
+            break;
+        }
+        // Parsing is surprisingly expensive for millions of lines, so use all cores
+        let parsed = chunk
+            .par_iter()
+            .map(|l| FileInfo::parse(l))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to read state from {:?}", state_path))?;
+        on_chunk(parsed);
+    }
+    let digest = reader.into_inner().finalize();
+    verify_sidecar(state_path, &digest)?;
+    Ok((header, digest))
+}
+
+/// Contents of a single state, .modified or .missing file
+pub struct StateFile {
+    pub header: StateHeader,
+    /// Entries in the order they're stored in
+    pub files: Vec<FileInfo>,
+    /// SHA-256 of the whole file
+    pub digest: [u8; 32],
+}
+
+/// Reads a single state, .modified or .missing file
+pub fn read_state_file(path: &Path) -> Result<StateFile> {
+    let mut files = Vec::new();
+    let (header, digest) = read_chunks(path, |parsed| files.extend(parsed))?;
+    Ok(StateFile {
+        header,
+        files,
+        digest,
+    })
+}
+
+pub fn read_state(state_dir: &Path) -> Result<(StateHeader, HashMap<PathBuf, FileInfo>)> {
+    read_state_at(latest_state_path(state_dir)?.as_deref())
+}
+
+/// Like [`read_state`], but reads the given state, an empty one if None
+pub fn read_state_at(
+    state_path: Option<&Path>,
+) -> Result<(StateHeader, HashMap<PathBuf, FileInfo>)> {
+    if let Some(state_path) = state_path {
+        let mut files = HashMap::new();
+        let (header, _) = read_chunks(state_path, |parsed| {
+            files.extend(parsed.into_iter().map(|f| (f.rel_path.clone(), f)))
+        })?;
+        Ok((header, files))
     } else {
-        println!("no previous state found in {:?}", state_dir);
-        Ok(HashMap::new())
+        Ok((StateHeader::default(), HashMap::new()))
     }
 }
 
-pub fn write_state<'a>(
+/// Writes a new state and returns its path
+///
+/// Results have to be sorted by path. The state, .modified and .missing
+/// files are written in that order, so they don't depend on the number of
+/// threads or the platform.
+pub fn write_state(
     state_dir: &Path,
-    checked_files: impl Iterator<Item = &'a FileCheckResult>,
-) -> Result<(), io::Error> {
-    let system_tz = time_tz::system::get_timezone().expect("Failed to find system timezone");
-    let now = OffsetDateTime::now_utc().to_timezone(system_tz);
-    let format =
-        time::format_description::parse("[year][month][day] [hour][minute][second]").unwrap();
+    header: &StateHeader,
+    checked_files: impl Iterator<Item = Result<FileCheckResult>>,
+) -> Result<PathBuf> {
+    let state_path = run_path(state_dir, "state")?;
+    write_state_at(&state_path, header, checked_files)?;
+    Ok(state_path)
+}
+
+/// Fails if a state can't be written to state_path, before any work is done for it
+pub fn check_new_state_path(state_path: &Path) -> Result<()> {
+    if state_path.extension().is_none_or(|ext| ext != "state") {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} doesn't end in .state, it wouldn't be found as a state",
+            state_path
+        )));
+    }
+    if state_path.exists() {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} exists already",
+            state_path
+        )));
+    }
+    Ok(())
+}
+
+/// Like [`write_state`], but to a path chosen by the caller, which has to end in .state
+///
+/// The .modified, .missing, .index and .digests files are named after it. Fails if
+/// the state exists already.
+pub fn write_state_at(
+    state_path: &Path,
+    header: &StateHeader,
+    checked_files: impl Iterator<Item = Result<FileCheckResult>>,
+) -> Result<()> {
+    check_new_state_path(state_path)?;
     let mut state_f = BufWriter::with_capacity(
         1024 * 1024,
-        File::options()
-            .write(true)
-            .create_new(true)
-            .open(state_dir.join(format!("{}.state", now.format(&format).unwrap())))?,
+        HashingWriter::new(
+            File::options()
+                .write(true)
+                .create_new(true)
+                .open(state_path)
+                .with_context(|| format!("Unable to create state {:?}", state_path))?,
+        ),
     );
+    let modified_path = state_path.with_extension("modified");
     let mut modified_f = BufWriter::with_capacity(
         1024 * 1024,
-        File::options()
-            .write(true)
-            .create_new(true)
-            .open(state_dir.join(format!("{}.modified", now.format(&format).unwrap())))?,
+        HashingWriter::new(
+            File::options()
+                .write(true)
+                .create_new(true)
+                .open(&modified_path)?,
+        ),
     );
+    let missing_path = state_path.with_extension("missing");
     let mut missing_f = BufWriter::with_capacity(
         1024 * 1024,
-        File::options()
-            .write(true)
-            .create_new(true)
-            .open(state_dir.join(format!("{}.missing", now.format(&format).unwrap())))?,
+        HashingWriter::new(
+            File::options()
+                .write(true)
+                .create_new(true)
+                .open(&missing_path)?,
+        ),
     );
 
+    header.write(&mut state_f)?;
+    header.write(&mut modified_f)?;
+    header.write(&mut missing_f)?;
+
     let mut modified_files = 0;
     let mut missing_files = 0;
+    let mut index_entries = Vec::new();
     for file in checked_files {
+        let file = file?;
+        let file = match header.canonical {
+            true => file.without_run_times(),
+            false => file,
+        };
         match file {
             FileCheckResult::New(fi) | FileCheckResult::Unmodifed(fi) => {
                 fi.write(&mut state_f)?;
+                index_entries.push((fi.digest, fi.rel_path));
             }
             FileCheckResult::Modified(fi) => {
                 modified_files += 1;
                 fi.previous.write(&mut modified_f)?;
                 fi.current.write(&mut state_f)?;
+                index_entries.push((fi.current.digest, fi.current.rel_path));
             }
             FileCheckResult::Missing(fi) => {
                 missing_files += 1;
@@ -85,15 +697,332 @@ pub fn write_state<'a>(
             }
         }
     }
-    drop(state_f);
-    drop(modified_f);
-    drop(missing_f);
-    if modified_files == 0 {
-        remove_file(state_dir.join(format!("{}.modified", now.format(&format).unwrap())))?;
+    // The checksums make corruption of the state itself detectable
+    for (f, path, files) in [
+        (state_f, state_path, 1),
+        (modified_f, &modified_path, modified_files),
+        (missing_f, &missing_path, missing_files),
+    ] {
+        let digest = f.into_inner().map_err(|err| err.into_error())?.finalize();
+        if files == 0 {
+            remove_file(path)?;
+        } else {
+            write_sidecar(path, &digest)?;
+        }
+    }
+    DigestCounts::of(index_entries.iter().map(|(digest, _)| *digest))
+        .write(&digest_counts_path(state_path), header)?;
+    write_index(&state_path.with_extension("index"), header, index_entries)?;
+    Ok(())
+}
+
+/// Path of a file in the state directory named after the current time, like states
+///
+/// In UTC unless the profile has `state-names: local`. If there's a file
+/// with that extension of the same second already, like from another run,
+/// a suffix is added that counts up, so the path is new and sorts after
+/// it, whatever else differs in the names.
+pub fn run_path(state_dir: &Path, extension: &str) -> Result<PathBuf> {
+    named_run_path(state_dir, extension, None, None)
+}
+
+/// Like [`run_path`], named by format instead of the format of the profile
+///
+/// label is the label of the state, for `{label}`.
+pub fn named_run_path(
+    state_dir: &Path,
+    extension: &str,
+    format: Option<&NameFormat>,
+    label: Option<&str>,
+) -> Result<PathBuf> {
+    let config = Config::read(state_dir)?.unwrap_or_default();
+    let names = config.state_names.unwrap_or_default();
+    let format = format
+        .or(config.state_name_format.as_ref())
+        .cloned()
+        .unwrap_or_default();
+    let now = OffsetDateTime::now_utc();
+    let name = match names {
+        StateNames::Utc => now.format(&time::format_description::parse(STATE_NAME_FORMAT).unwrap()),
+        StateNames::Local => {
+            let system_tz =
+                time_tz::system::get_timezone().expect("Failed to find system timezone");
+            now.to_timezone(system_tz)
+                .format(&time::format_description::parse(LOCAL_STATE_NAME_FORMAT).unwrap())
+        }
+    }
+    .unwrap();
+    let mut first = 0;
+    for entry in std::fs::read_dir(state_dir)
+        .with_context(|| format!("Unable to list files in {:?}", state_dir))?
+    {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if file_name.starts_with(&name) && file_name.ends_with(&format!(".{}", extension)) {
+            first = first.max(state_order(&path).1 + 1);
+        }
     }
-    if missing_files == 0 {
-        remove_file(state_dir.join(format!("{}.missing", now.format(&format).unwrap())))?;
+    let name = format.stem(&name, label);
+    Ok((first..)
+        .map(|n| match n {
+            0 => state_dir.join(format!("{}.{}", name, extension)),
+            n => state_dir.join(format!("{}-{}.{}", name, n, extension)),
+        })
+        .find(|path| !path.exists())
+        .unwrap())
+}
+
+/// Appends the files that couldn't be read in a run, one per line after the run ID
+///
+/// Runs may share a file, the run ID tells them apart.
+pub fn write_errors(path: &Path, run_id: &str, errors: &[ReadError]) -> Result<()> {
+    let mut f = BufWriter::new(
+        File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Unable to open {:?}", path))?,
+    );
+    writeln!(f, "# run: {}", run_id)?;
+    for error in errors {
+        error.write(&mut f)?;
     }
+    f.flush()?;
+    Ok(())
+}
 
+/// Writes the stats of the run that wrote a state next to it, as JSON
+pub fn write_stats(
+    state_path: &Path,
+    stats: &Stats,
+    duration: Duration,
+    run_id: Option<&str>,
+) -> Result<()> {
+    let path = state_path.with_extension("stats");
+    let mut f = BufWriter::new(
+        File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Unable to create {:?}", path))?,
+    );
+    stats.write_json(duration, run_id, &mut f)?;
+    f.flush()?;
     Ok(())
 }
+
+/// Removes a state along with its sidecar, indexes, stats and members, returns the bytes freed
+///
+/// The .modified and .missing files of the run are kept, they're the
+/// history of the archive.
+pub fn remove_state(path: &Path, dry_run: bool) -> Result<u64> {
+    let mut freed = 0;
+    for path in [
+        path.to_path_buf(),
+        sidecar_path(path),
+        index_path(path),
+        digest_counts_path(path),
+        path.with_extension("stats"),
+        members_path(path),
+    ] {
+        if let Ok(metadata) = path.metadata() {
+            freed += metadata.len();
+            if !dry_run {
+                remove_file(&path).with_context(|| format!("Unable to remove {:?}", path))?;
+            }
+        }
+    }
+    Ok(freed)
+}
+
+/// States to remove to keep only the latest keep and one per month before
+///
+/// Takes the paths of all states, oldest first. Of older states, the
+/// latest one of every month not kept already is kept. States not named
+/// after the time they were written in are always kept.
+fn states_to_rotate(states: &[PathBuf], keep: usize) -> Vec<PathBuf> {
+    let system_tz = time_tz::system::get_timezone().expect("Failed to find system timezone");
+    let mut months_kept = Vec::new();
+    let mut rotated = Vec::new();
+    for (i, path) in states.iter().rev().enumerate() {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        // Months in local time, like the names of older states
+        let Some(written) = state_name_time(&stem).map(|time| time.to_timezone(system_tz)) else {
+            continue;
+        };
+        let month = (written.year(), written.month());
+        if i >= keep && months_kept.contains(&month) {
+            rotated.push(path.clone());
+        } else {
+            months_kept.push(month);
+        }
+    }
+    rotated.reverse();
+    rotated
+}
+
+/// Removes old states, keeping the latest keep, one per month before and the golden one
+pub fn rotate_states(state_dir: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let states = state_paths(state_dir)?;
+    let pinned = pinned_state(state_dir)?;
+    let mut rotated = states_to_rotate(&states, keep);
+    rotated.retain(|path| Some(path) != pinned.as_ref());
+    for path in &rotated {
+        remove_state(path, false)?;
+    }
+    Ok(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn rotation_keeps_latest_and_monthly() {
+        let states = [
+            "20240105 010000",
+            "20240120 010000",
+            "20240201 010000",
+            "20240215 010000",
+            "20240301 010000",
+            "20240302 010000",
+            "20240303 010000",
+            "20240304 010000",
+        ]
+        .map(|name| PathBuf::from(format!("{}.state", name)));
+        assert_eq!(
+            states_to_rotate(&states, 3),
+            ["20240105 010000", "20240201 010000", "20240301 010000"]
+                .map(|name| PathBuf::from(format!("{}.state", name)))
+        );
+        assert!(states_to_rotate(&states, 10).is_empty());
+        assert!(states_to_rotate(&[PathBuf::from("baseline.state")], 0).is_empty());
+    }
+
+    #[test]
+    fn header_round_trip() -> Result<()> {
+        let header = StateHeader {
+            hash_algorithm: HashAlgorithm::Blake3,
+            filesystems: BTreeMap::from([
+                (String::new(), "1234-ABCD".to_owned()),
+                ("disk-2".to_owned(), "0b5c3f2e-6d0e".to_owned()),
+            ]),
+            canonical: false,
+            run_id: Some(new_run_id()),
+            label: Some("before the move: photos".to_owned()),
+            host: Some("nas".to_owned()),
+            covers: vec![PathBuf::from("photos/2023"), PathBuf::from("scans")],
+        };
+        let mut written = Vec::new();
+        header.write(&mut written)?;
+        let mut parsed = StateHeader::default();
+        for line in std::str::from_utf8(&written)?.lines() {
+            assert!(parsed.parse_line(line)?);
+        }
+        assert_eq!(parsed, header);
+        assert!(!parsed.parse_line("abcd a # mtime 1")?);
+        assert_ne!(new_run_id(), new_run_id());
+        Ok(())
+    }
+
+    #[test]
+    fn states_in_the_order_written() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            state_name_time("20230315T132501Z-2").map(|time| time.unix_timestamp()),
+            Some(1678886701)
+        );
+        assert!(state_name_time("20230315 142501").is_some());
+        assert_eq!(state_name_time("weekly"), None);
+        let mut paths = [
+            "20230315T132501Z-1.state",
+            "weekly.state",
+            "20230315T132501Z.state",
+            "20230315T132501Z-10.state",
+            "20230101 000000.state",
+            "20230315T132501Z-2.state",
+        ]
+        .map(PathBuf::from);
+        paths.sort_by_cached_key(|path| state_order(path));
+        assert_eq!(
+            paths.map(|path| path.to_string_lossy().into_owned()),
+            [
+                "20230101 000000.state",
+                "20230315T132501Z.state",
+                "20230315T132501Z-1.state",
+                "20230315T132501Z-2.state",
+                "20230315T132501Z-10.state",
+                "weekly.state",
+            ]
+        );
+
+        let dir = TestDir::new("state-names");
+        let first = run_path(&dir, "state")?;
+        File::create(&first)?;
+        // Another run in the same second, or the next one
+        let second = run_path(&dir, "state")?;
+        assert!(state_order(&second) > state_order(&first));
+        std::fs::write(dir.join(crate::config::CONFIG_NAME), "state-names: local\n")?;
+        let local = run_path(&dir, "state")?;
+        assert!(local.to_string_lossy().contains(' '));
+        Ok(())
+    }
+
+    #[test]
+    fn name_formats() -> Result<(), Box<dyn std::error::Error>> {
+        let format: NameFormat = "{timestamp}-{hostname}-{label}.state".parse()?;
+        let host = hostname();
+        assert!(!host.contains('.'));
+        assert_eq!(
+            format.stem("20230315T132501Z", Some("before the move")),
+            format!("20230315T132501Z-{}-before_the_move", host)
+        );
+        assert_eq!(
+            format.stem("20230315T132501Z", None),
+            format!("20230315T132501Z-{}", host)
+        );
+        // Hosts writing in the same second sort after each other
+        let named = |host: &str, suffix: &str| {
+            PathBuf::from(format!("20230315T132501Z-{}{}.state", host, suffix))
+        };
+        assert!(state_order(&named("nas", "")) < state_order(&named("pc", "")));
+        assert!(state_order(&named("pc", "")) < state_order(&named("nas", "-1")));
+        assert!(
+            state_order(&named("nas", "-1")) < state_order(Path::new("20230315T132502Z.state"))
+        );
+
+        for invalid in [
+            "{timestamp}",
+            "{hostname}-{timestamp}.state",
+            "{timestamp}-{host}.state",
+            "{timestamp}/{hostname}.state",
+            "{timestamp}.{hostname}.state",
+        ] {
+            assert!(invalid.parse::<NameFormat>().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pinned_state_survives_rotation() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("pinned");
+        let names = [
+            "20240105 010000.state",
+            "20240106 010000.state",
+            "20240107 010000.state",
+        ];
+        for name in names {
+            std::fs::write(dir.join(name), "")?;
+        }
+        assert_eq!(pinned_state(&dir)?, None);
+        assert!(pin_state(&dir, Some(Path::new("20240108 010000.state"))).is_err());
+        pin_state(&dir, Some(Path::new(names[0])))?;
+        assert_eq!(pinned_state(&dir)?, Some(dir.join(names[0])));
+        assert_eq!(rotate_states(&dir, 1)?, vec![dir.join(names[1])]);
+        assert!(dir.join(names[0]).exists());
+
+        pin_state(&dir, None)?;
+        assert_eq!(pinned_state(&dir)?, None);
+        Ok(())
+    }
+}
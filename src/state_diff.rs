@@ -0,0 +1,313 @@
+use rusty_archive::changeset::Changeset;
+use rusty_archive::file_info::FileInfo;
+use rusty_archive::history::{diff, state_paths};
+use rusty_archive::state::{read_header, read_state_file, resolve_state};
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// What diff prints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// Numbers of changes and the directories with the most of them
+    Summary,
+    /// A [`Changeset`] for the apply command
+    Manifest,
+}
+
+impl FromStr for DiffFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(DiffFormat::Summary),
+            "manifest" => Ok(DiffFormat::Manifest),
+            _ => Err(format!("expected `summary` or `manifest`, got `{}`", s)),
+        }
+    }
+}
+
+/// Files of a state that were in the archive at the time, sorted by path
+///
+/// Files kept in the state after they went missing aren't, a move
+/// shouldn't show up as a copy.
+fn present_files(path: &Path) -> Result<Vec<FileInfo>> {
+    let mut files = read_state_file(path)?.files;
+    files.retain(|fi| fi.missing_since.is_none());
+    files.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    Ok(files)
+}
+
+/// Changes below one directory, not counting its subdirectories
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DirectoryChurn {
+    added: u64,
+    modified: u64,
+    /// Moved here, or away from here
+    moved: u64,
+    removed: u64,
+    /// Size of the files added, modified and removed
+    bytes: u64,
+}
+
+impl DirectoryChurn {
+    fn changes(&self) -> u64 {
+        self.added + self.modified + self.moved + self.removed
+    }
+}
+
+/// How the archive drifted from one state to another
+#[derive(Debug, Default)]
+struct Drift<'a> {
+    added: Vec<&'a FileInfo>,
+    /// The older and the newer entry
+    modified: Vec<(&'a FileInfo, &'a FileInfo)>,
+    /// Contents that went from one path to another, the older and the newer entry
+    moved: Vec<(&'a FileInfo, &'a FileInfo)>,
+    removed: Vec<&'a FileInfo>,
+    unchanged: u64,
+    by_directory: BTreeMap<&'a Path, DirectoryChurn>,
+}
+
+/// Compares two lists of files sorted by path
+///
+/// A path gone along with a new one with the same contents is a move.
+/// Empty files all have the same digest and are never moved.
+fn drift<'a>(older: &'a [FileInfo], newer: &'a [FileInfo]) -> Drift<'a> {
+    let diff = diff(older, newer);
+    let mut gone = HashMap::<[u8; 32], Vec<&FileInfo>>::new();
+    for fi in diff.missing.iter().rev().filter(|fi| fi.len > 0) {
+        gone.entry(fi.digest).or_default().push(fi);
+    }
+    let mut drift = Drift {
+        unchanged: (newer.len() - diff.added.len() - diff.modified.len()) as u64,
+        modified: diff.modified,
+        ..Default::default()
+    };
+    for fi in diff.added {
+        // Paths sort the same before and after a move of their directory,
+        // so files are paired in order
+        match gone.get_mut(&fi.digest).and_then(|older| older.pop()) {
+            Some(older) => drift.moved.push((older, fi)),
+            None => drift.added.push(fi),
+        }
+    }
+    let moved_away = drift
+        .moved
+        .iter()
+        .map(|(o, _)| &o.rel_path)
+        .collect::<HashSet<_>>();
+    drift.removed = diff
+        .missing
+        .into_iter()
+        .filter(|fi| !moved_away.contains(&fi.rel_path))
+        .collect();
+
+    let by_directory = &mut drift.by_directory;
+    let mut churn = |fi: &'a FileInfo, count: fn(&mut DirectoryChurn) -> &mut u64, bytes| {
+        let churn = by_directory
+            .entry(fi.rel_path.parent().unwrap_or(Path::new("")))
+            .or_default();
+        *count(churn) += 1;
+        churn.bytes += bytes;
+    };
+    for fi in &drift.added {
+        churn(fi, |c| &mut c.added, fi.len);
+    }
+    for (_, fi) in &drift.modified {
+        churn(fi, |c| &mut c.modified, fi.len);
+    }
+    for (older, newer) in &drift.moved {
+        churn(older, |c| &mut c.moved, 0);
+        if older.rel_path.parent() != newer.rel_path.parent() {
+            churn(newer, |c| &mut c.moved, 0);
+        }
+    }
+    for fi in &drift.removed {
+        churn(fi, |c| &mut c.removed, fi.len);
+    }
+    drift
+}
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / 1024.0 / 1024.0 / 1024.0
+}
+
+/// The state name along with its label, if it has one
+fn described(path: &Path) -> Result<String> {
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    Ok(match read_header(path)?.label {
+        Some(label) => format!("{} '{}'", name, label),
+        None => name.into_owned(),
+    })
+}
+
+/// Summarizes how the archive drifted between the states from and to
+///
+/// Both are file names in the state directory, labels or paths. Without
+/// from, to is compared to the state before it. With paths, every path
+/// that changed is listed, otherwise only the top directories with the
+/// most changes. As a manifest, the changes are printed as a changeset
+/// instead.
+pub fn run(
+    state_dir: &Path,
+    from: Option<&str>,
+    to: &str,
+    format: DiffFormat,
+    paths: bool,
+    top: usize,
+) -> Result<()> {
+    let to_path = resolve_state(state_dir, to)?;
+    let from_path = match from {
+        Some(from) => resolve_state(state_dir, from)?,
+        None => {
+            let states = state_paths(state_dir)?;
+            let to_name = to_path.file_name();
+            let before = states
+                .iter()
+                .position(|path| path.file_name() == to_name)
+                .and_then(|i| i.checked_sub(1));
+            match before {
+                Some(i) => states[i].clone(),
+                None => {
+                    return Err(anyhow::Error::msg(format!(
+                        "no state before {:?} to compare to, pass --from",
+                        to_path
+                    )))
+                }
+            }
+        }
+    };
+    let hash_algorithm = read_header(&to_path)?.hash_algorithm;
+    if read_header(&from_path)?.hash_algorithm != hash_algorithm {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} and {:?} use different hash algorithms, their files can't be compared",
+            from_path, to_path
+        )));
+    }
+    let older = present_files(&from_path).context("Unable to read the state to compare from")?;
+    let newer = present_files(&to_path).context("Unable to read the state to compare to")?;
+    if format == DiffFormat::Manifest {
+        let changeset = Changeset::between(hash_algorithm, &from_path, &older, &to_path, &newer);
+        let mut stdout = std::io::stdout().lock();
+        changeset.write(&mut stdout)?;
+        return Ok(stdout.flush()?);
+    }
+    let drift = drift(&older, &newer);
+
+    println!(
+        "from {} to {}:",
+        described(&from_path)?,
+        described(&to_path)?
+    );
+    if paths {
+        for fi in &drift.added {
+            println!("A {}", fi.rel_path.to_string_lossy());
+        }
+        for (_, fi) in &drift.modified {
+            println!("M {}", fi.rel_path.to_string_lossy());
+        }
+        for (older, newer) in &drift.moved {
+            println!(
+                "V {} -> {}",
+                older.rel_path.to_string_lossy(),
+                newer.rel_path.to_string_lossy()
+            );
+        }
+        for fi in &drift.removed {
+            println!("R {}", fi.rel_path.to_string_lossy());
+        }
+    }
+    let sum = |files: &mut dyn Iterator<Item = &FileInfo>| gib(files.map(|fi| fi.len).sum());
+    println!(
+        "└ {} added ({:.1} GiB), {} modified ({:.1} GiB), {} moved, {} removed ({:.1} GiB)",
+        drift.added.len(),
+        sum(&mut drift.added.iter().copied()),
+        drift.modified.len(),
+        sum(&mut drift.modified.iter().map(|(_, fi)| *fi)),
+        drift.moved.len(),
+        drift.removed.len(),
+        sum(&mut drift.removed.iter().copied()),
+    );
+    println!("└ {} files unchanged", drift.unchanged);
+    if paths || drift.by_directory.is_empty() {
+        return Ok(());
+    }
+
+    let mut directories = drift.by_directory.iter().collect::<Vec<_>>();
+    directories.sort_by(|(_, a), (_, b)| b.changes().cmp(&a.changes()).then(b.bytes.cmp(&a.bytes)));
+    println!("directories with the most changes:");
+    for (directory, churn) in directories.iter().take(top) {
+        println!(
+            "└ {}: {} changes ({} added, {} modified, {} moved, {} removed, {:.1} GiB)",
+            match directory.as_os_str().is_empty() {
+                true => "the top directory".into(),
+                false => directory.to_string_lossy(),
+            },
+            churn.changes(),
+            churn.added,
+            churn.modified,
+            churn.moved,
+            churn.removed,
+            gib(churn.bytes)
+        );
+    }
+    if directories.len() > top {
+        println!("└ and {} more", directories.len() - top);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_and_churn_per_directory() {
+        let older = vec![
+            FileInfo::new("empty", [0; 32], 0),
+            FileInfo::new("keep", [6; 32], 1),
+            FileInfo::new("notes", [1; 32], 10),
+            FileInfo::new("old/a", [2; 32], 100),
+            FileInfo::new("old/b", [3; 32], 200),
+            FileInfo::new("scan", [4; 32], 50),
+        ];
+        let newer = vec![
+            FileInfo::new("keep", [6; 32], 1),
+            FileInfo::new("new/a", [2; 32], 100),
+            FileInfo::new("new/b", [3; 32], 200),
+            FileInfo::new("new/empty", [0; 32], 0),
+            FileInfo::new("notes", [5; 32], 20),
+        ];
+        let drift = drift(&older, &newer);
+        let paths = |files: &[&FileInfo]| {
+            files
+                .iter()
+                .map(|fi| fi.rel_path.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&drift.added), vec!["new/empty"]);
+        assert_eq!(paths(&drift.removed), vec!["empty", "scan"]);
+        assert_eq!(
+            drift.moved,
+            vec![(&older[3], &newer[1]), (&older[4], &newer[2])]
+        );
+        assert_eq!(drift.modified, vec![(&older[2], &newer[4])]);
+        assert_eq!(drift.unchanged, 1);
+        assert_eq!(
+            drift.by_directory[Path::new("")],
+            DirectoryChurn {
+                added: 0,
+                modified: 1,
+                moved: 0,
+                removed: 2,
+                bytes: 70,
+            }
+        );
+        assert_eq!(drift.by_directory[Path::new("old")].moved, 2);
+        assert_eq!(drift.by_directory[Path::new("new")].changes(), 3);
+    }
+}
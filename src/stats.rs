@@ -1,9 +1,82 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crate::file_info::FileInfo;
 
-#[derive(Default, Clone)]
+/// Name files without an extension are reported under
+const NO_EXTENSION: &str = "(none)";
+
+/// Upper bounds of the file size buckets, the last bucket holds everything larger
+pub const SIZE_BUCKETS: [u64; 4] = [64 << 10, 1 << 20, 100 << 20, 1 << 30];
+
+/// Number of files per size bucket
+pub type SizeHistogram = [u64; SIZE_BUCKETS.len() + 1];
+
+/// Index of the size bucket a file of len bytes belongs to
+pub fn size_bucket(len: u64) -> usize {
+    SIZE_BUCKETS
+        .iter()
+        .position(|&bound| len < bound)
+        .unwrap_or(SIZE_BUCKETS.len())
+}
+
+/// Extensions reported together under one name, e.g. `video` for mkv and mp4
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtensionGroup {
+    pub name: String,
+    pub extensions: Vec<String>,
+}
+
+/// Counters for the files of one extension or group of extensions
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtensionStats {
+    pub files: u64,
+    pub bytes: u64,
+    pub files_new: u64,
+    pub bytes_new: u64,
+    pub files_modified: u64,
+    pub bytes_modified: u64,
+}
+
+/// A file that took long to read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowFile {
+    pub rel_path: PathBuf,
+    pub len: u64,
+    /// Time it took to read and hash the file
+    pub duration: Duration,
+}
+
+impl SlowFile {
+    /// Effective read speed in bytes per second
+    pub fn bytes_per_second(&self) -> f64 {
+        self.len as f64 / self.duration.as_secs_f64()
+    }
+}
+
+/// Time spent in the phases of a run
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    /// Loading the previous state
+    pub load_state: Duration,
+    /// Listing files and getting their metadata
+    pub walk: Duration,
+    /// Listing files was paused because enough files were queued for reading,
+    /// including waiting for the last files to be read
+    pub wait_for_reads: Duration,
+    /// From the start of listing files until all files were read
+    pub check: Duration,
+    /// Writing the new state, zero for verify
+    pub write_state: Duration,
+    /// Time every thread spent reading files, by device and thread index
+    pub workers: BTreeMap<(u64, usize), Duration>,
+}
+
+/// Counters collected during a run
+#[derive(Debug, Default, Clone)]
 pub struct Stats {
     pub bytes_read: u64,
     pub files_checked: u64,
@@ -14,19 +87,204 @@ pub struct Stats {
     pub files_duplicate_removed: u64,
     pub files_unchanged: u64,
     pub files_unchanged_size: u64,
+    /// Files that couldn't be read, not counting locked files skipped
+    pub files_unreadable: u64,
+    /// Files skipped because another program locked them, only with skip_locked
+    pub files_locked: u64,
+    /// Size of all files found, only known after the state is written
+    pub logical_size: u64,
+    /// Size of all files found, counting every distinct digest once
+    pub unique_size: u64,
+    /// Files found, by lower case extension or group name
+    pub by_extension: BTreeMap<String, ExtensionStats>,
+    /// Sizes of all files found, see [`SIZE_BUCKETS`]
+    pub sizes_checked: SizeHistogram,
+    /// Sizes of modified files
+    pub sizes_modified: SizeHistogram,
+    /// Files that took the longest to read, slowest first
+    pub slowest_files: Vec<SlowFile>,
+    pub timings: Timings,
 }
 
-#[derive(Clone)]
+/// Quotes s as a JSON string
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn json_list<T>(items: impl IntoIterator<Item = T>, f: impl Fn(T) -> String) -> String {
+    let items = items.into_iter().map(f).collect::<Vec<_>>();
+    format!("[{}]", items.join(", "))
+}
+
+impl Stats {
+    /// Writes everything as a JSON object, for graphing runs over time
+    pub fn write_json(
+        &self,
+        duration: Duration,
+        run_id: Option<&str>,
+        to: &mut dyn Write,
+    ) -> io::Result<()> {
+        writeln!(to, "{{")?;
+        if let Some(run_id) = run_id {
+            writeln!(to, "  \"run_id\": {},", json_string(run_id))?;
+        }
+        writeln!(to, "  \"duration_secs\": {},", duration.as_secs_f64())?;
+        writeln!(
+            to,
+            "  \"bytes_per_second\": {},",
+            self.bytes_read as f64 / duration.as_secs_f64().max(f64::MIN_POSITIVE)
+        )?;
+        for (key, value) in [
+            ("bytes_read", self.bytes_read),
+            ("files_checked", self.files_checked),
+            ("files_read", self.files_read),
+            ("files_new", self.files_new),
+            ("files_modified", self.files_modified),
+            ("files_not_found", self.files_not_found),
+            ("files_duplicate_removed", self.files_duplicate_removed),
+            ("files_unchanged", self.files_unchanged),
+            ("files_unchanged_size", self.files_unchanged_size),
+            ("files_unreadable", self.files_unreadable),
+            ("files_locked", self.files_locked),
+            ("logical_size", self.logical_size),
+            ("unique_size", self.unique_size),
+        ] {
+            writeln!(to, "  \"{}\": {},", key, value)?;
+        }
+        let by_extension = self
+            .by_extension
+            .iter()
+            .map(|(extension, e)| {
+                format!(
+                    "    {}: {{\"files\": {}, \"bytes\": {}, \"files_new\": {}, \"bytes_new\": {}, \"files_modified\": {}, \"bytes_modified\": {}}}",
+                    json_string(extension),
+                    e.files,
+                    e.bytes,
+                    e.files_new,
+                    e.bytes_new,
+                    e.files_modified,
+                    e.bytes_modified
+                )
+            })
+            .collect::<Vec<_>>();
+        writeln!(
+            to,
+            "  \"by_extension\": {{\n{}\n  }},",
+            by_extension.join(",\n")
+        )?;
+        writeln!(
+            to,
+            "  \"size_buckets\": {},",
+            json_list(SIZE_BUCKETS, |b| b.to_string())
+        )?;
+        writeln!(
+            to,
+            "  \"sizes_checked\": {},",
+            json_list(self.sizes_checked, |n| n.to_string())
+        )?;
+        writeln!(
+            to,
+            "  \"sizes_modified\": {},",
+            json_list(self.sizes_modified, |n| n.to_string())
+        )?;
+        writeln!(
+            to,
+            "  \"slowest_files\": {},",
+            json_list(&self.slowest_files, |f| format!(
+                "{{\"path\": {}, \"len\": {}, \"duration_secs\": {}}}",
+                json_string(&f.rel_path.to_string_lossy()),
+                f.len,
+                f.duration.as_secs_f64()
+            ))
+        )?;
+        let t = &self.timings;
+        writeln!(
+            to,
+            "  \"timings\": {{\"load_state_secs\": {}, \"walk_secs\": {}, \"wait_for_reads_secs\": {}, \"check_secs\": {}, \"write_state_secs\": {}, \"workers\": {}}}",
+            t.load_state.as_secs_f64(),
+            t.walk.as_secs_f64(),
+            t.wait_for_reads.as_secs_f64(),
+            t.check.as_secs_f64(),
+            t.write_state.as_secs_f64(),
+            json_list(&t.workers, |((device, thread), busy)| format!(
+                "{{\"device\": {}, \"thread\": {}, \"busy_secs\": {}}}",
+                device,
+                thread,
+                busy.as_secs_f64()
+            ))
+        )?;
+        writeln!(to, "}}")
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct StatsCollector {
     stats: Arc<Mutex<Stats>>,
+    /// Group names by lower case extension
+    extension_groups: Arc<HashMap<String, String>>,
+    /// Number of slowest files to keep track of
+    slowest_files: usize,
 }
 
 impl StatsCollector {
     pub fn new() -> Self {
         StatsCollector {
             stats: Arc::new(Mutex::new(Stats::default())),
+            extension_groups: Arc::default(),
+            slowest_files: 0,
+        }
+    }
+
+    /// Reports the extensions of every group under the name of the group
+    pub fn with_extension_groups(self, groups: &[ExtensionGroup]) -> Self {
+        let extension_groups = groups
+            .iter()
+            .flat_map(|group| {
+                group
+                    .extensions
+                    .iter()
+                    .map(|extension| (extension.to_lowercase(), group.name.clone()))
+            })
+            .collect();
+        StatsCollector {
+            extension_groups: Arc::new(extension_groups),
+            ..self
+        }
+    }
+
+    /// Keeps track of the files that took the longest to read
+    pub fn with_slowest_files(self, slowest_files: usize) -> Self {
+        StatsCollector {
+            slowest_files,
+            ..self
+        }
+    }
+
+    fn extension_group(&self, path: &Path) -> String {
+        let extension = match path.extension() {
+            Some(extension) => extension.to_string_lossy().to_lowercase(),
+            None => return NO_EXTENSION.to_owned(),
+        };
+        match self.extension_groups.get(&extension) {
+            Some(name) => name.clone(),
+            None => extension,
         }
     }
+
     pub fn files_checked(&self, amount: u64) {
         let mut s = self.stats.lock().unwrap();
         s.files_checked += amount;
@@ -38,77 +296,200 @@ impl StatsCollector {
     pub fn file_not_found(&self) {
         self.files_not_found(1)
     }
+    pub fn file_unreadable(&self) {
+        let mut s = self.stats.lock().unwrap();
+        s.files_unreadable += 1;
+    }
+    pub fn file_locked(&self) {
+        let mut s = self.stats.lock().unwrap();
+        s.files_locked += 1;
+    }
     pub fn duplicates_removed(&self, amount: u64) {
         let mut s = self.stats.lock().unwrap();
         s.files_duplicate_removed += amount;
     }
+    pub fn content_sizes(&self, logical_size: u64, unique_size: u64) {
+        let mut s = self.stats.lock().unwrap();
+        s.logical_size = logical_size;
+        s.unique_size = unique_size;
+    }
     pub fn file_unchanged(&self, file_info: &FileInfo) {
+        let group = self.extension_group(&file_info.rel_path);
         let mut s = self.stats.lock().unwrap();
         s.files_unchanged += 1;
         s.files_unchanged_size += file_info.len;
+        s.sizes_checked[size_bucket(file_info.len)] += 1;
+        let e = s.by_extension.entry(group).or_default();
+        e.files += 1;
+        e.bytes += file_info.len;
     }
     pub fn file_read_unmodifed(&self, file_info: &FileInfo) {
-        println!("  {:}", file_info.rel_path.to_string_lossy());
+        let group = self.extension_group(&file_info.rel_path);
         let mut s = self.stats.lock().unwrap();
         s.files_read += 1;
         s.bytes_read += file_info.len;
         s.files_unchanged += 1;
         s.files_unchanged_size += file_info.len;
+        s.sizes_checked[size_bucket(file_info.len)] += 1;
+        let e = s.by_extension.entry(group).or_default();
+        e.files += 1;
+        e.bytes += file_info.len;
+    }
+    /// An unmodified file that passed all cheap tiers, it wasn't read completely
+    pub fn file_passed_tiers(&self, file_info: &FileInfo) {
+        let group = self.extension_group(&file_info.rel_path);
+        let mut s = self.stats.lock().unwrap();
+        s.files_unchanged += 1;
+        s.files_unchanged_size += file_info.len;
+        s.sizes_checked[size_bucket(file_info.len)] += 1;
+        let e = s.by_extension.entry(group).or_default();
+        e.files += 1;
+        e.bytes += file_info.len;
     }
     pub fn file_read_modified(&self, file_info: &FileInfo) {
-        println!("M {:}", file_info.rel_path.to_string_lossy());
+        let group = self.extension_group(&file_info.rel_path);
         let mut s = self.stats.lock().unwrap();
         s.files_read += 1;
         s.bytes_read += file_info.len;
         s.files_modified += 1;
+        s.sizes_modified[size_bucket(file_info.len)] += 1;
+        s.sizes_checked[size_bucket(file_info.len)] += 1;
+        let e = s.by_extension.entry(group).or_default();
+        e.files += 1;
+        e.bytes += file_info.len;
+        e.files_modified += 1;
+        e.bytes_modified += file_info.len;
     }
     pub fn file_read_new(&self, file_info: &FileInfo) {
-        println!("+ {:}", file_info.rel_path.to_string_lossy());
+        let group = self.extension_group(&file_info.rel_path);
         let mut s = self.stats.lock().unwrap();
         s.files_read += 1;
         s.bytes_read += file_info.len;
         s.files_new += 1;
+        s.sizes_checked[size_bucket(file_info.len)] += 1;
+        let e = s.by_extension.entry(group).or_default();
+        e.files += 1;
+        e.bytes += file_info.len;
+        e.files_new += 1;
+        e.bytes_new += file_info.len;
+    }
+    pub fn file_read_duration(&self, file_info: &FileInfo, duration: Duration) {
+        if self.slowest_files == 0 {
+            return;
+        }
+        let mut s = self.stats.lock().unwrap();
+        let slowest = &mut s.slowest_files;
+        if slowest.len() == self.slowest_files && slowest[slowest.len() - 1].duration >= duration {
+            return;
+        }
+        let position = slowest.partition_point(|slow_file| slow_file.duration >= duration);
+        slowest.insert(
+            position,
+            SlowFile {
+                rel_path: file_info.rel_path.clone(),
+                len: file_info.len,
+                duration,
+            },
+        );
+        slowest.truncate(self.slowest_files);
+    }
+    /// Adds the time the current thread of the pool for device spent on a file
+    pub fn worker_busy(&self, device: u64, duration: Duration) {
+        let thread = rayon::current_thread_index().unwrap_or_default();
+        let mut s = self.stats.lock().unwrap();
+        *s.timings.workers.entry((device, thread)).or_default() += duration;
+    }
+    pub fn update_timings(&self, f: impl FnOnce(&mut Timings)) {
+        let mut s = self.stats.lock().unwrap();
+        f(&mut s.timings);
     }
     pub fn get_results(&self) -> Stats {
         let s = self.stats.lock().unwrap();
         s.clone()
     }
-    pub fn print_results_for_update(&self, duration: Duration, newly_missing: u64) {
-        let r = self.get_results();
-        println!("{} files checked in {:.1?}:", r.files_checked, duration,);
+}
 
-        println!(
-            "└ {} files read ({:.1} GiB, {:.0} MiB/s):",
-            r.files_read,
-            (r.bytes_read as f64) / 1024.0 / 1024.0 / 1024.0,
-            (r.bytes_read as f64) / 1024.0 / 1024.0 / duration.as_secs_f64(),
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        println!("  └ {} new files", r.files_new);
-        println!("  └ {} files modified", r.files_modified,);
-        println!("└ {} files not found:", r.files_not_found,);
-        println!(
-            "  └ {} files found elsewhere (moved or duplicates removed)",
-            r.files_duplicate_removed,
-        );
-        println!("  └ {} files newly missing", newly_missing,);
-        println!(
-            "{} files unchanged ({:.1} GiB)",
-            r.files_unchanged,
-            r.files_unchanged_size as f64 / 1024.0 / 1024.0 / 1024.0,
+    #[test]
+    fn stats_by_extension() {
+        let stats = StatsCollector::new().with_extension_groups(&[ExtensionGroup {
+            name: "video".to_owned(),
+            extensions: vec!["mkv".to_owned(), "MP4".to_owned()],
+        }]);
+        stats.file_read_new(&FileInfo::new("a.MKV", [0; 32], 100));
+        stats.file_read_new(&FileInfo::new("b.mp4", [0; 32], 50));
+        stats.file_unchanged(&FileInfo::new("c.jpg", [0; 32], 10));
+        stats.file_read_modified(&FileInfo::new("d.JPG", [0; 32], 20));
+        stats.file_unchanged(&FileInfo::new("README", [0; 32], 1));
+
+        let by_extension = stats.get_results().by_extension;
+        assert_eq!(by_extension.len(), 3);
+        assert_eq!(by_extension["video"].files_new, 2);
+        assert_eq!(by_extension["video"].bytes_new, 150);
+        assert_eq!(by_extension["jpg"].bytes, 30);
+        assert_eq!(by_extension["jpg"].files_modified, 1);
+        assert_eq!(by_extension[NO_EXTENSION].files, 1);
+    }
+
+    #[test]
+    fn size_histogram() {
+        assert_eq!(size_bucket(0), 0);
+        assert_eq!(size_bucket((64 << 10) - 1), 0);
+        assert_eq!(size_bucket(64 << 10), 1);
+        assert_eq!(size_bucket(5 << 30), SIZE_BUCKETS.len());
+
+        let stats = StatsCollector::new();
+        stats.file_unchanged(&FileInfo::new("a", [0; 32], 10));
+        stats.file_read_modified(&FileInfo::new("b", [0; 32], 2 << 20));
+        let results = stats.get_results();
+        assert_eq!(results.sizes_checked, [1, 0, 1, 0, 0]);
+        assert_eq!(results.sizes_modified, [0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn slowest_files() {
+        let stats = StatsCollector::new().with_slowest_files(2);
+        for (path, millis) in [("a", 30), ("b", 10), ("c", 50), ("d", 20)] {
+            stats.file_read_duration(
+                &FileInfo::new(path, [0; 32], 1000),
+                Duration::from_millis(millis),
+            );
+        }
+        let slowest = stats.get_results().slowest_files;
+        assert_eq!(
+            slowest
+                .iter()
+                .map(|f| f.rel_path.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["c", "a"]
         );
+        assert_eq!(slowest[0].bytes_per_second(), 20000.0);
     }
 
-    pub(crate) fn print_results_for_verify(&self, duration: Duration) {
-        let r = self.get_results();
-        println!("{} files in archive:", r.files_checked);
+    #[test]
+    fn stats_as_json() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(json_string("a \"b\"\\\n"), r#""a \"b\"\\\n""#);
 
-        println!(
-            "└ {} files read in {:.1?} ({:.1} GiB, {:.0} MiB/s)",
-            r.files_read,
-            duration,
-            (r.bytes_read as f64) / 1024.0 / 1024.0 / 1024.0,
-            (r.bytes_read as f64) / 1024.0 / 1024.0 / duration.as_secs_f64(),
+        let stats = StatsCollector::new().with_slowest_files(1);
+        stats.file_read_new(&FileInfo::new("photo \"1\".jpg", [0; 32], 10));
+        stats.file_read_duration(
+            &FileInfo::new("photo \"1\".jpg", [0; 32], 10),
+            Duration::from_secs(1),
         );
+        let mut json = Vec::new();
+        stats
+            .get_results()
+            .write_json(Duration::from_secs(2), Some("0123abcd"), &mut json)?;
+        let json = String::from_utf8(json)?;
+        assert!(json.starts_with("{\n  \"run_id\": \"0123abcd\",\n"));
+        assert!(json.contains("\"bytes_per_second\": 5,"));
+        assert!(json.contains("\"files_new\": 1,"));
+        assert!(json.contains("\"jpg\": {\"files\": 1, \"bytes\": 10,"));
+        assert!(json.contains(r#"{"path": "photo \"1\".jpg", "len": 10, "duration_secs": 1}"#));
+        assert!(json.ends_with("}\n"));
+        Ok(())
     }
 }
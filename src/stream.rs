@@ -0,0 +1,141 @@
+use crate::archive::{Archive, UpdateOptions, UpdateReport, VerifyOptions, VerifyReport};
+use crate::file_check::FileCheckResult;
+use crate::observer::Observer;
+use crate::stats::Stats;
+
+use anyhow::Result;
+use futures_core::Stream;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// Progress of an update or verify, mirroring the methods of [`Observer`]
+#[derive(Debug, Clone)]
+pub enum Progress {
+    FileStarted(PathBuf),
+    Result(FileCheckResult),
+    Summary(Stats, Duration),
+    Message(String),
+}
+
+/// Forwards everything to the stream
+struct ChannelObserver {
+    sender: UnboundedSender<Progress>,
+}
+
+impl Observer for ChannelObserver {
+    // Sending fails if the stream was dropped, which is fine
+    fn on_file_start(&self, rel_path: &Path) {
+        self.sender
+            .send(Progress::FileStarted(rel_path.to_path_buf()))
+            .ok();
+    }
+
+    fn on_result(&self, result: &FileCheckResult) {
+        self.sender.send(Progress::Result(result.clone())).ok();
+    }
+
+    fn on_summary(&self, stats: &Stats, duration: Duration) {
+        self.sender
+            .send(Progress::Summary(stats.clone(), duration))
+            .ok();
+    }
+
+    fn on_message(&self, message: &str) {
+        self.sender.send(Progress::Message(message.to_owned())).ok();
+    }
+}
+
+/// Stream of progress of an update or verify running in the background
+///
+/// The stream ends once the run is done, [`ProgressStream::finish`] then
+/// returns the report.
+pub struct ProgressStream<R> {
+    progress: UnboundedReceiver<Progress>,
+    task: JoinHandle<Result<R>>,
+}
+
+impl<R> ProgressStream<R> {
+    /// Waits for the run to finish and returns its report
+    ///
+    /// Progress not consumed yet is discarded.
+    pub async fn finish(self) -> Result<R> {
+        self.task.await?
+    }
+}
+
+impl<R> Stream for ProgressStream<R> {
+    type Item = Progress;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Progress>> {
+        self.progress.poll_recv(cx)
+    }
+}
+
+fn spawn<R: Send + 'static>(
+    archive: Archive,
+    run: impl FnOnce(&Archive) -> Result<R> + Send + 'static,
+) -> ProgressStream<R> {
+    let (sender, progress) = unbounded_channel();
+    let archive = archive.with_observer(Arc::new(ChannelObserver { sender }));
+    // Runs take hours for large archives, so they can't block a runtime thread
+    let task = tokio::task::spawn_blocking(move || run(&archive));
+    ProgressStream { progress, task }
+}
+
+impl Archive {
+    /// Like [`Archive::update`], but runs in the background and streams progress
+    ///
+    /// Must be called from within a tokio runtime. Replaces the observer
+    /// set with [`Archive::with_observer`].
+    pub fn update_stream(self, options: UpdateOptions) -> ProgressStream<UpdateReport> {
+        spawn(self, move |archive| archive.update(&options))
+    }
+
+    /// Like [`Archive::verify`], but runs in the background and streams progress
+    ///
+    /// Must be called from within a tokio runtime. Replaces the observer
+    /// set with [`Archive::with_observer`].
+    pub fn verify_stream(self, options: VerifyOptions) -> ProgressStream<VerifyReport> {
+        spawn(self, move |archive| archive.verify(&options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::ArchiveOptions;
+    use crate::test_dir::TestDir;
+    use std::future::poll_fn;
+
+    #[tokio::test]
+    async fn update_stream() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("stream");
+        let files_dir = dir.join("files");
+        let state_dir = dir.join("state");
+        std::fs::create_dir_all(&files_dir)?;
+        std::fs::create_dir_all(&state_dir)?;
+        for i in 0..5 {
+            std::fs::write(
+                files_dir.join(format!("file {}", i)),
+                format!("contents {}", i),
+            )?;
+        }
+
+        let archive = Archive::new(&state_dir, &files_dir, ArchiveOptions::default());
+        let mut stream = archive.update_stream(UpdateOptions::default());
+        let mut results = 0;
+        while let Some(progress) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+            if let Progress::Result(FileCheckResult::New(_)) = progress {
+                results += 1;
+            }
+        }
+        assert_eq!(results, 5);
+        assert_eq!(stream.finish().await?.stats.files_new, 5);
+        Ok(())
+    }
+}
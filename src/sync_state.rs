@@ -0,0 +1,587 @@
+use rusty_archive::checksum::{verify_sidecar, HashingReader};
+
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What a sync did
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Synced {
+    copied: u64,
+    bytes_copied: u64,
+    up_to_date: u64,
+}
+
+/// Opens a file to read back what was written to it from the disk, not the page cache
+///
+/// Linux drops the cached pages of the file, they're clean after sync_all.
+/// macOS reads the descriptor uncached. Elsewhere it may come from the cache.
+fn open_uncached(path: &Path) -> io::Result<File> {
+    let f = File::open(path)?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let errno = unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::fcntl(f.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(f)
+}
+
+/// SHA-256 of a file, None if it doesn't exist
+///
+/// Copies are read with open_uncached, so a damaged copy isn't hidden by
+/// the cached pages of what was written.
+fn digest_of(path: &Path, copy: bool) -> Result<Option<[u8; 32]>> {
+    let opened = if copy {
+        open_uncached(path)
+    } else {
+        File::open(path)
+    };
+    let f = match opened {
+        Ok(f) => f,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Unable to open {:?}", path)),
+    };
+    let mut reader = HashingReader::new(f);
+    io::copy(&mut reader, &mut io::sink()).with_context(|| format!("Unable to read {:?}", path))?;
+    Ok(Some(reader.finalize()))
+}
+
+/// Copies from to to and syncs it, returning the digest and length of what was copied
+fn copy_hashed(from: &Path, to: &Path) -> Result<([u8; 32], u64)> {
+    let mut reader =
+        HashingReader::new(File::open(from).with_context(|| format!("Unable to open {:?}", from))?);
+    let mut writer =
+        BufWriter::new(File::create(to).with_context(|| format!("Unable to create {:?}", to))?);
+    let len = io::copy(&mut reader, &mut writer)
+        .with_context(|| format!("Unable to copy {:?} to {:?}", from, to))?;
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("Unable to write {:?}", to))?;
+    Ok((reader.finalize(), len))
+}
+
+/// Copies from to to through a temporary file next to it, returning the digest and length of what was copied
+///
+/// The copy is read back and only renamed once it matches, so DEST never
+/// holds a partial or damaged file under the name of a good one.
+fn copy_verified(from: &Path, to: &Path) -> Result<([u8; 32], u64)> {
+    let mut tmp_name = to.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = to.with_file_name(tmp_name);
+    let (digest, len) = copy_hashed(from, &tmp)?;
+    if digest_of(&tmp, true)? != Some(digest) {
+        fs::remove_file(&tmp)?;
+        return Err(anyhow::Error::msg(format!(
+            "the copy of {:?} at {:?} doesn't match the original when read back",
+            from, tmp
+        )));
+    }
+    fs::rename(&tmp, to).with_context(|| format!("Unable to rename {:?} to {:?}", tmp, to))?;
+    Ok((digest, len))
+}
+
+/// A file in the temporary directory, removed when dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> Scratch {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Scratch(std::env::temp_dir().join(format!(
+            "rusty-archive-sync-state-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        )))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Runs a command to completion, its output is captured
+fn output(command: &mut Command) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    command
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Unable to run {}, is it installed?", program))
+}
+
+/// Fails with what the command printed to stderr if it didn't succeed
+fn succeeded(output: Output, what: &str) -> Result<()> {
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(anyhow::Error::msg(format!(
+        "{} failed ({}): {}",
+        what,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    )))
+}
+
+/// A destination outside the local filesystem, files are accessed by name
+trait Remote {
+    /// Downloads `name` to `to`, false if there's no such file
+    fn download(&self, name: &str, to: &Path) -> Result<bool>;
+    fn upload(&self, from: &Path, name: &str) -> Result<()>;
+    /// Renames `from` to `to`, replacing it
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    fn remove(&self, name: &str) -> Result<()>;
+}
+
+/// SHA-256 of a remote file, None if it doesn't exist
+fn remote_digest_of(remote: &impl Remote, name: &str) -> Result<Option<[u8; 32]>> {
+    let scratch = Scratch::new();
+    if !remote.download(name, &scratch.0)? {
+        return Ok(None);
+    }
+    digest_of(&scratch.0, false)
+}
+
+/// Uploads from as name and downloads it again, returning the digest and length of what was uploaded
+///
+/// What's uploaded is copied from a snapshot, so what's downloaded is
+/// compared to exactly the bytes sent. Like copy_verified it goes to a
+/// temporary name first and is only renamed once it matched.
+fn remote_copy_verified(remote: &impl Remote, from: &Path, name: &str) -> Result<([u8; 32], u64)> {
+    let snapshot = Scratch::new();
+    let (digest, len) = copy_hashed(from, &snapshot.0)?;
+    let tmp = format!("{}.tmp", name);
+    remote.upload(&snapshot.0, &tmp)?;
+    if remote_digest_of(remote, &tmp)? != Some(digest) {
+        remote.remove(&tmp)?;
+        return Err(anyhow::Error::msg(format!(
+            "the copy of {:?} at {:?} doesn't match the original when downloaded again",
+            from, tmp
+        )));
+    }
+    remote.rename(&tmp, name)?;
+    Ok((digest, len))
+}
+
+/// A directory on an SFTP server, reached with the sftp of OpenSSH
+///
+/// It runs in batch mode, which can't ask for passwords, so the server
+/// has to accept a key. Renaming over a file needs the posix-rename
+/// extension, OpenSSH servers have it.
+#[derive(Debug, PartialEq, Eq)]
+struct Sftp {
+    /// `host` or `user@host`
+    host: String,
+    port: Option<u16>,
+    /// Relative to the directory logged into unless it starts with `/`
+    dir: String,
+}
+
+/// Quotes a path for an sftp batch, which otherwise splits at spaces and expands globs
+fn sftp_quote(path: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in path.chars() {
+        if matches!(c, '"' | '\\' | '*' | '?' | '[' | ']') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Sftp {
+    fn path(&self, name: &str) -> String {
+        if self.dir.is_empty() {
+            name.to_owned()
+        } else if self.dir.ends_with('/') {
+            format!("{}{}", self.dir, name)
+        } else {
+            format!("{}/{}", self.dir, name)
+        }
+    }
+
+    /// Runs the commands of batch, failing if one of them does unless it starts with `-`
+    fn batch(&self, batch: &str) -> Result<()> {
+        let mut command = Command::new("sftp");
+        command.args(["-q", "-b", "-"]);
+        if let Some(port) = self.port {
+            command.args(["-P", &port.to_string()]);
+        }
+        let mut child = command
+            .arg(&self.host)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Unable to run sftp, is it installed?")?;
+        let mut stdin = child.stdin.take().unwrap();
+        // Fails if sftp exited early, like when it couldn't connect, waiting tells why
+        let _ = stdin.write_all(batch.as_bytes());
+        drop(stdin);
+        succeeded(child.wait_with_output()?, &format!("sftp to {}", self.host))
+    }
+
+    /// Creates the directory and all of its parents
+    fn create(&self) -> Result<()> {
+        let mut batch = String::new();
+        for (end, _) in self.dir.match_indices('/').chain([(self.dir.len(), "")]) {
+            let parent = &self.dir[..end];
+            // Existing ones fail to be created, that's ignored
+            if !parent.is_empty() {
+                batch.push_str(&format!("-mkdir {}\n", sftp_quote(parent)));
+            }
+        }
+        self.batch(&batch)
+    }
+}
+
+impl Remote for Sftp {
+    fn download(&self, name: &str, to: &Path) -> Result<bool> {
+        let to = to.to_string_lossy();
+        // A missing file fails to be downloaded and nothing is written to `to`
+        self.batch(&format!(
+            "-get {} {}\n",
+            sftp_quote(&self.path(name)),
+            sftp_quote(&to)
+        ))?;
+        Ok(Path::new(&*to).exists())
+    }
+
+    fn upload(&self, from: &Path, name: &str) -> Result<()> {
+        self.batch(&format!(
+            "put {} {}\n",
+            sftp_quote(&from.to_string_lossy()),
+            sftp_quote(&self.path(name))
+        ))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.batch(&format!(
+            "rename {} {}\n",
+            sftp_quote(&self.path(from)),
+            sftp_quote(&self.path(to))
+        ))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.batch(&format!("rm {}\n", sftp_quote(&self.path(name))))
+    }
+}
+
+/// A prefix in an S3 bucket, reached with the AWS CLI
+///
+/// Credentials, the region and a custom endpoint are whatever the AWS CLI
+/// is configured to use, like with `AWS_PROFILE` or `AWS_ENDPOINT_URL`.
+#[derive(Debug, PartialEq, Eq)]
+struct S3 {
+    /// `s3://bucket` or `s3://bucket/prefix`, without a trailing `/`
+    url: String,
+}
+
+impl S3 {
+    fn url(&self, name: &str) -> String {
+        format!("{}/{}", self.url, name)
+    }
+
+    fn aws(&self, args: &[&str], what: &str) -> Result<()> {
+        succeeded(
+            output(
+                Command::new("aws")
+                    .args(["s3", "--only-show-errors"])
+                    .args(args),
+            )?,
+            what,
+        )
+    }
+}
+
+impl Remote for S3 {
+    fn download(&self, name: &str, to: &Path) -> Result<bool> {
+        let url = self.url(name);
+        let output = output(
+            Command::new("aws")
+                .args(["s3", "--only-show-errors", "cp", &url])
+                .arg(to),
+        )?;
+        if !output.status.success() && String::from_utf8_lossy(&output.stderr).contains("(404)") {
+            return Ok(false);
+        }
+        succeeded(output, &format!("Downloading {}", url))?;
+        Ok(true)
+    }
+
+    fn upload(&self, from: &Path, name: &str) -> Result<()> {
+        let url = self.url(name);
+        self.aws(
+            &["cp", &from.to_string_lossy(), &url],
+            &format!("Uploading {}", url),
+        )
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (self.url(from), self.url(to));
+        self.aws(&["mv", &from, &to], &format!("Moving {} to {}", from, to))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let url = self.url(name);
+        self.aws(&["rm", &url], &format!("Removing {}", url))
+    }
+}
+
+/// Where sync-state copies the state directory to
+#[derive(Debug, PartialEq, Eq)]
+enum Dest {
+    /// A directory, like a mounted NAS share
+    Local(PathBuf),
+    /// `sftp://[user@]host[:port]/path`, `/~/path` is relative to the home directory
+    Sftp(Sftp),
+    /// `s3://bucket/prefix`
+    S3(S3),
+}
+
+impl Dest {
+    fn parse(dest: &Path) -> Result<Dest> {
+        let url = dest.to_string_lossy();
+        let Some((scheme, rest)) = url.split_once("://").filter(|(scheme, _)| {
+            !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric())
+        }) else {
+            return Ok(Dest::Local(dest.to_path_buf()));
+        };
+        match scheme {
+            "sftp" => {
+                let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+                let (host, port) = match authority.rsplit_once(':') {
+                    Some((host, port)) => (
+                        host,
+                        Some(port.parse::<u16>().map_err(|err| {
+                            anyhow::Error::msg(format!("invalid port in {:?}: {}", url, err))
+                        })?),
+                    ),
+                    None => (authority, None),
+                };
+                // A host starting with `-` would be taken as an option of sftp
+                if host.is_empty() || host.starts_with('-') {
+                    return Err(anyhow::Error::msg(format!("invalid host in {:?}", url)));
+                }
+                let dir = match path.strip_prefix('~') {
+                    Some(home) => home.trim_start_matches('/').to_owned(),
+                    None => format!("/{}", path),
+                };
+                Ok(Dest::Sftp(Sftp {
+                    host: host.to_owned(),
+                    port,
+                    dir,
+                }))
+            }
+            "s3" => {
+                let path = rest.trim_end_matches('/');
+                if path.is_empty() || path.starts_with('/') {
+                    return Err(anyhow::Error::msg(format!("no bucket in {:?}", dest)));
+                }
+                Ok(Dest::S3(S3 {
+                    url: format!("s3://{}", path),
+                }))
+            }
+            _ => Err(anyhow::Error::msg(format!(
+                "{:?} is a URL sync-state doesn't support, only sftp:// and s3:// are. \
+                 Mount it, like with `rclone mount`, and pass the mount point instead",
+                dest
+            ))),
+        }
+    }
+
+    /// Creates the directory, S3 has no directories to create
+    fn create(&self) -> Result<()> {
+        match self {
+            Dest::Local(dir) => {
+                fs::create_dir_all(dir).with_context(|| format!("Unable to create {:?}", dir))
+            }
+            Dest::Sftp(sftp) => sftp.create(),
+            Dest::S3(_) => Ok(()),
+        }
+    }
+
+    /// SHA-256 of the copy of name, None if there's none
+    fn digest_of(&self, name: &str) -> Result<Option<[u8; 32]>> {
+        match self {
+            Dest::Local(dir) => digest_of(&dir.join(name), true),
+            Dest::Sftp(sftp) => remote_digest_of(sftp, name),
+            Dest::S3(s3) => remote_digest_of(s3, name),
+        }
+    }
+
+    /// Copies from to name, returning the digest and length of what was copied
+    fn copy_verified(&self, from: &Path, name: &str) -> Result<([u8; 32], u64)> {
+        match self {
+            Dest::Local(dir) => copy_verified(from, &dir.join(name)),
+            Dest::Sftp(sftp) => remote_copy_verified(sftp, from, name),
+            Dest::S3(s3) => remote_copy_verified(s3, from, name),
+        }
+    }
+}
+
+/// Copies all files of the state directory that DEST doesn't have as they are
+///
+/// Files already at DEST with the same SHA-256 are skipped. Files with a
+/// checksum sidecar are checked against it first, a corrupted state isn't
+/// copied over a good copy. Files only at DEST are left alone, so states
+/// removed by keep_states stay there.
+fn sync(state_dir: &Path, dest: &Dest) -> Result<Synced> {
+    dest.create()?;
+    let mut entries = fs::read_dir(state_dir)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .with_context(|| format!("Unable to list {:?}", state_dir))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut synced = Synced::default();
+    for entry in entries {
+        let file_name = entry.file_name();
+        // Spill files and copies of a run that's still going
+        if !entry.file_type()?.is_file() || file_name.to_string_lossy().ends_with(".tmp") {
+            continue;
+        }
+        let from = entry.path();
+        let name = file_name
+            .to_str()
+            .ok_or_else(|| anyhow::Error::msg(format!("{:?} isn't valid UTF-8", from)))?;
+        let Some(digest) = digest_of(&from, false)? else {
+            // Removed since it was listed, like by a concurrent compact
+            continue;
+        };
+        verify_sidecar(&from, &digest)?;
+        if dest.digest_of(name)? == Some(digest) {
+            synced.up_to_date += 1;
+            continue;
+        }
+        // The file may have changed since it was hashed, what was copied is checked again
+        let (copied, len) = dest.copy_verified(&from, name)?;
+        verify_sidecar(&from, &copied)?;
+        synced.copied += 1;
+        synced.bytes_copied += len;
+    }
+    Ok(synced)
+}
+
+/// Replicates the state directory to DEST, a local directory, SFTP server or S3 bucket
+///
+/// SFTP and S3 go through the sftp of OpenSSH and the AWS CLI, which have
+/// to be installed. Every copy is downloaded again to verify it.
+pub fn run(state_dir: &Path, dest: &Path) -> Result<()> {
+    let synced = sync(state_dir, &Dest::parse(dest)?)?;
+    println!(
+        "{} files ({} bytes) copied to {:?} and verified, {} up to date already",
+        synced.copied, synced.bytes_copied, dest, synced.up_to_date
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use rusty_archive::checksum::write_sidecar;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn only_changed_and_intact_files_copied() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("sync-state");
+        let state_dir = dir.join("state");
+        let dest = dir.join("copy");
+        fs::create_dir_all(&state_dir)?;
+        let state = state_dir.join("20240101 000000.state");
+        fs::write(&state, "# hash: sha256\n")?;
+        write_sidecar(&state, &Sha256::digest(fs::read(&state)?).into())?;
+        fs::write(state_dir.join("deleted.log"), "a\n")?;
+        fs::write(state_dir.join(".spill-1-0.tmp"), "spilled")?;
+
+        let synced = sync(&state_dir, &Dest::Local(dest.clone()))?;
+        assert_eq!((synced.copied, synced.up_to_date), (3, 0));
+        assert!(!dest.join(".spill-1-0.tmp").exists());
+        assert_eq!(fs::read(dest.join("deleted.log"))?, b"a\n");
+
+        // A damaged copy is replaced, the rest is left as it is
+        fs::write(dest.join("deleted.log"), "b\n")?;
+        let synced = sync(&state_dir, &Dest::Local(dest.clone()))?;
+        assert_eq!((synced.copied, synced.up_to_date), (1, 2));
+        assert_eq!(fs::read(dest.join("deleted.log"))?, b"a\n");
+
+        // A damaged state isn't copied over the good one
+        fs::write(&state, "# hash: sha257\n")?;
+        assert!(sync(&state_dir, &Dest::Local(dest.clone())).is_err());
+        assert_eq!(
+            fs::read(dest.join("20240101 000000.state"))?,
+            b"# hash: sha256\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dest_urls() -> Result<(), Box<dyn std::error::Error>> {
+        let local = |path: &str| Dest::Local(PathBuf::from(path));
+        let sftp = |host: &str, port, dir: &str| {
+            Dest::Sftp(Sftp {
+                host: host.to_owned(),
+                port,
+                dir: dir.to_owned(),
+            })
+        };
+        let parse = |dest: &str| Dest::parse(Path::new(dest));
+        assert_eq!(parse("/mnt/offsite/state")?, local("/mnt/offsite/state"));
+        assert_eq!(parse("backup/a://b")?, local("backup/a://b"));
+        assert_eq!(
+            parse("sftp://nas/srv/state")?,
+            sftp("nas", None, "/srv/state")
+        );
+        assert_eq!(
+            parse("sftp://me@nas:2222/~/state")?,
+            sftp("me@nas", Some(2222), "state")
+        );
+        assert_eq!(parse("sftp://nas")?, sftp("nas", None, "/"));
+        assert_eq!(
+            parse("s3://bucket/state/")?,
+            Dest::S3(S3 {
+                url: "s3://bucket/state".to_owned()
+            })
+        );
+        for invalid in [
+            "sftp://-oProxyCommand=x/state",
+            "sftp://nas:ssh/state",
+            "s3://",
+            "ftp://nas/state",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sftp_paths() {
+        let sftp = |dir: &str| Sftp {
+            host: "nas".to_owned(),
+            port: None,
+            dir: dir.to_owned(),
+        };
+        assert_eq!(sftp("").path("throughput"), "throughput");
+        assert_eq!(sftp("/").path("throughput"), "/throughput");
+        assert_eq!(sftp("/srv/state").path("a.state"), "/srv/state/a.state");
+        assert_eq!(
+            sftp_quote(r#"20240101 000000 "*?[x]\.state"#),
+            r#""20240101 000000 \"\*\?\[x\]\\.state""#
+        );
+    }
+}
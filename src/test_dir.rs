@@ -0,0 +1,43 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// An empty directory for a test in the temporary directory, removed when it's dropped
+///
+/// Named after the test and the process, so tests running at the same
+/// time don't share one. Also removed if the test fails, so failed runs
+/// don't leave directories behind.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!(
+            "rusty-archive-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        // Left behind by a run killed before it could clean up
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Unable to create the directory of the test");
+        TestDir(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::device_pools::{filesystem_uuid, DeviceId};
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the file in the state directory keeping the throughput of earlier runs
+pub const THROUGHPUT_NAME: &str = "throughput";
+
+/// Devices are only measured once they were read from for this long
+///
+/// Short reads are mostly served from the page cache and say little about
+/// the device itself.
+pub const MIN_MEASURED: Duration = Duration::from_secs(30);
+
+/// Bytes per second earlier runs read from each storage device
+///
+/// Devices are known by the UUID of their filesystem, device numbers can
+/// change with every boot. Stored one device per line as
+/// `<bytes per second> <device>`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Throughput {
+    by_device: BTreeMap<String, f64>,
+}
+
+/// Name a device is stored under, the UUID of the filesystem at path if known
+pub fn device_key(device: DeviceId, path: &Path) -> String {
+    filesystem_uuid(path).unwrap_or_else(|| format!("device {}", device))
+}
+
+impl Throughput {
+    /// Reads the throughput stored in a state directory, none if there's no such file
+    pub fn read(state_dir: &Path) -> Result<Throughput> {
+        let path = state_dir.join(THROUGHPUT_NAME);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Throughput::default()),
+            Err(err) => return Err(err).with_context(|| format!("Unable to read {:?}", path)),
+        };
+        let mut throughput = Throughput::default();
+        for line in contents.lines() {
+            let (rate, device) = line
+                .split_once(' ')
+                .and_then(|(rate, device)| Some((rate.parse().ok()?, device)))
+                .with_context(|| format!("invalid line in {:?}: '{}'", path, line))?;
+            throughput.by_device.insert(device.to_owned(), rate);
+        }
+        Ok(throughput)
+    }
+
+    pub fn write(&self, state_dir: &Path) -> Result<()> {
+        let path = state_dir.join(THROUGHPUT_NAME);
+        let contents = self
+            .by_device
+            .iter()
+            .map(|(device, rate)| format!("{:.0} {}\n", rate, device))
+            .collect::<String>();
+        std::fs::write(&path, contents).with_context(|| format!("Unable to write {:?}", path))
+    }
+
+    /// Bytes per second read from a device, None if it wasn't measured yet
+    pub fn get(&self, device: &str) -> Option<f64> {
+        self.by_device.get(device).copied()
+    }
+
+    /// Takes in what a run measured, weighted as much as all earlier runs together
+    ///
+    /// Recent runs count the most, a disk getting slower shows up after a
+    /// few runs.
+    pub fn record(&mut self, device: &str, bytes_per_sec: f64) {
+        let rate = match self.get(device) {
+            Some(earlier) => (earlier + bytes_per_sec) / 2.0,
+            None => bytes_per_sec,
+        };
+        self.by_device.insert(device.to_owned(), rate);
+    }
+}
+
+/// How far a run got reading the files it has to read, see [`crate::Observer::on_progress`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Size of the files read so far, or skipped
+    pub bytes_done: u64,
+    /// Size of all files to read, including those done already
+    pub bytes_queued: u64,
+    /// Whether all files are listed, bytes_queued only grows until then
+    pub listed: bool,
+    /// Time until all files queued are read, None if a device wasn't measured yet
+    pub remaining: Option<Duration>,
+}
+
+/// What's read from one device during a run
+#[derive(Debug)]
+struct DeviceProgress {
+    key: String,
+    queued: u64,
+    /// Size of the files queued that are done
+    done: u64,
+    /// Bytes actually read, less than done if files were skipped or only partly read
+    bytes_read: u64,
+    /// When the first file started being read
+    first_start: Option<Instant>,
+    last_end: Option<Instant>,
+}
+
+impl DeviceProgress {
+    fn elapsed(&self) -> Option<Duration> {
+        Some(self.last_end?.duration_since(self.first_start?))
+    }
+}
+
+/// Keeps track of the progress of reading files, per device
+///
+/// Until a device was read from for [`MIN_MEASURED`], the throughput of
+/// earlier runs is used to estimate the time left, afterwards what was
+/// read so far.
+#[derive(Debug)]
+pub(crate) struct ProgressTracker {
+    earlier: Throughput,
+    devices: Mutex<HashMap<DeviceId, DeviceProgress>>,
+    listed: Mutex<bool>,
+}
+
+impl ProgressTracker {
+    pub fn new(earlier: Throughput) -> Self {
+        ProgressTracker {
+            earlier,
+            devices: Mutex::new(HashMap::new()),
+            listed: Mutex::new(false),
+        }
+    }
+
+    /// Adds a file to read, path gives where it is if the device is new
+    pub fn queue(&self, device: DeviceId, path: impl FnOnce() -> PathBuf, len: u64) {
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .entry(device)
+            .or_insert_with(|| DeviceProgress {
+                key: device_key(device, &path()),
+                queued: 0,
+                done: 0,
+                bytes_read: 0,
+                first_start: None,
+                last_end: None,
+            })
+            .queued += len;
+    }
+
+    /// Notes that all files to read are queued
+    pub fn listed(&self) {
+        *self.listed.lock().unwrap() = true;
+    }
+
+    /// Notes that a file queued started being read
+    pub fn started(&self, device: DeviceId, at: Instant) {
+        if let Some(progress) = self.devices.lock().unwrap().get_mut(&device) {
+            progress.first_start.get_or_insert(at);
+        }
+    }
+
+    /// Notes that a file queued is done, returning the progress of the whole run
+    ///
+    /// len is the size it was queued with, so what's left adds up to the
+    /// size of the files not done yet. Only bytes_read counts towards the
+    /// throughput of the device.
+    pub fn finished(&self, device: DeviceId, len: u64, bytes_read: u64) -> Progress {
+        let now = Instant::now();
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(progress) = devices.get_mut(&device) {
+            progress.done += len;
+            progress.bytes_read += bytes_read;
+            progress.last_end = Some(now);
+        }
+        let mut remaining = Some(Duration::ZERO);
+        for progress in devices.values() {
+            let left = progress.queued.saturating_sub(progress.done);
+            if left == 0 {
+                continue;
+            }
+            // How fast files get done, which is the throughput unless
+            // they're skipped or not read completely
+            let measured = progress
+                .first_start
+                .map(|start| (progress.done as f64, now.duration_since(start)))
+                .filter(|(done, elapsed)| *done > 0.0 && !elapsed.is_zero());
+            let rate = match measured {
+                Some((done, elapsed)) if elapsed >= MIN_MEASURED => {
+                    Some(done / elapsed.as_secs_f64())
+                }
+                _ => self
+                    .earlier
+                    .get(&progress.key)
+                    .or(measured.map(|(done, elapsed)| done / elapsed.as_secs_f64())),
+            };
+            // Devices are read in parallel, the slowest one decides
+            remaining = match (remaining, rate) {
+                (Some(remaining), Some(rate)) if rate > 0.0 => {
+                    Some(remaining.max(Duration::from_secs_f64(left as f64 / rate)))
+                }
+                _ => None,
+            };
+        }
+        Progress {
+            bytes_done: devices.values().map(|p| p.done).sum(),
+            bytes_queued: devices.values().map(|p| p.queued).sum(),
+            listed: *self.listed.lock().unwrap(),
+            remaining,
+        }
+    }
+
+    /// The earlier throughput along with what this run measured, None if it measured nothing
+    pub fn measured(&self) -> Option<Throughput> {
+        let mut throughput = self.earlier.clone();
+        let mut any = false;
+        for progress in self.devices.lock().unwrap().values() {
+            match progress.elapsed() {
+                Some(elapsed) if elapsed >= MIN_MEASURED => {
+                    throughput.record(
+                        &progress.key,
+                        progress.bytes_read as f64 / elapsed.as_secs_f64(),
+                    );
+                    any = true;
+                }
+                _ => {}
+            }
+        }
+        any.then_some(throughput)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn throughput_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = TestDir::new("throughput");
+        assert_eq!(Throughput::read(&dir)?, Throughput::default());
+        let mut throughput = Throughput::default();
+        throughput.record("0d2c-11ee", 100e6);
+        throughput.record("device 2049", 50e6);
+        throughput.record("device 2049", 150e6);
+        throughput.write(&dir)?;
+        let read = Throughput::read(&dir)?;
+        assert_eq!(read, throughput);
+        assert_eq!(read.get("device 2049"), Some(100e6));
+        assert_eq!(read.get("device 2050"), None);
+
+        std::fs::write(dir.join(THROUGHPUT_NAME), "fast sda\n")?;
+        assert!(Throughput::read(&dir).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn remaining_time_from_earlier_runs() {
+        let mut earlier = Throughput::default();
+        earlier.record("device 1", 1000.0);
+        let tracker = ProgressTracker::new(earlier);
+        let path = || PathBuf::from("/nonexistent");
+        tracker.queue(1, path, 10_000);
+        tracker.queue(1, path, 10_000);
+        tracker.listed();
+        tracker.started(1, Instant::now());
+        let progress = tracker.finished(1, 10_000, 10_000);
+        assert_eq!(
+            (progress.bytes_done, progress.bytes_queued, progress.listed),
+            (10_000, 20_000, true)
+        );
+        // Read for far less than MIN_MEASURED, so it goes by the earlier runs
+        assert_eq!(progress.remaining, Some(Duration::from_secs(10)));
+        // Nothing known about this one
+        tracker.queue(2, path, 1);
+        assert_eq!(tracker.finished(1, 0, 0).remaining, None);
+        assert_eq!(tracker.measured(), None);
+    }
+}
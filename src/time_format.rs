@@ -0,0 +1,105 @@
+use rusty_archive::state::state_name_time;
+
+use std::time::{Duration, SystemTime};
+use time::OffsetDateTime;
+use time_tz::OffsetDateTimeExt;
+
+/// How times are printed in reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeFormat {
+    /// Like `94 days ago, 1653660817`
+    #[default]
+    Relative,
+    /// Seconds since the epoch, as stored in states
+    Epoch,
+    /// Local time, like `2022-05-27 14:13:37 +02:00`
+    Iso,
+}
+
+impl std::str::FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relative" => Ok(TimeFormat::Relative),
+            "epoch" => Ok(TimeFormat::Epoch),
+            "iso" => Ok(TimeFormat::Iso),
+            _ => Err(format!(
+                "expected `relative`, `epoch` or `iso`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// Largest whole unit of a duration, like `94 days` or `1 hour`
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (n, unit) = match secs {
+        0..=119 => (secs, "second"),
+        120..=7199 => (secs / 60, "minute"),
+        7200..=172_799 => (secs / 60 / 60, "hour"),
+        _ => (secs / 60 / 60 / 24, "day"),
+    };
+    format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" })
+}
+
+impl TimeFormat {
+    /// Formats a time relative to now
+    pub fn format(&self, time: SystemTime, now: SystemTime) -> String {
+        let epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self {
+            TimeFormat::Relative => match now.duration_since(time) {
+                Ok(ago) => format!("{} ago, {}", human_duration(ago), epoch),
+                Err(err) => format!("in {}, {}", human_duration(err.duration()), epoch),
+            },
+            TimeFormat::Epoch => epoch.to_string(),
+            TimeFormat::Iso => {
+                let system_tz =
+                    time_tz::system::get_timezone().expect("Failed to find system timezone");
+                let format = time::format_description::parse(
+                    "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour sign:mandatory]:[offset_minute]",
+                )
+                .unwrap();
+                OffsetDateTime::from(time)
+                    .to_timezone(system_tz)
+                    .format(&format)
+                    .unwrap()
+            }
+        }
+    }
+
+    /// Formats the time of a state, None if its name isn't a timestamp
+    pub fn format_state_name(&self, name: &str, now: SystemTime) -> Option<String> {
+        Some(self.format(state_name_time(name)?.into(), now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_times() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let fully_read = now - Duration::from_secs(94 * 24 * 60 * 60 + 5);
+        assert_eq!(
+            TimeFormat::Relative.format(fully_read, now),
+            "94 days ago, 1691878395"
+        );
+        assert_eq!(
+            TimeFormat::Relative.format(now - Duration::from_secs(3600), now),
+            "60 minutes ago, 1699996400"
+        );
+        assert_eq!(
+            TimeFormat::Relative.format(now + Duration::from_secs(1), now),
+            "in 1 second, 1700000001"
+        );
+        assert_eq!(TimeFormat::Epoch.format(fully_read, now), "1691878395");
+        assert_eq!("iso".parse(), Ok(TimeFormat::Iso));
+        assert!("local".parse::<TimeFormat>().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! BitTorrent v2 metainfo, see BEP 52
+//!
+//! Files are hashed as merkle trees of SHA-256 digests of 16 KiB blocks, so
+//! the plain digests of a state can't be reused and every file needs to be
+//! read again.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+
+/// Size of the leaves of the merkle trees
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// A bencoded value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    /// Keys are sorted by their raw bytes, as required
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Bencode::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.write(out);
+                }
+                out.push(b'e');
+            }
+            Bencode::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    Bencode::Bytes(key.clone()).write(out);
+                    value.write(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Bencode)>) -> Bencode {
+        Bencode::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+}
+
+/// Root of a merkle tree with `width` leaves, missing ones are all zeros
+fn merkle_root(leaves: &[[u8; 32]], width: usize) -> [u8; 32] {
+    debug_assert!(width.is_power_of_two() && leaves.len() <= width);
+    let mut layer = leaves.to_vec();
+    layer.resize(width, [0; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+    layer[0]
+}
+
+/// Merkle tree hashes of a single file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHashes {
+    pub len: u64,
+    /// None for empty files
+    pub pieces_root: Option<[u8; 32]>,
+    /// Roots of the subtrees of every piece, empty for files of up to one piece
+    pub piece_layer: Vec<[u8; 32]>,
+}
+
+/// Incrementally hashes a file into its merkle tree
+///
+/// Only the leaves of the current piece are kept, so memory use doesn't
+/// grow with the size of the file beyond one digest per piece.
+pub struct MerkleHasher {
+    blocks_per_piece: usize,
+    block: Vec<u8>,
+    leaves: Vec<[u8; 32]>,
+    piece_layer: Vec<[u8; 32]>,
+    len: u64,
+}
+
+impl MerkleHasher {
+    /// Piece length has to be a power of two of at least [`BLOCK_SIZE`]
+    pub fn new(piece_length: u64) -> Self {
+        assert!(piece_length.is_power_of_two() && piece_length >= BLOCK_SIZE as u64);
+        MerkleHasher {
+            blocks_per_piece: (piece_length / BLOCK_SIZE as u64) as usize,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            leaves: Vec::new(),
+            piece_layer: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let take = (BLOCK_SIZE - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.block.len() == BLOCK_SIZE {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn finish_block(&mut self) {
+        self.leaves.push(Sha256::digest(&self.block).into());
+        self.block.clear();
+        if self.leaves.len() == self.blocks_per_piece {
+            self.piece_layer
+                .push(merkle_root(&self.leaves, self.blocks_per_piece));
+            self.leaves.clear();
+        }
+    }
+
+    pub fn finalize(mut self) -> FileHashes {
+        if !self.block.is_empty() {
+            self.finish_block();
+        }
+        if self.len == 0 {
+            return FileHashes {
+                len: 0,
+                pieces_root: None,
+                piece_layer: Vec::new(),
+            };
+        }
+        if self.piece_layer.is_empty() {
+            // Up to one piece, the tree is only as wide as needed
+            let width = self.leaves.len().next_power_of_two();
+            return FileHashes {
+                len: self.len,
+                pieces_root: Some(merkle_root(&self.leaves, width)),
+                piece_layer: Vec::new(),
+            };
+        }
+        if !self.leaves.is_empty() {
+            self.piece_layer
+                .push(merkle_root(&self.leaves, self.blocks_per_piece));
+        }
+        // Pieces beyond the end of the file consist of zero leaves
+        let pad = merkle_root(&[], self.blocks_per_piece);
+        let width = self.piece_layer.len().next_power_of_two();
+        let mut layer = self.piece_layer.clone();
+        layer.resize(width, pad);
+        let root = merkle_root(&layer, width);
+        FileHashes {
+            len: self.len,
+            pieces_root: Some(root),
+            piece_layer: if self.piece_layer.len() > 1 {
+                self.piece_layer
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+/// Builds a v2 torrent of the given files and returns it along with its info hash
+pub fn metainfo<'a>(
+    name: &str,
+    piece_length: u64,
+    files: impl IntoIterator<Item = (&'a Path, &'a FileHashes)>,
+    announce: &[String],
+) -> (Vec<u8>, [u8; 32]) {
+    let mut file_tree = BTreeMap::new();
+    let mut piece_layers = BTreeMap::new();
+    for (path, hashes) in files {
+        let mut dir = &mut file_tree;
+        let components = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(c) => Some(c.to_string_lossy().into_owned().into_bytes()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let (file_name, parents) = components.split_last().unwrap();
+        for parent in parents {
+            dir = match dir
+                .entry(parent.clone())
+                .or_insert_with(|| Bencode::Dict(BTreeMap::new()))
+            {
+                Bencode::Dict(entries) => entries,
+                _ => unreachable!("directories are always dicts"),
+            };
+        }
+        let mut entry = vec![("length", Bencode::Int(hashes.len as i64))];
+        if let Some(root) = hashes.pieces_root {
+            entry.push(("pieces root", Bencode::Bytes(root.to_vec())));
+            if !hashes.piece_layer.is_empty() {
+                piece_layers.insert(root.to_vec(), Bencode::Bytes(hashes.piece_layer.concat()));
+            }
+        }
+        dir.insert(
+            file_name.clone(),
+            Bencode::dict([("", Bencode::dict(entry))]),
+        );
+    }
+
+    let info = Bencode::dict([
+        ("file tree", Bencode::Dict(file_tree)),
+        ("meta version", Bencode::Int(2)),
+        ("name", Bencode::Bytes(name.as_bytes().to_vec())),
+        ("piece length", Bencode::Int(piece_length as i64)),
+    ]);
+    let mut encoded_info = Vec::new();
+    info.write(&mut encoded_info);
+    let info_hash = Sha256::digest(&encoded_info).into();
+
+    let mut torrent = vec![
+        (
+            "created by",
+            Bencode::Bytes(format!("rusty-archive {}", env!("CARGO_PKG_VERSION")).into_bytes()),
+        ),
+        ("info", info),
+        ("piece layers", Bencode::Dict(piece_layers)),
+    ];
+    if let Some(first) = announce.first() {
+        torrent.push(("announce", Bencode::Bytes(first.as_bytes().to_vec())));
+        torrent.push((
+            "announce-list",
+            Bencode::List(
+                announce
+                    .iter()
+                    .map(|url| Bencode::List(vec![Bencode::Bytes(url.as_bytes().to_vec())]))
+                    .collect(),
+            ),
+        ));
+    }
+    let mut encoded = Vec::new();
+    Bencode::dict(torrent).write(&mut encoded);
+    (encoded, info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(data: &[u8], piece_length: u64) -> FileHashes {
+        let mut hasher = MerkleHasher::new(piece_length);
+        // Odd chunks, so blocks are assembled from several updates
+        for chunk in data.chunks(5000) {
+            hasher.update(chunk);
+        }
+        hasher.finalize()
+    }
+
+    fn sha256_pair(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(a)
+            .chain_update(b)
+            .finalize()
+            .into()
+    }
+
+    #[test]
+    fn merkle_roots() {
+        assert_eq!(hash(b"", 16384).pieces_root, None);
+
+        let small = hash(b"hello", 16384);
+        assert_eq!(small.pieces_root, Some(Sha256::digest(b"hello").into()));
+        assert!(small.piece_layer.is_empty());
+
+        // Three blocks in pieces of two, the second piece is padded with zeros
+        let data = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let leaves = data
+            .chunks(BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).into())
+            .collect::<Vec<[u8; 32]>>();
+        let pieces = [
+            sha256_pair(leaves[0], leaves[1]),
+            sha256_pair(leaves[2], [0; 32]),
+        ];
+        let hashes = hash(&data, 2 * BLOCK_SIZE as u64);
+        assert_eq!(hashes.piece_layer, pieces);
+        assert_eq!(hashes.pieces_root, Some(sha256_pair(pieces[0], pieces[1])));
+        // With a single piece, the root is the same and no layer is needed
+        let hashes = hash(&data, 4 * BLOCK_SIZE as u64);
+        assert!(hashes.piece_layer.is_empty());
+        assert_eq!(hashes.pieces_root, Some(sha256_pair(pieces[0], pieces[1])));
+    }
+
+    #[test]
+    fn bencode() {
+        let mut out = Vec::new();
+        Bencode::dict([
+            ("b", Bencode::List(vec![Bencode::Int(-1), Bencode::Int(0)])),
+            ("a", Bencode::Bytes(b"spam".to_vec())),
+        ])
+        .write(&mut out);
+        assert_eq!(out, b"d1:a4:spam1:bli-1ei0eee");
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use walkdir::{DirEntry, WalkDir};
+
+/// Files and directories to skip when listing files
+#[derive(Clone, Debug, Default)]
+pub struct Excludes {
+    /// Matched against directory names
+    pub exclude_directory: Vec<Regex>,
+    /// Matched against file names
+    pub exclude_file: Vec<Regex>,
+    /// Matched against full paths
+    pub exclude_path: Vec<Regex>,
+    /// Matched against the first bytes of files
+    pub exclude_content_type: Vec<ContentType>,
+    /// Skip files and directories whose names start with a dot
+    pub skip_hidden: bool,
+}
+
+/// Types with well-known first bytes, by name
+const MAGIC: [(&str, &[u8]); 7] = [
+    ("zip", b"PK\x03\x04"),
+    ("gzip", b"\x1f\x8b"),
+    ("pdf", b"%PDF-"),
+    ("png", b"\x89PNG\r\n\x1a\n"),
+    ("jpeg", b"\xff\xd8\xff"),
+    ("elf", b"\x7fELF"),
+    ("sqlite", b"SQLite format 3\0"),
+];
+
+/// Number of bytes read from files to recognize their type
+const HEAD_LEN: u64 = 4096;
+
+/// Kind of files recognized by their first bytes
+///
+/// Either a name from [`MAGIC`], `magic:` followed by the first bytes in
+/// hex, or `zeros` for files starting with 4 KiB of zeros. Torrent clients
+/// and download managers preallocate files like that before the data
+/// arrives.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentType {
+    /// As given, to write it back to the profile
+    name: String,
+    /// First bytes of matching files, None for files starting with zeros
+    magic: Option<Vec<u8>>,
+}
+
+impl std::str::FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let magic = if s == "zeros" {
+            None
+        } else if let Some(hex) = s.strip_prefix("magic:") {
+            match hex::decode(hex) {
+                Ok(magic) if !magic.is_empty() => Some(magic),
+                _ => {
+                    return Err(format!(
+                        "expected bytes in hex after `magic:`, got `{}`",
+                        hex
+                    ))
+                }
+            }
+        } else if let Some((_, magic)) = MAGIC.iter().find(|(name, _)| *name == s) {
+            Some(magic.to_vec())
+        } else {
+            let names = MAGIC.map(|(name, _)| name).join(", ");
+            return Err(format!(
+                "expected one of {}, zeros or magic:HEX, got `{}`",
+                names, s
+            ));
+        };
+        Ok(ContentType {
+            name: s.to_owned(),
+            magic,
+        })
+    }
+}
+
+impl std::fmt::Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl ContentType {
+    /// Whether a file starting with head is of this type
+    pub fn matches(&self, head: &[u8]) -> bool {
+        match &self.magic {
+            Some(magic) => head.starts_with(magic),
+            None => !head.is_empty() && head.iter().all(|b| *b == 0),
+        }
+    }
+}
+
+/// Whether a file is of one of the types, files that can't be read are kept
+fn excluded_content(path: &Path, types: &[ContentType]) -> bool {
+    let mut head = Vec::new();
+    // Errors are reported when the file is read
+    File::open(path)
+        .and_then(|f| f.take(HEAD_LEN).read_to_end(&mut head))
+        .is_ok_and(|_| types.iter().any(|t| t.matches(&head)))
+}
+
+/// What to do with symlinks, and junctions and other links on Windows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Links {
+    /// Leave them out, nothing is read through them
+    #[default]
+    Skip,
+    /// Keep track of where they point to instead of the contents
+    Record,
+    /// List the files they point to as if they were at the path of the link
+    ///
+    /// Links pointing to a directory above them are skipped, as everything
+    /// below it is listed already.
+    Follow,
+}
+
+impl std::str::FromStr for Links {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Links::Skip),
+            "record" => Ok(Links::Record),
+            "follow" => Ok(Links::Follow),
+            _ => Err(format!(
+                "expected `skip`, `record` or `follow`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// Order in which the entries of a directory are listed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Sorted by name
+    #[default]
+    Sorted,
+    /// As the filesystem returns them, which saves sorting huge directories
+    ///
+    /// States are sorted by path when they're written either way.
+    Fast,
+}
+
+impl std::str::FromStr for WalkOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sorted" => Ok(WalkOrder::Sorted),
+            "fast" => Ok(WalkOrder::Fast),
+            _ => Err(format!("expected `sorted` or `fast`, got `{}`", s)),
+        }
+    }
+}
+
+/// Lists all files below base_path, honoring the excludes
+///
+/// Windows reports junctions and symlinks as links, other reparse points
+/// like files synced by OneDrive are listed as files. With content types
+/// to exclude, the first bytes of every file are read while listing.
+pub fn list_files<'a>(
+    base_path: &Path,
+    opts: &'a Excludes,
+    links: Links,
+    order: WalkOrder,
+) -> impl Iterator<Item = Result<DirEntry>> + 'a {
+    let walk = WalkDir::new(base_path).follow_links(links == Links::Follow);
+    let walk = match order {
+        WalkOrder::Sorted => walk.sort_by_file_name(),
+        WalkOrder::Fast => walk,
+    };
+    filtered(walk, opts, links)
+}
+
+/// Lists the file at path on its own, nothing if it's excluded, gone or not a file
+///
+/// The directories of rel_path, its path in the state, have to pass the
+/// excludes as well, as they would when listing the whole directory.
+pub fn list_file<'a>(
+    path: &Path,
+    rel_path: &Path,
+    opts: &'a Excludes,
+    links: Links,
+) -> impl Iterator<Item = Result<DirEntry>> + 'a {
+    let hidden = |name: &str| opts.skip_hidden && name.starts_with('.');
+    let excluded = rel_path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .any(|c| {
+            let name = c.as_os_str().to_string_lossy();
+            hidden(&name) || opts.exclude_directory.iter().any(|re| re.is_match(&name))
+        })
+        || rel_path
+            .file_name()
+            .is_some_and(|name| hidden(&name.to_string_lossy()));
+    let walk = WalkDir::new(path)
+        .max_depth(0)
+        .follow_links(links == Links::Follow)
+        .follow_root_links(links == Links::Follow);
+    filtered(walk, opts, links)
+        .filter(move |_| !excluded)
+        // Listed files that are gone are missing, not an error
+        .filter(|e| {
+            let not_found = |err: &anyhow::Error| {
+                err.downcast_ref::<walkdir::Error>()
+                    .and_then(walkdir::Error::io_error)
+                    .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+            };
+            !e.as_ref().is_err_and(not_found)
+        })
+}
+
+fn filtered(
+    walk: WalkDir,
+    opts: &Excludes,
+    links: Links,
+) -> impl Iterator<Item = Result<DirEntry>> + '_ {
+    walk.into_iter()
+        .filter_entry(|e| {
+            let file_name = e.file_name().to_str().unwrap();
+            // The directory listed is never skipped, even if it's hidden itself
+            if opts.skip_hidden && e.depth() > 0 && file_name.starts_with('.') {
+                return false;
+            }
+            if e.file_type().is_dir() {
+                opts.exclude_directory
+                    .iter()
+                    .all(|re| !re.is_match(file_name))
+            } else {
+                opts.exclude_file.iter().all(|re| !re.is_match(file_name))
+            }
+        })
+        .filter(move |e| match e {
+            Ok(e) => e.file_type().is_file() || (links == Links::Record && e.path_is_symlink()),
+            Err(err) => err.loop_ancestor().is_none(),
+        })
+        .map(|e| e.context("Listing files failed"))
+        .filter(|e| match e {
+            Ok(e) => {
+                let path_str = e.path().as_os_str().to_str().unwrap();
+                opts.exclude_path.iter().all(|re| !re.is_match(path_str))
+            }
+            _ => true,
+        })
+        .filter(|e| match e {
+            Ok(e) if !opts.exclude_content_type.is_empty() && e.file_type().is_file() => {
+                !excluded_content(e.path(), &opts.exclude_content_type)
+            }
+            _ => true,
+        })
+}
+
+#[cfg(unix)]
+pub fn inode(entry: &DirEntry) -> u64 {
+    use walkdir::DirEntryExt;
+    entry.ino()
+}
+
+#[cfg(not(unix))]
+pub fn inode(_entry: &DirEntry) -> u64 {
+    // Not readily available, which keeps files in path order
+    0
+}